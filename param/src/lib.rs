@@ -126,8 +126,448 @@ pub trait ParamTake<T> {
     fn param_take(self) -> (Self::Transformed, T);
 }
 
+/// Item of type T may be missing or invalid, and is extracted as a `Result`.
+///
+/// `TryParam<T>` lets a config report a missing or invalid parameter as an
+/// error, rather than panicking or returning a dummy default from `param`.
+/// It is implemented for every `Param<T>`, so bounds can move from `Param<T>`
+/// to `TryParam<T>` without breaking existing configs.
+///
+/// # Example
+///
+/// ```rust
+/// use param::{ParamError, TryParam};
+///
+/// struct Port(u16);
+///
+/// struct Config {
+///     port: Option<u16>,
+/// }
+///
+/// impl TryParam<Port> for Config {
+///     fn try_param(&self) -> Result<Port, ParamError> {
+///         match self.port {
+///             Some(0) => Err(ParamError::invalid::<Port>("port must not be 0")),
+///             Some(port) => Ok(Port(port)),
+///             None => Err(ParamError::missing::<Port>()),
+///         }
+///     }
+/// }
+///
+/// assert!(Config { port: None }.try_param().is_err());
+/// ```
+pub trait TryParam<T> {
+    fn try_param(&self) -> Result<T, ParamError>;
+}
+
+impl<P: Param<T>, T> TryParam<T> for P {
+    #[inline]
+    fn try_param(&self) -> Result<T, ParamError> {
+        Ok(self.param())
+    }
+}
+
+/// Error of [`TryParam`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamError {
+    /// The parameter is not set.
+    Missing {
+        /// Type name of the parameter.
+        param: &'static str,
+    },
+    /// The parameter is set but invalid.
+    Invalid {
+        /// Type name of the parameter.
+        param: &'static str,
+        reason: String,
+    },
+}
+
+impl ParamError {
+    /// Parameter `T` is not set.
+    #[inline]
+    pub fn missing<T: ?Sized>() -> Self {
+        ParamError::Missing {
+            param: std::any::type_name::<T>(),
+        }
+    }
+
+    /// Parameter `T` is set but invalid.
+    #[inline]
+    pub fn invalid<T: ?Sized>(reason: impl Into<String>) -> Self {
+        ParamError::Invalid {
+            param: std::any::type_name::<T>(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Type name of the parameter.
+    #[inline]
+    pub fn param(&self) -> &'static str {
+        match self {
+            ParamError::Missing { param } | ParamError::Invalid { param, .. } => param,
+        }
+    }
+}
+
+impl std::fmt::Display for ParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamError::Missing { param } => write!(f, "missing param {param}"),
+            ParamError::Invalid { param, reason } => write!(f, "invalid param {param}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
 impl<T: Clone> Param<T> for T {
     fn param(&self) -> T {
         self.clone()
     }
 }
+
+// `Param` itself is not forwarded through pointers: the blanket impl above
+// already covers every `Clone` pointer, and would overlap.
+macro_rules! forward_ref {
+    ($($ptr:ty),*) => {
+        $(
+            impl<P: ParamRef<T> + ?Sized, T> ParamRef<T> for $ptr {
+                #[inline]
+                fn param_ref(&self) -> &T {
+                    (**self).param_ref()
+                }
+            }
+
+            impl<P: ParamMaybeRef<T> + ?Sized, T> ParamMaybeRef<T> for $ptr {
+                #[inline]
+                fn param_maybe_ref(&self) -> Option<&T> {
+                    (**self).param_maybe_ref()
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! forward_mut {
+    ($($ptr:ty),*) => {
+        $(
+            impl<P: ParamMut<T> + ?Sized, T> ParamMut<T> for $ptr {
+                #[inline]
+                fn param_mut(&mut self) -> &mut T {
+                    (**self).param_mut()
+                }
+            }
+
+            impl<P: ParamMaybeMut<T> + ?Sized, T> ParamMaybeMut<T> for $ptr {
+                #[inline]
+                fn param_maybe_mut(&mut self) -> Option<&mut T> {
+                    (**self).param_maybe_mut()
+                }
+            }
+        )*
+    };
+}
+
+forward_ref!(&P, &mut P, Box<P>, std::rc::Rc<P>, std::sync::Arc<P>);
+forward_mut!(&mut P, Box<P>);
+
+/// An empty slot of a context map generated by [`context_map!`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Vacant;
+
+/// A slot of a context map generated by [`context_map!`] which holds a `T`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Occupied<T>(pub T);
+
+/// A slot of a context map which may hold a `T`: [`Vacant`] or [`Occupied<T>`].
+pub trait Slot<T> {
+    fn get(&self) -> Option<&T>;
+    fn get_mut(&mut self) -> Option<&mut T>;
+}
+
+impl<T> Slot<T> for Vacant {
+    #[inline]
+    fn get(&self) -> Option<&T> {
+        None
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> Option<&mut T> {
+        None
+    }
+}
+
+impl<T> Slot<T> for Occupied<T> {
+    #[inline]
+    fn get(&self) -> Option<&T> {
+        Some(&self.0)
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> Option<&mut T> {
+        Some(&mut self.0)
+    }
+}
+
+/// Generates a typed context map: a struct with a [`Vacant`] or [`Occupied`]
+/// slot per field, implementing the `Param*` traits of this crate.
+///
+/// The type of each slot is a generic parameter of the struct, so whether a
+/// field is set is known at compile time: `Param<T>`, `ParamRef<T>`,
+/// `ParamMut<T>` and `ParamTake<T>` are only implemented once `T` has been set,
+/// and `ParamSet<T>` only while it is vacant. `ParamMaybeRef<T>`,
+/// `ParamMaybeMut<T>` and `ParamRemove<T>` are implemented in both states.
+/// `Param<T>` requires `T: Clone`.
+///
+/// Each field must have a distinct type, and a map has at most 24 fields.
+/// `new()` and `Default`, which is implemented by the macro, create a map
+/// with all slots vacant.
+///
+/// ```rust
+/// use param::{context_map, Param, ParamMaybeRef, ParamRef, ParamSet, ParamTake};
+///
+/// #[derive(Debug, Clone, Copy, PartialEq)]
+/// pub struct RequestId(u64);
+///
+/// context_map! {
+///     #[derive(Debug, Clone)]
+///     pub struct Context {
+///         peer: std::net::SocketAddr,
+///         request_id: RequestId,
+///     }
+/// }
+///
+/// let cx = Context::new();
+/// assert!(ParamMaybeRef::<RequestId>::param_maybe_ref(&cx).is_none());
+/// let cx = cx.param_set(RequestId(1));
+/// assert_eq!(Param::<RequestId>::param(&cx), RequestId(1));
+/// let (cx, id): (_, RequestId) = cx.param_take();
+/// assert_eq!(id, RequestId(1));
+/// let cx = cx.param_set("127.0.0.1:80".parse::<std::net::SocketAddr>().unwrap());
+/// let peer: &std::net::SocketAddr = cx.param_ref();
+/// assert_eq!(peer.port(), 80);
+/// ```
+#[macro_export]
+macro_rules! context_map {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$fattr:meta])* $fvis:vis $field:ident : $ty:ty),* $(,)?
+        }
+    ) => {
+        $crate::context_map!(
+            @zip [$(#[$attr])*] [$vis] $name []
+            [$($(#[$fattr])* $fvis $field : $ty,)*]
+            [
+                __S0 __S1 __S2 __S3 __S4 __S5 __S6 __S7 __S8 __S9 __S10 __S11
+                __S12 __S13 __S14 __S15 __S16 __S17 __S18 __S19 __S20 __S21 __S22 __S23
+            ]
+        );
+    };
+    (
+        @zip $attrs:tt $vis:tt $name:ident [$($done:tt)*]
+        [$(#[$fattr:meta])* $fvis:vis $field:ident : $ty:ty, $($rest:tt)*]
+        [$g:ident $($gs:ident)*]
+    ) => {
+        $crate::context_map!(
+            @zip $attrs $vis $name [$($done)* ([$(#[$fattr])* $fvis] $field $ty, $g)]
+            [$($rest)*] [$($gs)*]
+        );
+    };
+    (
+        @zip [$($attr:tt)*] [$vis:vis] $name:ident
+        [$(([$($fmeta:tt)*] $field:ident $ty:ty, $g:ident))*] [] [$($unused:ident)*]
+    ) => {
+        $($attr)*
+        $vis struct $name<$($g = $crate::Vacant),*> {
+            $($($fmeta)* $field: $g,)*
+        }
+
+        impl $name {
+            /// Create a map with all slots vacant.
+            #[inline]
+            #[allow(dead_code)]
+            pub const fn new() -> Self {
+                $name { $($field: $crate::Vacant,)* }
+            }
+        }
+
+        impl ::core::default::Default for $name {
+            #[inline]
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        $crate::context_map!(@each $name [] [$(($field $ty, $g))*]);
+    };
+    (@each $name:ident [$($before:tt)*] [$current:tt $($after:tt)*]) => {
+        $crate::context_map!(@field $name [$($before)*] $current [$($after)*]);
+        $crate::context_map!(@each $name [$($before)* $current] [$($after)*]);
+    };
+    (@each $name:ident [$($before:tt)*] []) => {};
+    (
+        @field $name:ident
+        [$(($bf:ident $bt:ty, $bg:ident))*]
+        ($field:ident $ty:ty, $g:ident)
+        [$(($af:ident $at:ty, $ag:ident))*]
+    ) => {
+        impl<$($bg,)* $($ag,)*> $crate::ParamSet<$ty>
+            for $name<$($bg,)* $crate::Vacant, $($ag,)*>
+        {
+            type Transformed = $name<$($bg,)* $crate::Occupied<$ty>, $($ag,)*>;
+
+            #[inline]
+            fn param_set(self, item: $ty) -> Self::Transformed {
+                $name {
+                    $($bf: self.$bf,)*
+                    $field: $crate::Occupied(item),
+                    $($af: self.$af,)*
+                }
+            }
+        }
+
+        impl<$($bg,)* $($ag,)*> $crate::ParamTake<$ty>
+            for $name<$($bg,)* $crate::Occupied<$ty>, $($ag,)*>
+        {
+            type Transformed = $name<$($bg,)* $crate::Vacant, $($ag,)*>;
+
+            #[inline]
+            fn param_take(self) -> (Self::Transformed, $ty) {
+                let map = $name {
+                    $($bf: self.$bf,)*
+                    $field: $crate::Vacant,
+                    $($af: self.$af,)*
+                };
+                (map, self.$field.0)
+            }
+        }
+
+        impl<$($bg,)* $g, $($ag,)*> $crate::ParamRemove<$ty>
+            for $name<$($bg,)* $g, $($ag,)*>
+        where
+            $g: $crate::Slot<$ty>,
+        {
+            type Transformed = $name<$($bg,)* $crate::Vacant, $($ag,)*>;
+
+            #[inline]
+            fn param_remove(self) -> Self::Transformed {
+                $name {
+                    $($bf: self.$bf,)*
+                    $field: $crate::Vacant,
+                    $($af: self.$af,)*
+                }
+            }
+        }
+
+        // The higher-ranked bound defers the `Clone` check to the use site, so
+        // fields which are not `Clone` only lose `Param`.
+        impl<$($bg,)* $($ag,)*> $crate::Param<$ty>
+            for $name<$($bg,)* $crate::Occupied<$ty>, $($ag,)*>
+        where
+            for<'__a> $ty: ::core::clone::Clone,
+        {
+            #[inline]
+            fn param(&self) -> $ty {
+                ::core::clone::Clone::clone(&self.$field.0)
+            }
+        }
+
+        impl<$($bg,)* $($ag,)*> $crate::ParamRef<$ty>
+            for $name<$($bg,)* $crate::Occupied<$ty>, $($ag,)*>
+        {
+            #[inline]
+            fn param_ref(&self) -> &$ty {
+                &self.$field.0
+            }
+        }
+
+        impl<$($bg,)* $($ag,)*> $crate::ParamMut<$ty>
+            for $name<$($bg,)* $crate::Occupied<$ty>, $($ag,)*>
+        {
+            #[inline]
+            fn param_mut(&mut self) -> &mut $ty {
+                &mut self.$field.0
+            }
+        }
+
+        impl<$($bg,)* $g, $($ag,)*> $crate::ParamMaybeRef<$ty>
+            for $name<$($bg,)* $g, $($ag,)*>
+        where
+            $g: $crate::Slot<$ty>,
+        {
+            #[inline]
+            fn param_maybe_ref(&self) -> Option<&$ty> {
+                $crate::Slot::get(&self.$field)
+            }
+        }
+
+        impl<$($bg,)* $g, $($ag,)*> $crate::ParamMaybeMut<$ty>
+            for $name<$($bg,)* $g, $($ag,)*>
+        where
+            $g: $crate::Slot<$ty>,
+        {
+            #[inline]
+            fn param_maybe_mut(&mut self) -> Option<&mut $ty> {
+                $crate::Slot::get_mut(&mut self.$field)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Peer(u16);
+
+    #[derive(Debug, PartialEq)]
+    struct Body(Vec<u8>);
+
+    context_map! {
+        struct Context {
+            peer: Peer,
+            body: Body,
+        }
+    }
+
+    #[test]
+    fn context_map_slots() {
+        let cx = Context::new().param_set(Body(vec![1]));
+        assert!(ParamMaybeRef::<Peer>::param_maybe_ref(&cx).is_none());
+
+        let mut cx = cx.param_set(Peer(80));
+        assert_eq!(Param::<Peer>::param(&cx), Peer(80));
+        ParamMut::<Body>::param_mut(&mut cx).0.push(2);
+        if let Some(peer) = ParamMaybeMut::<Peer>::param_maybe_mut(&mut cx) {
+            peer.0 += 1;
+        }
+        assert_eq!(ParamRef::<Peer>::param_ref(&cx), &Peer(81));
+
+        let (cx, body): (_, Body) = cx.param_take();
+        assert_eq!(body, Body(vec![1, 2]));
+        assert!(ParamMaybeRef::<Body>::param_maybe_ref(&cx).is_none());
+        let cx: Context = ParamRemove::<Peer>::param_remove(cx);
+        assert!(ParamMaybeRef::<Peer>::param_maybe_ref(&cx).is_none());
+    }
+
+    fn peer<P: ParamRef<Peer> + ParamMaybeRef<Body>>(p: P) -> (Peer, bool) {
+        (*p.param_ref(), p.param_maybe_ref().is_some())
+    }
+
+    #[test]
+    fn forwards_through_pointers() {
+        let mut cx = Context::new().param_set(Peer(80));
+        assert_eq!(peer(&cx), (Peer(80), false));
+        assert_eq!(peer(Box::new(&cx)), (Peer(80), false));
+        assert_eq!(peer(std::rc::Rc::new(&cx)), (Peer(80), false));
+        assert_eq!(peer(std::sync::Arc::new(&cx)), (Peer(80), false));
+
+        let mut boxed = Box::new(&mut cx);
+        ParamMut::<Peer>::param_mut(&mut boxed).0 = 443;
+        assert!(ParamMaybeMut::<Body>::param_maybe_mut(&mut boxed).is_none());
+        assert_eq!(peer(&mut cx), (Peer(443), false));
+    }
+}
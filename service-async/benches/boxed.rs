@@ -0,0 +1,92 @@
+#[cfg(not(feature = "boxed-futures"))]
+mod boxed {
+    use std::{
+        convert::Infallible,
+        future::Future,
+        hint::black_box,
+        pin::{pin, Pin},
+        task::{Context, Poll, Waker},
+    };
+
+    use criterion::{criterion_group, Criterion};
+    use service_async::{BoxService, Service};
+
+    /// Drive a future which never yields to completion.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+        }
+    }
+
+    struct Small;
+
+    impl Service<u64> for Small {
+        type Response = u64;
+        type Error = Infallible;
+
+        async fn call(&self, req: u64) -> Result<Self::Response, Self::Error> {
+            Ok(req + 1)
+        }
+    }
+
+    /// A service whose future is too large to be stored inline.
+    struct Large;
+
+    impl Service<u64> for Large {
+        type Response = u64;
+        type Error = Infallible;
+
+        async fn call(&self, req: u64) -> Result<Self::Response, Self::Error> {
+            let buf = black_box([req; 32]);
+            std::future::ready(()).await;
+            Ok(buf.iter().sum())
+        }
+    }
+
+    type DynFuture<'a> = Pin<Box<dyn Future<Output = Result<u64, Infallible>> + 'a>>;
+
+    /// What `BoxedService` did before inline future storage: box every future.
+    fn call_dyn<S: Service<u64, Response = u64, Error = Infallible>>(
+        svc: &S,
+        req: u64,
+    ) -> DynFuture<'_> {
+        Box::pin(svc.call(req))
+    }
+
+    fn bench_boxed_call(c: &mut Criterion) {
+        let mut group = c.benchmark_group("boxed_call");
+
+        group.bench_function("direct", |b| {
+            let svc = Small;
+            b.iter(|| block_on(svc.call(black_box(1))))
+        });
+        group.bench_function("box_dyn_future", |b| {
+            let svc = Small;
+            b.iter(|| block_on(call_dyn(&svc, black_box(1))))
+        });
+        group.bench_function("boxed_service_inline", |b| {
+            let svc = Small.into_boxed();
+            b.iter(|| block_on(svc.call(black_box(1))))
+        });
+        group.bench_function("boxed_service_large", |b| {
+            let svc = Large.into_boxed();
+            b.iter(|| block_on(svc.call(black_box(1))))
+        });
+
+        group.finish();
+    }
+
+    criterion_group!(benches, bench_boxed_call);
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+criterion::criterion_main!(boxed::benches);
+
+// The services of this bench are written with `async fn call`, which the
+// `boxed-futures` feature does not accept.
+#[cfg(feature = "boxed-futures")]
+fn main() {}
@@ -0,0 +1,183 @@
+#[cfg(not(feature = "boxed-futures"))]
+mod stack {
+    use std::{
+        convert::Infallible,
+        future::Future,
+        hint::black_box,
+        pin::pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll, Waker},
+    };
+
+    use criterion::{criterion_group, Criterion};
+    use service_async::{
+        layer::{layer_fn, FactoryLayer},
+        stack::FactoryStack,
+        MakeService, Param, Service,
+    };
+
+    /// Drive a future which never yields to completion.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct Config {
+        limit: usize,
+    }
+
+    #[derive(Clone, Copy)]
+    struct Limit(usize);
+
+    impl Param<Limit> for Config {
+        fn param(&self) -> Limit {
+            Limit(self.limit)
+        }
+    }
+
+    struct Leaf {
+        hits: AtomicUsize,
+    }
+
+    impl Service<usize> for Leaf {
+        type Response = usize;
+        type Error = Infallible;
+
+        async fn call(&self, req: usize) -> Result<Self::Response, Self::Error> {
+            Ok(self.hits.fetch_add(req, Ordering::Relaxed))
+        }
+    }
+
+    struct LeafFactory;
+
+    impl LeafFactory {
+        fn layer<C>() -> impl FactoryLayer<C, (), Factory = Self> {
+            layer_fn(|_: &C, ()| LeafFactory)
+        }
+    }
+
+    impl MakeService for LeafFactory {
+        type Service = Leaf;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+            let hits = old
+                .map(|o| o.hits.load(Ordering::Relaxed))
+                .unwrap_or_default();
+            Ok(Leaf { hits: hits.into() })
+        }
+    }
+
+    /// A middleware which keeps a counter across reloads.
+    struct Mid<T> {
+        limit: usize,
+        count: AtomicUsize,
+        inner: T,
+    }
+
+    impl<T> Service<usize> for Mid<T>
+    where
+        T: Service<usize, Response = usize, Error = Infallible>,
+    {
+        type Response = usize;
+        type Error = Infallible;
+
+        async fn call(&self, req: usize) -> Result<Self::Response, Self::Error> {
+            if self.count.fetch_add(1, Ordering::Relaxed) >= self.limit {
+                black_box(());
+            }
+            self.inner.call(req).await
+        }
+    }
+
+    struct MidFactory<F> {
+        limit: usize,
+        inner: F,
+    }
+
+    impl<F> MidFactory<F> {
+        fn layer<C: Param<Limit>>() -> impl FactoryLayer<C, F, Factory = Self> {
+            layer_fn(|c: &C, inner| MidFactory {
+                limit: c.param().0,
+                inner,
+            })
+        }
+    }
+
+    impl<F: MakeService> MakeService for MidFactory<F> {
+        type Service = Mid<F::Service>;
+        type Error = F::Error;
+
+        fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+            Ok(Mid {
+                limit: self.limit,
+                count: old
+                    .map(|o| o.count.load(Ordering::Relaxed))
+                    .unwrap_or_default()
+                    .into(),
+                inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            })
+        }
+    }
+
+    macro_rules! deep_stack {
+        ($config:expr) => {
+            FactoryStack::new($config)
+                .push(LeafFactory::layer())
+                .push(MidFactory::layer())
+                .push(MidFactory::layer())
+                .push(MidFactory::layer())
+                .push(MidFactory::layer())
+                .push(MidFactory::layer())
+                .push(MidFactory::layer())
+                .push(MidFactory::layer())
+                .push(MidFactory::layer())
+        };
+    }
+
+    fn bench_stack(c: &mut Criterion) {
+        let mut group = c.benchmark_group("stack");
+        let config = Config { limit: 10 };
+
+        group.bench_function("build_deep_stack", |b| {
+            b.iter(|| deep_stack!(black_box(config)))
+        });
+        group.bench_function("make_deep_stack", |b| {
+            let stack = deep_stack!(config);
+            b.iter(|| black_box(stack.make().unwrap()))
+        });
+        group.bench_function("make_via_ref_deep_stack", |b| {
+            let factory = deep_stack!(config).into_inner();
+            let old = factory.make().unwrap();
+            b.iter(|| black_box(factory.make_via_ref(Some(&old)).unwrap()))
+        });
+        group.bench_function("make_via_ref_boxed_factory", |b| {
+            let factory = deep_stack!(config).into_boxed_factory().into_inner();
+            let old = factory.make().unwrap();
+            b.iter(|| black_box(factory.make_via_ref(Some(&old)).unwrap()))
+        });
+
+        group.bench_function("call_deep_stack", |b| {
+            let svc = deep_stack!(config).make().unwrap();
+            b.iter(|| block_on(svc.call(black_box(1))))
+        });
+
+        group.finish();
+    }
+
+    criterion_group!(benches, bench_stack);
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+criterion::criterion_main!(stack::benches);
+
+// The services of this bench are written with `async fn call`, which the
+// `boxed-futures` feature does not accept.
+#[cfg(feature = "boxed-futures")]
+fn main() {}
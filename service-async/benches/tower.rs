@@ -0,0 +1,203 @@
+//! Compare an equivalent middleware chain built with this crate and with tower.
+
+#[cfg(not(feature = "boxed-futures"))]
+mod tower {
+    use std::{
+        convert::Infallible,
+        future::{ready, Future, Ready},
+        hint::black_box,
+        pin::pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll, Waker},
+    };
+
+    use criterion::{criterion_group, Criterion};
+    use service_async::{
+        layer::{layer_fn, FactoryLayer},
+        stack::FactoryStack,
+        BoxService, MakeService, Service,
+    };
+    use tower::{Layer, ServiceBuilder, ServiceExt};
+
+    /// Drive a future which never yields to completion.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+        }
+    }
+
+    // ===== service-async =====
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<u64> for Echo {
+        type Response = u64;
+        type Error = Infallible;
+
+        async fn call(&self, req: u64) -> Result<Self::Response, Self::Error> {
+            Ok(req)
+        }
+    }
+
+    impl MakeService for Echo {
+        type Service = Echo;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+            Ok(Echo)
+        }
+    }
+
+    /// Counts requests, borrowing its counter from `&self`.
+    struct Count<T> {
+        count: AtomicUsize,
+        inner: T,
+    }
+
+    impl<T> Service<u64> for Count<T>
+    where
+        T: Service<u64, Response = u64, Error = Infallible>,
+    {
+        type Response = u64;
+        type Error = Infallible;
+
+        async fn call(&self, req: u64) -> Result<Self::Response, Self::Error> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.inner.call(req + 1).await
+        }
+    }
+
+    struct CountFactory<F>(F);
+
+    impl<F> CountFactory<F> {
+        fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self> {
+            layer_fn(|_: &C, inner| CountFactory(inner))
+        }
+    }
+
+    impl<F: MakeService> MakeService for CountFactory<F> {
+        type Service = Count<F::Service>;
+        type Error = F::Error;
+
+        fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+            Ok(Count {
+                count: AtomicUsize::new(0),
+                inner: self.0.make_via_ref(old.map(|o| &o.inner))?,
+            })
+        }
+    }
+
+    // ===== tower =====
+
+    #[derive(Clone)]
+    struct TowerEcho;
+
+    impl tower::Service<u64> for TowerEcho {
+        type Response = u64;
+        type Error = Infallible;
+        type Future = Ready<Result<u64, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: u64) -> Self::Future {
+            ready(Ok(req))
+        }
+    }
+
+    #[derive(Clone)]
+    struct TowerCount<S> {
+        count: std::sync::Arc<AtomicUsize>,
+        inner: S,
+    }
+
+    impl<S: tower::Service<u64>> tower::Service<u64> for TowerCount<S> {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = S::Future;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: u64) -> Self::Future {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.inner.call(req + 1)
+        }
+    }
+
+    struct TowerCountLayer;
+
+    impl<S> Layer<S> for TowerCountLayer {
+        type Service = TowerCount<S>;
+
+        fn layer(&self, inner: S) -> Self::Service {
+            TowerCount {
+                count: Default::default(),
+                inner,
+            }
+        }
+    }
+
+    fn bench_tower(c: &mut Criterion) {
+        let mut group = c.benchmark_group("vs_tower");
+
+        group.bench_function("raw_async_fn", |b| {
+            async fn handle(req: u64) -> Result<u64, Infallible> {
+                Ok(req + 4)
+            }
+            b.iter(|| block_on(handle(black_box(1))))
+        });
+        group.bench_function("service_async_4_layers", |b| {
+            let svc = FactoryStack::new(())
+                .replace(Echo)
+                .push(CountFactory::layer())
+                .push(CountFactory::layer())
+                .push(CountFactory::layer())
+                .push(CountFactory::layer())
+                .make()
+                .unwrap();
+            b.iter(|| block_on(svc.call(black_box(1))))
+        });
+        group.bench_function("service_async_4_layers_boxed", |b| {
+            let svc = FactoryStack::new(())
+                .replace(Echo)
+                .push(CountFactory::layer())
+                .push(CountFactory::layer())
+                .push(CountFactory::layer())
+                .push(CountFactory::layer())
+                .make()
+                .unwrap()
+                .into_boxed();
+            b.iter(|| block_on(svc.call(black_box(1))))
+        });
+        group.bench_function("tower_4_layers", |b| {
+            let svc = ServiceBuilder::new()
+                .layer(TowerCountLayer)
+                .layer(TowerCountLayer)
+                .layer(TowerCountLayer)
+                .layer(TowerCountLayer)
+                .service(TowerEcho);
+            // Tower services are cloned per call when shared between tasks.
+            b.iter(|| block_on(svc.clone().oneshot(black_box(1))))
+        });
+
+        group.finish();
+    }
+
+    criterion_group!(benches, bench_tower);
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+criterion::criterion_main!(tower::benches);
+
+// The services of this bench are written with `async fn call`, which the
+// `boxed-futures` feature does not accept.
+#[cfg(feature = "boxed-futures")]
+fn main() {}
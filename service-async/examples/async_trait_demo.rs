@@ -0,0 +1,103 @@
+//! Plugging services written with `#[async_trait]` into a `FactoryStack`, and
+//! handing a service of this crate to code expecting one.
+
+#[cfg(not(feature = "boxed-futures"))]
+mod async_trait_demo {
+    use std::{convert::Infallible, sync::Arc};
+
+    use async_trait::async_trait;
+    #[cfg(unix)]
+    use monoio::main as main_macro;
+    use service_async::{
+        async_trait_compat::{
+            AsyncTraitService, FromAsyncTrait, IntoAsyncTrait, LocalAsyncTraitService,
+        },
+        layer::{layer_fn, FactoryLayer},
+        stack::FactoryStack,
+        utils::CloneFactory,
+        MakeService, Service,
+    };
+    #[cfg(not(unix))]
+    use tokio::main as main_macro;
+
+    /// A service from a crate which standardized on `#[async_trait]`.
+    struct Greeter {
+        greeting: String,
+    }
+
+    #[async_trait]
+    impl AsyncTraitService<String> for Greeter {
+        type Response = String;
+        type Error = Infallible;
+
+        async fn call(&self, name: String) -> Result<String, Infallible> {
+            Ok(format!("{}, {name}!", self.greeting))
+        }
+    }
+
+    /// A middleware of this crate, stacked on top of the `#[async_trait]` service.
+    struct Shout<T> {
+        inner: T,
+    }
+
+    impl<T: Service<String, Response = String>> Service<String> for Shout<T> {
+        type Response = String;
+        type Error = T::Error;
+
+        async fn call(&self, req: String) -> Result<String, T::Error> {
+            Ok(self.inner.call(req).await?.to_uppercase())
+        }
+    }
+
+    impl<F: MakeService> MakeService for Shout<F> {
+        type Service = Shout<F::Service>;
+        type Error = F::Error;
+
+        fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+            Ok(Shout {
+                inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            })
+        }
+    }
+
+    impl<F> Shout<F> {
+        fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self> {
+            layer_fn(|_: &C, inner| Shout { inner })
+        }
+    }
+
+    /// Code from the other crate, which takes its own trait objects.
+    async fn greet_all(
+        svc: &dyn LocalAsyncTraitService<String, Response = String, Error = Infallible>,
+    ) {
+        for name in ["alice", "bob"] {
+            println!("{}", svc.call(name.to_string()).await.unwrap());
+        }
+    }
+
+    #[main_macro]
+    pub async fn main() {
+        let greeter: Arc<dyn AsyncTraitService<String, Response = String, Error = Infallible>> =
+            Arc::new(Greeter {
+                greeting: "hello".to_string(),
+            });
+
+        let stack = FactoryStack::new(())
+            .push(layer_fn(move |_: &(), ()| {
+                CloneFactory::new(FromAsyncTrait(greeter.clone()))
+            }))
+            .push(Shout::layer());
+        let svc = stack.make().unwrap();
+        println!("{}", svc.call("world".to_string()).await.unwrap());
+
+        greet_all(&IntoAsyncTrait(svc)).await;
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+use async_trait_demo::main;
+
+// The services of this example are written with `async fn call`, which the
+// `boxed-futures` feature does not accept.
+#[cfg(feature = "boxed-futures")]
+fn main() {}
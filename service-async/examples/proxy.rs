@@ -0,0 +1,271 @@
+//! A TCP proxy forwarding each connection to an upstream, with the upstream
+//! reloaded live.
+//!
+//! The example starts two echo upstreams and a proxy in front of the first,
+//! sends a message through, then switches the proxy to the second upstream
+//! with a config reload and sends another. The connection counter of the
+//! forwarding service is migrated across the reload, and the load metrics are
+//! kept.
+//!
+//! Run with `cargo run --example proxy`. It uses monoio, so it is for unix only,
+//! and it does not build with the `boxed-futures` feature.
+
+#[cfg(all(unix, not(feature = "boxed-futures")))]
+mod proxy {
+    use std::{cell::Cell, convert::Infallible, io, net::SocketAddr, rc::Rc, time::Duration};
+
+    use monoio::{
+        io::{copy, AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, Splitable},
+        net::{TcpListener, TcpStream},
+    };
+    use service_async::{
+        budget::{Budget, Budgeted, MeasureSize},
+        layer::{layer_fn, FactoryLayer, Layered},
+        load::{LoadMetrics, LoadProbe},
+        reload::{watch, ReloadController},
+        serve::{Listener, Server},
+        stack::FactoryStack,
+        time::{timer_fn, TimerHandle},
+        utils::CloneFactory,
+        AsyncMakeService, Param, Service,
+    };
+
+    // ===== Config =====
+
+    #[derive(Clone)]
+    struct ProxyConfig {
+        upstream: SocketAddr,
+        session_timeout: Duration,
+        timer: TimerHandle,
+    }
+
+    impl ProxyConfig {
+        fn new(upstream: SocketAddr) -> Self {
+            ProxyConfig {
+                upstream,
+                session_timeout: Duration::from_secs(10),
+                timer: TimerHandle::new(timer_fn(monoio::time::sleep)),
+            }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct Upstream(SocketAddr);
+
+    impl Param<Upstream> for ProxyConfig {
+        fn param(&self) -> Upstream {
+            Upstream(self.upstream)
+        }
+    }
+
+    impl Param<Budget> for ProxyConfig {
+        fn param(&self) -> Budget {
+            Budget {
+                max_size: None,
+                max_time: Some(self.session_timeout),
+            }
+        }
+    }
+
+    impl Param<TimerHandle> for ProxyConfig {
+        fn param(&self) -> TimerHandle {
+            self.timer.clone()
+        }
+    }
+
+    // ===== Forward(impl Service) and ForwardFactory(impl AsyncMakeService) =====
+
+    /// Bytes copied by a proxied connection.
+    #[derive(Debug)]
+    struct Transferred {
+        sent: u64,
+        received: u64,
+    }
+
+    impl MeasureSize for Transferred {
+        fn size(&self) -> usize {
+            (self.sent + self.received) as usize
+        }
+    }
+
+    /// Forwards a client connection to the upstream until both sides close.
+    struct Forward {
+        upstream: SocketAddr,
+        // Kept across reloads.
+        connections: Rc<Cell<u64>>,
+    }
+
+    impl Forward {
+        fn connections(&self) -> u64 {
+            self.connections.get()
+        }
+    }
+
+    impl Service<TcpStream> for Forward {
+        type Response = Transferred;
+        type Error = io::Error;
+
+        async fn call(&self, client: TcpStream) -> Result<Self::Response, Self::Error> {
+            self.connections.set(self.connections.get() + 1);
+            let upstream = TcpStream::connect_addr(self.upstream).await?;
+            let (mut client_read, mut client_write) = client.into_split();
+            let (mut upstream_read, mut upstream_write) = upstream.into_split();
+            let (sent, received) = monoio::join!(
+                async {
+                    let sent = copy(&mut client_read, &mut upstream_write).await?;
+                    upstream_write.shutdown().await?;
+                    io::Result::Ok(sent)
+                },
+                async {
+                    let received = copy(&mut upstream_read, &mut client_write).await?;
+                    client_write.shutdown().await?;
+                    io::Result::Ok(received)
+                }
+            );
+            Ok(Transferred {
+                sent: sent?,
+                received: received?,
+            })
+        }
+    }
+
+    struct ForwardFactory {
+        upstream: SocketAddr,
+    }
+
+    impl ForwardFactory {
+        fn layer<C: Param<Upstream>>() -> impl FactoryLayer<C, (), Factory = Self> {
+            layer_fn(|c: &C, ()| ForwardFactory {
+                upstream: c.param().0,
+            })
+        }
+    }
+
+    impl AsyncMakeService for ForwardFactory {
+        type Service = Forward;
+        type Error = Infallible;
+
+        async fn make_via_ref(
+            &self,
+            old: Option<&Self::Service>,
+        ) -> Result<Self::Service, Self::Error> {
+            Ok(Forward {
+                upstream: self.upstream,
+                connections: old.map(|o| o.connections.clone()).unwrap_or_default(),
+            })
+        }
+    }
+
+    // ===== Listener =====
+
+    struct Tcp(TcpListener);
+
+    impl Listener for Tcp {
+        type Conn = TcpStream;
+        type Error = io::Error;
+
+        async fn accept(&self) -> io::Result<TcpStream> {
+            self.0.accept().await.map(|(conn, _)| conn)
+        }
+    }
+
+    // ===== Upstream and client =====
+
+    /// Start an echo server which prefixes its replies with `tag`.
+    fn echo_upstream(tag: &'static str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        monoio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                monoio::spawn(async move {
+                    let mut reply = tag.as_bytes().to_vec();
+                    loop {
+                        let (res, buf) = conn.read(Vec::with_capacity(1024)).await;
+                        match res {
+                            Ok(0) | Err(_) => break,
+                            Ok(_) => reply.extend_from_slice(&buf),
+                        }
+                    }
+                    let _ = conn.write_all(reply).await;
+                    let _ = conn.shutdown().await;
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn request(proxy: SocketAddr, msg: &'static str) -> io::Result<String> {
+        let mut conn = TcpStream::connect_addr(proxy).await?;
+        let (res, _) = conn.write_all(msg).await;
+        res?;
+        conn.shutdown().await?;
+        let mut reply = Vec::new();
+        loop {
+            let (res, buf) = conn.read(Vec::with_capacity(1024)).await;
+            if res? == 0 {
+                break;
+            }
+            reply.extend_from_slice(&buf);
+        }
+        Ok(String::from_utf8_lossy(&reply).into_owned())
+    }
+
+    pub async fn run() -> io::Result<()> {
+        let upstream_a = echo_upstream("a: ")?;
+        let upstream_b = echo_upstream("b: ")?;
+
+        // The metrics are created once, outside of the stack, so they survive reloads.
+        let metrics = LoadMetrics::new();
+        let build = {
+            let metrics = metrics.clone();
+            move |config: &ProxyConfig| {
+                FactoryStack::new(config.clone())
+                    .push(ForwardFactory::layer())
+                    .push(Budgeted::<_, TimerHandle>::layer())
+                    .push(LoadProbe::<_, TimerHandle>::layer(metrics.clone()))
+                    .into_inner()
+            }
+        };
+
+        let (configs, receiver) = watch(ProxyConfig::new(upstream_a));
+        let (controller, slot) = ReloadController::start(build, receiver)
+            .await
+            .unwrap_or_else(|e| match e {});
+        monoio::spawn(controller.run());
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let proxy = listener.local_addr()?;
+        // Every connection is served by the live service of the slot.
+        let server = Server::new(Tcp(listener), CloneFactory::new(slot.clone()), |fut| {
+            monoio::spawn(fut);
+        });
+        monoio::spawn(server.run());
+
+        println!("reply: {}", request(proxy, "hello").await?);
+
+        configs.send(ProxyConfig::new(upstream_b));
+        // Let the controller apply the config.
+        monoio::time::sleep(Duration::from_millis(10)).await;
+
+        println!("reply: {}", request(proxy, "world").await?);
+
+        let svc = slot.get();
+        println!(
+            "connections: {}, latency: {:?}",
+            svc.inner().inner().connections(),
+            metrics.latency()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(feature = "boxed-futures")))]
+#[monoio::main(timer_enabled = true)]
+async fn main() {
+    if let Err(e) = proxy::run().await {
+        eprintln!("proxy failed: {e}");
+    }
+}
+
+#[cfg(any(not(unix), feature = "boxed-futures"))]
+fn main() {}
@@ -0,0 +1,614 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    classify::{Classify, DefaultClassify},
+    either::{BoxError, EitherExt},
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    pushback::{Pushback, PushbackHint},
+    reload,
+    scheduler::{Admission, Fairness},
+    semaphore::Semaphore,
+    shutdown::impl_shutdown_inner,
+    time::Clock,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// How [`AdaptiveConcurrency`] adjusts its limit from observed calls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitAlgorithm {
+    /// Additive increase, multiplicative decrease.
+    ///
+    /// The limit grows by `increase` after a call which succeeded within
+    /// `max_latency` while the limit was at least half used, and is multiplied
+    /// by `backoff` after a call which failed or took longer.
+    Aimd {
+        increase: usize,
+        backoff: f64,
+        max_latency: Duration,
+    },
+    /// Follows the ratio of the long-term average latency to the latest one.
+    ///
+    /// The limit shrinks while latency rises above `tolerance` times the average,
+    /// and otherwise grows by a square-root sized headroom. Each update moves the
+    /// limit by a `smoothing` share (`0.0` to `1.0`) of the difference.
+    Gradient { smoothing: f64, tolerance: f64 },
+}
+
+impl Default for LimitAlgorithm {
+    #[inline]
+    fn default() -> Self {
+        LimitAlgorithm::Gradient {
+            smoothing: 0.2,
+            tolerance: 1.5,
+        }
+    }
+}
+
+/// Configuration of [`AdaptiveConcurrency`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveConcurrencyConfig {
+    /// Limit of a newly created service. A reload keeps the learned limit.
+    pub initial_limit: usize,
+    pub min_limit: usize,
+    pub max_limit: usize,
+    /// Max number of requests waiting for the limit; more are rejected.
+    pub max_queue: usize,
+    pub algorithm: LimitAlgorithm,
+}
+
+impl Default for AdaptiveConcurrencyConfig {
+    #[inline]
+    fn default() -> Self {
+        AdaptiveConcurrencyConfig {
+            initial_limit: 20,
+            min_limit: 1,
+            max_limit: 1000,
+            max_queue: 0,
+            algorithm: LimitAlgorithm::default(),
+        }
+    }
+}
+
+/// A completed call observed by [`AdaptiveConcurrency`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitSample {
+    /// The limit after this sample was applied.
+    pub limit: usize,
+    /// Number of calls in flight when this call was admitted, including itself.
+    pub in_flight: usize,
+    pub latency: Duration,
+    /// Whether the call was classified as a transient failure.
+    pub dropped: bool,
+}
+
+/// Receives every [`LimitSample`] of an [`AdaptiveConcurrency`], e.g. to export
+/// the current limit as a metric.
+pub trait ConcurrencyMetrics {
+    fn record(&self, sample: &LimitSample);
+}
+
+impl ConcurrencyMetrics for () {
+    #[inline]
+    fn record(&self, _sample: &LimitSample) {}
+}
+
+impl<F: Fn(&LimitSample)> ConcurrencyMetrics for F {
+    #[inline]
+    fn record(&self, sample: &LimitSample) {
+        (self)(sample)
+    }
+}
+
+/// Error returned by [`AdaptiveConcurrency`].
+#[derive(Debug)]
+pub enum AdaptiveConcurrencyError<E> {
+    /// The limit was reached and the queue was full.
+    Rejected,
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for AdaptiveConcurrencyError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdaptiveConcurrencyError::Rejected => f.write_str("concurrency limit reached"),
+            AdaptiveConcurrencyError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for AdaptiveConcurrencyError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AdaptiveConcurrencyError::Rejected => None,
+            AdaptiveConcurrencyError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<E: PushbackHint> PushbackHint for AdaptiveConcurrencyError<E> {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match self {
+            AdaptiveConcurrencyError::Rejected => Some(Pushback::saturated()),
+            AdaptiveConcurrencyError::Inner(e) => e.pushback(),
+        }
+    }
+}
+
+impl<E: Error + Send + Sync + 'static> EitherExt for AdaptiveConcurrencyError<E> {
+    #[inline]
+    fn flatten_error(self) -> BoxError {
+        Box::new(self)
+    }
+
+    #[inline]
+    fn as_dyn_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        self
+    }
+}
+
+// Weight of a new sample in the long-term latency average of `Gradient`.
+const LONG_RTT_WEIGHT: f64 = 0.05;
+
+struct Limiter {
+    sem: Semaphore,
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    config: AdaptiveConcurrencyConfig,
+    limit: f64,
+    long_rtt: Option<f64>,
+}
+
+impl LimitState {
+    fn clamp(&mut self) {
+        let min = self.config.min_limit.max(1) as f64;
+        let max = (self.config.max_limit as f64).max(min);
+        self.limit = self.limit.clamp(min, max);
+    }
+
+    fn update(&mut self, latency: Duration, in_flight: usize, dropped: bool) {
+        let in_flight = in_flight as f64;
+        match self.config.algorithm {
+            LimitAlgorithm::Aimd {
+                increase,
+                backoff,
+                max_latency,
+            } => {
+                if dropped || latency > max_latency {
+                    self.limit *= backoff;
+                } else if in_flight * 2.0 >= self.limit {
+                    self.limit += increase as f64;
+                }
+            }
+            LimitAlgorithm::Gradient {
+                smoothing,
+                tolerance,
+            } => {
+                let rtt = latency.as_secs_f64().max(f64::MIN_POSITIVE);
+                let long_rtt = match self.long_rtt {
+                    Some(long) => long * (1.0 - LONG_RTT_WEIGHT) + rtt * LONG_RTT_WEIGHT,
+                    None => rtt,
+                };
+                self.long_rtt = Some(long_rtt);
+                let gradient = if dropped {
+                    0.5
+                } else {
+                    (tolerance * long_rtt / rtt).clamp(0.5, 1.0)
+                };
+                // Do not grow a limit the load does not reach.
+                if gradient >= 1.0 && in_flight * 2.0 < self.limit {
+                    return;
+                }
+                let target = self.limit * gradient + self.limit.sqrt();
+                self.limit += (target - self.limit) * smoothing.clamp(0.0, 1.0);
+            }
+        }
+        self.clamp();
+    }
+}
+
+impl Limiter {
+    fn new(config: AdaptiveConcurrencyConfig) -> Self {
+        let mut state = LimitState {
+            config,
+            limit: config.initial_limit as f64,
+            long_rtt: None,
+        };
+        state.clamp();
+        Limiter {
+            sem: Semaphore::new(state.limit as usize, Fairness::Strict),
+            state: Mutex::new(state),
+        }
+    }
+
+    fn reconfigure(&self, config: AdaptiveConcurrencyConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.config = config;
+        state.clamp();
+        self.sem.resize(state.limit as usize);
+    }
+
+    fn record(&self, latency: Duration, in_flight: usize, dropped: bool) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.update(latency, in_flight, dropped);
+        let limit = state.limit as usize;
+        if limit != self.sem.capacity() {
+            self.sem.resize(limit);
+        }
+        limit
+    }
+}
+
+/// A middleware which limits concurrent calls to the inner service, adjusting
+/// the limit from the observed latency and errors.
+///
+/// Requests over the limit wait in a queue of up to `max_queue` requests, and
+/// are rejected with [`AdaptiveConcurrencyError::Rejected`] when it is full.
+/// Every completed call is reported to the [`ConcurrencyMetrics`] hook `M`.
+///
+/// Calls classified as transient failures by the [`Classify`] `K` shrink the
+/// limit; by default every error does.
+///
+/// The learned limit, the queue and the in-flight count are kept across reloads.
+/// Within a [`ServiceSlot::begin`](crate::reload::ServiceSlot::begin)
+/// transaction the new config is applied when it commits. The clock `CL`
+/// which measures the latency is taken from the stack config through
+/// `Param<CL>`.
+pub struct AdaptiveConcurrency<T, CL, M = (), K = DefaultClassify> {
+    limiter: Arc<Limiter>,
+    clock: CL,
+    metrics: M,
+    classifier: K,
+    inner: T,
+}
+
+impl<T, CL> AdaptiveConcurrency<T, CL> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = AdaptiveConcurrencyFactory<T, CL>>
+    where
+        C: Param<AdaptiveConcurrencyConfig> + Param<CL>,
+    {
+        Self::layer_with_metrics(())
+    }
+}
+
+impl<T, CL, M, K> AdaptiveConcurrency<T, CL, M, K> {
+    /// Current concurrency limit.
+    pub fn limit(&self) -> usize {
+        self.limiter.sem.capacity()
+    }
+
+    /// Number of calls being processed by the inner service.
+    pub fn in_flight(&self) -> usize {
+        self.limiter.sem.in_use()
+    }
+
+    /// Number of requests waiting to be admitted.
+    pub fn queued(&self) -> usize {
+        self.limiter.sem.queued()
+    }
+
+    /// Create a layer which classifies calls with `classifier`.
+    pub fn layer_with_classifier<C>(
+        metrics: M,
+        classifier: K,
+    ) -> impl FactoryLayer<C, T, Factory = AdaptiveConcurrencyFactory<T, CL, M, K>>
+    where
+        C: Param<AdaptiveConcurrencyConfig> + Param<CL>,
+        M: Clone,
+        K: Clone,
+    {
+        layer_fn(move |c: &C, inner| AdaptiveConcurrencyFactory {
+            config: Param::<AdaptiveConcurrencyConfig>::param(c),
+            clock: Param::<CL>::param(c),
+            metrics: metrics.clone(),
+            classifier: classifier.clone(),
+            inner,
+        })
+    }
+}
+
+impl<T, CL, M> AdaptiveConcurrency<T, CL, M> {
+    pub fn layer_with_metrics<C>(
+        metrics: M,
+    ) -> impl FactoryLayer<C, T, Factory = AdaptiveConcurrencyFactory<T, CL, M>>
+    where
+        C: Param<AdaptiveConcurrencyConfig> + Param<CL>,
+        M: Clone,
+    {
+        Self::layer_with_classifier(metrics, DefaultClassify)
+    }
+}
+
+impl<T, CL, M, K, R> Service<R> for AdaptiveConcurrency<T, CL, M, K>
+where
+    T: Service<R>,
+    CL: Clock,
+    M: ConcurrencyMetrics,
+    K: Classify<T::Response, T::Error>,
+{
+    type Response = T::Response;
+    type Error = AdaptiveConcurrencyError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let max_queue = self.limiter.state.lock().unwrap().config.max_queue;
+        let Some(acquire) = self.limiter.sem.acquire_bounded(Admission::default(), max_queue) else {
+            return Err(AdaptiveConcurrencyError::Rejected);
+        };
+        let _permit = acquire.await;
+        let in_flight = self.limiter.sem.in_use();
+        let start = self.clock.now();
+        let res = self.inner.call(req).await;
+        let latency = self.clock.now().saturating_duration_since(start);
+        let dropped = self.classifier.classify(&res).is_transient();
+        let limit = self.limiter.record(latency, in_flight, dropped);
+        self.metrics.record(&LimitSample {
+            limit,
+            in_flight,
+            latency,
+            dropped,
+        });
+        res.map_err(AdaptiveConcurrencyError::Inner)
+    }
+}
+
+/// Factory of [`AdaptiveConcurrency`].
+pub struct AdaptiveConcurrencyFactory<F, CL, M = (), K = DefaultClassify> {
+    config: AdaptiveConcurrencyConfig,
+    clock: CL,
+    metrics: M,
+    classifier: K,
+    inner: F,
+}
+
+impl<F, CL, M, K> AdaptiveConcurrencyFactory<F, CL, M, K> {
+    fn limiter<S>(&self, old: Option<&AdaptiveConcurrency<S, CL, M, K>>) -> Arc<Limiter> {
+        match old {
+            Some(old) => {
+                let (limiter, config) = (old.limiter.clone(), self.config);
+                reload::on_commit(move || limiter.reconfigure(config));
+                old.limiter.clone()
+            }
+            None => Arc::new(Limiter::new(self.config)),
+        }
+    }
+}
+
+impl<F: MakeService, CL: Clone, M: Clone, K: Clone> MakeService
+    for AdaptiveConcurrencyFactory<F, CL, M, K>
+{
+    type Service = AdaptiveConcurrency<F::Service, CL, M, K>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(AdaptiveConcurrency {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            limiter: self.limiter(old),
+            clock: self.clock.clone(),
+            metrics: self.metrics.clone(),
+            classifier: self.classifier.clone(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, CL: Clone, M: Clone, K: Clone> AsyncMakeService
+    for AdaptiveConcurrencyFactory<F, CL, M, K>
+{
+    type Service = AdaptiveConcurrency<F::Service, CL, M, K>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(AdaptiveConcurrency {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            limiter: self.limiter(old),
+            clock: self.clock.clone(),
+            metrics: self.metrics.clone(),
+            classifier: self.classifier.clone(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(AdaptiveConcurrencyFactory<_, CL, M, K> { config, clock, metrics, classifier });
+
+impl<T, CL, M, K> Layered for AdaptiveConcurrency<T, CL, M, K> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(AdaptiveConcurrency<T, CL, M, K>);
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::pin, time::Duration};
+
+    use super::{
+        AdaptiveConcurrency, AdaptiveConcurrencyConfig, AdaptiveConcurrencyError,
+        AdaptiveConcurrencyFactory, LimitAlgorithm,
+    };
+    use crate::{
+        layer::FactoryLayer,
+        reload::ServiceSlot,
+        test_util::{block_on, poll_once},
+        time::MockTimer,
+        utils::CloneFactory,
+        yielding::yield_now,
+        MakeService, Param, Service,
+    };
+
+    /// A call taking `latency` of mock time, which fails if `fail` is set.
+    #[derive(Clone, Copy)]
+    struct Call {
+        latency: Duration,
+        fail: bool,
+    }
+
+    const FAST: Call = Call {
+        latency: Duration::from_millis(10),
+        fail: false,
+    };
+    const SLOW: Call = Call {
+        latency: Duration::from_millis(200),
+        fail: false,
+    };
+    const FAILED: Call = Call {
+        latency: Duration::from_millis(10),
+        fail: true,
+    };
+
+    #[derive(Clone)]
+    struct Backend(MockTimer);
+
+    impl Service<Call> for Backend {
+        type Response = ();
+        type Error = &'static str;
+
+        async fn call(&self, req: Call) -> Result<(), &'static str> {
+            yield_now().await;
+            self.0.advance(req.latency);
+            if req.fail {
+                Err("failed")
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    struct Config(AdaptiveConcurrencyConfig, MockTimer);
+
+    impl Param<AdaptiveConcurrencyConfig> for Config {
+        fn param(&self) -> AdaptiveConcurrencyConfig {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    fn factory(
+        config: AdaptiveConcurrencyConfig,
+    ) -> AdaptiveConcurrencyFactory<CloneFactory<Backend>, MockTimer> {
+        let timer = MockTimer::new();
+        AdaptiveConcurrency::layer().layer(
+            &Config(config, timer.clone()),
+            CloneFactory::new(Backend(timer)),
+        )
+    }
+
+    fn aimd(initial_limit: usize, max_limit: usize) -> AdaptiveConcurrencyConfig {
+        AdaptiveConcurrencyConfig {
+            initial_limit,
+            min_limit: 1,
+            max_limit,
+            max_queue: 0,
+            algorithm: LimitAlgorithm::Aimd {
+                increase: 1,
+                backoff: 0.5,
+                max_latency: Duration::from_millis(100),
+            },
+        }
+    }
+
+    #[test]
+    fn aimd_limit() {
+        let svc = factory(aimd(2, 10)).make().unwrap();
+        block_on(svc.call(FAST)).unwrap();
+        assert_eq!(svc.limit(), 3);
+        // One call in flight does not use half of the limit.
+        block_on(svc.call(FAST)).unwrap();
+        assert_eq!(svc.limit(), 3);
+        block_on(svc.call(SLOW)).unwrap();
+        assert_eq!(svc.limit(), 1);
+        assert!(block_on(svc.call(FAILED)).is_err());
+        assert_eq!(svc.limit(), 1);
+    }
+
+    #[test]
+    fn gradient_shrinks_on_rising_latency() {
+        let svc = factory(AdaptiveConcurrencyConfig {
+            algorithm: LimitAlgorithm::Gradient {
+                smoothing: 1.0,
+                tolerance: 1.0,
+            },
+            ..AdaptiveConcurrencyConfig::default()
+        })
+        .make()
+        .unwrap();
+        block_on(svc.call(FAST)).unwrap();
+        assert_eq!(svc.limit(), 20);
+        block_on(svc.call(SLOW)).unwrap();
+        assert_eq!(svc.limit(), 14);
+    }
+
+    #[test]
+    fn rejects_over_the_limit() {
+        let svc = factory(aimd(1, 10)).make().unwrap();
+        let mut first = pin!(svc.call(FAST));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert_eq!(svc.in_flight(), 1);
+        assert!(matches!(
+            block_on(svc.call(FAST)),
+            Err(AdaptiveConcurrencyError::Rejected)
+        ));
+        block_on(first).unwrap();
+        assert_eq!(svc.in_flight(), 0);
+    }
+
+    #[test]
+    fn reload_keeps_the_learned_limit() {
+        let old = factory(aimd(2, 10)).make().unwrap();
+        block_on(old.call(FAST)).unwrap();
+
+        let new = factory(aimd(2, 10)).make_via_ref(Some(&old)).unwrap();
+        assert_eq!(new.limit(), 3);
+        let shrunk = factory(aimd(2, 2)).make_via_ref(Some(&new)).unwrap();
+        assert_eq!(shrunk.limit(), 2);
+    }
+
+    #[test]
+    fn rolled_back_reload_keeps_the_config() {
+        let slot = ServiceSlot::new(factory(aimd(2, 10)).make().unwrap());
+        block_on(slot.call(FAST)).unwrap();
+
+        let txn = block_on(slot.begin(&factory(aimd(2, 2)))).unwrap();
+        txn.rollback();
+        assert_eq!(slot.get().limit(), 3);
+        block_on(slot.call(FAST)).unwrap();
+        assert_eq!(slot.get().limit(), 3);
+
+        block_on(slot.begin(&factory(aimd(2, 2)))).unwrap().commit();
+        assert_eq!(slot.get().limit(), 2);
+    }
+}
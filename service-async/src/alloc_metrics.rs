@@ -0,0 +1,385 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::{Cell, RefCell},
+    fmt::Display,
+    rc::Rc,
+};
+
+#[cfg(not(feature = "boxed-futures"))]
+use crate::AsyncMakeService;
+use crate::{layer::impl_wrap_inner, metadata::short_type_name, MakeService, ServiceMetadata};
+
+/// Allocations counted by an [`AllocationTracker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AllocStats {
+    /// Number of allocations, including reallocations.
+    pub allocations: u64,
+    /// Bytes allocated.
+    pub bytes: u64,
+}
+
+impl AllocStats {
+    /// The allocations made between `earlier` and `self`.
+    #[inline]
+    pub fn since(self, earlier: AllocStats) -> AllocStats {
+        AllocStats {
+            allocations: self.allocations.saturating_sub(earlier.allocations),
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+        }
+    }
+}
+
+impl Display for AllocStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} allocs, {} bytes", self.allocations, self.bytes)
+    }
+}
+
+/// A source of allocation counters of the current thread.
+///
+/// [`ThreadAllocations`] reads the counters of [`TrackingAllocator`]; another
+/// implementation may read the statistics of the allocator in use instead.
+pub trait AllocationTracker {
+    /// The allocations made by the current thread so far.
+    fn snapshot(&self) -> AllocStats;
+}
+
+thread_local! {
+    static ALLOCATIONS: Cell<AllocStats> = const {
+        Cell::new(AllocStats {
+            allocations: 0,
+            bytes: 0,
+        })
+    };
+}
+
+/// A global allocator shim which counts the allocations of each thread and
+/// passes them on to the wrapped allocator.
+///
+/// ```
+/// use std::alloc::System;
+///
+/// use service_async::alloc_metrics::TrackingAllocator;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TrackingAllocator = TrackingAllocator::new(System);
+/// # fn main() {}
+/// ```
+#[derive(Debug, Default)]
+pub struct TrackingAllocator<A = System> {
+    inner: A,
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        TrackingAllocator { inner }
+    }
+}
+
+#[inline]
+fn count(bytes: usize) {
+    // The counters may be gone while the thread is torn down.
+    let _ = ALLOCATIONS.try_with(|stats| {
+        let mut s = stats.get();
+        s.allocations += 1;
+        s.bytes += bytes as u64;
+        stats.set(s);
+    });
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc_zeroed(layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        self.inner.realloc(ptr, layout, new_size)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// The [`AllocationTracker`] of [`TrackingAllocator`]. It reads zero if the
+/// global allocator is another one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadAllocations;
+
+impl AllocationTracker for ThreadAllocations {
+    #[inline]
+    fn snapshot(&self) -> AllocStats {
+        ALLOCATIONS.with(Cell::get)
+    }
+}
+
+/// Allocations made by one traced factory while making its service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerAllocs {
+    /// Short type name of the factory.
+    pub name: &'static str,
+    /// Number of traced factories outside of this one.
+    pub depth: usize,
+    /// Allocations of this factory and the factories it wraps.
+    pub total: AllocStats,
+    /// Allocations of this factory alone, without the traced factories it wraps.
+    pub own: AllocStats,
+}
+
+/// Allocations of a traced make, returned by
+/// [`FactoryStack::make_traced`](crate::stack::FactoryStack::make_traced).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AllocReport {
+    /// Allocations of the whole make.
+    pub total: AllocStats,
+    /// Allocations of the [`Traced`] factories, the outermost first.
+    pub layers: Vec<LayerAllocs>,
+}
+
+impl Display for AllocReport {
+    /// Print the layers as a tree, one per line.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "total: {}", self.total)?;
+        for layer in &self.layers {
+            writeln!(
+                f,
+                "{:indent$}{}: {} (own: {})",
+                "",
+                layer.name,
+                layer.total,
+                layer.own,
+                indent = 2 * (layer.depth + 1)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Tracing {
+    tracker: Rc<dyn AllocationTracker>,
+    // Total allocations of the traced children of each open frame.
+    children: Vec<AllocStats>,
+    layers: Vec<LayerAllocs>,
+}
+
+thread_local! {
+    static TRACING: RefCell<Option<Tracing>> = const { RefCell::new(None) };
+}
+
+fn snapshot() -> Option<AllocStats> {
+    TRACING.with(|t| t.borrow().as_ref().map(|t| t.tracker.snapshot()))
+}
+
+/// Run `f` with the allocations of [`Traced`] factories recorded.
+pub(crate) fn trace<T>(
+    tracker: Rc<dyn AllocationTracker>,
+    f: impl FnOnce() -> T,
+) -> (T, AllocReport) {
+    let prev = TRACING.with(|t| {
+        t.borrow_mut().replace(Tracing {
+            tracker: tracker.clone(),
+            children: Vec::with_capacity(16),
+            layers: Vec::with_capacity(16),
+        })
+    });
+    let start = tracker.snapshot();
+    let out = f();
+    let total = tracker.snapshot().since(start);
+    let tracing = TRACING.with(|t| std::mem::replace(&mut *t.borrow_mut(), prev));
+    let mut layers = tracing.map(|t| t.layers).unwrap_or_default();
+    // Frames are recorded when they end, so the inner ones come first.
+    layers.reverse();
+    (out, AllocReport { total, layers })
+}
+
+/// A factory which records the allocations made while its inner factory makes
+/// a service, when made through
+/// [`FactoryStack::make_traced`](crate::stack::FactoryStack::make_traced).
+///
+/// It makes the same service as the inner factory. Outside of a traced make
+/// it only forwards. Async makes are not traced, since other tasks may
+/// allocate on the thread meanwhile.
+#[derive(Debug, Clone)]
+pub struct Traced<F> {
+    inner: F,
+}
+
+impl<F> Traced<F> {
+    #[inline]
+    pub const fn new(inner: F) -> Self {
+        Traced { inner }
+    }
+}
+
+impl<F: MakeService> MakeService for Traced<F> {
+    type Service = F::Service;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let Some(start) = snapshot() else {
+            return self.inner.make_via_ref(old);
+        };
+        TRACING.with(|t| {
+            if let Some(t) = t.borrow_mut().as_mut() {
+                t.children.push(AllocStats::default());
+            }
+        });
+        let res = self.inner.make_via_ref(old);
+        let end = snapshot().unwrap_or(start);
+        TRACING.with(|t| {
+            if let Some(t) = t.borrow_mut().as_mut() {
+                let total = end.since(start);
+                let children = t.children.pop().unwrap_or_default();
+                if let Some(parent) = t.children.last_mut() {
+                    parent.allocations += total.allocations;
+                    parent.bytes += total.bytes;
+                }
+                t.layers.push(LayerAllocs {
+                    name: short_type_name(std::any::type_name::<F>()),
+                    depth: t.children.len(),
+                    total,
+                    own: total.since(children),
+                });
+            }
+        });
+        res
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F: AsyncMakeService> AsyncMakeService for Traced<F> {
+    type Service = F::Service;
+    type Error = F::Error;
+
+    #[inline]
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.inner.make_via_ref(old).await
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+impl_wrap_inner!(Traced<_> {});
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, convert::Infallible, rc::Rc};
+
+    use super::{AllocStats, AllocationTracker, LayerAllocs};
+    use crate::{layer::layer_fn, stack::FactoryStack, MakeService};
+
+    /// Counts the allocations the factories below pretend to make.
+    #[derive(Clone, Default)]
+    struct Counter(Rc<Cell<AllocStats>>);
+
+    impl Counter {
+        fn alloc(&self, bytes: u64) {
+            let s = self.0.get();
+            self.0.set(AllocStats {
+                allocations: s.allocations + 1,
+                bytes: s.bytes + bytes,
+            });
+        }
+    }
+
+    impl AllocationTracker for Counter {
+        fn snapshot(&self) -> AllocStats {
+            self.0.get()
+        }
+    }
+
+    struct Leaf(Counter);
+
+    impl MakeService for Leaf {
+        type Service = ();
+        type Error = Infallible;
+
+        fn make_via_ref(&self, _old: Option<&()>) -> Result<(), Infallible> {
+            self.0.alloc(8);
+            Ok(())
+        }
+    }
+
+    struct Bump<F>(Counter, F);
+
+    impl<F: MakeService> MakeService for Bump<F> {
+        type Service = F::Service;
+        type Error = F::Error;
+
+        fn make_via_ref(&self, old: Option<&F::Service>) -> Result<F::Service, F::Error> {
+            self.0.alloc(16);
+            self.1.make_via_ref(old)
+        }
+    }
+
+    #[test]
+    fn records_each_traced_layer() {
+        let counter = Counter::default();
+        let bump = counter.clone();
+        let stack = FactoryStack::new(())
+            .replace(Leaf(counter.clone()))
+            .traced()
+            .push(layer_fn(move |_: &(), inner| Bump(bump.clone(), inner)))
+            .traced();
+
+        let (res, report) = stack.make_traced_with(None, counter.clone());
+        res.unwrap();
+        let stats = |allocations, bytes| AllocStats { allocations, bytes };
+        assert_eq!(report.total, stats(2, 24));
+        assert_eq!(
+            report.layers,
+            [
+                LayerAllocs {
+                    name: "Bump",
+                    depth: 0,
+                    total: stats(2, 24),
+                    own: stats(1, 16),
+                },
+                LayerAllocs {
+                    name: "Leaf",
+                    depth: 1,
+                    total: stats(1, 8),
+                    own: stats(1, 8),
+                },
+            ]
+        );
+        assert_eq!(
+            report.to_string(),
+            "total: 2 allocs, 24 bytes\n  \
+             Bump: 2 allocs, 24 bytes (own: 1 allocs, 16 bytes)\n    \
+             Leaf: 1 allocs, 8 bytes (own: 1 allocs, 8 bytes)\n"
+        );
+
+        // Outside of a traced make the factories only forward.
+        stack.make().unwrap();
+        assert_eq!(counter.snapshot(), stats(4, 48));
+    }
+}
@@ -0,0 +1,176 @@
+use std::{future::Future, marker::PhantomData, sync::Arc};
+
+use crate::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, Param,
+};
+
+/// A parameter which takes I/O to resolve, like a secret from a vault or a
+/// certificate which is rotated.
+///
+/// It is the async counterpart of `Param<T>`. Since `FactoryLayer::layer` is
+/// sync, an `AsyncParam` is resolved by the factory, in `make_via_ref`, which
+/// also resolves it again on every reload. See [`async_param_layer`].
+///
+/// It is implemented for closures returning a future.
+pub trait AsyncParam<T> {
+    fn param(&self) -> impl Future<Output = T>;
+}
+
+impl<F, Fut> AsyncParam<Fut::Output> for F
+where
+    F: Fn() -> Fut,
+    Fut: Future,
+{
+    #[inline]
+    fn param(&self) -> impl Future<Output = Fut::Output> {
+        (self)()
+    }
+}
+
+/// Creates a layer whose factory resolves an [`AsyncParam`] on every make.
+///
+/// The source of the parameter, `P`, is taken from the stack config through
+/// `Param<P>`. On make, the parameter is resolved and passed to `f` along with
+/// the inner factory, and the factory returned by `f` makes the service.
+/// The inner factory is shared through an `Arc`, which is itself a factory.
+///
+/// ```rust
+/// use std::{convert::Infallible, sync::Arc};
+/// use service_async::{
+///     async_param::async_param_layer, stack::FactoryStack, utils::CloneFactory,
+///     AsyncMakeService, MakeService, Param,
+/// };
+///
+/// struct Config;
+///
+/// impl Param<fn() -> std::future::Ready<u32>> for Config {
+///     fn param(&self) -> fn() -> std::future::Ready<u32> {
+///         || std::future::ready(42)
+///     }
+/// }
+///
+/// struct Secret<F> {
+///     secret: u32,
+///     inner: Arc<F>,
+/// }
+///
+/// impl<F: AsyncMakeService> AsyncMakeService for Secret<F> {
+///     type Service = (u32, F::Service);
+///     type Error = F::Error;
+///
+///     async fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, F::Error> {
+///         Ok((self.secret, self.inner.make().await?))
+///     }
+/// }
+///
+/// # async fn run() {
+/// let stack = FactoryStack::new(Config)
+///     .push_clone_leaf(())
+///     .into_async()
+///     .push(async_param_layer(|secret, inner| Secret { secret, inner }));
+/// let (secret, _) = stack.make_async().await.unwrap();
+/// assert_eq!(secret, 42);
+/// # }
+/// ```
+pub fn async_param_layer<C, P, F, FN, T>(
+    f: FN,
+) -> impl FactoryLayer<C, F, Factory = AsyncParamFactory<P, F, FN, T>>
+where
+    C: Param<P>,
+    FN: Clone,
+{
+    layer_fn(move |c: &C, inner| AsyncParamFactory {
+        source: c.param(),
+        inner: Arc::new(inner),
+        f: f.clone(),
+        _marker: PhantomData,
+    })
+}
+
+/// Factory created by [`async_param_layer`].
+pub struct AsyncParamFactory<P, F, FN, T> {
+    source: P,
+    inner: Arc<F>,
+    f: FN,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<P, F, FN, T> AsyncParamFactory<P, F, FN, T> {
+    #[inline]
+    pub fn source(&self) -> &P {
+        &self.source
+    }
+}
+
+impl<P, F, FN, T, F2> AsyncMakeService for AsyncParamFactory<P, F, FN, T>
+where
+    P: AsyncParam<T>,
+    FN: Fn(T, Arc<F>) -> F2,
+    F2: AsyncMakeService,
+{
+    type Service = F2::Service;
+    type Error = F2::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let param = self.source.param().await;
+        (self.f)(param, self.inner.clone()).make_via_ref(old).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, sync::Arc};
+
+    use super::async_param_layer;
+    use crate::{
+        layer::FactoryLayer, make_service::AsyncMakeServiceWrapper, test_util::block_on,
+        utils::CloneFactory, yielding::yield_now, AsyncMakeService,
+    };
+
+    /// Pairs the resolved secret with the service of the inner factory.
+    struct Secret<F> {
+        secret: u32,
+        inner: Arc<F>,
+    }
+
+    impl<F: AsyncMakeService> AsyncMakeService for Secret<F> {
+        type Service = (u32, F::Service);
+        type Error = F::Error;
+
+        async fn make_via_ref(
+            &self,
+            _old: Option<&Self::Service>,
+        ) -> Result<Self::Service, F::Error> {
+            Ok((self.secret, self.inner.make().await?))
+        }
+    }
+
+    #[test]
+    fn resolves_on_every_make() {
+        let rotations = Rc::new(Cell::new(0));
+        // A secret which is rotated each time it is fetched.
+        let vault = {
+            let rotations = rotations.clone();
+            move || {
+                let rotations = rotations.clone();
+                async move {
+                    yield_now().await;
+                    rotations.set(rotations.get() + 1);
+                    rotations.get()
+                }
+            }
+        };
+        let factory = async_param_layer(|secret, inner| Secret { secret, inner })
+            .layer(&vault, AsyncMakeServiceWrapper(CloneFactory::new("svc")));
+        assert_eq!(rotations.get(), 0);
+
+        let first = block_on(factory.make()).unwrap();
+        let second = block_on(factory.make_via_ref(Some(&first))).unwrap();
+        assert_eq!(first, (1, "svc"));
+        assert_eq!(second, (2, "svc"));
+    }
+}
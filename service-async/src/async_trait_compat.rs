@@ -0,0 +1,215 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use crate::Service;
+
+/// The future returned by [`AsyncTraitService::call`].
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The future returned by [`LocalAsyncTraitService::call`].
+pub type LocalBoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// The object-safe service trait of crates built on `#[async_trait]`.
+///
+/// Its `call` has the signature `#[async_trait]` generates for
+/// `async fn call(&self, req: Req) -> Result<Self::Response, Self::Error>`,
+/// so such services implement it by putting `#[async_trait]` on the impl.
+/// `Box<dyn AsyncTraitService>` and [`FromAsyncTrait`] then implement
+/// [`Service`], which lets them take part in a `FactoryStack`.
+///
+/// The futures are boxed and `Send`, as `#[async_trait]` makes them.
+pub trait AsyncTraitService<Req>: Send + Sync {
+    type Response;
+    type Error;
+
+    fn call<'life0, 'async_trait>(
+        &'life0 self,
+        req: Req,
+    ) -> BoxFuture<'async_trait, Result<Self::Response, Self::Error>>
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait;
+}
+
+impl<T: AsyncTraitService<Req> + ?Sized, Req> AsyncTraitService<Req> for Box<T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    #[inline]
+    fn call<'life0, 'async_trait>(
+        &'life0 self,
+        req: Req,
+    ) -> BoxFuture<'async_trait, Result<Self::Response, Self::Error>>
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        (**self).call(req)
+    }
+}
+
+impl<T: AsyncTraitService<Req> + ?Sized, Req> AsyncTraitService<Req> for Arc<T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    #[inline]
+    fn call<'life0, 'async_trait>(
+        &'life0 self,
+        req: Req,
+    ) -> BoxFuture<'async_trait, Result<Self::Response, Self::Error>>
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        (**self).call(req)
+    }
+}
+
+impl<Req, Resp, E> Service<Req> for Box<dyn AsyncTraitService<Req, Response = Resp, Error = E>> {
+    type Response = Resp;
+    type Error = E;
+
+    #[inline]
+    fn call(&self, req: Req) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        AsyncTraitService::call(&**self, req)
+    }
+}
+
+/// A [`Service`] calling an [`AsyncTraitService`], e.g. an
+/// `Arc<dyn AsyncTraitService>` shared by the services made by a
+/// [`CloneFactory`](crate::utils::CloneFactory).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FromAsyncTrait<T>(pub T);
+
+impl<T: AsyncTraitService<Req>, Req> Service<Req> for FromAsyncTrait<T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    #[inline]
+    fn call(&self, req: Req) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.0.call(req)
+    }
+}
+
+/// The object-safe service trait of crates built on `#[async_trait(?Send)]`,
+/// whose futures need not be `Send`.
+///
+/// Services of this crate implement it through [`IntoAsyncTrait`], to be
+/// passed to such crates as `Box<dyn LocalAsyncTraitService>`. The futures of
+/// [`Service`] can not be required to be `Send`, so there is no adapter to
+/// [`AsyncTraitService`].
+pub trait LocalAsyncTraitService<Req> {
+    type Response;
+    type Error;
+
+    fn call<'life0, 'async_trait>(
+        &'life0 self,
+        req: Req,
+    ) -> LocalBoxFuture<'async_trait, Result<Self::Response, Self::Error>>
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait;
+}
+
+impl<Req, Resp, E> Service<Req>
+    for Box<dyn LocalAsyncTraitService<Req, Response = Resp, Error = E>>
+{
+    type Response = Resp;
+    type Error = E;
+
+    #[inline]
+    fn call(&self, req: Req) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        LocalAsyncTraitService::call(&**self, req)
+    }
+}
+
+/// A [`LocalAsyncTraitService`] calling a [`Service`] of this crate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IntoAsyncTrait<S>(pub S);
+
+impl<S, Req> LocalAsyncTraitService<Req> for IntoAsyncTrait<S>
+where
+    S: Service<Req>,
+    Req: 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    fn call<'life0, 'async_trait>(
+        &'life0 self,
+        req: Req,
+    ) -> LocalBoxFuture<'async_trait, Result<Self::Response, Self::Error>>
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(self.0.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Arc};
+
+    use async_trait::async_trait;
+
+    use super::{AsyncTraitService, FromAsyncTrait, IntoAsyncTrait, LocalAsyncTraitService};
+    use crate::{test_util::block_on, Service};
+
+    struct Double;
+
+    #[async_trait]
+    impl AsyncTraitService<u32> for Double {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<u32, Infallible> {
+            Ok(req * 2)
+        }
+    }
+
+    struct Negate;
+
+    #[async_trait(?Send)]
+    impl LocalAsyncTraitService<i32> for Negate {
+        type Response = i32;
+        type Error = Infallible;
+
+        async fn call(&self, req: i32) -> Result<i32, Infallible> {
+            Ok(-req)
+        }
+    }
+
+    /// A service of this crate.
+    struct Echo;
+
+    impl Service<i32> for Echo {
+        type Response = i32;
+        type Error = Infallible;
+
+        async fn call(&self, req: i32) -> Result<i32, Infallible> {
+            Ok(req)
+        }
+    }
+
+    #[test]
+    fn async_trait_services_are_services() {
+        let boxed: Box<dyn AsyncTraitService<u32, Response = u32, Error = Infallible>> =
+            Box::new(Double);
+        assert_eq!(block_on(Service::call(&boxed, 2)), Ok(4));
+        let shared = FromAsyncTrait(Arc::new(Double));
+        assert_eq!(block_on(shared.call(3)), Ok(6));
+
+        type Local = Box<dyn LocalAsyncTraitService<i32, Response = i32, Error = Infallible>>;
+        let local: Local = Box::new(Negate);
+        assert_eq!(block_on(Service::call(&local, 4)), Ok(-4));
+    }
+
+    #[test]
+    fn services_are_local_async_trait_services() {
+        let local: Box<dyn LocalAsyncTraitService<i32, Response = i32, Error = Infallible>> =
+            Box::new(IntoAsyncTrait(Echo));
+        assert_eq!(block_on(local.call(5)), Ok(5));
+        // And back.
+        assert_eq!(block_on(Service::call(&local, 6)), Ok(6));
+    }
+}
@@ -0,0 +1,310 @@
+use std::{collections::HashMap, error::Error, fmt::Display, future::Future, sync::Arc};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, ParamSet, Service, ServiceMetadata,
+};
+
+/// The caller of a request, as established by an [`Authenticator`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    /// Who the caller is, e.g. a user or service name.
+    pub subject: String,
+}
+
+impl Identity {
+    #[inline]
+    pub fn new(subject: impl Into<String>) -> Self {
+        Identity {
+            subject: subject.into(),
+        }
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.subject.fmt(f)
+    }
+}
+
+/// Validates the credentials of a request, e.g. a bearer token or a client
+/// certificate, and returns the identity of the caller.
+///
+/// It may do I/O, e.g. to ask an identity provider.
+pub trait Authenticator<R> {
+    type Identity;
+    type Error;
+
+    fn authenticate(&self, req: &R) -> impl Future<Output = Result<Self::Identity, Self::Error>>;
+}
+
+impl<A: Authenticator<R> + ?Sized, R> Authenticator<R> for Arc<A> {
+    type Identity = A::Identity;
+    type Error = A::Error;
+
+    #[inline]
+    fn authenticate(&self, req: &R) -> impl Future<Output = Result<Self::Identity, Self::Error>> {
+        (**self).authenticate(req)
+    }
+}
+
+/// Error returned by [`Authenticate`].
+#[derive(Debug)]
+pub enum AuthError<AE, E> {
+    /// The credentials were missing or invalid.
+    Rejected(AE),
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<AE: Display, E: Display> Display for AuthError<AE, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Rejected(e) => write!(f, "authentication failed: {e}"),
+            AuthError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<AE: Error + 'static, E: Error + 'static> Error for AuthError<AE, E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AuthError::Rejected(e) => Some(e),
+            AuthError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A middleware which authenticates each request with an [`Authenticator`]
+/// and rejects the ones which fail with [`AuthError::Rejected`].
+///
+/// It serves `(R, CX)` requests: the inner service is called with the
+/// identity set in the context through `ParamSet<A::Identity>`, so layers
+/// below read it with `Param`. The authenticator is taken from the stack
+/// config through `Param<A>`, so reloads pick up new credentials.
+pub struct Authenticate<T, A> {
+    authenticator: A,
+    inner: T,
+}
+
+impl<T, A> Authenticate<T, A> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<A>,
+    {
+        layer_fn(|c: &C, inner| Authenticate {
+            authenticator: c.param(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn authenticator(&self) -> &A {
+        &self.authenticator
+    }
+}
+
+impl<T, A, R, CX> Service<(R, CX)> for Authenticate<T, A>
+where
+    A: Authenticator<R>,
+    CX: ParamSet<A::Identity>,
+    T: Service<(R, CX::Transformed)>,
+{
+    type Response = T::Response;
+    type Error = AuthError<A::Error, T::Error>;
+
+    async fn call(&self, (req, cx): (R, CX)) -> Result<Self::Response, Self::Error> {
+        let identity = self
+            .authenticator
+            .authenticate(&req)
+            .await
+            .map_err(AuthError::Rejected)?;
+        self.inner
+            .call((req, cx.param_set(identity)))
+            .await
+            .map_err(AuthError::Inner)
+    }
+}
+
+impl<F: MakeService, A: Clone> MakeService for Authenticate<F, A> {
+    type Service = Authenticate<F::Service, A>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Authenticate {
+            authenticator: self.authenticator.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, A: Clone> AsyncMakeService for Authenticate<F, A> {
+    type Service = Authenticate<F::Service, A>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Authenticate {
+            authenticator: self.authenticator.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(Authenticate<_, A> { authenticator });
+
+impl<T, A> Layered for Authenticate<T, A> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Authenticate<T, A>);
+
+/// Error of [`StaticTokens`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    /// The request carries no token.
+    Missing,
+    /// The token is not known.
+    Invalid,
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Missing => write!(f, "missing token"),
+            TokenError::Invalid => write!(f, "invalid token"),
+        }
+    }
+}
+
+impl Error for TokenError {}
+
+/// An [`Authenticator`] which accepts a fixed set of tokens, each standing
+/// for an [`Identity`]. Clones share the tokens.
+///
+/// `extract` takes the token out of the request, e.g. from an
+/// `Authorization: Bearer` header.
+pub struct StaticTokens<X> {
+    tokens: Arc<HashMap<String, Identity>>,
+    extract: X,
+}
+
+impl<X> StaticTokens<X> {
+    pub fn new(extract: X) -> Self {
+        StaticTokens {
+            tokens: Arc::new(HashMap::new()),
+            extract,
+        }
+    }
+
+    /// Accept `token` as `identity`.
+    pub fn token(mut self, token: impl Into<String>, identity: Identity) -> Self {
+        Arc::make_mut(&mut self.tokens).insert(token.into(), identity);
+        self
+    }
+}
+
+impl<X: Clone> Clone for StaticTokens<X> {
+    fn clone(&self) -> Self {
+        StaticTokens {
+            tokens: self.tokens.clone(),
+            extract: self.extract.clone(),
+        }
+    }
+}
+
+impl<X, R> Authenticator<R> for StaticTokens<X>
+where
+    X: Fn(&R) -> Option<&str>,
+{
+    type Identity = Identity;
+    type Error = TokenError;
+
+    async fn authenticate(&self, req: &R) -> Result<Identity, TokenError> {
+        let token = (self.extract)(req).ok_or(TokenError::Missing)?;
+        self.tokens.get(token).cloned().ok_or(TokenError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use param::context_map;
+
+    use super::{AuthError, Authenticate, Identity, StaticTokens, TokenError};
+    use crate::{layer::FactoryLayer, test_util::block_on, Param, Service};
+
+    context_map! {
+        struct Context {
+            identity: Identity,
+        }
+    }
+
+    /// Answers with the identity set by [`Authenticate`].
+    struct Whoami;
+
+    impl<CX: Param<Identity>> Service<(&'static str, CX)> for Whoami {
+        type Response = String;
+        type Error = Infallible;
+
+        async fn call(&self, (_, cx): (&'static str, CX)) -> Result<String, Infallible> {
+            Ok(cx.param().subject)
+        }
+    }
+
+    fn bearer<'a>(req: &'a &'static str) -> Option<&'a str> {
+        req.strip_prefix("Bearer ")
+    }
+
+    type Tokens = StaticTokens<for<'a> fn(&'a &'static str) -> Option<&'a str>>;
+
+    #[test]
+    fn sets_the_identity_or_rejects() {
+        let tokens: Tokens = StaticTokens::new(bearer as _).token("t1", Identity::new("alice"));
+        let svc = Authenticate::<_, Tokens>::layer().layer(&tokens, Whoami);
+        let call = |req| block_on(svc.call((req, Context::new())));
+
+        assert_eq!(call("Bearer t1").unwrap(), "alice");
+        assert!(matches!(
+            call("Bearer t2"),
+            Err(AuthError::Rejected(TokenError::Invalid))
+        ));
+        let err = call("Basic t1").unwrap_err();
+        assert!(matches!(err, AuthError::Rejected(TokenError::Missing)));
+        assert_eq!(err.to_string(), "authentication failed: missing token");
+
+        // Tokens added to a new config are accepted by the services it makes.
+        let tokens = tokens.token("t2", Identity::new("bob"));
+        let new = Authenticate::<_, Tokens>::layer().layer(&tokens, Whoami);
+        assert_eq!(
+            block_on(new.call(("Bearer t2", Context::new()))).unwrap(),
+            "bob"
+        );
+        assert!(call("Bearer t2").is_err());
+    }
+}
@@ -0,0 +1,703 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    hash::Hash,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    parallel::{try_join_all, ParallelMake},
+    pushback::{Pushback, PushbackHint},
+    random::seeded_hash,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Error returned by the balancers in this module.
+#[derive(Debug)]
+pub enum BalanceError<E> {
+    /// There is no backend to send the request to.
+    NoBackend,
+    /// The backend failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for BalanceError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BalanceError::NoBackend => f.write_str("no backend available"),
+            BalanceError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for BalanceError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BalanceError::NoBackend => None,
+            BalanceError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<E: PushbackHint> PushbackHint for BalanceError<E> {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match self {
+            BalanceError::NoBackend => None,
+            BalanceError::Inner(e) => e.pushback(),
+        }
+    }
+}
+
+/// Weight of a backend. Backends with weight 0 receive no requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Weight(pub u32);
+
+/// A service which distributes requests over backends in proportion to their
+/// weights, with smooth weighted round robin.
+///
+/// Each backend is identified by a key `K`. On reload, backends are rebuilt with
+/// `make_via_ref` against the old backend of the same key, and keep their place
+/// in the round robin.
+pub struct WeightedBalance<K, S> {
+    backends: Vec<(K, S, Weight)>,
+    current: Mutex<Vec<i64>>,
+}
+
+impl<K, S> WeightedBalance<K, S> {
+    /// The backends with their keys and weights.
+    #[inline]
+    pub fn backends(&self) -> &[(K, S, Weight)] {
+        &self.backends
+    }
+
+    /// Get a backend by key.
+    pub fn backend(&self, key: &K) -> Option<&S>
+    where
+        K: PartialEq,
+    {
+        self.backends
+            .iter()
+            .find_map(|(k, s, _)| (k == key).then_some(s))
+    }
+
+    pub(crate) fn pick(&self) -> Option<(&K, &S)> {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for (idx, (_, _, weight)) in self.backends.iter().enumerate() {
+            if weight.0 == 0 {
+                continue;
+            }
+            let weight = weight.0 as i64;
+            current[idx] += weight;
+            total += weight;
+            if best.is_none_or(|b| current[idx] > current[b]) {
+                best = Some(idx);
+            }
+        }
+        let best = best?;
+        current[best] -= total;
+        let (key, svc, _) = &self.backends[best];
+        Some((key, svc))
+    }
+}
+
+impl<K, S, R> Service<R> for WeightedBalance<K, S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = BalanceError<S::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let (_, backend) = self.pick().ok_or(BalanceError::NoBackend)?;
+        backend.call(req).await.map_err(BalanceError::Inner)
+    }
+}
+
+/// Factory of [`WeightedBalance`] over keyed, weighted backend factories.
+#[derive(Debug, Clone)]
+pub struct WeightedBalanceFactory<K, F> {
+    backends: Vec<(K, F, Weight)>,
+}
+
+impl<K, F> WeightedBalanceFactory<K, F> {
+    #[inline]
+    pub const fn new(backends: Vec<(K, F, Weight)>) -> Self {
+        WeightedBalanceFactory { backends }
+    }
+
+    fn old_index<S>(old: Option<&WeightedBalance<K, S>>) -> HashMap<&K, usize>
+    where
+        K: Hash + Eq,
+    {
+        old.map(|o| {
+            o.backends
+                .iter()
+                .enumerate()
+                .map(|(idx, (k, _, _))| (k, idx))
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    fn current<S>(
+        &self,
+        old: Option<&WeightedBalance<K, S>>,
+        index: &HashMap<&K, usize>,
+    ) -> Mutex<Vec<i64>>
+    where
+        K: Hash + Eq,
+    {
+        let old_current = old.map(|o| o.current.lock().unwrap().clone());
+        let current = self
+            .backends
+            .iter()
+            .map(|(k, _, _)| match (&old_current, index.get(k)) {
+                (Some(c), Some(idx)) => c[*idx],
+                _ => 0,
+            })
+            .collect();
+        Mutex::new(current)
+    }
+}
+
+impl<K, F> From<Vec<(K, F, Weight)>> for WeightedBalanceFactory<K, F> {
+    #[inline]
+    fn from(backends: Vec<(K, F, Weight)>) -> Self {
+        Self::new(backends)
+    }
+}
+
+impl<K, F> MakeService for WeightedBalanceFactory<K, F>
+where
+    K: Hash + Eq + Clone,
+    F: MakeService,
+{
+    type Service = WeightedBalance<K, F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let index = Self::old_index(old);
+        let mut backends = Vec::with_capacity(self.backends.len());
+        for (key, factory, weight) in self.backends.iter() {
+            let old_svc = old.zip(index.get(key)).map(|(o, idx)| &o.backends[*idx].1);
+            backends.push((key.clone(), factory.make_via_ref(old_svc)?, *weight));
+        }
+        Ok(WeightedBalance {
+            current: self.current(old, &index),
+            backends,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.backends.iter().try_for_each(|(_, f, _)| f.validate())
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        let meta = ServiceMetadata::of::<Self::Service>()
+            .with_resource("backends", self.backends.len() as u64);
+        self.backends
+            .iter()
+            .fold(meta, |meta, (_, f, _)| meta.with_inner(f.service_metadata()))
+    }
+}
+
+impl<K, F> AsyncMakeService for WeightedBalanceFactory<K, F>
+where
+    K: Hash + Eq + Clone,
+    F: AsyncMakeService,
+{
+    type Service = WeightedBalance<K, F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let index = Self::old_index(old);
+        let mut backends = Vec::with_capacity(self.backends.len());
+        for (key, factory, weight) in self.backends.iter() {
+            let old_svc = old.zip(index.get(key)).map(|(o, idx)| &o.backends[*idx].1);
+            backends.push((key.clone(), factory.make_via_ref(old_svc).await?, *weight));
+        }
+        Ok(WeightedBalance {
+            current: self.current(old, &index),
+            backends,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.backends.iter().try_for_each(|(_, f, _)| f.validate())
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        let meta = ServiceMetadata::of::<Self::Service>()
+            .with_resource("backends", self.backends.len() as u64);
+        self.backends
+            .iter()
+            .fold(meta, |meta, (_, f, _)| meta.with_inner(f.service_metadata()))
+    }
+}
+
+/// Makes the backends concurrently.
+impl<K, F> AsyncMakeService for ParallelMake<WeightedBalanceFactory<K, F>>
+where
+    K: Hash + Eq + Clone,
+    F: AsyncMakeService,
+{
+    type Service = WeightedBalance<K, F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let factory = self.get_ref();
+        let index = WeightedBalanceFactory::<K, F>::old_index(old);
+        let index = &index;
+        let backends = try_join_all(factory.backends.iter().map(|(key, f, weight)| async move {
+            let old_svc = old.zip(index.get(key)).map(|(o, idx)| &o.backends[*idx].1);
+            Ok((key.clone(), f.make_via_ref(old_svc).await?, *weight))
+        }))
+        .await?;
+        Ok(WeightedBalance {
+            current: factory.current(old, index),
+            backends,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        AsyncMakeService::validate(self.get_ref())
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        AsyncMakeService::service_metadata(self.get_ref())
+    }
+}
+
+const RING_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Default number of virtual nodes per backend of [`ConsistentHash`].
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// A service which routes requests to backends with a consistent hash ring.
+///
+/// The key `Q` is taken from the request through `Param<Q>`. Each backend is
+/// identified by a key `K` and placed on the ring at a number of virtual nodes.
+/// Adding or removing a backend only remaps the keys of its share of the ring.
+///
+/// On reload, backends are rebuilt with `make_via_ref` against the old backend
+/// of the same key, and the ring is reused when the backends did not change.
+pub struct ConsistentHash<K, S, Q> {
+    backends: Vec<(K, S)>,
+    ring: Arc<Ring<K>>,
+    _marker: PhantomData<fn(Q)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Ring<K> {
+    keys: Vec<K>,
+    virtual_nodes: usize,
+    // (hash point, backend index), sorted by hash point.
+    points: Vec<(u64, usize)>,
+}
+
+impl<K: Hash> Ring<K> {
+    fn new(keys: Vec<K>, virtual_nodes: usize) -> Self {
+        let mut points: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, key)| {
+                (0..virtual_nodes).map(move |v| (seeded_hash(RING_SEED, &(key, v)), idx))
+            })
+            .collect();
+        points.sort_unstable();
+        Ring {
+            keys,
+            virtual_nodes,
+            points,
+        }
+    }
+
+    fn lookup(&self, hash: u64) -> Option<usize> {
+        let pos = self.points.partition_point(|(point, _)| *point < hash);
+        self.points
+            .get(pos)
+            .or_else(|| self.points.first())
+            .map(|(_, idx)| *idx)
+    }
+}
+
+impl<K, S, Q> ConsistentHash<K, S, Q> {
+    /// The backends with their keys.
+    #[inline]
+    pub fn backends(&self) -> &[(K, S)] {
+        &self.backends
+    }
+
+    /// Get the backend a request key is routed to.
+    pub fn route(&self, key: &Q) -> Option<&(K, S)>
+    where
+        K: Hash,
+        Q: Hash,
+    {
+        let idx = self.ring.lookup(seeded_hash(RING_SEED, key))?;
+        self.backends.get(idx)
+    }
+}
+
+impl<K, S, Q, R> Service<R> for ConsistentHash<K, S, Q>
+where
+    S: Service<R>,
+    R: Param<Q>,
+    K: Hash,
+    Q: Hash,
+{
+    type Response = S::Response;
+    type Error = BalanceError<S::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let (_, backend) = self.route(&req.param()).ok_or(BalanceError::NoBackend)?;
+        backend.call(req).await.map_err(BalanceError::Inner)
+    }
+}
+
+/// Factory of [`ConsistentHash`] over keyed backend factories.
+pub struct ConsistentHashFactory<K, F, Q> {
+    backends: Vec<(K, F)>,
+    virtual_nodes: usize,
+    _marker: PhantomData<fn(Q)>,
+}
+
+impl<K, F, Q> ConsistentHashFactory<K, F, Q> {
+    /// Create a factory with [`DEFAULT_VIRTUAL_NODES`] per backend.
+    #[inline]
+    pub const fn new(backends: Vec<(K, F)>) -> Self {
+        Self::with_virtual_nodes(backends, DEFAULT_VIRTUAL_NODES)
+    }
+
+    #[inline]
+    pub const fn with_virtual_nodes(backends: Vec<(K, F)>, virtual_nodes: usize) -> Self {
+        ConsistentHashFactory {
+            backends,
+            virtual_nodes,
+            _marker: PhantomData,
+        }
+    }
+
+    fn ring<S>(&self, old: Option<&ConsistentHash<K, S, Q>>) -> Arc<Ring<K>>
+    where
+        K: Hash + Eq + Clone,
+    {
+        match old {
+            Some(old)
+                if old.ring.virtual_nodes == self.virtual_nodes
+                    && old.ring.keys.iter().eq(self.backends.iter().map(|(k, _)| k)) =>
+            {
+                old.ring.clone()
+            }
+            _ => Arc::new(Ring::new(
+                self.backends.iter().map(|(k, _)| k.clone()).collect(),
+                self.virtual_nodes,
+            )),
+        }
+    }
+}
+
+impl<K: Clone, F: Clone, Q> Clone for ConsistentHashFactory<K, F, Q> {
+    fn clone(&self) -> Self {
+        Self::with_virtual_nodes(self.backends.clone(), self.virtual_nodes)
+    }
+}
+
+impl<K, F, Q> MakeService for ConsistentHashFactory<K, F, Q>
+where
+    K: Hash + Eq + Clone,
+    F: MakeService,
+{
+    type Service = ConsistentHash<K, F::Service, Q>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let mut backends = Vec::with_capacity(self.backends.len());
+        for (key, factory) in self.backends.iter() {
+            let old_svc = old.and_then(|o| o.backends.iter().find(|(k, _)| k == key));
+            backends.push((key.clone(), factory.make_via_ref(old_svc.map(|(_, s)| s))?));
+        }
+        Ok(ConsistentHash {
+            ring: self.ring(old),
+            backends,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.backends.iter().try_for_each(|(_, f)| f.validate())
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        let meta = ServiceMetadata::of::<Self::Service>()
+            .with_config("virtual_nodes", self.virtual_nodes)
+            .with_resource("backends", self.backends.len() as u64);
+        self.backends
+            .iter()
+            .fold(meta, |meta, (_, f)| meta.with_inner(f.service_metadata()))
+    }
+}
+
+impl<K, F, Q> AsyncMakeService for ConsistentHashFactory<K, F, Q>
+where
+    K: Hash + Eq + Clone,
+    F: AsyncMakeService,
+{
+    type Service = ConsistentHash<K, F::Service, Q>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let mut backends = Vec::with_capacity(self.backends.len());
+        for (key, factory) in self.backends.iter() {
+            let old_svc = old.and_then(|o| o.backends.iter().find(|(k, _)| k == key));
+            backends.push((
+                key.clone(),
+                factory.make_via_ref(old_svc.map(|(_, s)| s)).await?,
+            ));
+        }
+        Ok(ConsistentHash {
+            ring: self.ring(old),
+            backends,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.backends.iter().try_for_each(|(_, f)| f.validate())
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        let meta = ServiceMetadata::of::<Self::Service>()
+            .with_config("virtual_nodes", self.virtual_nodes)
+            .with_resource("backends", self.backends.len() as u64);
+        self.backends
+            .iter()
+            .fold(meta, |meta, (_, f)| meta.with_inner(f.service_metadata()))
+    }
+}
+
+/// Makes the backends concurrently.
+impl<K, F, Q> AsyncMakeService for ParallelMake<ConsistentHashFactory<K, F, Q>>
+where
+    K: Hash + Eq + Clone,
+    F: AsyncMakeService,
+{
+    type Service = ConsistentHash<K, F::Service, Q>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let factory = self.get_ref();
+        let backends = try_join_all(factory.backends.iter().map(|(key, f)| async move {
+            let old_svc = old.and_then(|o| o.backends.iter().find(|(k, _)| k == key));
+            Ok((key.clone(), f.make_via_ref(old_svc.map(|(_, s)| s)).await?))
+        }))
+        .await?;
+        Ok(ConsistentHash {
+            ring: factory.ring(old),
+            backends,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        AsyncMakeService::validate(self.get_ref())
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        AsyncMakeService::service_metadata(self.get_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Arc};
+
+    use super::{
+        BalanceError, ConsistentHash, ConsistentHashFactory, Weight, WeightedBalance,
+        WeightedBalanceFactory,
+    };
+    use crate::{parallel::ParallelMake, test_util::block_on, MakeService, Service};
+
+    /// A backend which answers with its name, and counts the makes it went
+    /// through.
+    struct Backend {
+        name: &'static str,
+        generation: usize,
+    }
+
+    impl Service<u32> for Backend {
+        type Response = &'static str;
+        type Error = Infallible;
+
+        async fn call(&self, _req: u32) -> Result<&'static str, Infallible> {
+            Ok(self.name)
+        }
+    }
+
+    #[derive(Clone)]
+    struct BackendFactory(&'static str);
+
+    impl MakeService for BackendFactory {
+        type Service = Backend;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, old: Option<&Backend>) -> Result<Backend, Infallible> {
+            Ok(Backend {
+                name: self.0,
+                generation: old.map_or(0, |o| o.generation + 1),
+            })
+        }
+    }
+
+    impl crate::AsyncMakeService for BackendFactory {
+        type Service = Backend;
+        type Error = Infallible;
+
+        async fn make_via_ref(&self, old: Option<&Backend>) -> Result<Backend, Infallible> {
+            MakeService::make_via_ref(self, old)
+        }
+    }
+
+    fn weighted(backends: &[(&'static str, u32)]) -> WeightedBalanceFactory<u32, BackendFactory> {
+        WeightedBalanceFactory::new(
+            backends
+                .iter()
+                .enumerate()
+                .map(|(key, &(name, weight))| (key as u32, BackendFactory(name), Weight(weight)))
+                .collect(),
+        )
+    }
+
+    fn picks(svc: &WeightedBalance<u32, Backend>, n: usize) -> String {
+        (0..n).map(|_| block_on(svc.call(0)).unwrap()).collect()
+    }
+
+    #[test]
+    fn smooth_weighted_round_robin() {
+        let svc = weighted(&[("a", 5), ("b", 1), ("c", 1)]).make().unwrap();
+        assert_eq!(picks(&svc, 14), "aabacaaaabacaa");
+    }
+
+    #[test]
+    fn zero_weight_is_skipped() {
+        let svc = weighted(&[("a", 1), ("b", 0)]).make().unwrap();
+        assert_eq!(picks(&svc, 3), "aaa");
+
+        let svc = weighted(&[("a", 0)]).make().unwrap();
+        assert!(matches!(
+            block_on(svc.call(0)),
+            Err(BalanceError::NoBackend)
+        ));
+    }
+
+    #[test]
+    fn reload_keeps_backends_and_position() {
+        let factory = weighted(&[("a", 1), ("b", 1)]);
+        let old = factory.make().unwrap();
+        assert_eq!(picks(&old, 1), "a");
+
+        let new = factory.make_via_ref(Some(&old)).unwrap();
+        assert_eq!(new.backend(&0).unwrap().generation, 1);
+        assert_eq!(picks(&new, 3), "bab");
+
+        // A backend with a new key starts over.
+        let grown = weighted(&[("a", 1), ("b", 1), ("c", 1)])
+            .make_via_ref(Some(&new))
+            .unwrap();
+        assert_eq!(grown.backend(&1).unwrap().generation, 2);
+        assert_eq!(grown.backend(&2).unwrap().generation, 0);
+    }
+
+    type Ring = ConsistentHash<&'static str, Backend, u32>;
+
+    fn ring(names: &[&'static str]) -> ConsistentHashFactory<&'static str, BackendFactory, u32> {
+        ConsistentHashFactory::new(names.iter().map(|&n| (n, BackendFactory(n))).collect())
+    }
+
+    fn routes(svc: &Ring) -> Vec<&'static str> {
+        (0..1000)
+            .map(|req| block_on(svc.call(req)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn consistent_hash_spreads_keys() {
+        let svc = ring(&["a", "b", "c"]).make().unwrap();
+        let spread = routes(&svc);
+        for name in ["a", "b", "c"] {
+            let n = spread.iter().filter(|r| **r == name).count();
+            assert!((200..470).contains(&n), "{n} of 1000 keys routed to {name}");
+        }
+        assert_eq!(spread, routes(&ring(&["a", "b", "c"]).make().unwrap()));
+
+        let empty = ring(&[]).make().unwrap();
+        assert!(matches!(
+            block_on(empty.call(1)),
+            Err(BalanceError::NoBackend)
+        ));
+    }
+
+    #[test]
+    fn consistent_hash_remaps_only_removed_share() {
+        let full = routes(&ring(&["a", "b", "c"]).make().unwrap());
+        let shrunk = routes(&ring(&["a", "c"]).make().unwrap());
+        for (before, after) in full.iter().zip(&shrunk) {
+            if *before != "b" {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hash_ring_survives_reload() {
+        let factory = ring(&["a", "b"]);
+        let old = factory.make().unwrap();
+        let new = factory.make_via_ref(Some(&old)).unwrap();
+        assert!(Arc::ptr_eq(&old.ring, &new.ring));
+        assert_eq!(new.route(&7).unwrap().1.generation, 1);
+        assert_eq!(routes(&old), routes(&new));
+
+        let grown = ring(&["a", "b", "c"]).make_via_ref(Some(&new)).unwrap();
+        assert!(!Arc::ptr_eq(&new.ring, &grown.ring));
+        for (before, after) in routes(&new).into_iter().zip(routes(&grown)) {
+            assert!(after == before || after == "c");
+        }
+    }
+
+    #[test]
+    fn parallel_make_migrates_like_sequential() {
+        use crate::AsyncMakeService;
+
+        let factory = weighted(&[("a", 1), ("b", 1)]);
+        let old = MakeService::make(&factory).unwrap();
+        assert_eq!(picks(&old, 1), "a");
+        let factory = ParallelMake::new(factory);
+        let new = block_on(AsyncMakeService::make_via_ref(&factory, Some(&old))).unwrap();
+        assert_eq!(new.backend(&0).unwrap().generation, 1);
+        assert_eq!(picks(&new, 3), "bab");
+
+        let factory = ParallelMake::new(ring(&["a", "b"]));
+        let old = block_on(AsyncMakeService::make(&factory)).unwrap();
+        let new = block_on(AsyncMakeService::make_via_ref(&factory, Some(&old))).unwrap();
+        assert!(Arc::ptr_eq(&old.ring, &new.ring));
+        assert_eq!(new.route(&7).unwrap().1.generation, 1);
+        assert_eq!(routes(&old), routes(&new));
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::pin,
+    rc::Rc,
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    pool::{oneshot, Sender},
+    serve::Spawn,
+    shutdown::Shutdown,
+    time::Timer,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Limits of the batches of [`Batch`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfig {
+    /// Max number of requests in a batch.
+    pub max_items: usize,
+    /// Max time the first request of a batch waits for more requests.
+    pub max_delay: Duration,
+}
+
+/// Error returned by [`Batch`].
+#[derive(Debug)]
+pub enum BatchError<E> {
+    /// The batch worker has stopped, e.g. because its runtime shut down.
+    Closed,
+    /// The inner service returned fewer responses than it was given requests.
+    Missing,
+    /// The inner service failed. Every request of the batch gets the error.
+    Inner(E),
+}
+
+impl<E: Display> Display for BatchError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::Closed => write!(f, "batch worker closed"),
+            BatchError::Missing => write!(f, "no response in batch for request"),
+            BatchError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for BatchError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BatchError::Closed | BatchError::Missing => None,
+            BatchError::Inner(e) => Some(e),
+        }
+    }
+}
+
+type Reply<Resp, E> = Sender<Result<Resp, BatchError<E>>>;
+
+struct Queue<R, Resp, E> {
+    items: Vec<(R, Reply<Resp, E>)>,
+    closed: bool,
+    waker: Option<Waker>,
+    /// Whether the worker has stopped.
+    stopped: bool,
+    /// Tasks waiting for the worker to stop.
+    shutdown: Vec<Waker>,
+}
+
+impl<R, Resp, E> Queue<R, Resp, E> {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A middleware which buffers requests and passes them to the inner service
+/// in batches, for backends which are more efficient with bulk calls.
+///
+/// The inner service takes a `Vec<R>` and returns one response per request,
+/// in order. A batch is sent when it has `max_items` requests, or `max_delay`
+/// after its first request arrived. Batches are sent one at a time by a worker
+/// task on the current thread, spawned with a [`Spawn`]; requests arriving
+/// meanwhile are buffered for the next batch.
+///
+/// Each service made has its own worker. On reload the old worker flushes the
+/// requests it has, and stops when the old service is dropped. Its
+/// [`Shutdown`] sends the queued requests right away and refuses new ones, then
+/// shuts the inner service down once the worker has stopped.
+pub struct Batch<S, R, Resp, E> {
+    queue: Rc<RefCell<Queue<R, Resp, E>>>,
+    inner: Rc<S>,
+}
+
+impl<S, R, Resp, E> Batch<S, R, Resp, E> {
+    /// Number of requests waiting for the next batch.
+    pub fn queued(&self) -> usize {
+        self.queue.borrow().items.len()
+    }
+}
+
+impl<S, R, Resp, E> Drop for Batch<S, R, Resp, E> {
+    fn drop(&mut self) {
+        let mut queue = self.queue.borrow_mut();
+        queue.closed = true;
+        queue.wake();
+    }
+}
+
+impl<S: Shutdown, R, Resp, E> Shutdown for Batch<S, R, Resp, E> {
+    async fn shutdown(&self) {
+        {
+            let mut queue = self.queue.borrow_mut();
+            queue.closed = true;
+            queue.wake();
+        }
+        poll_fn(|cx| {
+            let mut queue = self.queue.borrow_mut();
+            if queue.stopped {
+                return Poll::Ready(());
+            }
+            queue.shutdown.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await;
+        self.inner.shutdown().await;
+    }
+}
+
+/// Marks the worker stopped when dropped, also when its task is dropped before
+/// it completes.
+struct Stopped<'a, R, Resp, E>(&'a RefCell<Queue<R, Resp, E>>);
+
+impl<R, Resp, E> Drop for Stopped<'_, R, Resp, E> {
+    fn drop(&mut self) {
+        let mut queue = self.0.borrow_mut();
+        queue.stopped = true;
+        for waker in queue.shutdown.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<S, R, Resp, E> Service<R> for Batch<S, R, Resp, E> {
+    type Response = Resp;
+    type Error = BatchError<E>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let (tx, rx) = oneshot();
+        {
+            let mut queue = self.queue.borrow_mut();
+            if queue.closed {
+                return Err(BatchError::Closed);
+            }
+            queue.items.push((req, tx));
+            queue.wake();
+        }
+        rx.recv().await.ok_or(BatchError::Closed)?
+    }
+}
+
+async fn run_batch<S, R, Resp, E, TM>(
+    svc: Rc<S>,
+    queue: Rc<RefCell<Queue<R, Resp, E>>>,
+    config: BatchConfig,
+    timer: TM,
+) where
+    S: Service<Vec<R>, Response = Vec<Resp>, Error = E>,
+    E: Clone,
+    TM: Timer,
+{
+    let _stopped = Stopped(&queue);
+    let max_items = config.max_items.max(1);
+    loop {
+        // Wait for the first request of a batch.
+        let open = poll_fn(|cx| {
+            let mut q = queue.borrow_mut();
+            if !q.items.is_empty() {
+                Poll::Ready(true)
+            } else if q.closed {
+                Poll::Ready(false)
+            } else {
+                q.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await;
+        if !open {
+            return;
+        }
+
+        // Wait for the batch to fill up, or the delay to pass.
+        let mut sleep = pin!(timer.sleep(config.max_delay));
+        poll_fn(|cx| {
+            {
+                let mut q = queue.borrow_mut();
+                if q.items.len() >= max_items || q.closed {
+                    return Poll::Ready(());
+                }
+                q.waker = Some(cx.waker().clone());
+            }
+            sleep.as_mut().poll(cx)
+        })
+        .await;
+
+        let (reqs, replies): (Vec<_>, Vec<_>) = {
+            let mut q = queue.borrow_mut();
+            let n = q.items.len().min(max_items);
+            q.items.drain(..n).unzip()
+        };
+        match svc.call(reqs).await {
+            Ok(resps) => {
+                let mut resps = resps.into_iter();
+                for tx in replies {
+                    tx.send(resps.next().ok_or(BatchError::Missing));
+                }
+            }
+            Err(e) => {
+                for tx in replies {
+                    tx.send(Err(BatchError::Inner(e.clone())));
+                }
+            }
+        }
+    }
+}
+
+/// Factory of [`Batch`].
+///
+/// The time source `TM` is taken from the stack config through `Param<TM>`.
+pub struct BatchFactory<F, R, TM, SP> {
+    config: BatchConfig,
+    timer: TM,
+    spawner: SP,
+    inner: F,
+    _marker: PhantomData<fn(R)>,
+}
+
+impl<F, R, TM, SP> BatchFactory<F, R, TM, SP> {
+    pub fn layer<C>(spawner: SP) -> impl FactoryLayer<C, F, Factory = Self>
+    where
+        C: Param<BatchConfig> + Param<TM>,
+        SP: Clone,
+    {
+        layer_fn(move |c: &C, inner| BatchFactory {
+            config: Param::<BatchConfig>::param(c),
+            timer: Param::<TM>::param(c),
+            spawner: spawner.clone(),
+            inner,
+            _marker: PhantomData,
+        })
+    }
+
+    fn spawn_worker<S, Resp, E>(&self, inner: S) -> Batch<S, R, Resp, E>
+    where
+        S: Service<Vec<R>, Response = Vec<Resp>, Error = E> + 'static,
+        R: 'static,
+        Resp: 'static,
+        E: Clone + 'static,
+        TM: Timer + Clone + 'static,
+        SP: Spawn,
+    {
+        let inner = Rc::new(inner);
+        let queue = Rc::new(RefCell::new(Queue {
+            items: Vec::new(),
+            closed: false,
+            waker: None,
+            stopped: false,
+            shutdown: Vec::new(),
+        }));
+        self.spawner.spawn(Box::pin(run_batch(
+            inner.clone(),
+            queue.clone(),
+            self.config,
+            self.timer.clone(),
+        )));
+        Batch { queue, inner }
+    }
+}
+
+impl<F, R, TM, SP, Resp, E> MakeService for BatchFactory<F, R, TM, SP>
+where
+    F: MakeService,
+    F::Service: Service<Vec<R>, Response = Vec<Resp>, Error = E> + 'static,
+    R: 'static,
+    Resp: 'static,
+    E: Clone + 'static,
+    TM: Timer + Clone + 'static,
+    SP: Spawn,
+{
+    type Service = Batch<F::Service, R, Resp, E>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let inner = self.inner.make_via_ref(old.map(|o| &*o.inner))?;
+        Ok(self.spawn_worker(inner))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("max_items", self.config.max_items)
+            .with_config("max_delay", format_args!("{:?}", self.config.max_delay))
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F, R, TM, SP, Resp, E> AsyncMakeService for BatchFactory<F, R, TM, SP>
+where
+    F: AsyncMakeService,
+    F::Service: Service<Vec<R>, Response = Vec<Resp>, Error = E> + 'static,
+    R: 'static,
+    Resp: 'static,
+    E: Clone + 'static,
+    TM: Timer + Clone + 'static,
+    SP: Spawn,
+{
+    type Service = Batch<F::Service, R, Resp, E>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let inner = self.inner.make_via_ref(old.map(|o| &*o.inner)).await?;
+        Ok(self.spawn_worker(inner))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("max_items", self.config.max_items)
+            .with_config("max_delay", format_args!("{:?}", self.config.max_delay))
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(BatchFactory<_, R, TM, SP> { config, timer, spawner, _marker });
+
+impl<S, R, Resp, E> Layered for Batch<S, R, Resp, E> {
+    type Inner = S;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        future::Future,
+        pin::{pin, Pin},
+        rc::Rc,
+        task::Poll,
+        time::Duration,
+    };
+
+    use super::{BatchConfig, BatchError, BatchFactory};
+    use crate::{
+        layer::FactoryLayer,
+        shutdown::Shutdown,
+        test_util::{poll_once, LocalExecutor, WakeCounter},
+        time::MockTimer,
+        utils::CloneFactory,
+        MakeService, Param, Service,
+    };
+
+    struct Config(BatchConfig, MockTimer);
+
+    impl Param<BatchConfig> for Config {
+        fn param(&self) -> BatchConfig {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    /// Multiplies each request by 10, logging the batch sizes and whether it
+    /// was shut down; a batch with a zero fails.
+    #[derive(Clone, Default)]
+    struct Times10(Rc<RefCell<Vec<usize>>>, Rc<Cell<bool>>);
+
+    impl Service<Vec<u32>> for Times10 {
+        type Response = Vec<u32>;
+        type Error = &'static str;
+
+        async fn call(&self, reqs: Vec<u32>) -> Result<Vec<u32>, &'static str> {
+            self.0.borrow_mut().push(reqs.len());
+            if reqs.contains(&0) {
+                return Err("zero");
+            }
+            Ok(reqs.into_iter().map(|r| r * 10).collect())
+        }
+    }
+
+    impl Shutdown for Times10 {
+        async fn shutdown(&self) {
+            self.1.set(true);
+        }
+    }
+
+    type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+    fn config(timer: &MockTimer) -> Config {
+        let limits = BatchConfig {
+            max_items: 3,
+            max_delay: Duration::from_millis(10),
+        };
+        Config(limits, timer.clone())
+    }
+
+    #[test]
+    fn worker_is_woken() {
+        let timer = MockTimer::new();
+        let spawned = Rc::new(RefCell::new(None::<Task>));
+        let spawner = {
+            let spawned = spawned.clone();
+            move |task| *spawned.borrow_mut() = Some(task)
+        };
+        let inner = Times10::default();
+        let factory = BatchFactory::<_, u32, MockTimer, _>::layer(spawner)
+            .layer(&config(&timer), CloneFactory::new(inner.clone()));
+        let svc = factory.make().unwrap();
+        let mut worker = spawned.take().unwrap();
+        let waker = WakeCounter::new();
+        let mut poll_worker = || waker.poll(worker.as_mut());
+        assert!(poll_worker().is_pending());
+
+        // The first request wakes the idle worker, which then waits for the
+        // delay.
+        let mut first = Box::pin(svc.call(1));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert_eq!(waker.count(), 1);
+        assert!(poll_worker().is_pending());
+        timer.advance(Duration::from_millis(10));
+        assert_eq!(waker.count(), 2);
+        assert!(poll_worker().is_pending());
+        assert_eq!(
+            poll_once(first.as_mut()).map(Result::ok),
+            Poll::Ready(Some(10))
+        );
+
+        // A full batch wakes the worker before the delay.
+        let mut calls: Vec<_> = (2..=4).map(|req| Box::pin(svc.call(req))).collect();
+        assert!(poll_once(calls[0].as_mut()).is_pending());
+        assert_eq!(waker.count(), 3);
+        assert!(poll_worker().is_pending());
+        assert!(poll_once(calls[1].as_mut()).is_pending());
+        assert_eq!(waker.count(), 4);
+        assert!(poll_worker().is_pending());
+        assert!(poll_once(calls[2].as_mut()).is_pending());
+        assert_eq!(waker.count(), 5);
+        assert!(poll_worker().is_pending());
+        let resps: Vec<_> = calls
+            .iter_mut()
+            .map(|c| poll_once(c.as_mut()).map(Result::ok))
+            .collect();
+        assert_eq!(resps, [20, 30, 40].map(|r| Poll::Ready(Some(r))));
+        assert_eq!(*inner.0.borrow(), [1, 3]);
+
+        // Dropping the service wakes the worker to stop.
+        drop((first, calls));
+        drop(svc);
+        assert_eq!(waker.count(), 6);
+        assert!(poll_worker().is_ready());
+    }
+
+    #[test]
+    fn batch_shares_the_error() {
+        let timer = MockTimer::new();
+        let ex = LocalExecutor::new();
+        let factory = BatchFactory::<_, u32, MockTimer, _>::layer(ex.spawner())
+            .layer(&config(&timer), CloneFactory::new(Times10::default()));
+        let svc = factory.make().unwrap();
+
+        let mut ok = pin!(svc.call(1));
+        let mut zero = pin!(svc.call(0));
+        assert!(poll_once(ok.as_mut()).is_pending());
+        assert!(poll_once(zero.as_mut()).is_pending());
+        ex.run_tasks();
+        assert_eq!(svc.queued(), 2);
+        timer.advance(Duration::from_millis(10));
+        assert!(matches!(ex.block_on(ok), Err(BatchError::Inner("zero"))));
+        assert!(matches!(ex.block_on(zero), Err(BatchError::Inner("zero"))));
+    }
+
+    #[test]
+    fn shutdown_flushes_the_queue() {
+        let timer = MockTimer::new();
+        let ex = LocalExecutor::new();
+        let inner = Times10::default();
+        let factory = BatchFactory::<_, u32, MockTimer, _>::layer(ex.spawner())
+            .layer(&config(&timer), CloneFactory::new(inner.clone()));
+        let svc = factory.make().unwrap();
+
+        let mut queued = pin!(svc.call(1));
+        assert!(poll_once(queued.as_mut()).is_pending());
+        // The batch is sent without waiting for the delay.
+        ex.block_on(svc.shutdown());
+        assert_eq!(*inner.0.borrow(), [1]);
+        assert!(inner.1.get());
+        assert!(matches!(ex.block_on(queued), Ok(10)));
+        assert!(matches!(ex.block_on(svc.call(2)), Err(BatchError::Closed)));
+        assert_eq!(ex.pending_tasks(), 0);
+    }
+}
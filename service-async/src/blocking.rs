@@ -0,0 +1,256 @@
+use std::{
+    future::{poll_fn, Future},
+    pin::pin,
+    time::Duration,
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    time::Clock,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Polls of [`BlockingDetector`] longer than this are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockingThreshold(pub Duration);
+
+/// A poll of the inner future which took longer than the threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockingPoll {
+    /// Type name of the service whose call blocked.
+    pub service: &'static str,
+    /// Duration of this poll.
+    pub poll: Duration,
+    /// Time spent in all polls of the call so far, including this one.
+    pub busy: Duration,
+    /// Wall-clock time since the call started.
+    pub elapsed: Duration,
+}
+
+/// Receives the long polls of [`BlockingDetector`], e.g. to log them with the
+/// logger of the application.
+///
+/// It is implemented for closures taking the poll.
+pub trait BlockingObserver {
+    fn on_blocking_poll(&self, poll: &BlockingPoll);
+}
+
+impl<F: Fn(&BlockingPoll)> BlockingObserver for F {
+    #[inline]
+    fn on_blocking_poll(&self, poll: &BlockingPoll) {
+        (self)(poll)
+    }
+}
+
+/// A middleware which reports single polls of the inner future longer than a
+/// threshold, pointing at blocking calls inside a service stack.
+///
+/// A future which blocks in a poll stalls every other task of its thread,
+/// which thread-per-core runtimes like monoio are especially sensitive to.
+/// Unlike [`SlowRequestWatchdog`](crate::watchdog::SlowRequestWatchdog), time
+/// spent waiting for I/O or timers is not counted.
+///
+/// Polls are only timed in debug builds; in release builds the call is passed
+/// through. The threshold and the clock `CL` are taken from the stack config
+/// through `Param<BlockingThreshold>` and `Param<CL>`. It is its own factory.
+pub struct BlockingDetector<T, O, CL> {
+    threshold: Duration,
+    observer: O,
+    clock: CL,
+    inner: T,
+}
+
+impl<T, O, CL> BlockingDetector<T, O, CL> {
+    pub fn layer<C>(observer: O) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<BlockingThreshold> + Param<CL>,
+        O: Clone,
+    {
+        layer_fn(move |c: &C, inner| BlockingDetector {
+            threshold: Param::<BlockingThreshold>::param(c).0,
+            observer: observer.clone(),
+            clock: Param::<CL>::param(c),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+}
+
+impl<T, O, CL, R> Service<R> for BlockingDetector<T, O, CL>
+where
+    T: Service<R>,
+    O: BlockingObserver,
+    CL: Clock,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        if !cfg!(debug_assertions) {
+            return self.inner.call(req).await;
+        }
+
+        let start = self.clock.now();
+        let mut busy = Duration::ZERO;
+        let mut fut = pin!(self.inner.call(req));
+        poll_fn(|cx| {
+            let poll_start = self.clock.now();
+            let res = fut.as_mut().poll(cx);
+            let now = self.clock.now();
+            let poll = now.saturating_duration_since(poll_start);
+            busy += poll;
+            if poll > self.threshold {
+                self.observer.on_blocking_poll(&BlockingPoll {
+                    service: std::any::type_name::<T>(),
+                    poll,
+                    busy,
+                    elapsed: now.saturating_duration_since(start),
+                });
+            }
+            res
+        })
+        .await
+    }
+}
+
+impl<F: MakeService, O: Clone, CL: Clone> MakeService for BlockingDetector<F, O, CL> {
+    type Service = BlockingDetector<F::Service, O, CL>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(BlockingDetector {
+            threshold: self.threshold,
+            observer: self.observer.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("threshold", format_args!("{:?}", self.threshold))
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, O: Clone, CL: Clone> AsyncMakeService
+    for BlockingDetector<F, O, CL>
+{
+    type Service = BlockingDetector<F::Service, O, CL>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(BlockingDetector {
+            threshold: self.threshold,
+            observer: self.observer.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("threshold", format_args!("{:?}", self.threshold))
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(BlockingDetector<_, O, CL> { threshold, observer, clock });
+
+impl<T, O, CL> Layered for BlockingDetector<T, O, CL> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(BlockingDetector<T, O, CL>);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, convert::Infallible, pin::pin, rc::Rc, task::Poll, time::Duration};
+
+    use super::{BlockingDetector, BlockingPoll, BlockingThreshold};
+    use crate::{
+        layer::FactoryLayer, test_util::poll_once, time::MockTimer, yielding::yield_now, Param,
+        Service,
+    };
+
+    struct Config(BlockingThreshold, MockTimer);
+
+    impl Param<BlockingThreshold> for Config {
+        fn param(&self) -> BlockingThreshold {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    /// Works for 1ms, waits, then blocks for 20ms.
+    struct Blocks(MockTimer);
+
+    impl Service<()> for Blocks {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, _: ()) -> Result<(), Infallible> {
+            self.0.advance(Duration::from_millis(1));
+            yield_now().await;
+            self.0.advance(Duration::from_millis(20));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reports_long_polls() {
+        let timer = MockTimer::new();
+        let polls = Rc::new(RefCell::new(Vec::new()));
+        let observer = {
+            let polls = polls.clone();
+            move |poll: &BlockingPoll| polls.borrow_mut().push(*poll)
+        };
+        let config = Config(BlockingThreshold(Duration::from_millis(10)), timer.clone());
+        let svc = BlockingDetector::<_, _, MockTimer>::layer(observer)
+            .layer(&config, Blocks(timer.clone()));
+
+        let mut call = pin!(svc.call(()));
+        assert!(poll_once(call.as_mut()).is_pending());
+        // Waiting is not counted as busy.
+        timer.advance(Duration::from_millis(100));
+        assert_eq!(poll_once(call.as_mut()), Poll::Ready(Ok(())));
+
+        let expected = BlockingPoll {
+            service: std::any::type_name::<Blocks>(),
+            poll: Duration::from_millis(20),
+            busy: Duration::from_millis(21),
+            elapsed: Duration::from_millis(121),
+        };
+        if cfg!(debug_assertions) {
+            assert_eq!(*polls.borrow(), [expected]);
+        } else {
+            assert!(polls.borrow().is_empty());
+        }
+    }
+}
@@ -0,0 +1,517 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    fmt,
+    future::Future,
+    marker::PhantomData,
+};
+
+use crate::{
+    boxed::SmallFuture, layer::impl_wrap_inner, AsyncMakeService, MakeService, Service,
+    ServiceMetadata,
+};
+
+/// A service which takes requests by mutable reference, for any borrow lifetime.
+///
+/// This is an alias for `for<'a> Service<&'a mut T>` with a fixed response and
+/// error type, so zero-copy handlers can be named in bounds without spelling
+/// out the higher-ranked lifetime. The borrow ends when the call completes,
+/// and the caller can use the request again afterwards.
+///
+/// ```rust
+/// use std::convert::Infallible;
+/// use service_async::{borrow::BorrowingService, Service};
+///
+/// struct Connection {
+///     served: usize,
+/// }
+///
+/// struct Count;
+///
+/// impl<'a> Service<&'a mut Connection> for Count {
+///     type Response = usize;
+///     type Error = Infallible;
+///
+///     async fn call(&self, conn: &'a mut Connection) -> Result<usize, Infallible> {
+///         conn.served += 1;
+///         Ok(conn.served)
+///     }
+/// }
+///
+/// async fn serve_twice(svc: &impl BorrowingService<Connection, usize, Infallible>) {
+///     let mut conn = Connection { served: 0 };
+///     let _ = svc.call_borrowed(&mut conn).await;
+///     let _ = svc.call_borrowed(&mut conn).await;
+///     assert_eq!(conn.served, 2);
+/// }
+/// # use std::{future::Future, pin::pin, task::{Context, Waker}};
+/// # let done = pin!(serve_twice(&Count)).poll(&mut Context::from_waker(Waker::noop()));
+/// # assert!(done.is_ready());
+/// ```
+pub trait BorrowingService<T: ?Sized, Resp, E>:
+    for<'a> Service<&'a mut T, Response = Resp, Error = E>
+{
+    /// Call the service with a borrowed request.
+    #[inline]
+    fn call_borrowed(&self, req: &mut T) -> impl Future<Output = Result<Resp, E>> {
+        self.call(req)
+    }
+}
+
+impl<S, T: ?Sized, Resp, E> BorrowingService<T, Resp, E> for S where
+    S: for<'a> Service<&'a mut T, Response = Resp, Error = E>
+{
+}
+
+/// A type-erased [`BorrowingService`].
+///
+/// [`BoxedService`](crate::BoxedService) requires `'static` requests, as its
+/// request type is fixed. `BoxedBorrowingService` instead implements
+/// `Service<&'a mut T>` for every `'a`, and the returned future can not outlive
+/// the borrow.
+pub struct BoxedBorrowingService<T: ?Sized, Resp, E> {
+    svc: *const (),
+    type_id: TypeId,
+    call: unsafe fn(raw: *const (), req: &mut T) -> SmallFuture<'_, Resp, E>,
+    drop: unsafe fn(raw: *const ()),
+}
+
+impl<T: ?Sized, Resp, E> BoxedBorrowingService<T, Resp, E> {
+    pub fn new<S>(s: S) -> Self
+    where
+        S: BorrowingService<T, Resp, E> + 'static,
+    {
+        let svc = Box::into_raw(Box::new(s)) as *const ();
+        BoxedBorrowingService {
+            svc,
+            type_id: TypeId::of::<S>(),
+            call: call::<T, S, Resp, E>,
+            drop: drop::<S>,
+        }
+    }
+
+    pub fn downcast_ref<S: Any>(&self) -> Option<&S> {
+        if self.type_id == TypeId::of::<S>() {
+            Some(unsafe { &*(self.svc as *const S) })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: ?Sized, Resp, E> Drop for BoxedBorrowingService<T, Resp, E> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.svc) };
+    }
+}
+
+impl<'a, T: ?Sized, Resp, E> Service<&'a mut T> for BoxedBorrowingService<T, Resp, E> {
+    type Response = Resp;
+    type Error = E;
+
+    // The returned future captures `'a` and the borrow of `self`, which keeps
+    // the lifetime-erased `SmallFuture` from outliving either.
+    #[inline]
+    fn call(&self, req: &'a mut T) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        unsafe { (self.call)(self.svc, req) }
+    }
+}
+
+unsafe fn call<T: ?Sized, S, Resp, E>(svc: *const (), req: &mut T) -> SmallFuture<'_, Resp, E>
+where
+    S: BorrowingService<T, Resp, E>,
+{
+    let svc = &*svc.cast::<S>();
+    SmallFuture::new(svc.call_borrowed(req))
+}
+
+unsafe fn drop<S>(raw: *const ()) {
+    std::mem::drop(Box::from_raw(raw as *mut S));
+}
+
+/// A family of request types parameterized by a lifetime, e.g. a request
+/// borrowing the buffer of its connection.
+///
+/// It names `Request<'a>` for every `'a`, so a service taking such requests can
+/// be type-erased by [`ScopedBoxedService`].
+///
+/// ```rust
+/// use service_async::borrow::RequestFamily;
+///
+/// struct Frame<'a> {
+///     payload: &'a [u8],
+/// }
+///
+/// struct Frames;
+///
+/// impl RequestFamily for Frames {
+///     type Request<'a> = Frame<'a>;
+/// }
+/// ```
+pub trait RequestFamily: 'static {
+    type Request<'a>;
+}
+
+/// The [`RequestFamily`] of shared references `&'a T`.
+pub struct RefRequest<T: ?Sized + 'static>(PhantomData<fn(&T)>);
+
+impl<T: ?Sized + 'static> RequestFamily for RefRequest<T> {
+    type Request<'a> = &'a T;
+}
+
+/// The [`RequestFamily`] of mutable references `&'a mut T`.
+pub struct MutRequest<T: ?Sized + 'static>(PhantomData<fn(&mut T)>);
+
+impl<T: ?Sized + 'static> RequestFamily for MutRequest<T> {
+    type Request<'a> = &'a mut T;
+}
+
+type ScopedCall<Fam, Resp, E> = for<'a> unsafe fn(
+    raw: *const (),
+    req: <Fam as RequestFamily>::Request<'a>,
+) -> SmallFuture<'static, Resp, E>;
+
+/// A type-erased service which takes the requests of a [`RequestFamily`] for
+/// any lifetime.
+///
+/// Like [`BoxedBorrowingService`], the returned future can not outlive the
+/// request, so stacks whose requests borrow connection buffers can be stored
+/// in registries. It is called with [`call_scoped`](Self::call_scoped), or as
+/// a `Service` for [`RefRequest`] and [`MutRequest`].
+pub struct ScopedBoxedService<Fam: RequestFamily, Resp, E> {
+    svc: *const (),
+    type_id: TypeId,
+    type_name: &'static str,
+    call: ScopedCall<Fam, Resp, E>,
+    drop: unsafe fn(raw: *const ()),
+}
+
+impl<Fam: RequestFamily, Resp, E> ScopedBoxedService<Fam, Resp, E> {
+    pub fn new<S>(s: S) -> Self
+    where
+        S: for<'a> Service<Fam::Request<'a>, Response = Resp, Error = E> + 'static,
+    {
+        let svc = Box::into_raw(Box::new(s)) as *const ();
+        ScopedBoxedService {
+            svc,
+            type_id: TypeId::of::<S>(),
+            type_name: type_name::<S>(),
+            call: call_scoped::<Fam, S, Resp, E>,
+            drop: drop::<S>,
+        }
+    }
+
+    pub fn downcast_ref<S: Any>(&self) -> Option<&S> {
+        if self.type_id == TypeId::of::<S>() {
+            Some(unsafe { &*(self.svc as *const S) })
+        } else {
+            None
+        }
+    }
+
+    /// Type name of the boxed service, as given by [`std::any::type_name`].
+    #[inline]
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+}
+
+impl<Fam: RequestFamily, Resp, E> fmt::Debug for ScopedBoxedService<Fam, Resp, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedBoxedService")
+            .field("service", &self.type_name)
+            .field("request", &type_name::<Fam>())
+            .finish()
+    }
+}
+
+impl<Fam: RequestFamily, Resp, E> Drop for ScopedBoxedService<Fam, Resp, E> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.svc) };
+    }
+}
+
+impl<Fam: RequestFamily, Resp, E> ScopedBoxedService<Fam, Resp, E> {
+    /// Call the service with a request of any lifetime.
+    ///
+    /// `Service` is only implemented for the families of references, as the
+    /// lifetime of `Fam::Request<'a>` can not be named by a trait impl; other
+    /// families are called through this method.
+    #[inline]
+    pub fn call_scoped<'a>(
+        &'a self,
+        req: Fam::Request<'a>,
+    ) -> impl Future<Output = Result<Resp, E>> + 'a
+    where
+        Resp: 'a,
+        E: 'a,
+    {
+        unsafe { (self.call)(self.svc, req) }
+    }
+}
+
+impl<'a, T: ?Sized + 'static, Resp, E> Service<&'a T>
+    for ScopedBoxedService<RefRequest<T>, Resp, E>
+{
+    type Response = Resp;
+    type Error = E;
+
+    // As for `BoxedBorrowingService`, the returned future captures `'a` and
+    // the borrow of `self`.
+    #[inline]
+    fn call(&self, req: &'a T) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        unsafe { (self.call)(self.svc, req) }
+    }
+}
+
+impl<'a, T: ?Sized + 'static, Resp, E> Service<&'a mut T>
+    for ScopedBoxedService<MutRequest<T>, Resp, E>
+{
+    type Response = Resp;
+    type Error = E;
+
+    #[inline]
+    fn call(&self, req: &'a mut T) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        unsafe { (self.call)(self.svc, req) }
+    }
+}
+
+unsafe fn call_scoped<'a, Fam, S, Resp, E>(
+    svc: *const (),
+    req: Fam::Request<'a>,
+) -> SmallFuture<'static, Resp, E>
+where
+    Fam: RequestFamily,
+    S: for<'b> Service<Fam::Request<'b>, Response = Resp, Error = E>,
+{
+    let svc = &*svc.cast::<S>();
+    SmallFuture::new(svc.call(req))
+}
+
+type FactoryMarker<Fam, Resp, E> = PhantomData<fn() -> (Fam, Resp, E)>;
+
+/// A factory which makes [`ScopedBoxedService`]s, the counterpart of
+/// [`BoxServiceFactory`](crate::BoxServiceFactory) for requests which are not
+/// `'static`.
+///
+/// On reload the old service is downcast to the service type of the inner
+/// factory to migrate its state; if the type changed, the service is made
+/// from scratch.
+pub struct ScopedBoxServiceFactory<F, Fam, Resp, E> {
+    inner: F,
+    _marker: FactoryMarker<Fam, Resp, E>,
+}
+
+impl<F, Fam, Resp, E> ScopedBoxServiceFactory<F, Fam, Resp, E> {
+    #[inline]
+    pub const fn new(inner: F) -> Self {
+        ScopedBoxServiceFactory {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn as_inner(&self) -> &F {
+        &self.inner
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F, Fam, Resp, E> MakeService for ScopedBoxServiceFactory<F, Fam, Resp, E>
+where
+    F: MakeService,
+    F::Service: for<'a> Service<Fam::Request<'a>, Response = Resp, Error = E> + 'static,
+    Fam: RequestFamily,
+{
+    type Service = ScopedBoxedService<Fam, Resp, E>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let svc = self
+            .inner
+            .make_via_ref(old.and_then(|o| o.downcast_ref()))?;
+        Ok(ScopedBoxedService::new(svc))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+impl<F, Fam, Resp, E> AsyncMakeService for ScopedBoxServiceFactory<F, Fam, Resp, E>
+where
+    F: AsyncMakeService,
+    F::Service: for<'a> Service<Fam::Request<'a>, Response = Resp, Error = E> + 'static,
+    Fam: RequestFamily,
+{
+    type Service = ScopedBoxedService<Fam, Resp, E>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let svc = self
+            .inner
+            .make_via_ref(old.and_then(|o| o.downcast_ref()))
+            .await?;
+        Ok(ScopedBoxedService::new(svc))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+impl_wrap_inner!(ScopedBoxServiceFactory<_, Fam, Resp, E> { _marker });
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{
+        BorrowingService, BoxedBorrowingService, RefRequest, RequestFamily, ScopedBoxServiceFactory,
+    };
+    use crate::{map::MapTargetService, test_util::block_on, MakeService, Service};
+
+    struct Connection {
+        served: usize,
+        mapped: usize,
+    }
+
+    struct Count;
+
+    impl<'a> Service<&'a mut Connection> for Count {
+        type Response = usize;
+        type Error = Infallible;
+
+        async fn call(&self, conn: &'a mut Connection) -> Result<usize, Infallible> {
+            conn.served += 1;
+            Ok(conn.served)
+        }
+    }
+
+    fn mark(conn: &mut Connection) -> &mut Connection {
+        conn.mapped += 1;
+        conn
+    }
+
+    async fn serve_twice(svc: &impl BorrowingService<Connection, usize, Infallible>) -> Connection {
+        let mut conn = Connection {
+            served: 0,
+            mapped: 0,
+        };
+        assert_eq!(svc.call_borrowed(&mut conn).await, Ok(1));
+        assert_eq!(svc.call_borrowed(&mut conn).await, Ok(2));
+        conn
+    }
+
+    #[test]
+    fn borrowed_request_through_layer() {
+        let svc = MapTargetService {
+            f: mark,
+            inner: Count,
+        };
+        let conn = block_on(serve_twice(&svc));
+        assert_eq!((conn.served, conn.mapped), (2, 2));
+    }
+
+    #[test]
+    fn boxed_borrowing_service() {
+        type Marked = MapTargetService<Count, fn(&mut Connection) -> &mut Connection>;
+
+        let svc = BoxedBorrowingService::new(Marked {
+            f: mark,
+            inner: Count,
+        });
+        let conn = block_on(serve_twice(&svc));
+        assert_eq!((conn.served, conn.mapped), (2, 2));
+        assert!(svc.downcast_ref::<Marked>().is_some());
+        assert!(svc.downcast_ref::<Count>().is_none());
+    }
+
+    struct Frame<'a> {
+        payload: &'a [u8],
+    }
+
+    struct Frames;
+
+    impl RequestFamily for Frames {
+        type Request<'a> = Frame<'a>;
+    }
+
+    /// Returns the payload length and the number of reloads it went through.
+    struct Len {
+        generation: usize,
+    }
+
+    impl<'a> Service<Frame<'a>> for Len {
+        type Response = (usize, usize);
+        type Error = Infallible;
+
+        async fn call(&self, frame: Frame<'a>) -> Result<(usize, usize), Infallible> {
+            Ok((frame.payload.len(), self.generation))
+        }
+    }
+
+    impl<'a> Service<&'a [u8]> for Len {
+        type Response = (usize, usize);
+        type Error = Infallible;
+
+        async fn call(&self, payload: &'a [u8]) -> Result<(usize, usize), Infallible> {
+            Ok((payload.len(), self.generation))
+        }
+    }
+
+    struct LenFactory;
+
+    impl MakeService for LenFactory {
+        type Service = Len;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, old: Option<&Len>) -> Result<Len, Infallible> {
+            Ok(Len {
+                generation: old.map_or(0, |o| o.generation + 1),
+            })
+        }
+    }
+
+    #[test]
+    fn scoped_boxed_service() {
+        let factory = ScopedBoxServiceFactory::<_, Frames, _, _>::new(LenFactory);
+        let svc = factory.make().unwrap();
+        let buf = vec![0u8; 5];
+        let frame = Frame { payload: &buf[1..] };
+        assert_eq!(block_on(svc.call_scoped(frame)), Ok((4, 0)));
+
+        // The old service is downcast to migrate its state.
+        let svc = factory.make_via_ref(Some(&svc)).unwrap();
+        assert_eq!(
+            block_on(svc.call_scoped(Frame { payload: &buf })),
+            Ok((5, 1))
+        );
+        assert!(svc.type_name().ends_with("Len"));
+    }
+
+    #[test]
+    fn scoped_boxed_service_of_references() {
+        let factory = ScopedBoxServiceFactory::<_, RefRequest<[u8]>, _, _>::new(LenFactory);
+        let svc = factory.make().unwrap();
+        let buf = [1u8, 2, 3];
+        assert_eq!(block_on(svc.call(&buf[..])), Ok((3, 0)));
+    }
+}
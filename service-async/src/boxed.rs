@@ -1,17 +1,53 @@
 use std::{
-    any::{Any, TypeId},
+    any::{type_name, Any, TypeId},
+    fmt,
     future::Future,
-    marker::PhantomData,
+    marker::{PhantomData, PhantomPinned},
+    mem::{align_of, size_of, MaybeUninit},
     pin::Pin,
+    task::{Context, Poll},
 };
 
-use crate::{AsyncMakeService, MakeService, Service};
+#[cfg(not(feature = "boxed-futures"))]
+use crate::AsyncMakeService;
+use crate::{layer::impl_wrap_inner, MakeService, Service, ServiceMetadata};
 /// A type-erased wrapper for services, enabling dynamic dispatch.
 ///  `BoxedService` allows for storing and using services of different types
 /// through a common interface.
+///
+/// Futures returned by the inner service are stored inline when they fit in
+/// 8 machine words, so small calls do not allocate. Larger futures fall back
+/// to a heap allocation.
+///
+/// The future of a call borrows the service, so it can not be dropped while a
+/// call is pending:
+///
+/// ```rust,compile_fail
+/// use std::convert::Infallible;
+/// use service_async::{impl_service, BoxedService, Service};
+///
+/// struct Greeting(String);
+///
+/// impl Service<()> for Greeting {
+///     impl_service! {
+///         type Response = usize;
+///         type Error = Infallible;
+///
+///         async fn call(&self, _req: ()) -> Result<usize, Infallible> {
+///             Ok(self.0.len())
+///         }
+///     }
+/// }
+///
+/// let svc = BoxedService::new(Greeting("hello".into()));
+/// let fut = svc.call(());
+/// drop(svc);
+/// drop(fut);
+/// ```
 pub struct BoxedService<Request, Response, E> {
     svc: *const (),
     type_id: TypeId,
+    type_name: &'static str,
     vtable: ServiceVtable<Request, Response, E>,
 }
 
@@ -26,6 +62,7 @@ impl<Request, Response, E> BoxedService<Request, Response, E> {
         BoxedService {
             svc,
             type_id,
+            type_name: type_name::<S>(),
             vtable: ServiceVtable {
                 call: call::<Request, S>,
                 drop: drop::<S>,
@@ -47,6 +84,23 @@ impl<Request, Response, E> BoxedService<Request, Response, E> {
     pub unsafe fn downcast_ref_unchecked<T: Any>(&self) -> &T {
         &*(self.svc as *const T)
     }
+
+    /// Type name of the boxed service, as given by [`std::any::type_name`].
+    #[inline]
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+}
+
+impl<Request, Response, E> fmt::Debug for BoxedService<Request, Response, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoxedService")
+            .field("service", &self.type_name)
+            .field("request", &type_name::<Request>())
+            .field("response", &type_name::<Response>())
+            .field("error", &type_name::<E>())
+            .finish()
+    }
 }
 
 impl<Request, Response, E> Drop for BoxedService<Request, Response, E> {
@@ -56,6 +110,7 @@ impl<Request, Response, E> Drop for BoxedService<Request, Response, E> {
     }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<Request, Response, E> Service<Request> for BoxedService<Request, Response, E> {
     type Response = Response;
     type Error = E;
@@ -66,6 +121,25 @@ impl<Request, Response, E> Service<Request> for BoxedService<Request, Response,
     }
 }
 
+#[cfg(feature = "boxed-futures")]
+impl<Request, Response, E> Service<Request> for BoxedService<Request, Response, E> {
+    type Response = Response;
+    type Error = E;
+    type Future<'a>
+        = SmallFuture<'a, Response, E>
+    where
+        Self: 'a,
+        Request: 'a;
+
+    #[inline]
+    fn call<'a>(&'a self, req: Request) -> Self::Future<'a>
+    where
+        Request: 'a,
+    {
+        unsafe { (self.vtable.call)(self.svc, req) }
+    }
+}
+
 /// Trait for converting a service into a boxed service.
 ///
 /// This trait provides a method to convert any compatible service
@@ -84,25 +158,150 @@ where
     }
 }
 
-type LocalStaticBoxedFuture<T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + 'static>>;
+const INLINE_FUTURE_WORDS: usize = 8;
+const INLINE_FUTURE_SIZE: usize = INLINE_FUTURE_WORDS * size_of::<usize>();
+
+/// A type-erased future which stores small futures inline and boxes the rest.
+///
+/// `'a` is the lifetime of the borrows of the inner future, e.g. of the service
+/// it was called on.
+pub struct SmallFuture<'a, T, E> {
+    storage: MaybeUninit<[usize; INLINE_FUTURE_WORDS]>,
+    poll: unsafe fn(raw: *mut (), cx: &mut Context<'_>) -> Poll<Result<T, E>>,
+    drop: unsafe fn(raw: *mut ()),
+    // The inner future may be !Unpin and !Send.
+    _marker: PhantomData<(&'a (), *const (), PhantomPinned)>,
+}
+
+impl<'a, T, E> SmallFuture<'a, T, E> {
+    /// The caller must not let the result outlive the borrows of `fut`, which
+    /// are erased: `F` is not required to outlive `'a`.
+    #[inline]
+    pub(crate) fn new<F>(fut: F) -> Self
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let mut storage = MaybeUninit::<[usize; INLINE_FUTURE_WORDS]>::uninit();
+        if size_of::<F>() <= INLINE_FUTURE_SIZE && align_of::<F>() <= align_of::<usize>() {
+            unsafe { storage.as_mut_ptr().cast::<F>().write(fut) };
+            SmallFuture {
+                storage,
+                poll: poll_inline::<F, T, E>,
+                drop: drop_inline::<F>,
+                _marker: PhantomData,
+            }
+        } else {
+            let boxed = Box::into_raw(Box::new(fut));
+            unsafe { storage.as_mut_ptr().cast::<*mut F>().write(boxed) };
+            SmallFuture {
+                storage,
+                poll: poll_boxed::<F, T, E>,
+                drop: drop_boxed::<F>,
+                _marker: PhantomData,
+            }
+        }
+    }
+}
+
+impl<T, E> Future for SmallFuture<'_, T, E> {
+    type Output = Result<T, E>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the storage is never moved out of a pinned `SmallFuture`. It is
+        // reached through a raw pointer, as a `&mut` to it would invalidate the
+        // references an inline future holds into itself.
+        unsafe {
+            let this = self.get_unchecked_mut();
+            (this.poll)(std::ptr::addr_of_mut!(this.storage).cast(), cx)
+        }
+    }
+}
+
+impl<T, E> Drop for SmallFuture<'_, T, E> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { (self.drop)(std::ptr::addr_of_mut!(self.storage).cast()) };
+    }
+}
+
+unsafe fn poll_inline<F, T, E>(raw: *mut (), cx: &mut Context<'_>) -> Poll<Result<T, E>>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    Pin::new_unchecked(&mut *raw.cast::<F>()).poll(cx)
+}
+
+unsafe fn drop_inline<F>(raw: *mut ()) {
+    std::ptr::drop_in_place(raw.cast::<F>());
+}
+
+unsafe fn poll_boxed<F, T, E>(raw: *mut (), cx: &mut Context<'_>) -> Poll<Result<T, E>>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    Pin::new_unchecked(&mut **raw.cast::<*mut F>()).poll(cx)
+}
+
+unsafe fn drop_boxed<F>(raw: *mut ()) {
+    std::mem::drop(Box::from_raw(*raw.cast::<*mut F>()));
+}
 
 struct ServiceVtable<T, U, E> {
-    call: unsafe fn(raw: *const (), req: T) -> LocalStaticBoxedFuture<U, E>,
+    // The future borrows the service; `BoxedService::call` narrows the
+    // lifetime to the borrow of `self`.
+    call: unsafe fn(raw: *const (), req: T) -> SmallFuture<'static, U, E>,
     drop: unsafe fn(raw: *const ()),
 }
 
-unsafe fn call<R, S>(svc: *const (), req: R) -> LocalStaticBoxedFuture<S::Response, S::Error>
+unsafe fn call<R, S>(svc: *const (), req: R) -> SmallFuture<'static, S::Response, S::Error>
 where
     R: 'static,
     S: Service<R> + 'static,
 {
     let svc = &*svc.cast::<S>();
-    let fut = S::call(svc, req);
-    Box::pin(fut)
+    SmallFuture::new(S::call(svc, req))
 }
 
 unsafe fn drop<S>(raw: *const ()) {
-    std::ptr::drop_in_place(raw as *mut S);
+    std::mem::drop(Box::from_raw(raw as *mut S));
+}
+
+/// Make a boxed service with `factory`, migrating the state of `old` if it
+/// boxes the service type of `factory`.
+///
+/// It is the downcast, `make_via_ref` and re-box steps of reloading a boxed
+/// service. If `old` boxes another type, e.g. because the layers changed, the
+/// service is made from scratch.
+pub fn make_via_downcast<F, Req, Resp, E>(
+    factory: &F,
+    old: Option<&BoxedService<Req, Resp, E>>,
+) -> Result<BoxedService<Req, Resp, E>, F::Error>
+where
+    F: MakeService + ?Sized,
+    F::Service: Service<Req, Response = Resp, Error = E> + 'static,
+    Req: 'static,
+{
+    let svc = factory.make_via_ref(old.and_then(|o| o.downcast_ref()))?;
+    Ok(svc.into_boxed())
+}
+
+/// The async counterpart of [`make_via_downcast`], for an [`AsyncMakeService`]
+/// such as a [`BoxedAsyncMakeService`].
+#[cfg(not(feature = "boxed-futures"))]
+pub async fn make_via_downcast_async<F, Req, Resp, E>(
+    factory: &F,
+    old: Option<&BoxedService<Req, Resp, E>>,
+) -> Result<BoxedService<Req, Resp, E>, F::Error>
+where
+    F: AsyncMakeService + ?Sized,
+    F::Service: Service<Req, Response = Resp, Error = E> + 'static,
+    Req: 'static,
+{
+    let svc = factory
+        .make_via_ref(old.and_then(|o| o.downcast_ref()))
+        .await?;
+    Ok(svc.into_boxed())
 }
 
 // A factory for creating boxed services.
@@ -125,6 +324,29 @@ impl<F, Req> BoxServiceFactory<F, Req> {
             _marker: PhantomData,
         }
     }
+
+    /// Get a reference to the wrapped factory.
+    #[inline]
+    pub fn as_inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Take the wrapped factory out, e.g. to wrap it again before boxing.
+    #[inline]
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl_wrap_inner!(BoxServiceFactory < _, Req > { _marker });
+
+impl<F, Req> fmt::Debug for BoxServiceFactory<F, Req> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoxServiceFactory")
+            .field("factory", &type_name::<F>())
+            .field("request", &type_name::<Req>())
+            .finish()
+    }
 }
 
 impl<F, Req> MakeService for BoxServiceFactory<F, Req>
@@ -141,14 +363,19 @@ where
     type Error = F::Error;
 
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
-        let svc = match old {
-            Some(inner) => self.inner.make_via_ref(inner.downcast_ref())?,
-            None => self.inner.make()?,
-        };
-        Ok(svc.into_boxed())
+        make_via_downcast(&self.inner, old)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
     }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<F, Req> AsyncMakeService for BoxServiceFactory<F, Req>
 where
     F: AsyncMakeService,
@@ -166,26 +393,35 @@ where
         &self,
         old: Option<&Self::Service>,
     ) -> Result<Self::Service, Self::Error> {
-        let svc = match old {
-            Some(inner) => self.inner.make_via_ref(inner.downcast_ref()).await?,
-            None => self.inner.make().await?,
-        };
-        Ok(svc.into_boxed())
+        make_via_downcast_async(&self.inner, old).await
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
     }
 }
 /// A type-erased wrapper for asynchronous service factories.
 ///
 /// `BoxedAsyncMakeService` enables dynamic dispatch for async service factories,
 /// allowing for flexible composition of asynchronous service creation pipelines.
+#[cfg(not(feature = "boxed-futures"))]
 pub struct BoxedAsyncMakeService<S, E> {
     svc: *const (),
     type_id: TypeId,
+    type_name: &'static str,
     vtable: AsyncMakeServiceVtable<S, E>,
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 unsafe impl<S, E> Send for BoxedAsyncMakeService<S, E> {}
+#[cfg(not(feature = "boxed-futures"))]
 unsafe impl<S, E> Sync for BoxedAsyncMakeService<S, E> {}
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<S, E> BoxedAsyncMakeService<S, E> {
     pub fn new<AMS>(ams: AMS) -> Self
     where
@@ -197,8 +433,11 @@ impl<S, E> BoxedAsyncMakeService<S, E> {
         BoxedAsyncMakeService {
             svc,
             type_id,
+            type_name: type_name::<AMS>(),
             vtable: AsyncMakeServiceVtable {
                 make_via_ref: make_via_ref::<AMS, S, E>,
+                validate: validate::<AMS, S, E>,
+                service_metadata: service_metadata::<AMS>,
                 drop: drop::<AMS>,
             },
         }
@@ -218,8 +457,47 @@ impl<S, E> BoxedAsyncMakeService<S, E> {
     pub unsafe fn downcast_ref_unchecked<T: Any>(&self) -> &T {
         &*(self.svc as *const T)
     }
+
+    /// Whether the boxed factory is a `T`.
+    #[inline]
+    pub fn is<T: Any>(&self) -> bool {
+        self.type_id == TypeId::of::<T>()
+    }
+
+    /// Take the boxed factory out if it is a `T`, or give the box back.
+    ///
+    /// Together with [`BoxServiceFactory::into_inner`] this recovers the
+    /// original factory, e.g. to reach layer specific APIs or box it again
+    /// with more layers.
+    pub fn downcast<T: Any>(self) -> Result<T, Self> {
+        if !self.is::<T>() {
+            return Err(self);
+        }
+        let this = std::mem::ManuallyDrop::new(self);
+        // SAFETY: the pointer comes from `Box::<T>::into_raw` as the type
+        // matches, and `this` is not dropped so it is not freed twice.
+        Ok(*unsafe { Box::from_raw(this.svc as *mut T) })
+    }
+
+    /// Type name of the boxed factory, as given by [`std::any::type_name`].
+    #[inline]
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<S, E> fmt::Debug for BoxedAsyncMakeService<S, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoxedAsyncMakeService")
+            .field("factory", &self.type_name)
+            .field("service", &type_name::<S>())
+            .field("error", &type_name::<E>())
+            .finish()
+    }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<S, E> Drop for BoxedAsyncMakeService<S, E> {
     #[inline]
     fn drop(&mut self) {
@@ -227,6 +505,7 @@ impl<S, E> Drop for BoxedAsyncMakeService<S, E> {
     }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<S, E> AsyncMakeService for BoxedAsyncMakeService<S, E> {
     type Service = S;
     type Error = E;
@@ -238,15 +517,30 @@ impl<S, E> AsyncMakeService for BoxedAsyncMakeService<S, E> {
     ) -> Result<Self::Service, Self::Error> {
         unsafe { (self.vtable.make_via_ref)(self.svc, old.map(|s| s as _)) }.await
     }
+
+    #[inline]
+    fn validate(&self) -> Result<(), Self::Error> {
+        unsafe { (self.vtable.validate)(self.svc) }
+    }
+
+    #[inline]
+    fn service_metadata(&self) -> ServiceMetadata {
+        unsafe { (self.vtable.service_metadata)(self.svc) }
+    }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 type LocalBoxedFuture<T, E> = Pin<Box<dyn Future<Output = Result<T, E>>>>;
 
+#[cfg(not(feature = "boxed-futures"))]
 struct AsyncMakeServiceVtable<S, E> {
     make_via_ref: unsafe fn(raw: *const (), old: Option<*const S>) -> LocalBoxedFuture<S, E>,
+    validate: unsafe fn(raw: *const ()) -> Result<(), E>,
+    service_metadata: unsafe fn(raw: *const ()) -> ServiceMetadata,
     drop: unsafe fn(raw: *const ()),
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 unsafe fn make_via_ref<AMS, S, E>(
     svc: *const (),
     old: Option<*const AMS::Service>,
@@ -259,3 +553,313 @@ where
     let fut = AMS::make_via_ref(svc, old.map(|s| unsafe { &*s }));
     Box::pin(fut)
 }
+
+#[cfg(not(feature = "boxed-futures"))]
+unsafe fn validate<AMS, S, E>(svc: *const ()) -> Result<(), E>
+where
+    AMS: AsyncMakeService<Service = S, Error = E> + 'static,
+{
+    let svc = &*svc.cast::<AMS>();
+    AMS::validate(svc)
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+unsafe fn service_metadata<AMS: AsyncMakeService + 'static>(svc: *const ()) -> ServiceMetadata {
+    let svc = &*svc.cast::<AMS>();
+    AMS::service_metadata(svc)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        future::Future,
+        mem::{align_of_val, size_of_val},
+        pin::{pin, Pin},
+        rc::Rc,
+        task::{Context, Poll},
+    };
+
+    use super::{SmallFuture, INLINE_FUTURE_SIZE};
+    use crate::test_util::{block_on, poll_once};
+
+    /// Returns `Pending` on its first poll.
+    #[derive(Default)]
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if std::mem::replace(&mut self.0, true) {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    /// Sets its flag when dropped.
+    struct DropFlag(Rc<Cell<bool>>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    fn fits_inline<F>(fut: &F) -> bool {
+        size_of_val(fut) <= INLINE_FUTURE_SIZE && align_of_val(fut) <= align_of::<usize>()
+    }
+
+    #[test]
+    fn inline_future() {
+        let n = 20u64;
+        let fut = async move { Ok::<_, ()>(n + 1) };
+        assert!(fits_inline(&fut));
+        assert_eq!(block_on(SmallFuture::new(fut)), Ok(21));
+    }
+
+    #[test]
+    fn heap_future() {
+        let data = [3u64; 64];
+        let fut = async move { Ok::<_, ()>(data.iter().sum::<u64>()) };
+        assert!(!fits_inline(&fut));
+        assert_eq!(block_on(SmallFuture::new(fut)), Ok(192));
+    }
+
+    #[test]
+    fn self_referential_future() {
+        // The reference into `data` is held across the await, so the future
+        // is only sound if it does not move between polls.
+        let small = async {
+            let data = [1u64, 2];
+            let r = &data;
+            YieldOnce::default().await;
+            Ok::<_, ()>(r.iter().sum::<u64>())
+        };
+        let large = async {
+            let data = [1u64; 32];
+            let r = &data;
+            YieldOnce::default().await;
+            Ok::<_, ()>(r.iter().sum::<u64>())
+        };
+        assert!(fits_inline(&small));
+        assert!(!fits_inline(&large));
+
+        let mut small = pin!(SmallFuture::new(small));
+        assert!(poll_once(small.as_mut()).is_pending());
+        assert_eq!(poll_once(small.as_mut()), Poll::Ready(Ok(3)));
+        let mut large = pin!(SmallFuture::new(large));
+        assert!(poll_once(large.as_mut()).is_pending());
+        assert_eq!(poll_once(large.as_mut()), Poll::Ready(Ok(32)));
+    }
+
+    #[test]
+    fn drop_before_completion() {
+        for padding in [0, 256] {
+            // Dropped after a poll, while suspended at the await.
+            let dropped = Rc::new(Cell::new(false));
+            let flag = DropFlag(dropped.clone());
+            let buf = vec![0u8; padding];
+            let fut = async move {
+                let _flag = flag;
+                YieldOnce::default().await;
+                Ok::<_, ()>(buf.len())
+            };
+            let mut fut = Box::pin(SmallFuture::new(fut));
+            assert!(poll_once(fut.as_mut()).is_pending());
+            assert!(!dropped.get());
+            drop(fut);
+            assert!(dropped.get());
+
+            // Dropped before the first poll.
+            let dropped = Rc::new(Cell::new(false));
+            let flag = DropFlag(dropped.clone());
+            let big = [0u8; 256];
+            let fut = async move {
+                let _flag = flag;
+                Ok::<_, ()>(big.len() + padding)
+            };
+            drop(SmallFuture::new(fut));
+            assert!(dropped.get());
+        }
+    }
+
+    #[test]
+    fn over_aligned_future() {
+        #[repr(align(64))]
+        struct Aligned(u8);
+
+        let value = Aligned(9);
+        let fut = async move {
+            let value = &value;
+            YieldOnce::default().await;
+            assert_eq!(value as *const Aligned as usize % 64, 0);
+            Ok::<_, ()>(value.0)
+        };
+        assert!(align_of_val(&fut) > align_of::<usize>());
+        assert_eq!(block_on(SmallFuture::new(fut)), Ok(9));
+    }
+
+    #[test]
+    fn zero_sized_future() {
+        struct Ready;
+
+        impl Future for Ready {
+            type Output = Result<u8, ()>;
+
+            fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+                Poll::Ready(Ok(7))
+            }
+        }
+
+        assert_eq!(size_of_val(&Ready), 0);
+        assert_eq!(block_on(SmallFuture::new(Ready)), Ok(7));
+        drop(SmallFuture::new(Ready));
+    }
+
+    #[cfg(not(feature = "boxed-futures"))]
+    #[test]
+    fn boxed_service_call() {
+        use crate::{BoxService, Service};
+
+        struct Add(u64);
+
+        impl Service<u64> for Add {
+            type Response = u64;
+            type Error = ();
+
+            async fn call(&self, req: u64) -> Result<u64, ()> {
+                YieldOnce::default().await;
+                Ok(self.0 + req)
+            }
+        }
+
+        let svc = Add(1).into_boxed();
+        assert_eq!(block_on(svc.call(2)), Ok(3));
+        assert_eq!(svc.downcast_ref::<Add>().map(|s| s.0), Some(1));
+    }
+
+    #[cfg(not(feature = "boxed-futures"))]
+    #[test]
+    fn debug_names_the_boxed_type() {
+        use super::{BoxServiceFactory, BoxedAsyncMakeService};
+        use crate::{
+            make_service::AsyncMakeServiceWrapper, utils::CloneFactory, BoxService, Service,
+        };
+
+        #[derive(Clone)]
+        struct Id;
+
+        impl Service<u8> for Id {
+            type Response = u8;
+            type Error = ();
+
+            async fn call(&self, req: u8) -> Result<u8, ()> {
+                Ok(req)
+            }
+        }
+
+        let svc = Id.into_boxed();
+        assert!(svc.type_name().ends_with("::Id"));
+        let debug = format!("{svc:?}");
+        assert!(debug.starts_with("BoxedService { service: \""), "{debug}");
+        assert!(debug.contains("request: \"u8\""), "{debug}");
+
+        let factory = BoxServiceFactory::<_, u8>::new(CloneFactory::new(Id));
+        assert!(format!("{factory:?}").contains("CloneFactory<"));
+
+        let factory = BoxedAsyncMakeService::new(AsyncMakeServiceWrapper(CloneFactory::new(Id)));
+        assert!(factory.type_name().contains("AsyncMakeServiceWrapper"));
+        assert!(format!("{factory:?}").starts_with("BoxedAsyncMakeService { factory: "));
+    }
+
+    #[cfg(not(feature = "boxed-futures"))]
+    #[test]
+    fn make_via_downcast_migrates_the_same_type() {
+        use std::convert::Infallible;
+
+        use super::{make_via_downcast, make_via_downcast_async};
+        use crate::{make_service::AsyncMakeServiceWrapper, BoxService, MakeService, Service};
+
+        struct Generation(u32);
+
+        impl Service<()> for Generation {
+            type Response = u32;
+            type Error = Infallible;
+
+            async fn call(&self, _: ()) -> Result<u32, Infallible> {
+                Ok(self.0)
+            }
+        }
+
+        struct Other;
+
+        impl Service<()> for Other {
+            type Response = u32;
+            type Error = Infallible;
+
+            async fn call(&self, _: ()) -> Result<u32, Infallible> {
+                Ok(u32::MAX)
+            }
+        }
+
+        struct Factory;
+
+        impl MakeService for Factory {
+            type Service = Generation;
+            type Error = Infallible;
+
+            fn make_via_ref(&self, old: Option<&Generation>) -> Result<Generation, Infallible> {
+                Ok(Generation(old.map_or(0, |o| o.0 + 1)))
+            }
+        }
+
+        let svc = make_via_downcast(&Factory, None).unwrap();
+        let svc = make_via_downcast(&Factory, Some(&svc)).unwrap();
+        assert_eq!(block_on(svc.call(())), Ok(1));
+        let other = Other.into_boxed();
+        let svc = make_via_downcast(&Factory, Some(&other)).unwrap();
+        assert_eq!(block_on(svc.call(())), Ok(0));
+
+        let factory = AsyncMakeServiceWrapper(Factory);
+        let svc = block_on(make_via_downcast_async(&factory, Some(&svc))).unwrap();
+        assert_eq!(block_on(svc.call(())), Ok(1));
+    }
+
+    #[cfg(not(feature = "boxed-futures"))]
+    #[test]
+    fn recover_the_boxed_factory() {
+        use super::{BoxServiceFactory, BoxedAsyncMakeService};
+        use crate::{
+            make_service::AsyncMakeServiceWrapper, utils::CloneFactory, AsyncMakeService, Service,
+        };
+
+        #[derive(Debug, Clone)]
+        struct Tag(u8);
+
+        impl Service<()> for Tag {
+            type Response = u8;
+            type Error = ();
+
+            async fn call(&self, _: ()) -> Result<u8, ()> {
+                Ok(self.0)
+            }
+        }
+
+        let factory = BoxServiceFactory::<_, ()>::new(CloneFactory::new(Tag(1)));
+        assert_eq!(factory.as_inner().clone().into_inner().0, 1);
+        let inner = factory.into_inner();
+
+        let boxed = BoxedAsyncMakeService::new(AsyncMakeServiceWrapper(inner));
+        let svc = block_on(boxed.make()).unwrap();
+        assert_eq!(block_on(svc.call(())), Ok(1));
+        assert!(!boxed.is::<Tag>());
+        let boxed = boxed.downcast::<Tag>().unwrap_err();
+        let AsyncMakeServiceWrapper(inner) = boxed
+            .downcast::<AsyncMakeServiceWrapper<CloneFactory<Tag>>>()
+            .unwrap();
+        assert_eq!(inner.into_inner().0, 1);
+    }
+}
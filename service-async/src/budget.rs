@@ -0,0 +1,414 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::pin,
+    task::Poll,
+    time::Duration,
+};
+
+use param::ParamMaybeRef;
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    overrides,
+    shutdown::impl_shutdown_inner,
+    time::Timer,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// The size of a response, as counted against [`Budget::max_size`].
+pub trait MeasureSize {
+    fn size(&self) -> usize;
+}
+
+impl MeasureSize for Vec<u8> {
+    #[inline]
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl MeasureSize for String {
+    #[inline]
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl MeasureSize for Box<[u8]> {
+    #[inline]
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl MeasureSize for &[u8] {
+    #[inline]
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl MeasureSize for &str {
+    #[inline]
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: MeasureSize> MeasureSize for Option<T> {
+    #[inline]
+    fn size(&self) -> usize {
+        self.as_ref().map_or(0, T::size)
+    }
+}
+
+/// Limits of a call made through [`Budgeted`]. `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Budget {
+    /// Max size of the response, as given by [`MeasureSize`].
+    pub max_size: Option<usize>,
+    /// Max time the call may take.
+    pub max_time: Option<Duration>,
+}
+
+/// Error returned by [`Budgeted`].
+#[derive(Debug)]
+pub enum BudgetError<E> {
+    /// The call took longer than `max_time`.
+    Elapsed,
+    /// The response was larger than `max_size`.
+    TooLarge { size: usize, limit: usize },
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for BudgetError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetError::Elapsed => write!(f, "time budget elapsed"),
+            BudgetError::TooLarge { size, limit } => {
+                write!(f, "response of {size} bytes exceeds the budget of {limit}")
+            }
+            BudgetError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for BudgetError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BudgetError::Elapsed | BudgetError::TooLarge { .. } => None,
+            BudgetError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// [`Budgeted`] enforces the [`Budget`] of the stack config.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConfigBudget;
+
+/// [`Budgeted`] handles `(R, CX)` requests and enforces the [`Budget`] in
+/// `CX`, falling back to the one of the stack config.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContextBudget;
+
+/// A middleware which fails calls exceeding a [`Budget`] of response size or
+/// time, protecting e.g. proxies against pathological upstreams.
+///
+/// The budget is taken from the stack config through `Param<Budget>`, and the
+/// time source `TM` through `Param<TM>`. With [`ContextBudget`] a budget in
+/// the request context takes precedence, for per-call limits. A budget in the
+/// [`Overrides`](crate::overrides::Overrides) of a make replaces the one of the
+/// config for the service made.
+pub struct Budgeted<T, TM, SRC = ConfigBudget> {
+    budget: Budget,
+    timer: TM,
+    inner: T,
+    _marker: PhantomData<SRC>,
+}
+
+impl<T, TM> Budgeted<T, TM> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<Budget> + Param<TM>,
+    {
+        Self::layer_with_source()
+    }
+}
+
+impl<T, TM> Budgeted<T, TM, ContextBudget> {
+    pub fn layer_from_context<C>() -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<Budget> + Param<TM>,
+    {
+        Self::layer_with_source()
+    }
+}
+
+impl<T, TM, SRC> Budgeted<T, TM, SRC> {
+    fn layer_with_source<C>() -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<Budget> + Param<TM>,
+    {
+        layer_fn(|c: &C, inner| Budgeted {
+            budget: Param::<Budget>::param(c),
+            timer: Param::<TM>::param(c),
+            inner,
+            _marker: PhantomData,
+        })
+    }
+
+    #[inline]
+    pub fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    async fn enforce<Resp, E>(
+        &self,
+        budget: Budget,
+        call: impl Future<Output = Result<Resp, E>>,
+    ) -> Result<Resp, BudgetError<E>>
+    where
+        TM: Timer,
+        Resp: MeasureSize,
+    {
+        let resp = match budget.max_time {
+            Some(max_time) => {
+                let mut call = pin!(call);
+                let mut sleep = pin!(self.timer.sleep(max_time));
+                poll_fn(|cx| {
+                    if let Poll::Ready(res) = call.as_mut().poll(cx) {
+                        return Poll::Ready(res.map_err(BudgetError::Inner));
+                    }
+                    sleep.as_mut().poll(cx).map(|_| Err(BudgetError::Elapsed))
+                })
+                .await?
+            }
+            None => call.await.map_err(BudgetError::Inner)?,
+        };
+        match budget.max_size {
+            Some(limit) if resp.size() > limit => Err(BudgetError::TooLarge {
+                size: resp.size(),
+                limit,
+            }),
+            _ => Ok(resp),
+        }
+    }
+}
+
+impl<T, TM, R> Service<R> for Budgeted<T, TM, ConfigBudget>
+where
+    T: Service<R>,
+    T::Response: MeasureSize,
+    TM: Timer,
+{
+    type Response = T::Response;
+    type Error = BudgetError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        self.enforce(self.budget, self.inner.call(req)).await
+    }
+}
+
+impl<T, TM, R, CX> Service<(R, CX)> for Budgeted<T, TM, ContextBudget>
+where
+    CX: ParamMaybeRef<Budget>,
+    T: Service<(R, CX)>,
+    T::Response: MeasureSize,
+    TM: Timer,
+{
+    type Response = T::Response;
+    type Error = BudgetError<T::Error>;
+
+    async fn call(&self, (req, cx): (R, CX)) -> Result<Self::Response, Self::Error> {
+        let budget = cx.param_maybe_ref().copied().unwrap_or(self.budget);
+        self.enforce(budget, self.inner.call((req, cx))).await
+    }
+}
+
+impl<F: MakeService, TM: Clone, SRC> MakeService for Budgeted<F, TM, SRC> {
+    type Service = Budgeted<F::Service, TM, SRC>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Budgeted {
+            budget: overrides::current_or(self.budget),
+            timer: self.timer.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        budget_metadata::<Self::Service>(self.budget).with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, TM: Clone, SRC> AsyncMakeService for Budgeted<F, TM, SRC> {
+    type Service = Budgeted<F::Service, TM, SRC>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Budgeted {
+            budget: overrides::current_or(self.budget),
+            timer: self.timer.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        budget_metadata::<Self::Service>(self.budget).with_inner(self.inner.service_metadata())
+    }
+}
+
+fn budget_metadata<S>(budget: Budget) -> ServiceMetadata {
+    let mut meta = ServiceMetadata::of::<S>();
+    if let Some(max_size) = budget.max_size {
+        meta = meta.with_config("max_size", max_size);
+    }
+    if let Some(max_time) = budget.max_time {
+        meta = meta.with_config("max_time", format_args!("{max_time:?}"));
+    }
+    meta
+}
+
+impl_wrap_inner!(Budgeted<_, TM, SRC> { budget, timer, _marker });
+
+impl<T, TM, SRC> Layered for Budgeted<T, TM, SRC> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Budgeted<T, TM, SRC>);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin, task::Poll, time::Duration};
+
+    use param::{context_map, ParamSet};
+
+    use super::{Budget, BudgetError, Budgeted};
+    use crate::{
+        layer::FactoryLayer,
+        test_util::{block_on, poll_once},
+        time::{MockTimer, Timer},
+        Param, Service,
+    };
+
+    struct Config(Budget, MockTimer);
+
+    impl Param<Budget> for Config {
+        fn param(&self) -> Budget {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    context_map! {
+        struct Context {
+            budget: Budget,
+        }
+    }
+
+    /// Answers with `len` bytes after `millis` of mock time.
+    struct Req {
+        len: usize,
+        millis: u64,
+    }
+
+    struct Slow(MockTimer);
+
+    impl Service<Req> for Slow {
+        type Response = String;
+        type Error = Infallible;
+
+        async fn call(&self, req: Req) -> Result<String, Infallible> {
+            self.0.sleep(Duration::from_millis(req.millis)).await;
+            Ok("x".repeat(req.len))
+        }
+    }
+
+    impl<CX> Service<(Req, CX)> for Slow {
+        type Response = String;
+        type Error = Infallible;
+
+        async fn call(&self, (req, _): (Req, CX)) -> Result<String, Infallible> {
+            self.call(req).await
+        }
+    }
+
+    fn config(timer: &MockTimer) -> Config {
+        let budget = Budget {
+            max_size: Some(3),
+            max_time: Some(Duration::from_millis(10)),
+        };
+        Config(budget, timer.clone())
+    }
+
+    #[test]
+    fn enforces_size_and_time() {
+        let timer = MockTimer::new();
+        let svc = Budgeted::<_, MockTimer>::layer().layer(&config(&timer), Slow(timer.clone()));
+        assert_eq!(svc.budget().max_size, Some(3));
+
+        let mut call = pin!(svc.call(Req { len: 3, millis: 5 }));
+        assert!(poll_once(call.as_mut()).is_pending());
+        timer.advance(Duration::from_millis(5));
+        assert!(matches!(poll_once(call.as_mut()), Poll::Ready(Ok(s)) if s == "xxx"));
+
+        let mut call = pin!(svc.call(Req { len: 1, millis: 20 }));
+        assert!(poll_once(call.as_mut()).is_pending());
+        timer.advance(Duration::from_millis(10));
+        assert!(matches!(
+            poll_once(call.as_mut()),
+            Poll::Ready(Err(BudgetError::Elapsed))
+        ));
+
+        let res = block_on(svc.call(Req { len: 4, millis: 0 }));
+        assert!(matches!(
+            res,
+            Err(BudgetError::TooLarge { size: 4, limit: 3 })
+        ));
+    }
+
+    #[test]
+    fn context_budget_takes_precedence() {
+        let timer = MockTimer::new();
+        let svc = Budgeted::<_, MockTimer, _>::layer_from_context()
+            .layer(&config(&timer), Slow(timer.clone()));
+        let req = || Req { len: 4, millis: 0 };
+
+        let res = block_on(svc.call((req(), Context::new())));
+        assert!(matches!(res, Err(BudgetError::TooLarge { .. })));
+
+        let cx = Context::new().param_set(Budget {
+            max_size: Some(10),
+            max_time: None,
+        });
+        assert_eq!(block_on(svc.call((req(), cx))).unwrap(), "xxxx");
+    }
+}
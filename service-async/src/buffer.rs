@@ -0,0 +1,370 @@
+use std::{error::Error, fmt::Display, rc::Rc};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer},
+    pool::{oneshot, task, ServiceMut, Worker},
+    pushback::{Pushback, PushbackHint},
+    serve::Spawn,
+    shutdown::Shutdown,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Configuration of [`Buffer`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// Max number of requests waiting for the service. Requests beyond it are
+    /// rejected.
+    pub capacity: usize,
+}
+
+/// Error returned by [`Buffer`].
+#[derive(Debug)]
+pub enum BufferError<E> {
+    /// The queue is full.
+    Full,
+    /// The worker task has stopped, e.g. because its runtime shut down.
+    Closed,
+    /// The service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for BufferError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BufferError::Full => f.write_str("buffer is full"),
+            BufferError::Closed => f.write_str("buffer worker closed"),
+            BufferError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for BufferError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BufferError::Full | BufferError::Closed => None,
+            BufferError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<E: PushbackHint> PushbackHint for BufferError<E> {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match self {
+            BufferError::Full => Some(Pushback::saturated()),
+            BufferError::Closed => None,
+            BufferError::Inner(e) => e.pushback(),
+        }
+    }
+}
+
+/// A cloneable handle to a service owned by a worker task, which takes the
+/// requests of every handle through a bounded queue.
+///
+/// It hosts services which can not be shared: ones called through `&mut`
+/// (see [`ServiceMut`]) or holding `!Sync` state. The worker handles one
+/// request at a time, on the thread it was spawned on. It stops once every
+/// handle is dropped and the queue is drained. Its [`Shutdown`] shuts the
+/// service down after the requests already queued, and refuses new ones.
+///
+/// Use [`FactoryStack::push_buffered_clone`](crate::stack::FactoryStack::push_buffered_clone)
+/// to host the service of a stack, or [`WorkerPool`](crate::pool::WorkerPool)
+/// to spread the requests over several instances.
+pub struct Buffer<S> {
+    worker: Rc<Worker<S>>,
+    capacity: usize,
+}
+
+impl<S: 'static> Buffer<S> {
+    /// Spawn a worker task owning `svc` with `spawner`.
+    pub fn new(svc: S, config: BufferConfig, spawner: &impl Spawn) -> Self {
+        Buffer {
+            worker: Rc::new(Worker::spawn(svc, spawner)),
+            capacity: config.capacity,
+        }
+    }
+}
+
+impl<S> Buffer<S> {
+    /// Host the service of the inner factory in a worker task spawned with
+    /// `spawner`. The queue capacity is taken from the stack config through
+    /// `Param<BufferConfig>`.
+    pub fn layer<C, SP>(spawner: SP) -> impl FactoryLayer<C, S, Factory = BufferFactory<S, SP>>
+    where
+        C: Param<BufferConfig>,
+        SP: Clone,
+    {
+        layer_fn(move |c: &C, inner| BufferFactory {
+            config: c.param(),
+            spawner: spawner.clone(),
+            inner,
+        })
+    }
+
+    /// Number of requests waiting for the service.
+    #[inline]
+    pub fn queued(&self) -> usize {
+        self.worker.queued()
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl<S> Clone for Buffer<S> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Buffer {
+            worker: self.worker.clone(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl<S: Shutdown + 'static> Shutdown for Buffer<S> {
+    async fn shutdown(&self) {
+        self.worker.shutdown().recv().await;
+    }
+}
+
+impl<S, R> Service<R> for Buffer<S>
+where
+    S: ServiceMut<R> + 'static,
+    R: 'static,
+    S::Response: 'static,
+    S::Error: 'static,
+{
+    type Response = S::Response;
+    type Error = BufferError<S::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        if self.worker.queued() >= self.capacity {
+            return Err(BufferError::Full);
+        }
+        let (tx, rx) = oneshot();
+        let pushed = self.worker.push(task(move |svc: &mut S| {
+            Box::pin(async move { tx.send(ServiceMut::call(svc, req).await) })
+        }));
+        if !pushed {
+            return Err(BufferError::Closed);
+        }
+        rx.recv()
+            .await
+            .ok_or(BufferError::Closed)?
+            .map_err(BufferError::Inner)
+    }
+}
+
+/// Factory of [`Buffer`], made by [`Buffer::layer`] or
+/// [`FactoryStack::push_buffered_clone`](crate::stack::FactoryStack::push_buffered_clone).
+///
+/// Every service is handed to a new worker task. On reload the old worker keeps
+/// serving the handles of the old buffer, and stops once they are dropped and
+/// the requests queued to it are handled, so a reload which fails or is rolled
+/// back leaves it as it was. As the old service is owned by its worker, the new
+/// one is made from scratch rather than migrated from it.
+pub struct BufferFactory<F, SP> {
+    config: BufferConfig,
+    spawner: SP,
+    inner: F,
+}
+
+impl<F, SP> MakeService for BufferFactory<F, SP>
+where
+    F: MakeService,
+    F::Service: 'static,
+    SP: Spawn,
+{
+    type Service = Buffer<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let svc = self.inner.make()?;
+        Ok(Buffer::new(svc, self.config, &self.spawner))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("capacity", self.config.capacity)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F, SP> AsyncMakeService for BufferFactory<F, SP>
+where
+    F: AsyncMakeService,
+    F::Service: 'static,
+    SP: Spawn,
+{
+    type Service = Buffer<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        _old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let svc = self.inner.make().await?;
+        Ok(Buffer::new(svc, self.config, &self.spawner))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("capacity", self.config.capacity)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(BufferFactory<_, SP> { config, spawner });
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, convert::Infallible, future::Future, pin::pin, rc::Rc};
+
+    use super::{Buffer, BufferConfig, BufferError};
+    use crate::{
+        layer::FactoryLayer,
+        pool::ServiceMut,
+        shutdown::Shutdown,
+        test_util::{poll_once, LocalExecutor},
+        MakeService, Service,
+    };
+
+    /// Counts its calls, which needs exclusive access.
+    struct Counter {
+        offset: usize,
+        calls: usize,
+    }
+
+    impl ServiceMut<()> for Counter {
+        type Response = usize;
+        type Error = Infallible;
+
+        fn call(&mut self, _req: ()) -> impl Future<Output = Result<usize, Infallible>> {
+            self.calls += 1;
+            let n = self.offset + self.calls;
+            async move { Ok(n) }
+        }
+    }
+
+    struct CounterFactory(usize);
+
+    impl MakeService for CounterFactory {
+        type Service = Counter;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, _old: Option<&Counter>) -> Result<Counter, Infallible> {
+            Ok(Counter {
+                offset: self.0,
+                calls: 0,
+            })
+        }
+    }
+
+    fn config(capacity: usize) -> BufferConfig {
+        BufferConfig { capacity }
+    }
+
+    #[test]
+    fn handles_share_the_service() {
+        let executor = LocalExecutor::new();
+        let svc = Buffer::layer(executor.spawner())
+            .layer(&config(4), CounterFactory(0))
+            .make()
+            .unwrap();
+        let clone = svc.clone();
+        assert_eq!(executor.block_on(svc.call(())).ok(), Some(1));
+        assert_eq!(executor.block_on(clone.call(())).ok(), Some(2));
+        assert_eq!(executor.pending_tasks(), 1);
+    }
+
+    #[test]
+    fn reload_drains_the_old_worker() {
+        let executor = LocalExecutor::new();
+        let svc = Buffer::layer(executor.spawner())
+            .layer(&config(4), CounterFactory(0))
+            .make()
+            .unwrap();
+        assert_eq!(executor.block_on(svc.call(())).ok(), Some(1));
+
+        // A request queued before the reload is handled by the old service.
+        let mut queued = Box::pin(svc.call(()));
+        assert!(poll_once(queued.as_mut()).is_pending());
+        let reloaded = Buffer::layer(executor.spawner())
+            .layer(&config(8), CounterFactory(100))
+            .make_via_ref(Some(&svc))
+            .unwrap();
+        assert_eq!(reloaded.capacity(), 8);
+        assert_eq!(executor.pending_tasks(), 2);
+        assert_eq!(executor.block_on(queued).ok(), Some(2));
+        assert_eq!(executor.block_on(reloaded.call(())).ok(), Some(101));
+        // The old handles keep the old service until they are dropped.
+        assert_eq!(executor.block_on(svc.call(())).ok(), Some(3));
+        drop(svc);
+        executor.run_tasks();
+        assert_eq!(executor.pending_tasks(), 1);
+    }
+
+    #[test]
+    fn rejects_when_full() {
+        let executor = LocalExecutor::new();
+        let svc = Buffer::layer(executor.spawner())
+            .layer(&config(1), CounterFactory(0))
+            .make()
+            .unwrap();
+        // The worker does not run, so the first request stays queued.
+        let mut queued = pin!(svc.call(()));
+        assert!(poll_once(queued.as_mut()).is_pending());
+        assert_eq!(svc.queued(), 1);
+        assert!(matches!(
+            poll_once(pin!(svc.call(()))),
+            std::task::Poll::Ready(Err(BufferError::Full))
+        ));
+        assert_eq!(executor.block_on(queued).ok(), Some(1));
+    }
+
+    /// Tells whether it was shut down.
+    struct Closing(Rc<Cell<bool>>);
+
+    impl Service<()> for Closing {
+        type Response = bool;
+        type Error = Infallible;
+
+        async fn call(&self, _req: ()) -> Result<bool, Infallible> {
+            Ok(self.0.get())
+        }
+    }
+
+    impl Shutdown for Closing {
+        async fn shutdown(&self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn shutdown_flushes_the_queue() {
+        let executor = LocalExecutor::new();
+        let closed = Rc::new(Cell::new(false));
+        let svc = Buffer::new(Closing(closed.clone()), config(4), &executor.spawner());
+        let mut queued = Box::pin(svc.call(()));
+        assert!(poll_once(queued.as_mut()).is_pending());
+
+        executor.block_on(svc.shutdown());
+        assert!(closed.get());
+        assert_eq!(executor.block_on(queued).ok(), Some(false));
+        assert!(matches!(
+            executor.block_on(svc.call(())),
+            Err(BufferError::Closed)
+        ));
+        assert_eq!(executor.pending_tasks(), 0);
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    hash::Hash,
+    marker::PhantomData,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    either::{BoxError, EitherExt},
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    pushback::{Pushback, PushbackHint},
+    reload,
+    scheduler::{Admission, Fairness},
+    semaphore::Semaphore,
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Configuration of [`Bulkhead`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkheadConfig {
+    /// Max number of concurrent calls per partition.
+    pub max_concurrency: usize,
+    /// Max number of requests waiting per partition. Requests beyond it are rejected.
+    pub max_queue: usize,
+}
+
+/// Error returned by [`Bulkhead`].
+#[derive(Debug)]
+pub enum BulkheadError<E> {
+    /// The partition of the request is saturated.
+    Rejected,
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for BulkheadError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BulkheadError::Rejected => f.write_str("bulkhead partition is full"),
+            BulkheadError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for BulkheadError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BulkheadError::Rejected => None,
+            BulkheadError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<E: PushbackHint> PushbackHint for BulkheadError<E> {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match self {
+            BulkheadError::Rejected => Some(Pushback::saturated()),
+            BulkheadError::Inner(e) => e.pushback(),
+        }
+    }
+}
+
+impl<E: Error + Send + Sync + 'static> EitherExt for BulkheadError<E> {
+    #[inline]
+    fn flatten_error(self) -> BoxError {
+        Box::new(self)
+    }
+
+    #[inline]
+    fn as_dyn_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        self
+    }
+}
+
+/// A middleware which isolates requests into partitions by a key, each with its
+/// own concurrency limit and queue.
+///
+/// The key `K` is taken from the request through `Param<K>`, e.g. a tenant or an
+/// upstream. A saturated partition rejects new requests with
+/// [`BulkheadError::Rejected`] without affecting the other partitions.
+///
+/// Partitions are kept across reloads, and dropped as soon as no request
+/// holds or waits for a permit of theirs. Within a
+/// [`ServiceSlot::begin`](crate::reload::ServiceSlot::begin) transaction new
+/// limits are applied when it commits.
+pub struct Bulkhead<T, K> {
+    partitions: Arc<Partitions<K>>,
+    inner: T,
+}
+
+struct Partitions<K> {
+    state: Mutex<PartitionsState<K>>,
+}
+
+struct PartitionsState<K> {
+    config: BulkheadConfig,
+    // The semaphores are only cloned and dropped under the lock, so a strong
+    // count of 1 means no request uses the partition.
+    map: HashMap<K, Arc<Semaphore>>,
+}
+
+impl<K> Partitions<K> {
+    fn lock(&self) -> MutexGuard<'_, PartitionsState<K>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K: Hash + Eq + Clone> Partitions<K> {
+    fn get(&self, key: K) -> (Partition<'_, K>, usize) {
+        let mut state = self.lock();
+        let config = state.config;
+        let sem = state
+            .map
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(config.max_concurrency, Fairness::Strict)))
+            .clone();
+        let partition = Partition {
+            partitions: self,
+            key,
+            sem: Some(sem),
+        };
+        (partition, config.max_queue)
+    }
+
+    fn reconfigure(&self, config: BulkheadConfig) {
+        let mut state = self.lock();
+        state.config = config;
+        for sem in state.map.values() {
+            sem.resize(config.max_concurrency);
+        }
+    }
+}
+
+/// The semaphore of a partition used by a request, which drops the partition
+/// when the last request using it is done.
+struct Partition<'a, K: Hash + Eq> {
+    partitions: &'a Partitions<K>,
+    key: K,
+    sem: Option<Arc<Semaphore>>,
+}
+
+impl<K: Hash + Eq> Partition<'_, K> {
+    fn sem(&self) -> &Semaphore {
+        self.sem.as_ref().expect("semaphore taken before drop")
+    }
+}
+
+impl<K: Hash + Eq> Drop for Partition<'_, K> {
+    fn drop(&mut self) {
+        let mut state = self.partitions.lock();
+        drop(self.sem.take());
+        if state
+            .map
+            .get(&self.key)
+            .is_some_and(|sem| Arc::strong_count(sem) == 1)
+        {
+            state.map.remove(&self.key);
+        }
+    }
+}
+
+impl<T, K> Bulkhead<T, K> {
+    /// Number of (in-flight, queued) requests of a partition.
+    pub fn partition_load(&self, key: &K) -> Option<(usize, usize)>
+    where
+        K: Hash + Eq,
+    {
+        let state = self.partitions.lock();
+        state.map.get(key).map(|sem| (sem.in_use(), sem.queued()))
+    }
+
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = BulkheadFactory<T, K>>
+    where
+        C: Param<BulkheadConfig>,
+    {
+        layer_fn(|c: &C, inner| BulkheadFactory {
+            config: c.param(),
+            inner,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T, K, R> Service<R> for Bulkhead<T, K>
+where
+    T: Service<R>,
+    R: Param<K>,
+    K: Hash + Eq + Clone,
+{
+    type Response = T::Response;
+    type Error = BulkheadError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let (partition, max_queue) = self.partitions.get(req.param());
+        let Some(acquire) = partition
+            .sem()
+            .acquire_bounded(Admission::default(), max_queue)
+        else {
+            return Err(BulkheadError::Rejected);
+        };
+        let _permit = acquire.await;
+        self.inner.call(req).await.map_err(BulkheadError::Inner)
+    }
+}
+
+/// Factory of [`Bulkhead`].
+pub struct BulkheadFactory<F, K> {
+    config: BulkheadConfig,
+    inner: F,
+    _marker: PhantomData<fn() -> K>,
+}
+
+impl<F, K: Hash + Eq + Clone + 'static> BulkheadFactory<F, K> {
+    fn partitions<S>(&self, old: Option<&Bulkhead<S, K>>) -> Arc<Partitions<K>> {
+        match old {
+            Some(old) => {
+                let (partitions, config) = (old.partitions.clone(), self.config);
+                reload::on_commit(move || partitions.reconfigure(config));
+                old.partitions.clone()
+            }
+            None => Arc::new(Partitions {
+                state: Mutex::new(PartitionsState {
+                    config: self.config,
+                    map: HashMap::new(),
+                }),
+            }),
+        }
+    }
+}
+
+impl<F, K> MakeService for BulkheadFactory<F, K>
+where
+    F: MakeService,
+    K: Hash + Eq + Clone + 'static,
+{
+    type Service = Bulkhead<F::Service, K>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Bulkhead {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            partitions: self.partitions(old),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("max_concurrency", self.config.max_concurrency)
+            .with_config("max_queue", self.config.max_queue)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F, K> AsyncMakeService for BulkheadFactory<F, K>
+where
+    F: AsyncMakeService,
+    K: Hash + Eq + Clone + 'static,
+{
+    type Service = Bulkhead<F::Service, K>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Bulkhead {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            partitions: self.partitions(old),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("max_concurrency", self.config.max_concurrency)
+            .with_config("max_queue", self.config.max_queue)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(BulkheadFactory<_, K> { config, _marker });
+
+impl<T, K> Layered for Bulkhead<T, K> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Bulkhead<T, K>);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin, task::Poll};
+
+    use super::{Bulkhead, BulkheadConfig, BulkheadError};
+    use crate::{
+        layer::FactoryLayer,
+        reload::ServiceSlot,
+        test_util::{block_on, poll_once},
+        utils::CloneFactory,
+        MakeService, Service,
+    };
+
+    /// Handles requests keyed by partition; requests for partition 0 complete
+    /// at once, the others never do.
+    #[derive(Clone)]
+    struct Partitioned;
+
+    impl Service<u32> for Partitioned {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, key: u32) -> Result<(), Infallible> {
+            if key != 0 {
+                std::future::pending::<()>().await;
+            }
+            Ok(())
+        }
+    }
+
+    fn config(max_concurrency: usize, max_queue: usize) -> BulkheadConfig {
+        BulkheadConfig {
+            max_concurrency,
+            max_queue,
+        }
+    }
+
+    #[test]
+    fn partitions_are_isolated() {
+        let svc = Bulkhead::<_, u32>::layer()
+            .layer(&config(1, 0), CloneFactory::new(Partitioned))
+            .make()
+            .unwrap();
+
+        let mut a = pin!(svc.call(1));
+        assert!(poll_once(a.as_mut()).is_pending());
+        assert!(matches!(
+            poll_once(pin!(svc.call(1))),
+            Poll::Ready(Err(BulkheadError::Rejected))
+        ));
+        let mut b = pin!(svc.call(2));
+        assert!(poll_once(b.as_mut()).is_pending());
+        assert_eq!(svc.partition_load(&1), Some((1, 0)));
+        assert_eq!(svc.partition_load(&2), Some((1, 0)));
+    }
+
+    #[test]
+    fn idle_partitions_are_dropped() {
+        let svc = Bulkhead::<_, u32>::layer()
+            .layer(&config(1, 1), CloneFactory::new(Partitioned))
+            .make()
+            .unwrap();
+
+        block_on(svc.call(0)).unwrap();
+        assert_eq!(svc.partition_load(&0), None);
+
+        // Dropped once the last running or queued call of the partition is.
+        let mut running = Box::pin(svc.call(1));
+        let mut queued = Box::pin(svc.call(1));
+        assert!(poll_once(running.as_mut()).is_pending());
+        assert!(poll_once(queued.as_mut()).is_pending());
+        assert_eq!(svc.partition_load(&1), Some((1, 1)));
+        drop(queued);
+        assert_eq!(svc.partition_load(&1), Some((1, 0)));
+        drop(running);
+        assert_eq!(svc.partition_load(&1), None);
+    }
+
+    #[test]
+    fn reload_resizes_busy_partitions() {
+        let factory =
+            Bulkhead::<_, u32>::layer().layer(&config(1, 0), CloneFactory::new(Partitioned));
+        let svc = factory.make().unwrap();
+        let mut a = pin!(svc.call(1));
+        assert!(poll_once(a.as_mut()).is_pending());
+
+        let factory =
+            Bulkhead::<_, u32>::layer().layer(&config(2, 0), CloneFactory::new(Partitioned));
+        let reloaded = factory.make_via_ref(Some(&svc)).unwrap();
+        let mut b = pin!(reloaded.call(1));
+        assert!(poll_once(b.as_mut()).is_pending());
+        assert_eq!(reloaded.partition_load(&1), Some((2, 0)));
+        assert!(matches!(
+            poll_once(pin!(reloaded.call(1))),
+            Poll::Ready(Err(BulkheadError::Rejected))
+        ));
+    }
+
+    #[test]
+    fn rolled_back_reload_keeps_the_limits() {
+        let factory = |max_concurrency| {
+            Bulkhead::<_, u32>::layer()
+                .layer(&config(max_concurrency, 0), CloneFactory::new(Partitioned))
+        };
+        let slot = ServiceSlot::new(factory(1).make().unwrap());
+        let svc = slot.get();
+        let mut a = pin!(svc.call(1));
+        assert!(poll_once(a.as_mut()).is_pending());
+
+        block_on(slot.begin(&factory(2))).unwrap().rollback();
+        assert!(matches!(
+            poll_once(pin!(svc.call(1))),
+            Poll::Ready(Err(BulkheadError::Rejected))
+        ));
+
+        block_on(slot.begin(&factory(2))).unwrap().commit();
+        let mut b = pin!(slot.call(1));
+        assert!(poll_once(b.as_mut()).is_pending());
+        assert_eq!(slot.get().partition_load(&1), Some((2, 0)));
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::VecDeque,
+    future::{poll_fn, Future},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    task::{Poll, Waker},
+};
+
+/// A typed pub/sub bus, for sibling services of a stack to exchange events,
+/// e.g. a health layer telling a balance layer a backend is down, without
+/// global statics.
+///
+/// Create it while building the stack, put its [`handle`](Self::handle) in
+/// the config, and take it in the layers with `Param<BusHandle<T>>`. Since
+/// the config outlives reloads, services made on reload join the same bus.
+///
+/// Each receiver has a queue of `capacity` events. When a receiver falls
+/// behind, its oldest events are dropped, so a slow subscriber never blocks
+/// publishers.
+pub struct Bus<T> {
+    handle: BusHandle<T>,
+}
+
+impl<T> Bus<T> {
+    pub fn new(capacity: usize) -> Self {
+        Bus {
+            handle: BusHandle {
+                shared: Arc::new(Shared {
+                    capacity: capacity.max(1),
+                    subscribers: Mutex::new(Vec::new()),
+                }),
+            },
+        }
+    }
+
+    #[inline]
+    pub fn handle(&self) -> BusHandle<T> {
+        self.handle.clone()
+    }
+}
+
+struct Shared<T> {
+    capacity: usize,
+    subscribers: Mutex<Vec<Weak<Mutex<Queue<T>>>>>,
+}
+
+struct Queue<T> {
+    events: VecDeque<T>,
+    dropped: u64,
+    waker: Option<Waker>,
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A handle to a [`Bus`], to publish events and subscribe to them. Clones
+/// share the same bus.
+pub struct BusHandle<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> BusHandle<T> {
+    /// Create a receiver of the events published from now on.
+    pub fn subscribe(&self) -> BusReceiver<T> {
+        let queue = Arc::new(Mutex::new(Queue {
+            events: VecDeque::new(),
+            dropped: 0,
+            waker: None,
+        }));
+        lock(&self.shared.subscribers).push(Arc::downgrade(&queue));
+        BusReceiver {
+            queue,
+            capacity: self.shared.capacity,
+        }
+    }
+
+    /// Number of live receivers.
+    pub fn subscribers(&self) -> usize {
+        let mut subscribers = lock(&self.shared.subscribers);
+        subscribers.retain(|s| s.strong_count() > 0);
+        subscribers.len()
+    }
+
+    /// Send `event` to every live receiver. Returns the number of receivers
+    /// it was sent to.
+    pub fn publish(&self, event: T) -> usize
+    where
+        T: Clone,
+    {
+        let mut subscribers = lock(&self.shared.subscribers);
+        let mut sent = 0;
+        subscribers.retain(|s| {
+            let Some(queue) = s.upgrade() else {
+                return false;
+            };
+            let mut queue = lock(&queue);
+            if queue.events.len() >= self.shared.capacity {
+                queue.events.pop_front();
+                queue.dropped += 1;
+            }
+            queue.events.push_back(event.clone());
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+            sent += 1;
+            true
+        });
+        sent
+    }
+}
+
+impl<T> Clone for BusHandle<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        BusHandle {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for BusHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BusHandle")
+            .field("capacity", &self.shared.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Receives the events of a [`Bus`]. Created by [`BusHandle::subscribe`].
+///
+/// Dropping it unsubscribes.
+pub struct BusReceiver<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+    capacity: usize,
+}
+
+impl<T> BusReceiver<T> {
+    /// Wait for the next event. It is cancel safe.
+    pub fn recv(&mut self) -> impl Future<Output = T> + '_ {
+        poll_fn(|cx| {
+            let mut queue = lock(&self.queue);
+            match queue.events.pop_front() {
+                Some(event) => Poll::Ready(event),
+                None => {
+                    queue.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+
+    /// Take the next event if one is queued.
+    pub fn try_recv(&mut self) -> Option<T> {
+        lock(&self.queue).events.pop_front()
+    }
+
+    /// Number of events dropped because this receiver fell more than
+    /// `capacity` events behind.
+    pub fn dropped(&self) -> u64 {
+        lock(&self.queue).dropped
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::pin, task::Poll};
+
+    use super::Bus;
+    use crate::test_util::WakeCounter;
+
+    #[test]
+    fn publishes_to_live_receivers() {
+        let bus = Bus::new(2);
+        let handle = bus.handle();
+        let mut early = handle.subscribe();
+        assert_eq!(handle.publish(1), 1);
+        let mut late = bus.handle().subscribe();
+        assert_eq!(handle.publish(2), 2);
+        assert_eq!(early.try_recv(), Some(1));
+        assert_eq!(late.try_recv(), Some(2));
+
+        drop(late);
+        assert_eq!(handle.subscribers(), 1);
+        assert_eq!(handle.publish(3), 1);
+        assert_eq!((early.try_recv(), early.try_recv()), (Some(2), Some(3)));
+        assert_eq!(early.try_recv(), None);
+    }
+
+    #[test]
+    fn slow_receivers_drop_the_oldest_events() {
+        let bus = Bus::new(2);
+        let mut rx = bus.handle().subscribe();
+        for event in 1..=5 {
+            bus.handle().publish(event);
+        }
+        assert_eq!(rx.capacity(), 2);
+        assert_eq!(rx.dropped(), 3);
+        assert_eq!((rx.try_recv(), rx.try_recv()), (Some(4), Some(5)));
+    }
+
+    #[test]
+    fn recv_wakes_on_publish() {
+        let bus = Bus::new(1);
+        let mut rx = bus.handle().subscribe();
+        let waker = WakeCounter::new();
+        {
+            let mut recv = pin!(rx.recv());
+            assert!(waker.poll(recv.as_mut()).is_pending());
+            bus.handle().publish("up");
+            assert_eq!(waker.count(), 1);
+            assert_eq!(waker.poll(recv.as_mut()), Poll::Ready("up"));
+        }
+        // A cancelled recv loses no event.
+        assert!(waker.poll(pin!(rx.recv())).is_pending());
+        bus.handle().publish("down");
+        assert_eq!(rx.try_recv(), Some("down"));
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::pending,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    random::next_u64,
+    shutdown::impl_shutdown_inner,
+    time::Timer,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Faults injected by [`FaultInject`]. Probabilities are in `0.0..=1.0`; the
+/// default injects nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FaultConfig {
+    /// Probability of delaying a call by `latency` before it is sent on.
+    pub latency_probability: f64,
+    pub latency: Duration,
+    /// Probability of failing a call with [`FaultInjectError::Injected`].
+    pub error_probability: f64,
+    /// Probability of dropping a call: it is never sent on and never completes,
+    /// like a request lost on the network.
+    pub drop_probability: f64,
+}
+
+/// A handle to the [`FaultConfig`] of running [`FaultInject`] services.
+///
+/// Clones share the config, so a test can keep a clone and change the faults
+/// while services run.
+#[derive(Debug, Clone, Default)]
+pub struct FaultHandle {
+    state: Arc<FaultState>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    latency_probability: AtomicU64,
+    latency_nanos: AtomicU64,
+    error_probability: AtomicU64,
+    drop_probability: AtomicU64,
+}
+
+impl FaultHandle {
+    pub fn new(config: FaultConfig) -> Self {
+        let handle = FaultHandle::default();
+        handle.set(config);
+        handle
+    }
+
+    pub fn set(&self, config: FaultConfig) {
+        let state = &self.state;
+        state
+            .latency_probability
+            .store(config.latency_probability.to_bits(), Ordering::Relaxed);
+        state.latency_nanos.store(
+            config.latency.as_nanos().min(u64::MAX as u128) as u64,
+            Ordering::Relaxed,
+        );
+        state
+            .error_probability
+            .store(config.error_probability.to_bits(), Ordering::Relaxed);
+        state
+            .drop_probability
+            .store(config.drop_probability.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> FaultConfig {
+        let state = &self.state;
+        FaultConfig {
+            latency_probability: f64::from_bits(state.latency_probability.load(Ordering::Relaxed)),
+            latency: Duration::from_nanos(state.latency_nanos.load(Ordering::Relaxed)),
+            error_probability: f64::from_bits(state.error_probability.load(Ordering::Relaxed)),
+            drop_probability: f64::from_bits(state.drop_probability.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Stop injecting faults.
+    #[inline]
+    pub fn disable(&self) {
+        self.set(FaultConfig::default());
+    }
+}
+
+fn chance(probability: f64) -> bool {
+    probability > 0.0 && ((next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+}
+
+/// Error returned by [`FaultInject`].
+#[derive(Debug)]
+pub enum FaultInjectError<E> {
+    /// The error was injected.
+    Injected,
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for FaultInjectError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaultInjectError::Injected => f.write_str("injected fault"),
+            FaultInjectError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for FaultInjectError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FaultInjectError::Injected => None,
+            FaultInjectError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A middleware which injects latency, errors and dropped calls at random, to
+/// exercise the timeouts, retries and fallbacks of a stack in tests.
+///
+/// The faults are read from a [`FaultHandle`] on every call, so they can be
+/// changed at runtime. The handle and the time source `TM` are taken from the
+/// stack config through `Param`. Available with the `chaos` feature.
+pub struct FaultInject<T, TM> {
+    handle: FaultHandle,
+    timer: TM,
+    inner: T,
+}
+
+impl<T, TM> FaultInject<T, TM> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = FaultInject<T, TM>>
+    where
+        C: Param<FaultHandle> + Param<TM>,
+    {
+        layer_fn(|c: &C, inner| FaultInject {
+            handle: Param::<FaultHandle>::param(c),
+            timer: Param::<TM>::param(c),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn handle(&self) -> &FaultHandle {
+        &self.handle
+    }
+}
+
+impl<T, TM, R> Service<R> for FaultInject<T, TM>
+where
+    T: Service<R>,
+    TM: Timer,
+{
+    type Response = T::Response;
+    type Error = FaultInjectError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let faults = self.handle.get();
+        if chance(faults.drop_probability) {
+            return pending().await;
+        }
+        if chance(faults.latency_probability) {
+            self.timer.sleep(faults.latency).await;
+        }
+        if chance(faults.error_probability) {
+            return Err(FaultInjectError::Injected);
+        }
+        self.inner.call(req).await.map_err(FaultInjectError::Inner)
+    }
+}
+
+impl<F: MakeService, TM: Clone> MakeService for FaultInject<F, TM> {
+    type Service = FaultInject<F::Service, TM>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(FaultInject {
+            handle: self.handle.clone(),
+            timer: self.timer.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, TM: Clone> AsyncMakeService for FaultInject<F, TM> {
+    type Service = FaultInject<F::Service, TM>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(FaultInject {
+            handle: self.handle.clone(),
+            timer: self.timer.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(FaultInject<_, TM> { handle, timer });
+
+impl<T, TM> Layered for FaultInject<T, TM> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(FaultInject<T, TM>);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin, task::Poll, time::Duration};
+
+    use super::{FaultConfig, FaultHandle, FaultInject, FaultInjectError};
+    use crate::{
+        layer::FactoryLayer,
+        test_util::{block_on, poll_once},
+        time::MockTimer,
+        Param, Service,
+    };
+
+    struct Config(FaultHandle, MockTimer);
+
+    impl Param<FaultHandle> for Config {
+        fn param(&self) -> FaultHandle {
+            self.0.clone()
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    struct Pass;
+
+    impl Service<u32> for Pass {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<u32, Infallible> {
+            Ok(req)
+        }
+    }
+
+    #[test]
+    fn faults_follow_the_handle() {
+        let handle = FaultHandle::default();
+        let timer = MockTimer::new();
+        let svc = FaultInject::<_, MockTimer>::layer()
+            .layer(&Config(handle.clone(), timer.clone()), Pass);
+        assert!(matches!(block_on(svc.call(1)), Ok(1)));
+
+        handle.set(FaultConfig {
+            error_probability: 1.0,
+            ..Default::default()
+        });
+        assert!(matches!(
+            block_on(svc.call(1)),
+            Err(FaultInjectError::Injected)
+        ));
+
+        handle.set(FaultConfig {
+            latency_probability: 1.0,
+            latency: Duration::from_secs(1),
+            ..Default::default()
+        });
+        let mut call = pin!(svc.call(2));
+        assert!(poll_once(call.as_mut()).is_pending());
+        timer.advance(Duration::from_secs(1));
+        assert!(matches!(poll_once(call.as_mut()), Poll::Ready(Ok(2))));
+
+        handle.set(FaultConfig {
+            drop_probability: 1.0,
+            ..Default::default()
+        });
+        let mut call = pin!(svc.call(3));
+        timer.advance(Duration::from_secs(60));
+        assert!(poll_once(call.as_mut()).is_pending());
+
+        handle.disable();
+        assert_eq!(handle.get(), FaultConfig::default());
+        assert!(matches!(block_on(svc.call(4)), Ok(4)));
+    }
+}
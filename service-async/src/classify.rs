@@ -0,0 +1,168 @@
+/// Outcome of a call, as seen by retry, circuit breaking and metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    Success,
+    /// A failure which may go away, e.g. a timeout or an overloaded backend.
+    /// Worth retrying, and a sign of overload.
+    Transient,
+    /// A failure which will happen again, e.g. a rejected request.
+    Permanent,
+}
+
+impl Class {
+    #[inline]
+    pub fn is_success(self) -> bool {
+        self == Class::Success
+    }
+
+    #[inline]
+    pub fn is_failure(self) -> bool {
+        self != Class::Success
+    }
+
+    #[inline]
+    pub fn is_transient(self) -> bool {
+        self == Class::Transient
+    }
+}
+
+/// Classifies the result of a call into a [`Class`].
+///
+/// Middleware which reacts to failures takes a classifier, so they agree on
+/// what counts as one. It is implemented for closures taking the result.
+///
+/// A response may be a failure too, e.g. an HTTP response with a 5xx status:
+///
+/// ```
+/// use service_async::classify::{classify_response, Class, Classify};
+///
+/// struct Response {
+///     status: u16,
+/// }
+///
+/// let classifier = classify_response(|resp: &Response| match resp.status {
+///     500.. => Class::Transient,
+///     400.. => Class::Permanent,
+///     _ => Class::Success,
+/// });
+/// let res: Result<_, ()> = Ok(Response { status: 503 });
+/// assert_eq!(classifier.classify(&res), Class::Transient);
+/// ```
+pub trait Classify<Resp, E> {
+    fn classify(&self, res: &Result<Resp, E>) -> Class;
+}
+
+impl<Resp, E, F: Fn(&Result<Resp, E>) -> Class> Classify<Resp, E> for F {
+    #[inline]
+    fn classify(&self, res: &Result<Resp, E>) -> Class {
+        (self)(res)
+    }
+}
+
+/// The default classifier: every response is a success and every error a
+/// transient failure.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultClassify;
+
+impl<Resp, E> Classify<Resp, E> for DefaultClassify {
+    #[inline]
+    fn classify(&self, res: &Result<Resp, E>) -> Class {
+        match res {
+            Ok(_) => Class::Success,
+            Err(_) => Class::Transient,
+        }
+    }
+}
+
+/// A classifier which inspects the response, and treats errors as transient
+/// failures. Created by [`classify_response`].
+#[derive(Debug, Clone, Copy)]
+pub struct ClassifyResponse<F>(F);
+
+/// Create a classifier which calls `f` on responses.
+#[inline]
+pub const fn classify_response<F>(f: F) -> ClassifyResponse<F> {
+    ClassifyResponse(f)
+}
+
+impl<Resp, E, F: Fn(&Resp) -> Class> Classify<Resp, E> for ClassifyResponse<F> {
+    #[inline]
+    fn classify(&self, res: &Result<Resp, E>) -> Class {
+        match res {
+            Ok(resp) => (self.0)(resp),
+            Err(_) => Class::Transient,
+        }
+    }
+}
+
+/// A classifier which inspects the error, and treats responses as successes.
+/// Created by [`classify_error`].
+#[derive(Debug, Clone, Copy)]
+pub struct ClassifyError<F>(F);
+
+/// Create a classifier which calls `f` on errors.
+#[inline]
+pub const fn classify_error<F>(f: F) -> ClassifyError<F> {
+    ClassifyError(f)
+}
+
+impl<F> ClassifyError<F> {
+    /// Classify an error.
+    #[inline]
+    pub fn classify_err<E>(&self, err: &E) -> Class
+    where
+        F: Fn(&E) -> Class,
+    {
+        (self.0)(err)
+    }
+}
+
+impl<Resp, E, F: Fn(&E) -> Class> Classify<Resp, E> for ClassifyError<F> {
+    #[inline]
+    fn classify(&self, res: &Result<Resp, E>) -> Class {
+        match res {
+            Ok(_) => Class::Success,
+            Err(e) => self.classify_err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify_error, classify_response, Class, Classify, DefaultClassify};
+
+    #[test]
+    fn classifiers() {
+        let ok: Result<u16, &str> = Ok(404);
+        let timeout: Result<u16, &str> = Err("timeout");
+        let rejected: Result<u16, &str> = Err("rejected");
+
+        assert_eq!(DefaultClassify.classify(&ok), Class::Success);
+        assert_eq!(DefaultClassify.classify(&timeout), Class::Transient);
+
+        let by_status = classify_response(|status: &u16| match status {
+            500.. => Class::Transient,
+            400.. => Class::Permanent,
+            _ => Class::Success,
+        });
+        assert_eq!(by_status.classify(&ok), Class::Permanent);
+        assert_eq!(by_status.classify(&timeout), Class::Transient);
+
+        let by_error = classify_error(|e: &&str| match *e {
+            "timeout" => Class::Transient,
+            _ => Class::Permanent,
+        });
+        assert_eq!(by_error.classify(&ok), Class::Success);
+        assert_eq!(by_error.classify(&timeout), Class::Transient);
+        assert_eq!(by_error.classify(&rejected), Class::Permanent);
+        assert_eq!(by_error.classify_err(&"rejected"), Class::Permanent);
+
+        let closure = |res: &Result<u16, &str>| match res {
+            Ok(_) => Class::Success,
+            Err(_) => Class::Permanent,
+        };
+        assert!(closure.classify(&rejected).is_failure());
+        assert!(!Class::Permanent.is_transient());
+        assert!(Class::Success.is_success());
+    }
+}
@@ -0,0 +1,72 @@
+/// Implement the body of a [`Service`](crate::Service) impl with an `async fn call`.
+///
+/// Without the `boxed-futures` feature it expands to the items as written. With the
+/// feature it defines `Future` as a boxed future and wraps the body of `call` into it,
+/// so the same impl compiles on compilers without `impl Trait` in trait methods.
+///
+/// ```rust
+/// use std::convert::Infallible;
+/// use service_async::{impl_service, Service};
+///
+/// struct Echo;
+///
+/// impl Service<String> for Echo {
+///     impl_service! {
+///         type Response = String;
+///         type Error = Infallible;
+///
+///         async fn call(&self, req: String) -> Result<Self::Response, Self::Error> {
+///             Ok(req)
+///         }
+///     }
+/// }
+/// ```
+#[cfg(not(feature = "boxed-futures"))]
+#[macro_export]
+macro_rules! impl_service {
+    (
+        type Response = $resp:ty;
+        type Error = $err:ty;
+        $(#[$meta:meta])*
+        async fn call(&$this:ident, $req:ident: $req_ty:ty) -> $ret:ty $body:block
+    ) => {
+        type Response = $resp;
+        type Error = $err;
+
+        $(#[$meta])*
+        async fn call(&$this, $req: $req_ty) -> $ret $body
+    };
+}
+
+/// Implement the body of a [`Service`](crate::Service) impl with an `async fn call`.
+///
+/// Without the `boxed-futures` feature it expands to the items as written. With the
+/// feature it defines `Future` as a boxed future and wraps the body of `call` into it,
+/// so the same impl compiles on compilers without `impl Trait` in trait methods.
+#[cfg(feature = "boxed-futures")]
+#[macro_export]
+macro_rules! impl_service {
+    (
+        type Response = $resp:ty;
+        type Error = $err:ty;
+        $(#[$meta:meta])*
+        async fn call(&$this:ident, $req:ident: $req_ty:ty) -> $ret:ty $body:block
+    ) => {
+        type Response = $resp;
+        type Error = $err;
+        type Future<'__a> = ::std::pin::Pin<
+            ::std::boxed::Box<dyn ::std::future::Future<Output = $ret> + '__a>,
+        >
+        where
+            Self: '__a,
+            $req_ty: '__a;
+
+        $(#[$meta])*
+        fn call<'__a>(&'__a $this, $req: $req_ty) -> Self::Future<'__a>
+        where
+            $req_ty: '__a,
+        {
+            ::std::boxed::Box::pin(async move $body)
+        }
+    };
+}
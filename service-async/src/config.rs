@@ -0,0 +1,178 @@
+use std::{ops::Deref, sync::Arc};
+
+use param::{Param, ParamMaybeRef, ParamRef};
+
+/// An immutable, versioned snapshot of a stack config, shared through an `Arc`.
+///
+/// A snapshot implements `Param<T>`, `ParamRef<T>` and `ParamMaybeRef<T>` for
+/// everything the config `C` does, so layers read it like the config itself.
+/// All layers of a stack, and all stacks built from the same snapshot, share
+/// one copy of the config.
+///
+/// A snapshot is not `Clone`: that would overlap with the blanket
+/// `impl<T: Clone> Param<T> for T` of the forwarding impls. Use
+/// [`share`](ConfigSnapshot::share) to get another handle.
+#[derive(Debug)]
+pub struct ConfigSnapshot<C> {
+    config: Arc<C>,
+    version: u64,
+}
+
+impl<C> ConfigSnapshot<C> {
+    /// Create a snapshot with version 0.
+    #[inline]
+    pub fn new(config: C) -> Self {
+        Self::from_arc(Arc::new(config))
+    }
+
+    /// Create a snapshot of a shared config, with version 0.
+    #[inline]
+    pub const fn from_arc(config: Arc<C>) -> Self {
+        ConfigSnapshot { config, version: 0 }
+    }
+
+    /// Set the version of the snapshot.
+    #[inline]
+    pub const fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Create the snapshot following this one, for a reload.
+    #[inline]
+    pub fn next(&self, config: C) -> Self {
+        ConfigSnapshot {
+            config: Arc::new(config),
+            version: self.version + 1,
+        }
+    }
+
+    #[inline]
+    pub const fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Get another handle to the same snapshot.
+    #[inline]
+    pub fn share(&self) -> Self {
+        ConfigSnapshot {
+            config: self.config.clone(),
+            version: self.version,
+        }
+    }
+
+    #[inline]
+    pub fn as_arc(&self) -> &Arc<C> {
+        &self.config
+    }
+}
+
+impl<C> Deref for ConfigSnapshot<C> {
+    type Target = C;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.config
+    }
+}
+
+impl<C> From<Arc<C>> for ConfigSnapshot<C> {
+    #[inline]
+    fn from(config: Arc<C>) -> Self {
+        Self::from_arc(config)
+    }
+}
+
+impl<C: Param<T>, T> Param<T> for ConfigSnapshot<C> {
+    #[inline]
+    fn param(&self) -> T {
+        C::param(&self.config)
+    }
+}
+
+impl<C: ParamRef<T>, T> ParamRef<T> for ConfigSnapshot<C> {
+    #[inline]
+    fn param_ref(&self) -> &T {
+        C::param_ref(&self.config)
+    }
+}
+
+impl<C: ParamMaybeRef<T>, T> ParamMaybeRef<T> for ConfigSnapshot<C> {
+    #[inline]
+    fn param_maybe_ref(&self) -> Option<&T> {
+        C::param_maybe_ref(&self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use param::{Param, ParamMaybeRef, ParamRef};
+
+    use super::ConfigSnapshot;
+    use crate::{layer::layer_fn, stack::FactoryStack};
+
+    struct AppConfig {
+        timeout: u32,
+        name: String,
+    }
+
+    impl Param<u32> for AppConfig {
+        fn param(&self) -> u32 {
+            self.timeout
+        }
+    }
+
+    impl ParamRef<String> for AppConfig {
+        fn param_ref(&self) -> &String {
+            &self.name
+        }
+    }
+
+    impl ParamMaybeRef<String> for AppConfig {
+        fn param_maybe_ref(&self) -> Option<&String> {
+            Some(&self.name)
+        }
+    }
+
+    fn config(timeout: u32) -> AppConfig {
+        AppConfig {
+            timeout,
+            name: "edge".to_owned(),
+        }
+    }
+
+    #[test]
+    fn snapshot_forwards_params() {
+        let snapshot = ConfigSnapshot::new(config(30));
+        assert_eq!(Param::<u32>::param(&snapshot), 30);
+        assert_eq!(ParamRef::<String>::param_ref(&snapshot), "edge");
+        assert_eq!(
+            ParamMaybeRef::<String>::param_maybe_ref(&snapshot).unwrap(),
+            "edge"
+        );
+        assert_eq!(snapshot.version(), 0);
+
+        let shared = snapshot.share();
+        assert!(Arc::ptr_eq(snapshot.as_arc(), shared.as_arc()));
+        let next = snapshot.next(config(60));
+        assert_eq!((next.version(), next.timeout), (1, 60));
+        assert_eq!(snapshot.with_version(7).version(), 7);
+    }
+
+    #[test]
+    fn stack_layers_share_the_config() {
+        let stack = FactoryStack::new_shared(config(30))
+            .push(layer_fn(|c: &ConfigSnapshot<AppConfig>, ()| c.share()))
+            .push(layer_fn(|c: &ConfigSnapshot<AppConfig>, inner| {
+                (c.share(), inner)
+            }));
+        let (outer, inner) = stack.into_inner();
+        assert!(Arc::ptr_eq(outer.as_arc(), inner.as_arc()));
+
+        let config = Arc::new(config(30));
+        let stack = FactoryStack::new_arc(config.clone());
+        assert!(Arc::ptr_eq(stack.config().as_arc(), &config));
+    }
+}
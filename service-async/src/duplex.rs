@@ -0,0 +1,384 @@
+use std::{
+    any::{Any, TypeId},
+    future::Future,
+    marker::PhantomData,
+};
+
+use crate::{
+    boxed::SmallFuture,
+    layer::{impl_wrap_inner, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Service, ServiceMetadata,
+};
+
+/// A connection handler: a call takes over the connection `IO`, reads requests
+/// from it and writes responses to it until the connection is done.
+///
+/// This is the shape of proxies and other connection-oriented protocols, which
+/// have no single response to return. `IO` may be a stream, or a pair of input
+/// and output halves.
+pub trait DuplexService<IO> {
+    type Error;
+
+    fn handle(&self, io: IO) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+impl<D: DuplexService<IO> + ?Sized, IO> DuplexService<IO> for &D {
+    type Error = D::Error;
+
+    #[inline]
+    fn handle(&self, io: IO) -> impl Future<Output = Result<(), Self::Error>> {
+        (**self).handle(io)
+    }
+}
+
+impl<D: DuplexService<IO> + ?Sized, IO> DuplexService<IO> for std::sync::Arc<D> {
+    type Error = D::Error;
+
+    #[inline]
+    fn handle(&self, io: IO) -> impl Future<Output = Result<(), Self::Error>> {
+        (**self).handle(io)
+    }
+}
+
+/// Use a `Service<IO>` as a [`DuplexService`], discarding its response.
+///
+/// It is its own factory, so it can wrap a factory in a stack.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServiceDuplex<S> {
+    pub inner: S,
+}
+
+impl<S> ServiceDuplex<S> {
+    #[inline]
+    pub const fn new(inner: S) -> Self {
+        ServiceDuplex { inner }
+    }
+}
+
+impl<S: Service<IO>, IO> DuplexService<IO> for ServiceDuplex<S> {
+    type Error = S::Error;
+
+    async fn handle(&self, io: IO) -> Result<(), Self::Error> {
+        self.inner.call(io).await.map(|_| ())
+    }
+}
+
+/// Use a [`DuplexService`] as a `Service<IO>` with a unit response, e.g. to
+/// put middleware around it or to serve it.
+///
+/// It is its own factory, so it can wrap a factory in a stack.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DuplexAsService<D> {
+    pub inner: D,
+}
+
+impl<D> DuplexAsService<D> {
+    #[inline]
+    pub const fn new(inner: D) -> Self {
+        DuplexAsService { inner }
+    }
+}
+
+impl<D: DuplexService<IO>, IO> Service<IO> for DuplexAsService<D> {
+    type Response = ();
+    type Error = D::Error;
+
+    #[inline]
+    fn call(&self, io: IO) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.inner.handle(io)
+    }
+}
+
+macro_rules! impl_wrapper_factory {
+    ($ty:ident) => {
+        impl<F: MakeService> MakeService for $ty<F> {
+            type Service = $ty<F::Service>;
+            type Error = F::Error;
+
+            fn make_via_ref(
+                &self,
+                old: Option<&Self::Service>,
+            ) -> Result<Self::Service, Self::Error> {
+                Ok($ty {
+                    inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+                })
+            }
+
+            fn validate(&self) -> Result<(), Self::Error> {
+                self.inner.validate()
+            }
+
+            fn service_metadata(&self) -> ServiceMetadata {
+                ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+            }
+        }
+
+        impl<F: AsyncMakeService> AsyncMakeService for $ty<F> {
+            type Service = $ty<F::Service>;
+            type Error = F::Error;
+
+            async fn make_via_ref(
+                &self,
+                old: Option<&Self::Service>,
+            ) -> Result<Self::Service, Self::Error> {
+                Ok($ty {
+                    inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+                })
+            }
+
+            fn validate(&self) -> Result<(), Self::Error> {
+                self.inner.validate()
+            }
+
+            fn service_metadata(&self) -> ServiceMetadata {
+                ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+            }
+        }
+
+        impl_wrap_inner!($ty<_> {});
+
+        impl<T> Layered for $ty<T> {
+            type Inner = T;
+
+            #[inline]
+            fn inner(&self) -> &Self::Inner {
+                &self.inner
+            }
+        }
+
+        impl_shutdown_inner!($ty<T>);
+    };
+}
+
+impl_wrapper_factory!(ServiceDuplex);
+impl_wrapper_factory!(DuplexAsService);
+
+/// A type-erased [`DuplexService`].
+///
+/// Like [`BoxedService`](crate::BoxedService), small handler futures are stored
+/// inline instead of being boxed.
+pub struct BoxedDuplexService<IO, E> {
+    svc: *const (),
+    type_id: TypeId,
+    handle: unsafe fn(raw: *const (), io: IO) -> SmallFuture<'static, (), E>,
+    drop: unsafe fn(raw: *const ()),
+}
+
+impl<IO, E> BoxedDuplexService<IO, E> {
+    pub fn new<D>(d: D) -> Self
+    where
+        D: DuplexService<IO, Error = E> + 'static,
+        IO: 'static,
+    {
+        let svc = Box::into_raw(Box::new(d)) as *const ();
+        BoxedDuplexService {
+            svc,
+            type_id: TypeId::of::<D>(),
+            handle: handle::<IO, D>,
+            drop: drop::<D>,
+        }
+    }
+
+    pub fn downcast_ref<D: Any>(&self) -> Option<&D> {
+        if self.type_id == TypeId::of::<D>() {
+            Some(unsafe { &*(self.svc as *const D) })
+        } else {
+            None
+        }
+    }
+}
+
+impl<IO, E> Drop for BoxedDuplexService<IO, E> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.svc) };
+    }
+}
+
+impl<IO, E> DuplexService<IO> for BoxedDuplexService<IO, E> {
+    type Error = E;
+
+    #[inline]
+    fn handle(&self, io: IO) -> impl Future<Output = Result<(), Self::Error>> {
+        unsafe { (self.handle)(self.svc, io) }
+    }
+}
+
+unsafe fn handle<IO, D>(svc: *const (), io: IO) -> SmallFuture<'static, (), D::Error>
+where
+    IO: 'static,
+    D: DuplexService<IO> + 'static,
+{
+    let svc = &*svc.cast::<D>();
+    SmallFuture::new(svc.handle(io))
+}
+
+unsafe fn drop<D>(raw: *const ()) {
+    std::mem::drop(Box::from_raw(raw as *mut D));
+}
+
+/// A factory which makes [`BoxedDuplexService`]s from a factory of handlers.
+///
+/// On reload, the old boxed handler is downcast to the inner handler type to
+/// migrate its state.
+pub struct BoxDuplexFactory<F, IO> {
+    pub inner: F,
+    _marker: PhantomData<fn(IO)>,
+}
+
+impl_wrap_inner!(BoxDuplexFactory<_, IO> { _marker });
+
+impl<F, IO> BoxDuplexFactory<F, IO> {
+    #[inline]
+    pub const fn new(inner: F) -> Self {
+        BoxDuplexFactory {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<F, IO> MakeService for BoxDuplexFactory<F, IO>
+where
+    F: MakeService,
+    F::Service: DuplexService<IO> + 'static,
+    IO: 'static,
+{
+    type Service = BoxedDuplexService<IO, <F::Service as DuplexService<IO>>::Error>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let old = old.and_then(|o| o.downcast_ref());
+        Ok(BoxedDuplexService::new(self.inner.make_via_ref(old)?))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+impl<F, IO> AsyncMakeService for BoxDuplexFactory<F, IO>
+where
+    F: AsyncMakeService,
+    F::Service: DuplexService<IO> + 'static,
+    IO: 'static,
+{
+    type Service = BoxedDuplexService<IO, <F::Service as DuplexService<IO>>::Error>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let old = old.and_then(|o| o.downcast_ref());
+        Ok(BoxedDuplexService::new(self.inner.make_via_ref(old).await?))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, convert::Infallible, rc::Rc};
+
+    use super::{BoxDuplexFactory, DuplexAsService, DuplexService, ServiceDuplex};
+    use crate::{
+        test_util::block_on, utils::CloneFactory, yielding::yield_now, MakeService, Service,
+    };
+
+    /// A connection: requests to read and the responses written.
+    struct Conn {
+        input: Vec<u32>,
+        output: Rc<RefCell<Vec<u32>>>,
+    }
+
+    fn conn(input: &[u32]) -> (Conn, Rc<RefCell<Vec<u32>>>) {
+        let output = Rc::default();
+        let conn = Conn {
+            input: input.to_vec(),
+            output: Rc::clone(&output),
+        };
+        (conn, output)
+    }
+
+    /// Answers each request with it times `factor`, plus the number of
+    /// handlers it was made against.
+    #[derive(Clone)]
+    struct Scale {
+        factor: u32,
+        generation: u32,
+    }
+
+    impl DuplexService<Conn> for Scale {
+        type Error = Infallible;
+
+        async fn handle(&self, io: Conn) -> Result<(), Infallible> {
+            for req in io.input {
+                yield_now().await;
+                io.output
+                    .borrow_mut()
+                    .push(req * self.factor + self.generation);
+            }
+            Ok(())
+        }
+    }
+
+    impl MakeService for Scale {
+        type Service = Scale;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, old: Option<&Scale>) -> Result<Scale, Infallible> {
+            Ok(Scale {
+                factor: self.factor,
+                generation: old.map_or(0, |o| o.generation + 1),
+            })
+        }
+    }
+
+    #[test]
+    fn service_adapters() {
+        let svc = DuplexAsService::new(Scale {
+            factor: 2,
+            generation: 0,
+        });
+        let (io, output) = conn(&[1, 2]);
+        assert_eq!(block_on(svc.call(io)), Ok(()));
+        assert_eq!(*output.borrow(), [2, 4]);
+
+        let duplex = ServiceDuplex::new(svc);
+        let (io, output) = conn(&[3]);
+        assert_eq!(block_on(duplex.handle(io)), Ok(()));
+        assert_eq!(*output.borrow(), [6]);
+    }
+
+    #[test]
+    fn boxed_duplex_service() {
+        let factory = BoxDuplexFactory::new(Scale {
+            factor: 10,
+            generation: 0,
+        });
+        let svc = factory.make().unwrap();
+        let (io, output) = conn(&[1, 2]);
+        assert_eq!(block_on(svc.handle(io)), Ok(()));
+        assert_eq!(*output.borrow(), [10, 20]);
+
+        // The old handler is downcast to migrate its state.
+        let svc = factory.make_via_ref(Some(&svc)).unwrap();
+        assert_eq!(svc.downcast_ref::<Scale>().unwrap().generation, 1);
+        assert!(svc.downcast_ref::<CloneFactory<Scale>>().is_none());
+        let (io, output) = conn(&[1]);
+        assert_eq!(block_on(svc.handle(io)), Ok(()));
+        assert_eq!(*output.borrow(), [11]);
+    }
+}
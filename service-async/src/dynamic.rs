@@ -0,0 +1,257 @@
+use std::{
+    any::{type_name, Any},
+    error::Error,
+    fmt::{self, Display},
+    future::Future,
+    marker::PhantomData,
+};
+
+use crate::{either::BoxError, BoxedService, Service};
+
+/// A type-erased request or response of a [`DynAnyService`].
+pub type AnyBox = Box<dyn Any>;
+
+/// Error returned by [`DynAnyService`] and [`TypedService`].
+#[derive(Debug)]
+pub enum DynAnyError {
+    /// The request is not of the type the service takes. It is handed back.
+    Request {
+        expected: &'static str,
+        request: AnyBox,
+    },
+    /// The response is not of the type the caller expects.
+    Response { expected: &'static str },
+    /// The service failed.
+    Inner(BoxError),
+}
+
+impl Display for DynAnyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DynAnyError::Request { expected, .. } => write!(f, "request is not a {expected}"),
+            DynAnyError::Response { expected } => write!(f, "response is not a {expected}"),
+            DynAnyError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for DynAnyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DynAnyError::Inner(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A service with both the request and the response erased to `Box<dyn Any>`.
+///
+/// It lets a pipeline be assembled at runtime, e.g. from plugins picked by
+/// configuration, when the request types are not known at compile time. The
+/// request is checked against the type the service takes on every call.
+/// [`typed`](DynAnyService::typed) gives back a statically typed facade.
+///
+/// ```rust
+/// use std::convert::Infallible;
+/// use service_async::{dynamic::DynAnyService, Service};
+///
+/// struct Double;
+///
+/// impl Service<u32> for Double {
+///     type Response = u32;
+///     type Error = Infallible;
+///
+///     async fn call(&self, req: u32) -> Result<u32, Infallible> {
+///         Ok(req * 2)
+///     }
+/// }
+///
+/// # async fn run() {
+/// let svc = DynAnyService::new::<u32, _>(Double);
+/// let resp = svc.call(Box::new(21u32)).await.unwrap();
+/// assert_eq!(resp.downcast_ref::<u32>(), Some(&42));
+/// assert!(svc.call(Box::new("21")).await.is_err());
+///
+/// let typed = svc.typed::<u32, u32>();
+/// assert_eq!(typed.call(1).await.unwrap(), 2);
+/// # }
+/// ```
+pub struct DynAnyService {
+    inner: BoxedService<AnyBox, AnyBox, DynAnyError>,
+    service: &'static str,
+    request: &'static str,
+    response: &'static str,
+}
+
+impl DynAnyService {
+    /// Erase a `Service<Req>`.
+    pub fn new<Req, S>(svc: S) -> Self
+    where
+        S: Service<Req> + 'static,
+        S::Response: 'static,
+        S::Error: Into<BoxError>,
+        Req: 'static,
+    {
+        DynAnyService {
+            inner: BoxedService::new(Erased {
+                inner: svc,
+                _marker: PhantomData::<fn(Req)>,
+            }),
+            service: type_name::<S>(),
+            request: type_name::<Req>(),
+            response: type_name::<S::Response>(),
+        }
+    }
+
+    /// Type name of the request the service takes.
+    #[inline]
+    pub fn request_type_name(&self) -> &'static str {
+        self.request
+    }
+
+    /// Type name of the response the service returns.
+    #[inline]
+    pub fn response_type_name(&self) -> &'static str {
+        self.response
+    }
+
+    /// Use the service with typed requests and responses again. Type
+    /// mismatches are reported by [`DynAnyError`] on call.
+    #[inline]
+    pub fn typed<Req, Resp>(self) -> TypedService<Req, Resp> {
+        TypedService {
+            inner: self,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for DynAnyService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynAnyService")
+            .field("service", &self.service)
+            .field("request", &self.request)
+            .field("response", &self.response)
+            .finish()
+    }
+}
+
+impl Service<AnyBox> for DynAnyService {
+    type Response = AnyBox;
+    type Error = DynAnyError;
+
+    #[inline]
+    fn call(&self, req: AnyBox) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.inner.call(req)
+    }
+}
+
+struct Erased<S, Req> {
+    inner: S,
+    _marker: PhantomData<fn(Req)>,
+}
+
+impl<S, Req> Service<AnyBox> for Erased<S, Req>
+where
+    S: Service<Req>,
+    S::Response: 'static,
+    S::Error: Into<BoxError>,
+    Req: 'static,
+{
+    type Response = AnyBox;
+    type Error = DynAnyError;
+
+    async fn call(&self, req: AnyBox) -> Result<Self::Response, Self::Error> {
+        let req = req.downcast::<Req>().map_err(|request| DynAnyError::Request {
+            expected: type_name::<Req>(),
+            request,
+        })?;
+        match self.inner.call(*req).await {
+            Ok(resp) => Ok(Box::new(resp)),
+            Err(e) => Err(DynAnyError::Inner(e.into())),
+        }
+    }
+}
+
+/// A typed facade over a [`DynAnyService`], created by
+/// [`DynAnyService::typed`].
+pub struct TypedService<Req, Resp> {
+    inner: DynAnyService,
+    _marker: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<Req, Resp> TypedService<Req, Resp> {
+    #[inline]
+    pub fn into_inner(self) -> DynAnyService {
+        self.inner
+    }
+}
+
+impl<Req, Resp> fmt::Debug for TypedService<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TypedService").field(&self.inner).finish()
+    }
+}
+
+impl<Req: 'static, Resp: 'static> Service<Req> for TypedService<Req, Resp> {
+    type Response = Resp;
+    type Error = DynAnyError;
+
+    async fn call(&self, req: Req) -> Result<Self::Response, Self::Error> {
+        let resp = self.inner.call(Box::new(req)).await?;
+        resp.downcast::<Resp>()
+            .map(|resp| *resp)
+            .map_err(|_| DynAnyError::Response {
+                expected: type_name::<Resp>(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::ParseIntError;
+
+    use super::{DynAnyError, DynAnyService};
+    use crate::{test_util::block_on, Service};
+
+    struct Parse;
+
+    impl Service<String> for Parse {
+        type Response = u32;
+        type Error = ParseIntError;
+
+        async fn call(&self, req: String) -> Result<u32, ParseIntError> {
+            req.parse()
+        }
+    }
+
+    #[test]
+    fn checks_types_on_call() {
+        let svc = DynAnyService::new::<String, _>(Parse);
+        assert_eq!(svc.request_type_name(), "alloc::string::String");
+        assert_eq!(svc.response_type_name(), "u32");
+
+        let resp = block_on(svc.call(Box::new("7".to_string()))).unwrap();
+        assert_eq!(resp.downcast_ref::<u32>(), Some(&7));
+        match block_on(svc.call(Box::new(7u32))) {
+            Err(DynAnyError::Request { request, .. }) => {
+                assert_eq!(request.downcast_ref::<u32>(), Some(&7))
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(matches!(
+            block_on(svc.call(Box::new("x".to_string()))),
+            Err(DynAnyError::Inner(_))
+        ));
+    }
+
+    #[test]
+    fn typed_facade() {
+        let typed = DynAnyService::new::<String, _>(Parse).typed::<String, u32>();
+        assert_eq!(block_on(typed.call("42".into())).unwrap(), 42);
+
+        let wrong = typed.into_inner().typed::<String, u64>();
+        let err = block_on(wrong.call("42".into())).unwrap_err();
+        assert_eq!(err.to_string(), "response is not a u64");
+    }
+}
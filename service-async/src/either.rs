@@ -1,6 +1,13 @@
-use std::{error::Error, fmt::Display, future::Future, pin::Pin};
+use std::{
+    convert::Infallible, error::Error, fmt::Display, future::Future, marker::PhantomData, pin::Pin,
+};
 
-use crate::{layer::FactoryLayer, AsyncMakeService, MakeService, Service};
+#[cfg(not(feature = "boxed-futures"))]
+use crate::AsyncMakeService;
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    MakeService, Service, ServiceMetadata,
+};
 
 /// An Enum representing a value of one of two possible types.
 ///
@@ -33,7 +40,10 @@ use crate::{layer::FactoryLayer, AsyncMakeService, MakeService, Service};
 /// This pattern enables runtime control over the service composition, making it possible to
 /// dynamically include or exclude certain layers based on configuration or runtime conditions.
 ///
+/// With the `serde` feature, `Either` is serialized externally tagged, e.g.
+/// `{"Left": 1}`.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Either<A, B> {
     Left(A),
     Right(B),
@@ -94,8 +104,23 @@ where
             .map_err(Either::Right),
         }
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        match self {
+            Either::Left(f) => f.validate().map_err(Either::Left),
+            Either::Right(f) => f.validate().map_err(Either::Right),
+        }
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        match self {
+            Either::Left(f) => f.service_metadata(),
+            Either::Right(f) => f.service_metadata(),
+        }
+    }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<A, B> AsyncMakeService for Either<A, B>
 where
     A: AsyncMakeService,
@@ -123,8 +148,121 @@ where
             .map_err(Either::Right),
         }
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        match self {
+            Either::Left(f) => f.validate().map_err(Either::Left),
+            Either::Right(f) => f.validate().map_err(Either::Right),
+        }
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        match self {
+            Either::Left(f) => f.service_metadata(),
+            Either::Right(f) => f.service_metadata(),
+        }
+    }
+}
+
+/// A factory which makes the service of one of two factories like [`Either`],
+/// but converts the errors of both into `E`.
+///
+/// The make error of an `Either` factory is `Either<A::Error, B::Error>`, so
+/// switching an optional layer on or off changes the error type of the stack,
+/// and with it the type of a [`BoxedMakeService`](crate::BoxedMakeService) the
+/// stack is boxed into. With `EitherUnified` the error stays `E` either way.
+/// Push an optional layer with
+/// [`FactoryStack::push_unified`](crate::stack::FactoryStack::push_unified),
+/// or convert an `Either` factory with [`Either::unified`].
+pub struct EitherUnified<A, B, E> {
+    pub inner: Either<A, B>,
+    _marker: PhantomData<fn() -> E>,
+}
+
+impl<A, B, E> EitherUnified<A, B, E> {
+    #[inline]
+    pub const fn new(inner: Either<A, B>) -> Self {
+        EitherUnified {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> Either<A, B> {
+        self.inner
+    }
+}
+
+impl<A, B> Either<A, B> {
+    /// Convert the factory into one whose make errors are converted into `E`.
+    #[inline]
+    pub fn unified<E>(self) -> EitherUnified<A, B, E> {
+        EitherUnified::new(self)
+    }
 }
 
+impl<A: Clone, B: Clone, E> Clone for EitherUnified<A, B, E> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone())
+    }
+}
+
+impl<A, B, E> MakeService for EitherUnified<A, B, E>
+where
+    A: MakeService,
+    B: MakeService,
+    A::Error: Into<E>,
+    B::Error: Into<E>,
+{
+    type Service = Either<A::Service, B::Service>;
+    type Error = E;
+
+    #[inline]
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.inner.make_via_ref(old).map_err(Either::unify)
+    }
+
+    #[inline]
+    fn validate(&self) -> Result<(), Self::Error> {
+        MakeService::validate(&self.inner).map_err(Either::unify)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        MakeService::service_metadata(&self.inner)
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<A, B, E> AsyncMakeService for EitherUnified<A, B, E>
+where
+    A: AsyncMakeService,
+    B: AsyncMakeService,
+    A::Error: Into<E>,
+    B::Error: Into<E>,
+{
+    type Service = Either<A::Service, B::Service>;
+    type Error = E;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.inner.make_via_ref(old).await.map_err(Either::unify)
+    }
+
+    #[inline]
+    fn validate(&self) -> Result<(), Self::Error> {
+        AsyncMakeService::validate(&self.inner).map_err(Either::unify)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        AsyncMakeService::service_metadata(&self.inner)
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
 impl<A, B, R> Service<R> for Either<A, B>
 where
     A: Service<R>,
@@ -142,6 +280,32 @@ where
     }
 }
 
+#[cfg(feature = "boxed-futures")]
+impl<A, B, R> Service<R> for Either<A, B>
+where
+    A: Service<R>,
+    B: Service<R, Response = A::Response, Error = A::Error>,
+{
+    type Response = A::Response;
+    type Error = A::Error;
+    type Future<'a>
+        = Either<A::Future<'a>, B::Future<'a>>
+    where
+        Self: 'a,
+        R: 'a;
+
+    #[inline]
+    fn call<'a>(&'a self, req: R) -> Self::Future<'a>
+    where
+        R: 'a,
+    {
+        match self {
+            Either::Left(s) => Either::Left(s.call(req)),
+            Either::Right(s) => Either::Right(s.call(req)),
+        }
+    }
+}
+
 impl<A, B> Future for Either<A, B>
 where
     A: Future,
@@ -223,3 +387,575 @@ impl<T> Either<T, T> {
         }
     }
 }
+
+impl<T> Either<T, T> {
+    /// Map the value, whichever side it is on.
+    #[inline]
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Either<U, U> {
+        match self {
+            Either::Left(t) => Either::Left(f(t)),
+            Either::Right(t) => Either::Right(f(t)),
+        }
+    }
+}
+
+impl<A, B> From<Result<A, B>> for Either<A, B> {
+    /// Convert `Ok` to `Left` and `Err` to `Right`.
+    #[inline]
+    fn from(r: Result<A, B>) -> Self {
+        match r {
+            Ok(a) => Either::Left(a),
+            Err(b) => Either::Right(b),
+        }
+    }
+}
+
+impl<A, B> From<Either<A, B>> for Result<A, B> {
+    /// Convert `Left` to `Ok` and `Right` to `Err`.
+    #[inline]
+    fn from(e: Either<A, B>) -> Self {
+        match e {
+            Either::Left(a) => Ok(a),
+            Either::Right(b) => Err(b),
+        }
+    }
+}
+
+impl<A, B> Either<A, B> {
+    #[inline]
+    pub fn is_left(&self) -> bool {
+        matches!(self, Either::Left(_))
+    }
+
+    #[inline]
+    pub fn is_right(&self) -> bool {
+        matches!(self, Either::Right(_))
+    }
+
+    /// Get the left value, if any.
+    #[inline]
+    pub fn left(self) -> Option<A> {
+        match self {
+            Either::Left(a) => Some(a),
+            Either::Right(_) => None,
+        }
+    }
+
+    /// Get the right value, if any.
+    #[inline]
+    pub fn right(self) -> Option<B> {
+        match self {
+            Either::Left(_) => None,
+            Either::Right(b) => Some(b),
+        }
+    }
+
+    /// Convert `&Either<A, B>` to `Either<&A, &B>`.
+    #[inline]
+    pub fn as_ref(&self) -> Either<&A, &B> {
+        match self {
+            Either::Left(a) => Either::Left(a),
+            Either::Right(b) => Either::Right(b),
+        }
+    }
+
+    /// Convert `&mut Either<A, B>` to `Either<&mut A, &mut B>`.
+    #[inline]
+    pub fn as_mut(&mut self) -> Either<&mut A, &mut B> {
+        match self {
+            Either::Left(a) => Either::Left(a),
+            Either::Right(b) => Either::Right(b),
+        }
+    }
+
+    /// Map the left value, keeping the right one.
+    #[inline]
+    pub fn map_left<T>(self, f: impl FnOnce(A) -> T) -> Either<T, B> {
+        match self {
+            Either::Left(a) => Either::Left(f(a)),
+            Either::Right(b) => Either::Right(b),
+        }
+    }
+
+    /// Map the right value, keeping the left one.
+    #[inline]
+    pub fn map_right<T>(self, f: impl FnOnce(B) -> T) -> Either<A, T> {
+        match self {
+            Either::Left(a) => Either::Left(a),
+            Either::Right(b) => Either::Right(f(b)),
+        }
+    }
+
+    /// Convert both sides into a common type, e.g. the responses of two
+    /// branches into the response type of the application.
+    #[inline]
+    pub fn unify<T>(self) -> T
+    where
+        A: Into<T>,
+        B: Into<T>,
+    {
+        match self {
+            Either::Left(a) => a.into(),
+            Either::Right(b) => b.into(),
+        }
+    }
+}
+
+/// A type-erased error.
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Helpers to inspect errors built from nested `Either`s.
+///
+/// Optional layers make error types like `Either<Either<A, B>, C>`. This trait
+/// unwraps the nesting to the concrete error, so it can be downcast without
+/// matching every level.
+///
+/// It is implemented for `Either`, some std errors and the errors of this crate.
+/// Use [`impl_flatten_error!`](crate::impl_flatten_error) for other error types.
+pub trait EitherExt {
+    /// Unwrap nested `Either`s and box the concrete error.
+    fn flatten_error(self) -> BoxError;
+
+    /// Unwrap nested `Either`s and borrow the concrete error.
+    fn as_dyn_error(&self) -> &(dyn Error + Send + Sync + 'static);
+}
+
+impl<A: EitherExt, B: EitherExt> EitherExt for Either<A, B> {
+    #[inline]
+    fn flatten_error(self) -> BoxError {
+        match self {
+            Either::Left(inner) => inner.flatten_error(),
+            Either::Right(inner) => inner.flatten_error(),
+        }
+    }
+
+    #[inline]
+    fn as_dyn_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        match self {
+            Either::Left(inner) => inner.as_dyn_error(),
+            Either::Right(inner) => inner.as_dyn_error(),
+        }
+    }
+}
+
+/// Implement [`EitherExt`](crate::either::EitherExt) for error types which
+/// are not `Either`, so they can be the leaves of nested `Either` errors.
+#[macro_export]
+macro_rules! impl_flatten_error {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl $crate::either::EitherExt for $ty {
+                #[inline]
+                fn flatten_error(self) -> $crate::either::BoxError {
+                    ::std::boxed::Box::new(self)
+                }
+
+                #[inline]
+                fn as_dyn_error(
+                    &self,
+                ) -> &(
+                    dyn ::std::error::Error
+                        + ::std::marker::Send
+                        + ::std::marker::Sync
+                        + 'static
+                ) {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+impl_flatten_error!(std::io::Error, std::fmt::Error);
+
+impl EitherExt for BoxError {
+    #[inline]
+    fn flatten_error(self) -> BoxError {
+        self
+    }
+
+    #[inline]
+    fn as_dyn_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        &**self
+    }
+}
+
+impl EitherExt for Infallible {
+    #[inline]
+    fn flatten_error(self) -> BoxError {
+        match self {}
+    }
+
+    #[inline]
+    fn as_dyn_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        match *self {}
+    }
+}
+
+/// A service and factory which converts errors made of nested `Either`s into [`BoxError`].
+///
+/// Push it at the boundary of a stack whose error type depends on which optional
+/// layers are enabled, so the outer part of the stack sees a single error type.
+pub struct FlattenErr<T> {
+    inner: T,
+}
+
+impl<T> FlattenErr<T> {
+    #[inline]
+    pub const fn new(inner: T) -> Self {
+        FlattenErr { inner }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = Self> {
+        layer_fn(|_: &C, inner| FlattenErr { inner })
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<T, R> Service<R> for FlattenErr<T>
+where
+    T: Service<R>,
+    T::Error: EitherExt,
+{
+    type Response = T::Response;
+    type Error = BoxError;
+
+    #[inline]
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        self.inner.call(req).await.map_err(EitherExt::flatten_error)
+    }
+}
+
+#[cfg(feature = "boxed-futures")]
+impl<T, R> Service<R> for FlattenErr<T>
+where
+    T: Service<R>,
+    T::Error: EitherExt,
+{
+    crate::impl_service! {
+        type Response = T::Response;
+        type Error = BoxError;
+
+        async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+            self.inner.call(req).await.map_err(EitherExt::flatten_error)
+        }
+    }
+}
+
+impl<F: MakeService> MakeService for FlattenErr<F> {
+    type Service = FlattenErr<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(FlattenErr {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F: AsyncMakeService> AsyncMakeService for FlattenErr<F> {
+    type Service = FlattenErr<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(FlattenErr {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(FlattenErr<_> {});
+
+impl<T> Layered for FlattenErr<T> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+/// A service and factory over an `Either` of services which respond with
+/// different types, e.g. the branches of a split or a router.
+///
+/// `Either` itself only serves when both sides have the same response and error
+/// types. `RespondEither` responds with `Either<A::Response, B::Response>` and
+/// fails with `Either<A::Error, B::Error>` instead, which the caller converges
+/// with [`Either::unify`] or [`Either::map_left`] and [`Either::map_right`],
+/// without an enum written for every pair of branches.
+pub struct RespondEither<A, B> {
+    inner: Either<A, B>,
+}
+
+impl<A, B> RespondEither<A, B> {
+    #[inline]
+    pub const fn new(inner: Either<A, B>) -> Self {
+        RespondEither { inner }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> Either<A, B> {
+        self.inner
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &Either<A, B> {
+        &self.inner
+    }
+
+    /// Wrap the `Either` factory of the stack, e.g. the one made by an
+    /// optional layer.
+    pub fn layer<C>() -> impl FactoryLayer<C, Either<A, B>, Factory = Self> {
+        layer_fn(|_: &C, inner| RespondEither { inner })
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<A, B, R> Service<R> for RespondEither<A, B>
+where
+    A: Service<R>,
+    B: Service<R>,
+{
+    type Response = Either<A::Response, B::Response>;
+    type Error = Either<A::Error, B::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        match &self.inner {
+            Either::Left(s) => s.call(req).await.map(Either::Left).map_err(Either::Left),
+            Either::Right(s) => s.call(req).await.map(Either::Right).map_err(Either::Right),
+        }
+    }
+}
+
+#[cfg(feature = "boxed-futures")]
+impl<A, B, R> Service<R> for RespondEither<A, B>
+where
+    A: Service<R>,
+    B: Service<R>,
+{
+    crate::impl_service! {
+        type Response = Either<A::Response, B::Response>;
+        type Error = Either<A::Error, B::Error>;
+
+        async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+            match &self.inner {
+                Either::Left(s) => s.call(req).await.map(Either::Left).map_err(Either::Left),
+                Either::Right(s) => s.call(req).await.map(Either::Right).map_err(Either::Right),
+            }
+        }
+    }
+}
+
+impl<FA: MakeService, FB: MakeService> MakeService for RespondEither<FA, FB> {
+    type Service = RespondEither<FA::Service, FB::Service>;
+    type Error = Either<FA::Error, FB::Error>;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(RespondEither {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<FA: AsyncMakeService, FB: AsyncMakeService> AsyncMakeService for RespondEither<FA, FB> {
+    type Service = RespondEither<FA::Service, FB::Service>;
+    type Error = Either<FA::Error, FB::Error>;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(RespondEither {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        AsyncMakeService::validate(&self.inner)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_inner(AsyncMakeService::service_metadata(&self.inner))
+    }
+}
+
+#[cfg(all(test, not(feature = "boxed-futures")))]
+mod tests {
+    use std::{convert::Infallible, fmt, io};
+
+    use super::{BoxError, Either, EitherExt, FlattenErr, RespondEither};
+    use crate::{
+        layer::layer_fn, make_service::AsyncMakeServiceWrapper, stack::FactoryStack,
+        test_util::block_on, utils::CloneFactory, AsyncMakeService, MakeService, Service,
+    };
+
+    #[derive(Debug, Clone)]
+    struct Len;
+
+    impl Service<&'static str> for Len {
+        type Response = usize;
+        type Error = Infallible;
+
+        async fn call(&self, req: &'static str) -> Result<usize, Infallible> {
+            Ok(req.len())
+        }
+    }
+
+    #[derive(Clone)]
+    struct Upper;
+
+    impl Service<&'static str> for Upper {
+        type Response = String;
+        type Error = Infallible;
+
+        async fn call(&self, req: &'static str) -> Result<String, Infallible> {
+            Ok(req.to_uppercase())
+        }
+    }
+
+    #[test]
+    fn respond_either() {
+        let left = RespondEither::new(Either::<_, Upper>::Left(Len));
+        let right = RespondEither::new(Either::<Len, _>::Right(Upper));
+        assert!(matches!(block_on(left.call("abc")), Ok(Either::Left(3))));
+        assert!(matches!(block_on(right.call("abc")), Ok(Either::Right(s)) if s == "ABC"));
+    }
+
+    #[test]
+    fn respond_either_metadata() {
+        let factory = RespondEither::new(Either::<_, CloneFactory<Upper>>::Left(
+            CloneFactory::new(Len),
+        ));
+        let sync = MakeService::service_metadata(&factory);
+        assert_eq!(sync.name, "RespondEither");
+        assert_eq!(sync.inner.len(), 1);
+
+        let factory = RespondEither::new(
+            Either::<_, AsyncMakeServiceWrapper<CloneFactory<Upper>>>::Left(
+                AsyncMakeServiceWrapper(CloneFactory::new(Len)),
+            ),
+        );
+        assert_eq!(AsyncMakeService::service_metadata(&factory), sync);
+    }
+
+    type Nested = Either<Either<io::Error, fmt::Error>, Infallible>;
+
+    struct Failing;
+
+    impl Service<&'static str> for Failing {
+        type Response = ();
+        type Error = Nested;
+
+        async fn call(&self, _req: &'static str) -> Result<(), Nested> {
+            Err(Either::Left(Either::Right(fmt::Error)))
+        }
+    }
+
+    #[test]
+    fn flatten_error() {
+        let err: Nested = Either::Left(Either::Left(io::Error::other("down")));
+        assert_eq!(err.as_dyn_error().to_string(), "down");
+        let boxed = err.flatten_error();
+        assert_eq!(
+            boxed.downcast::<io::Error>().unwrap().kind(),
+            io::ErrorKind::Other
+        );
+
+        let err = block_on(FlattenErr::new(Failing).call("abc")).unwrap_err();
+        assert!(err.is::<fmt::Error>());
+    }
+
+    /// Fails to make [`Len`].
+    #[derive(Clone)]
+    struct Refuse;
+
+    impl MakeService for Refuse {
+        type Service = Len;
+        type Error = &'static str;
+
+        fn make_via_ref(&self, _old: Option<&Len>) -> Result<Len, &'static str> {
+            Err("refused")
+        }
+    }
+
+    #[test]
+    fn unified_make_errors() {
+        let stack = |refuse: bool| {
+            let layer = refuse.then_some(layer_fn(|_: &(), _inner| Refuse));
+            FactoryStack::new(())
+                .push_clone_leaf(Len)
+                .push_unified::<BoxError, _, _, _>(layer)
+        };
+        let svc = stack(false).make().unwrap();
+        assert_eq!(block_on(svc.call("abc")), Ok(3));
+        assert_eq!(stack(true).make().unwrap_err().to_string(), "refused");
+
+        let factory = Either::<_, CloneFactory<Len>>::Left(AsyncMakeServiceWrapper(Refuse));
+        let factory = factory.unified::<BoxError>();
+        let err = block_on(AsyncMakeService::make(&factory)).unwrap_err();
+        assert_eq!(err.to_string(), "refused");
+    }
+
+    #[test]
+    fn result_conversions_and_accessors() {
+        let mut left: Either<u32, &str> = Ok(1).into();
+        assert!(left.is_left() && !left.is_right());
+        if let Either::Left(n) = left.as_mut() {
+            *n += 1;
+        }
+        assert_eq!(left.as_ref().left(), Some(&2));
+        assert_eq!(Result::from(left.clone()), Ok(2));
+        assert_eq!(left.right(), None);
+
+        let right: Either<u32, &str> = Err("no").into();
+        assert!(right.is_right());
+        assert_eq!(Result::from(right), Err("no"));
+
+        let same: Either<u32, u32> = Either::Right(3);
+        assert_eq!(same.map(|n| n * 2).into_inner(), 6);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_externally_tagged() {
+        let json = serde_json::to_string(&Either::<u32, String>::Left(1)).unwrap();
+        assert_eq!(json, r#"{"Left":1}"#);
+        let back: Either<u32, String> = serde_json::from_str(r#"{"Right":"a"}"#).unwrap();
+        assert_eq!(back.right().as_deref(), Some("a"));
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// The epoch of the config a stack is built from, e.g. the version of its
+/// [`ConfigSnapshot`](crate::config::ConfigSnapshot), extracted from the stack
+/// config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Epoch(pub u64);
+
+/// The latest config epoch, shared between the reload controller and the
+/// services made. Clones share the same value.
+///
+/// The controller advances it once a reload is rolled out; services made from
+/// an older config then see that they are stale.
+#[derive(Debug, Clone, Default)]
+pub struct CurrentEpoch(Arc<AtomicU64>);
+
+impl CurrentEpoch {
+    pub fn new(epoch: Epoch) -> Self {
+        CurrentEpoch(Arc::new(AtomicU64::new(epoch.0)))
+    }
+
+    #[inline]
+    pub fn get(&self) -> Epoch {
+        Epoch(self.0.load(Ordering::Acquire))
+    }
+
+    /// Set the current epoch. It never goes back.
+    #[inline]
+    pub fn set(&self, epoch: Epoch) {
+        self.0.fetch_max(epoch.0, Ordering::AcqRel);
+    }
+}
+
+/// A call served by a service made from an older config than the current one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleService {
+    /// The epoch the service was made with.
+    pub epoch: Epoch,
+    /// The current epoch.
+    pub current: Epoch,
+}
+
+impl Display for StaleService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "service of config epoch {} is stale, current epoch is {}",
+            self.epoch.0, self.current.0
+        )
+    }
+}
+
+impl Error for StaleService {}
+
+/// Error returned by [`EpochGate`].
+#[derive(Debug)]
+pub enum EpochError<E> {
+    /// The service is stale and refuses calls.
+    Stale(StaleService),
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for EpochError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EpochError::Stale(e) => e.fmt(f),
+            EpochError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for EpochError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EpochError::Stale(e) => Some(e),
+            EpochError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// Receives the calls [`EpochGate`] lets through although its service is stale.
+pub trait StaleObserver {
+    fn observe(&self, stale: &StaleService);
+}
+
+impl StaleObserver for () {
+    #[inline]
+    fn observe(&self, _stale: &StaleService) {}
+}
+
+impl<F: Fn(&StaleService)> StaleObserver for F {
+    #[inline]
+    fn observe(&self, stale: &StaleService) {
+        (self)(stale)
+    }
+}
+
+/// A middleware which records the config [`Epoch`] its service was made with,
+/// and checks it against the [`CurrentEpoch`] on every call.
+///
+/// Calls to a stale service, e.g. one left behind by a partially failed
+/// reload, are refused with [`EpochError::Stale`], or let through and passed
+/// to an observer, so operators notice workers which do not pick up reloads.
+pub struct EpochGate<T, O = ()> {
+    epoch: Epoch,
+    current: CurrentEpoch,
+    refuse: bool,
+    observer: O,
+    inner: T,
+}
+
+impl<T> EpochGate<T> {
+    /// Refuse the calls of stale services.
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<Epoch> + Param<CurrentEpoch>,
+    {
+        Self::layer_with(true, ())
+    }
+}
+
+impl<T, O> EpochGate<T, O> {
+    /// Let the calls of stale services through, and pass them to `observer`.
+    pub fn layer_observed<C>(observer: O) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<Epoch> + Param<CurrentEpoch>,
+        O: Clone,
+    {
+        Self::layer_with(false, observer)
+    }
+
+    fn layer_with<C>(refuse: bool, observer: O) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<Epoch> + Param<CurrentEpoch>,
+        O: Clone,
+    {
+        layer_fn(move |c: &C, inner| EpochGate {
+            epoch: Param::<Epoch>::param(c),
+            current: Param::<CurrentEpoch>::param(c),
+            refuse,
+            observer: observer.clone(),
+            inner,
+        })
+    }
+
+    /// The epoch the service was made with.
+    #[inline]
+    pub fn epoch(&self) -> Epoch {
+        self.epoch
+    }
+
+    /// Check whether the service is stale.
+    pub fn stale(&self) -> Option<StaleService> {
+        let current = self.current.get();
+        (self.epoch < current).then_some(StaleService {
+            epoch: self.epoch,
+            current,
+        })
+    }
+}
+
+impl<T, O, R> Service<R> for EpochGate<T, O>
+where
+    T: Service<R>,
+    O: StaleObserver,
+{
+    type Response = T::Response;
+    type Error = EpochError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        if let Some(stale) = self.stale() {
+            if self.refuse {
+                return Err(EpochError::Stale(stale));
+            }
+            self.observer.observe(&stale);
+        }
+        self.inner.call(req).await.map_err(EpochError::Inner)
+    }
+}
+
+impl<F: MakeService, O: Clone> MakeService for EpochGate<F, O> {
+    type Service = EpochGate<F::Service, O>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(EpochGate {
+            epoch: self.epoch,
+            current: self.current.clone(),
+            refuse: self.refuse,
+            observer: self.observer.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("epoch", self.epoch.0)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, O: Clone> AsyncMakeService for EpochGate<F, O> {
+    type Service = EpochGate<F::Service, O>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(EpochGate {
+            epoch: self.epoch,
+            current: self.current.clone(),
+            refuse: self.refuse,
+            observer: self.observer.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("epoch", self.epoch.0)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(EpochGate<_, O> { epoch, current, refuse, observer });
+
+impl<T, O> Layered for EpochGate<T, O> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(EpochGate<T, O>);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, convert::Infallible, rc::Rc};
+
+    use super::{CurrentEpoch, Epoch, EpochError, EpochGate, StaleService};
+    use crate::{layer::FactoryLayer, test_util::block_on, Param, Service};
+
+    struct Config(Epoch, CurrentEpoch);
+
+    impl Param<Epoch> for Config {
+        fn param(&self) -> Epoch {
+            self.0
+        }
+    }
+
+    impl Param<CurrentEpoch> for Config {
+        fn param(&self) -> CurrentEpoch {
+            self.1.clone()
+        }
+    }
+
+    struct Echo;
+
+    impl Service<u32> for Echo {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<u32, Infallible> {
+            Ok(req)
+        }
+    }
+
+    #[test]
+    fn refuses_or_reports_stale_services() {
+        let current = CurrentEpoch::new(Epoch(1));
+        let config = Config(Epoch(1), current.clone());
+        let refusing = EpochGate::layer().layer(&config, Echo);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let observed = EpochGate::layer_observed({
+            let seen = seen.clone();
+            move |stale: &StaleService| seen.borrow_mut().push(*stale)
+        })
+        .layer(&config, Echo);
+        assert_eq!(block_on(refusing.call(1)).unwrap(), 1);
+
+        current.set(Epoch(3));
+        // It never goes back.
+        current.set(Epoch(2));
+        let stale = StaleService {
+            epoch: Epoch(1),
+            current: Epoch(3),
+        };
+        assert_eq!(refusing.stale(), Some(stale));
+        assert!(matches!(
+            block_on(refusing.call(2)),
+            Err(EpochError::Stale(s)) if s == stale
+        ));
+        assert_eq!(block_on(observed.call(2)).unwrap(), 2);
+        assert_eq!(*seen.borrow(), [stale]);
+
+        let fresh = EpochGate::layer().layer(&Config(Epoch(3), current), Echo);
+        assert_eq!(fresh.epoch(), Epoch(3));
+        assert_eq!(block_on(fresh.call(3)).unwrap(), 3);
+    }
+}
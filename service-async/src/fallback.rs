@@ -0,0 +1,421 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::{
+    classify::{Class, ClassifyError},
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::{impl_shutdown_inner, Shutdown},
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Decides whether a failed call to the primary service is retried on the fallback one.
+pub trait FallbackPolicy<E> {
+    fn should_fallback(&self, err: &E) -> bool;
+}
+
+impl<E, F> FallbackPolicy<E> for F
+where
+    F: Fn(&E) -> bool,
+{
+    #[inline]
+    fn should_fallback(&self, err: &E) -> bool {
+        (self)(err)
+    }
+}
+
+/// A [`FallbackPolicy`] which falls back on every error.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AlwaysFallback;
+
+impl<E> FallbackPolicy<E> for AlwaysFallback {
+    #[inline]
+    fn should_fallback(&self, _err: &E) -> bool {
+        true
+    }
+}
+
+/// Falls back on errors classified as transient failures.
+impl<E, F: Fn(&E) -> Class> FallbackPolicy<E> for ClassifyError<F> {
+    #[inline]
+    fn should_fallback(&self, err: &E) -> bool {
+        self.classify_err(err).is_transient()
+    }
+}
+
+/// A service which calls the primary service and, if it fails with an error
+/// accepted by the policy, calls the fallback service with the same request.
+///
+/// Unlike [`Either`](crate::either::Either), which picks one service when the
+/// stack is built, `Fallback` holds both and fails over at runtime. The request
+/// must be `Clone` to be sent twice.
+///
+/// It is also the factory of itself: both factories take part in `make_via_ref`.
+#[derive(Debug, Clone)]
+pub struct Fallback<A, B, P = AlwaysFallback> {
+    pub primary: A,
+    pub fallback: B,
+    pub policy: P,
+}
+
+impl<A, B, P> Fallback<A, B, P> {
+    #[inline]
+    pub const fn new(primary: A, fallback: B, policy: P) -> Self {
+        Fallback {
+            primary,
+            fallback,
+            policy,
+        }
+    }
+
+    /// Wrap the inner factory as the primary, with the given fallback factory.
+    pub fn layer<C>(fallback: B, policy: P) -> impl FactoryLayer<C, A, Factory = Self>
+    where
+        B: Clone,
+        P: Clone,
+    {
+        layer_fn(move |_: &C, primary| Fallback {
+            primary,
+            fallback: fallback.clone(),
+            policy: policy.clone(),
+        })
+    }
+}
+
+impl<A, B, P, R> Service<R> for Fallback<A, B, P>
+where
+    A: Service<R>,
+    B: Service<R, Response = A::Response, Error = A::Error>,
+    P: FallbackPolicy<A::Error>,
+    R: Clone,
+{
+    type Response = A::Response;
+    type Error = A::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        match self.primary.call(req.clone()).await {
+            Err(e) if self.policy.should_fallback(&e) => self.fallback.call(req).await,
+            r => r,
+        }
+    }
+}
+
+impl<FA, FB, P> MakeService for Fallback<FA, FB, P>
+where
+    FA: MakeService,
+    FB: MakeService<Error = FA::Error>,
+    P: Clone,
+{
+    type Service = Fallback<FA::Service, FB::Service, P>;
+    type Error = FA::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Fallback {
+            primary: self.primary.make_via_ref(old.map(|o| &o.primary))?,
+            fallback: self.fallback.make_via_ref(old.map(|o| &o.fallback))?,
+            policy: self.policy.clone(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.primary.validate()?;
+        self.fallback.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_inner(self.primary.service_metadata())
+            .with_inner(self.fallback.service_metadata())
+    }
+}
+
+impl<FA, FB, P> AsyncMakeService for Fallback<FA, FB, P>
+where
+    FA: AsyncMakeService,
+    FB: AsyncMakeService<Error = FA::Error>,
+    P: Clone,
+{
+    type Service = Fallback<FA::Service, FB::Service, P>;
+    type Error = FA::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Fallback {
+            primary: self.primary.make_via_ref(old.map(|o| &o.primary)).await?,
+            fallback: self.fallback.make_via_ref(old.map(|o| &o.fallback)).await?,
+            policy: self.policy.clone(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.primary.validate()?;
+        self.fallback.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_inner(self.primary.service_metadata())
+            .with_inner(self.fallback.service_metadata())
+    }
+}
+
+impl<A, B, P> Layered for Fallback<A, B, P> {
+    type Inner = A;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.primary
+    }
+}
+
+impl<A: Shutdown, B: Shutdown, P> Shutdown for Fallback<A, B, P> {
+    async fn shutdown(&self) {
+        self.primary.shutdown().await;
+        self.fallback.shutdown().await;
+    }
+}
+
+/// Configuration of [`StaticFallback`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FallbackConfig {
+    /// Whether degraded responses are served. When disabled, errors are
+    /// returned as they are.
+    pub enabled: bool,
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        FallbackConfig { enabled: true }
+    }
+}
+
+/// Receives the errors [`StaticFallback`] answered with a degraded response.
+pub trait DegradeMetrics<E> {
+    fn record_degraded(&self, err: &E);
+}
+
+impl<E> DegradeMetrics<E> for () {
+    #[inline]
+    fn record_degraded(&self, _err: &E) {}
+}
+
+impl<E, F: Fn(&E)> DegradeMetrics<E> for F {
+    #[inline]
+    fn record_degraded(&self, err: &E) {
+        (self)(err)
+    }
+}
+
+/// A [`DegradeMetrics`] counting degraded responses. Clones share the count.
+#[derive(Debug, Clone, Default)]
+pub struct DegradedCounter(Arc<AtomicU64>);
+
+impl DegradedCounter {
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl<E> DegradeMetrics<E> for DegradedCounter {
+    #[inline]
+    fn record_degraded(&self, _err: &E) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A middleware which answers failed calls with a static, degraded response
+/// built by a closure, e.g. an empty list or a cached default page.
+///
+/// Errors accepted by the policy are replaced, including the errors of an
+/// open circuit in the inner chain. Each replaced error is passed to the
+/// metrics. It is switched on and off by `Param<FallbackConfig>`.
+pub struct StaticFallback<T, FN, P = AlwaysFallback, M = ()> {
+    config: FallbackConfig,
+    degrade: Arc<FN>,
+    policy: P,
+    metrics: M,
+    inner: T,
+}
+
+impl<T, FN> StaticFallback<T, FN> {
+    pub fn layer<C>(degrade: FN) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<FallbackConfig>,
+    {
+        Self::layer_with(degrade, AlwaysFallback, ())
+    }
+}
+
+impl<T, FN, P, M> StaticFallback<T, FN, P, M> {
+    pub fn layer_with<C>(
+        degrade: FN,
+        policy: P,
+        metrics: M,
+    ) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<FallbackConfig>,
+        P: Clone,
+        M: Clone,
+    {
+        let degrade = Arc::new(degrade);
+        layer_fn(move |c: &C, inner| StaticFallback {
+            config: c.param(),
+            degrade: degrade.clone(),
+            policy: policy.clone(),
+            metrics: metrics.clone(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn metrics(&self) -> &M {
+        &self.metrics
+    }
+}
+
+impl<T, FN, P, M, R> Service<R> for StaticFallback<T, FN, P, M>
+where
+    T: Service<R>,
+    FN: Fn() -> T::Response,
+    P: FallbackPolicy<T::Error>,
+    M: DegradeMetrics<T::Error>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        match self.inner.call(req).await {
+            Err(e) if self.config.enabled && self.policy.should_fallback(&e) => {
+                self.metrics.record_degraded(&e);
+                Ok((self.degrade)())
+            }
+            r => r,
+        }
+    }
+}
+
+impl<F: MakeService, FN, P: Clone, M: Clone> MakeService for StaticFallback<F, FN, P, M> {
+    type Service = StaticFallback<F::Service, FN, P, M>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(StaticFallback {
+            config: self.config,
+            degrade: self.degrade.clone(),
+            policy: self.policy.clone(),
+            metrics: self.metrics.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("enabled", self.config.enabled)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, FN, P: Clone, M: Clone> AsyncMakeService
+    for StaticFallback<F, FN, P, M>
+{
+    type Service = StaticFallback<F::Service, FN, P, M>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(StaticFallback {
+            config: self.config,
+            degrade: self.degrade.clone(),
+            policy: self.policy.clone(),
+            metrics: self.metrics.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("enabled", self.config.enabled)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(StaticFallback<_, FN, P, M> { config, degrade, policy, metrics });
+
+impl<T, FN, P, M> Layered for StaticFallback<T, FN, P, M> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(StaticFallback<T, FN, P, M>);
+
+#[cfg(test)]
+mod tests {
+    use super::{AlwaysFallback, DegradedCounter, Fallback, FallbackConfig, StaticFallback};
+    use crate::{layer::FactoryLayer, test_util::block_on, Service};
+
+    /// Answers every request with the same result.
+    #[derive(Clone)]
+    struct Fixed(Result<&'static str, &'static str>);
+
+    impl Service<u32> for Fixed {
+        type Response = &'static str;
+        type Error = &'static str;
+
+        async fn call(&self, _req: u32) -> Result<Self::Response, Self::Error> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn fails_over_on_error() {
+        let up = Fallback::new(Fixed(Ok("primary")), Fixed(Ok("fallback")), AlwaysFallback);
+        assert_eq!(block_on(up.call(1)), Ok("primary"));
+
+        let down = Fallback::new(Fixed(Err("down")), Fixed(Ok("fallback")), AlwaysFallback);
+        assert_eq!(block_on(down.call(1)), Ok("fallback"));
+    }
+
+    #[test]
+    fn policy_rejects_fallback() {
+        let svc = Fallback::new(Fixed(Err("fatal")), Fixed(Ok("fallback")), |e: &&str| {
+            *e != "fatal"
+        });
+        assert_eq!(block_on(svc.call(1)), Err("fatal"));
+    }
+
+    #[test]
+    fn static_fallback_degrades_and_counts() {
+        let enabled = FallbackConfig::default();
+        let counter = DegradedCounter::default();
+        let layer = |primary| {
+            StaticFallback::layer_with(|| "degraded", |e: &&str| *e != "fatal", counter.clone())
+                .layer(&enabled, primary)
+        };
+        assert_eq!(block_on(layer(Fixed(Ok("primary"))).call(1)), Ok("primary"));
+        assert_eq!(block_on(layer(Fixed(Err("down"))).call(1)), Ok("degraded"));
+        assert_eq!(block_on(layer(Fixed(Err("fatal"))).call(1)), Err("fatal"));
+        assert_eq!(counter.get(), 1);
+
+        let disabled = FallbackConfig { enabled: false };
+        let svc = StaticFallback::layer(|| "degraded").layer(&disabled, Fixed(Err("down")));
+        assert_eq!(block_on(svc.call(1)), Err("down"));
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::Display,
+    future::{pending, poll_fn, Future, Pending},
+    pin::{pin, Pin},
+    task::{Context, Poll},
+};
+
+use crate::{streaming::BodyStream, Service};
+
+/// A sink of outbound frames, such as the write half of a framed transport.
+///
+/// It has the shape of `futures::Sink`; with the `futures` feature,
+/// [`FromFutures`] adapts any `futures::Sink`.
+pub trait FrameSink<Item> {
+    type Error;
+
+    /// Wait until the sink can accept a frame.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>;
+
+    /// Send a frame. Only called after `poll_ready` returned `Ready(Ok(()))`.
+    fn start_send(self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error>;
+
+    /// Flush the frames sent so far.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>;
+
+    /// Flush and close the sink.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>;
+}
+
+impl<S: FrameSink<Item> + Unpin + ?Sized, Item> FrameSink<Item> for &mut S {
+    type Error = S::Error;
+
+    #[inline]
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut **self).poll_ready(cx)
+    }
+
+    #[inline]
+    fn start_send(mut self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+        Pin::new(&mut **self).start_send(item)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut **self).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut **self).poll_close(cx)
+    }
+}
+
+/// Adapts a `futures::Stream` into a [`BodyStream`], and a `futures::Sink`
+/// into a [`FrameSink`].
+#[cfg(feature = "futures")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FromFutures<T>(pub T);
+
+#[cfg(feature = "futures")]
+impl<T> FromFutures<T> {
+    fn project(self: Pin<&mut Self>) -> Pin<&mut T> {
+        // SAFETY: the field is structurally pinned; `FromFutures` is only
+        // `Unpin` if `T` is, and it does not move `T` out of a pin.
+        unsafe { self.map_unchecked_mut(|s| &mut s.0) }
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<T: futures_core::Stream> BodyStream for FromFutures<T> {
+    type Item = T::Item;
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().poll_next(cx)
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<T: futures_sink::Sink<Item>, Item> FrameSink<Item> for FromFutures<T> {
+    type Error = T::Error;
+
+    #[inline]
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().poll_ready(cx)
+    }
+
+    #[inline]
+    fn start_send(self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+        self.project().start_send(item)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().poll_close(cx)
+    }
+}
+
+/// In which order [`Framed`] writes responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResponseOrder {
+    /// In the order of the requests, as pipelined protocols require.
+    #[default]
+    Ordered,
+    /// As soon as each call completes, for protocols which tag frames with an ID.
+    Unordered,
+}
+
+/// Error returned by [`Framed::run`].
+#[derive(Debug)]
+pub enum FramedError<S, E> {
+    /// Writing to the sink failed.
+    Sink(S),
+    /// The service failed. Services which answer errors with a frame should
+    /// turn them into responses instead.
+    Service(E),
+}
+
+impl<S: Display, E: Display> Display for FramedError<S, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FramedError::Sink(e) => write!(f, "sink error: {e}"),
+            FramedError::Service(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<S: Error + 'static, E: Error + 'static> Error for FramedError<S, E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FramedError::Sink(e) => Some(e),
+            FramedError::Service(e) => Some(e),
+        }
+    }
+}
+
+/// Drives a service over a framed connection: it reads request frames from a
+/// [`BodyStream`], calls the service for each and writes the responses into a
+/// [`FrameSink`].
+///
+/// Up to `max_in_flight` calls run concurrently on the current task; reading
+/// pauses while the limit is reached. Responses are written in the
+/// [`ResponseOrder`] configured. When the inbound stream ends, or the shutdown
+/// signal fires, reading stops, the calls in flight are completed and
+/// written, and the sink is closed.
+pub struct Framed<St, Si, SD = Pending<()>> {
+    inbound: St,
+    outbound: Si,
+    shutdown: SD,
+    max_in_flight: usize,
+    order: ResponseOrder,
+}
+
+/// Default limit of concurrent calls of [`Framed`].
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+impl<St, Si> Framed<St, Si> {
+    pub fn new(inbound: St, outbound: Si) -> Self {
+        Framed {
+            inbound,
+            outbound,
+            shutdown: pending(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            order: ResponseOrder::Ordered,
+        }
+    }
+}
+
+impl<St, Si, SD> Framed<St, Si, SD> {
+    /// Stop reading requests when `signal` completes, and shut down gracefully.
+    pub fn shutdown<SD2: Future<Output = ()>>(self, signal: SD2) -> Framed<St, Si, SD2> {
+        Framed {
+            inbound: self.inbound,
+            outbound: self.outbound,
+            shutdown: signal,
+            max_in_flight: self.max_in_flight,
+            order: self.order,
+        }
+    }
+
+    /// Set the limit of concurrent calls. Zero is treated as one.
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    pub fn order(mut self, order: ResponseOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Serve the connection with `svc` until it is done.
+    ///
+    /// A service error stops the connection without closing the sink.
+    pub async fn run<S>(self, svc: &S) -> Result<(), FramedError<Si::Error, S::Error>>
+    where
+        St: BodyStream,
+        S: Service<St::Item>,
+        Si: FrameSink<S::Response>,
+        SD: Future<Output = ()>,
+    {
+        let Framed {
+            inbound,
+            outbound,
+            shutdown,
+            max_in_flight,
+            order,
+        } = self;
+        let mut inbound = pin!(inbound);
+        let mut outbound = pin!(outbound);
+        let mut shutdown = pin!(shutdown);
+        let mut reading = true;
+        let mut in_flight = VecDeque::new();
+        let mut out = VecDeque::new();
+        let mut unflushed = false;
+
+        poll_fn(|cx| loop {
+            let mut progress = false;
+
+            if reading && shutdown.as_mut().poll(cx).is_ready() {
+                reading = false;
+            }
+            while reading && in_flight.len() < max_in_flight {
+                match inbound.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(req)) => {
+                        in_flight.push_back((Box::pin(svc.call(req)), None));
+                        progress = true;
+                    }
+                    Poll::Ready(None) => reading = false,
+                    Poll::Pending => break,
+                }
+            }
+
+            for (call, res) in in_flight.iter_mut() {
+                if res.is_none() {
+                    if let Poll::Ready(r) = call.as_mut().poll(cx) {
+                        *res = Some(r);
+                    }
+                }
+            }
+            let mut take = |res: Option<Result<_, _>>| match res {
+                Some(Ok(resp)) => {
+                    out.push_back(resp);
+                    Ok(())
+                }
+                Some(Err(e)) => Err(FramedError::Service(e)),
+                None => Ok(()),
+            };
+            match order {
+                ResponseOrder::Ordered => {
+                    while in_flight.front().is_some_and(|(_, res)| res.is_some()) {
+                        let (_, res) = in_flight.pop_front().unwrap();
+                        take(res)?;
+                        progress = true;
+                    }
+                }
+                ResponseOrder::Unordered => {
+                    let before = in_flight.len();
+                    let mut failed = None;
+                    in_flight.retain_mut(|(_, res)| match res.take() {
+                        Some(r) => {
+                            if let Err(e) = take(Some(r)) {
+                                failed.get_or_insert(e);
+                            }
+                            false
+                        }
+                        None => true,
+                    });
+                    if let Some(e) = failed {
+                        return Poll::Ready(Err(e));
+                    }
+                    progress |= in_flight.len() != before;
+                }
+            }
+
+            while !out.is_empty() {
+                match outbound.as_mut().poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        let resp = out.pop_front().unwrap();
+                        outbound
+                            .as_mut()
+                            .start_send(resp)
+                            .map_err(FramedError::Sink)?;
+                        unflushed = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(FramedError::Sink(e))),
+                    Poll::Pending => break,
+                }
+            }
+            if unflushed {
+                match outbound.as_mut().poll_flush(cx) {
+                    Poll::Ready(Ok(())) => unflushed = false,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(FramedError::Sink(e))),
+                    Poll::Pending => {}
+                }
+            }
+
+            if !reading && in_flight.is_empty() && out.is_empty() {
+                return outbound.as_mut().poll_close(cx).map_err(FramedError::Sink);
+            }
+            if !progress {
+                return Poll::Pending;
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::VecDeque,
+        convert::Infallible,
+        future::ready,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::{BodyStream, FrameSink, Framed, FramedError, ResponseOrder};
+    use crate::{test_util::block_on, yielding::yield_now, Service};
+
+    struct Frames(VecDeque<u32>);
+
+    impl BodyStream for Frames {
+        type Item = u32;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<u32>> {
+            Poll::Ready(self.get_mut().0.pop_front())
+        }
+    }
+
+    #[derive(Default)]
+    struct Sink {
+        sent: Vec<u32>,
+        flushed: usize,
+        closed: bool,
+    }
+
+    impl FrameSink<u32> for Sink {
+        type Error = Infallible;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: u32) -> Result<(), Infallible> {
+            self.get_mut().sent.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            let this = self.get_mut();
+            this.flushed = this.sent.len();
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            self.get_mut().closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Yields as many times as the request says, and answers with it; fails
+    /// on zero. Tracks the most calls in flight at once.
+    #[derive(Default)]
+    struct Delay {
+        in_flight: Cell<usize>,
+        max_in_flight: Cell<usize>,
+    }
+
+    impl Service<u32> for Delay {
+        type Response = u32;
+        type Error = &'static str;
+
+        async fn call(&self, req: u32) -> Result<u32, &'static str> {
+            self.in_flight.set(self.in_flight.get() + 1);
+            self.max_in_flight
+                .set(self.max_in_flight.get().max(self.in_flight.get()));
+            for _ in 0..req {
+                yield_now().await;
+            }
+            self.in_flight.set(self.in_flight.get() - 1);
+            if req == 0 {
+                return Err("zero");
+            }
+            Ok(req)
+        }
+    }
+
+    fn frames(reqs: &[u32]) -> Frames {
+        Frames(reqs.iter().copied().collect())
+    }
+
+    #[test]
+    fn response_order() {
+        let svc = Delay::default();
+        let mut sink = Sink::default();
+        block_on(Framed::new(frames(&[3, 1, 2]), &mut sink).run(&svc)).unwrap();
+        assert_eq!(sink.sent, [3, 1, 2]);
+        assert_eq!(sink.flushed, 3);
+        assert!(sink.closed);
+
+        let mut sink = Sink::default();
+        let framed = Framed::new(frames(&[3, 1, 2]), &mut sink).order(ResponseOrder::Unordered);
+        block_on(framed.run(&svc)).unwrap();
+        assert_eq!(sink.sent, [1, 2, 3]);
+        assert!(sink.closed);
+    }
+
+    #[test]
+    fn limits_calls_in_flight() {
+        let svc = Delay::default();
+        let mut sink = Sink::default();
+        let framed = Framed::new(frames(&[2; 8]), &mut sink).max_in_flight(3);
+        block_on(framed.run(&svc)).unwrap();
+        assert_eq!(sink.sent, [2; 8]);
+        assert_eq!(svc.max_in_flight.get(), 3);
+    }
+
+    #[test]
+    fn shutdown_and_errors() {
+        let svc = Delay::default();
+        let mut sink = Sink::default();
+        let framed = Framed::new(frames(&[1, 2]), &mut sink).shutdown(ready(()));
+        block_on(framed.run(&svc)).unwrap();
+        assert!(sink.sent.is_empty());
+        assert!(sink.closed);
+
+        let mut sink = Sink::default();
+        let res = block_on(Framed::new(frames(&[1, 3, 0]), &mut sink).run(&svc));
+        assert!(matches!(res, Err(FramedError::Service("zero"))));
+        assert_eq!(sink.sent, [1]);
+        assert!(!sink.closed);
+    }
+}
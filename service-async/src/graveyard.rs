@@ -0,0 +1,339 @@
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    serve::Spawn,
+    shutdown::DrainHandle,
+    time::{Clock, Timer},
+};
+
+/// Configuration of a [`Graveyard`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GraveyardConfig {
+    /// How often the sweeper drops the services which became idle.
+    pub sweep_interval: Duration,
+    /// Age after which a service still in use is reported as leaked.
+    pub leak_after: Duration,
+}
+
+/// A replaced service which outlived [`GraveyardConfig::leak_after`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Leak {
+    /// Type name of the service.
+    pub type_name: &'static str,
+    /// Time since the service was buried.
+    pub age: Duration,
+}
+
+/// Receives the leaks found by a [`Graveyard`], e.g. to log a warning.
+pub trait LeakHandler {
+    fn on_leak(&self, leak: &Leak);
+}
+
+impl LeakHandler for () {
+    #[inline]
+    fn on_leak(&self, _leak: &Leak) {}
+}
+
+impl<F: Fn(&Leak)> LeakHandler for F {
+    #[inline]
+    fn on_leak(&self, leak: &Leak) {
+        (self)(leak)
+    }
+}
+
+struct Grave {
+    type_name: &'static str,
+    buried_at: Instant,
+    reported: bool,
+    // Owns the service, and tells whether its calls have finished.
+    idle: Box<dyn Fn() -> bool>,
+}
+
+struct Yard<TM, H> {
+    graves: RefCell<Vec<Grave>>,
+    config: GraveyardConfig,
+    timer: TM,
+    handler: H,
+}
+
+/// Where replaced services are parked until their calls in flight finish, to
+/// be dropped by a sweeper instead of by whichever call ends last.
+///
+/// A service replaced in a [`ServiceSlot`](crate::reload::ServiceSlot) lives
+/// on in the calls still holding it, and the last of them drops it, in the
+/// middle of serving a request. Burying it moves the drop to the sweeper,
+/// and reports services which stay in use suspiciously long, e.g. because a
+/// connection never ends, through the [`LeakHandler`].
+///
+/// The graveyard is local to a thread, like the tasks of [`Spawn`]. Clones
+/// share the same graves.
+pub struct Graveyard<TM, H = ()> {
+    yard: Rc<Yard<TM, H>>,
+}
+
+impl<TM> Graveyard<TM> {
+    pub fn new(config: GraveyardConfig, timer: TM) -> Self {
+        Self::with_handler(config, timer, ())
+    }
+}
+
+impl<TM, H> Graveyard<TM, H> {
+    /// Create a graveyard passing its leaks to `handler`.
+    pub fn with_handler(config: GraveyardConfig, timer: TM, handler: H) -> Self {
+        Graveyard {
+            yard: Rc::new(Yard {
+                graves: RefCell::new(Vec::new()),
+                config,
+                timer,
+                handler,
+            }),
+        }
+    }
+
+    /// Number of services waiting to be dropped.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.yard.graves.borrow().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<TM: Clock, H: LeakHandler> Graveyard<TM, H> {
+    /// Park `svc`, e.g. as returned by
+    /// [`ServiceSlot::swap`](crate::reload::ServiceSlot::swap), until the
+    /// graveyard holds its last reference.
+    pub fn bury<S: 'static>(&self, svc: Arc<S>) {
+        self.park(std::any::type_name::<S>(), svc, |svc| Arc::strong_count(svc) == 1);
+    }
+
+    /// Park `svc` until no call is in flight on `handle`.
+    ///
+    /// `handle` must count the calls of `svc` alone, e.g. the handle of a
+    /// [`Draining`](crate::shutdown::Draining) layer made for this service,
+    /// not one shared with the service replacing it.
+    pub fn bury_draining<S: 'static>(&self, svc: S, handle: DrainHandle) {
+        self.bury_when(svc, move |_| handle.in_flight() == 0);
+    }
+
+    /// Park `svc` until `idle` returns true.
+    pub fn bury_when<S: 'static>(&self, svc: S, idle: impl Fn(&S) -> bool + 'static) {
+        self.park(std::any::type_name::<S>(), svc, idle);
+    }
+
+    fn park<T: 'static>(
+        &self,
+        type_name: &'static str,
+        svc: T,
+        idle: impl Fn(&T) -> bool + 'static,
+    ) {
+        self.yard.graves.borrow_mut().push(Grave {
+            type_name,
+            buried_at: self.yard.timer.now(),
+            reported: false,
+            idle: Box::new(move || idle(&svc)),
+        });
+    }
+
+    /// Drop the services which became idle and report new leaks. Returns the
+    /// number of services dropped.
+    pub fn sweep(&self) -> usize {
+        let now = self.yard.timer.now();
+        let leak_after = self.yard.config.leak_after;
+        let mut dead = Vec::new();
+        let mut leaks = Vec::new();
+        {
+            let mut graves = self.yard.graves.borrow_mut();
+            let mut i = 0;
+            while i < graves.len() {
+                if (graves[i].idle)() {
+                    dead.push(graves.swap_remove(i));
+                    continue;
+                }
+                let grave = &mut graves[i];
+                let age = now.saturating_duration_since(grave.buried_at);
+                if !grave.reported && age >= leak_after {
+                    grave.reported = true;
+                    leaks.push(Leak {
+                        type_name: grave.type_name,
+                        age,
+                    });
+                }
+                i += 1;
+            }
+        }
+        // Drop and report outside the borrow, so the services and the handler
+        // may use the graveyard.
+        let swept = dead.len();
+        drop(dead);
+        for leak in &leaks {
+            self.yard.handler.on_leak(leak);
+        }
+        swept
+    }
+}
+
+impl<TM, H> Graveyard<TM, H>
+where
+    TM: Timer + Clock + 'static,
+    H: LeakHandler + 'static,
+{
+    /// Spawn a task which sweeps every [`GraveyardConfig::sweep_interval`].
+    ///
+    /// The task stops once every handle of the graveyard is dropped, which
+    /// drops the services left in it.
+    pub fn spawn_sweeper(&self, spawner: &impl Spawn) {
+        let yard = Rc::downgrade(&self.yard);
+        spawner.spawn(Box::pin(sweep_loop(yard)));
+    }
+}
+
+async fn sweep_loop<TM, H>(yard: Weak<Yard<TM, H>>)
+where
+    TM: Timer + Clock,
+    H: LeakHandler,
+{
+    loop {
+        let sleep = match yard.upgrade() {
+            Some(yard) => yard.timer.sleep(yard.config.sweep_interval),
+            None => return,
+        };
+        sleep.await;
+        match yard.upgrade() {
+            Some(yard) => Graveyard { yard }.sweep(),
+            None => return,
+        };
+    }
+}
+
+impl<TM, H> Clone for Graveyard<TM, H> {
+    fn clone(&self) -> Self {
+        Graveyard {
+            yard: self.yard.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        convert::Infallible,
+        future::pending,
+        rc::Rc,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::{Graveyard, GraveyardConfig, Leak};
+    use crate::{
+        layer::FactoryLayer,
+        shutdown::{DrainHandle, Draining},
+        test_util::{poll_once, LocalExecutor},
+        time::MockTimer,
+        Service,
+    };
+
+    const CONFIG: GraveyardConfig = GraveyardConfig {
+        sweep_interval: Duration::from_secs(1),
+        leak_after: Duration::from_secs(10),
+    };
+
+    /// Records its drop.
+    struct Tomb(Arc<AtomicBool>);
+
+    impl Drop for Tomb {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// A service whose calls never complete.
+    struct Hang;
+
+    impl Service<()> for Hang {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, _req: ()) -> Result<(), Infallible> {
+            pending().await
+        }
+    }
+
+    #[test]
+    fn sweeps_idle_services_and_reports_leaks() {
+        let timer = MockTimer::new();
+        let leaks = Rc::new(RefCell::new(Vec::new()));
+        let handler = {
+            let leaks = leaks.clone();
+            move |leak: &Leak| leaks.borrow_mut().push(*leak)
+        };
+        let graveyard = Graveyard::with_handler(CONFIG, timer.clone(), handler);
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let held = Arc::new(Tomb(dropped.clone()));
+        graveyard.bury(held.clone());
+        assert_eq!(graveyard.sweep(), 0);
+        timer.advance(Duration::from_secs(10));
+        assert_eq!(graveyard.sweep(), 0);
+        // A leak is reported once.
+        assert_eq!(graveyard.sweep(), 0);
+        assert_eq!(
+            *leaks.borrow(),
+            [Leak {
+                type_name: std::any::type_name::<Tomb>(),
+                age: Duration::from_secs(10),
+            }]
+        );
+        drop(held);
+        assert!(!dropped.load(Ordering::SeqCst));
+        assert_eq!(graveyard.sweep(), 1);
+        assert!(dropped.load(Ordering::SeqCst) && graveyard.is_empty());
+
+        let handle = DrainHandle::new();
+        let svc = Draining::layer().layer(&handle, Hang);
+        let mut call = Box::pin(svc.call(()));
+        assert!(poll_once(call.as_mut()).is_pending());
+        let dropped = Arc::new(AtomicBool::new(false));
+        graveyard.bury_draining(Tomb(dropped.clone()), handle);
+        assert_eq!(graveyard.sweep(), 0);
+        drop(call);
+        assert_eq!(graveyard.sweep(), 1);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn sweeper_stops_with_the_graveyard() {
+        let timer = MockTimer::new();
+        let executor = LocalExecutor::new();
+        let graveyard = Graveyard::new(CONFIG, timer.clone());
+        graveyard.spawn_sweeper(&executor.spawner());
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        graveyard.bury(Arc::new(Tomb(dropped.clone())));
+        executor.run_tasks();
+        assert_eq!(graveyard.len(), 1);
+        timer.advance(Duration::from_secs(1));
+        executor.run_tasks();
+        assert!(dropped.load(Ordering::SeqCst) && graveyard.is_empty());
+
+        graveyard.bury(Arc::new(Tomb(dropped.clone())));
+        dropped.store(false, Ordering::SeqCst);
+        drop(graveyard);
+        assert!(dropped.load(Ordering::SeqCst));
+        timer.advance(Duration::from_secs(1));
+        executor.run_tasks();
+        assert_eq!(executor.pending_tasks(), 0);
+    }
+}
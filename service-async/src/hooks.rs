@@ -0,0 +1,295 @@
+use std::time::Duration;
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    time::Clock,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Called by [`Hooks`] when a call succeeds, with the request metadata `M`
+/// and the latency of the call.
+pub trait OnResponse<M, Resp> {
+    fn on_response(&self, meta: &M, resp: &Resp, latency: Duration);
+}
+
+impl<M, Resp> OnResponse<M, Resp> for () {
+    #[inline]
+    fn on_response(&self, _meta: &M, _resp: &Resp, _latency: Duration) {}
+}
+
+impl<M, Resp, F: Fn(&M, &Resp, Duration)> OnResponse<M, Resp> for F {
+    #[inline]
+    fn on_response(&self, meta: &M, resp: &Resp, latency: Duration) {
+        (self)(meta, resp, latency)
+    }
+}
+
+/// Called by [`Hooks`] when a call fails, with the request metadata `M` and
+/// the latency of the call.
+pub trait OnError<M, E> {
+    fn on_error(&self, meta: &M, err: &E, latency: Duration);
+}
+
+impl<M, E> OnError<M, E> for () {
+    #[inline]
+    fn on_error(&self, _meta: &M, _err: &E, _latency: Duration) {}
+}
+
+impl<M, E, F: Fn(&M, &E, Duration)> OnError<M, E> for F {
+    #[inline]
+    fn on_error(&self, meta: &M, err: &E, latency: Duration) {
+        (self)(meta, err, latency)
+    }
+}
+
+/// The hooks run by [`Hooks`] around each call of a service.
+///
+/// The request is moved into the inner service, so the metadata the hooks
+/// need is taken from it up front with [`meta`](CallHooks::meta). Usually
+/// this is a [`HookSet`].
+pub trait CallHooks<R, Resp, E> {
+    /// Metadata of a request, passed to the hooks after the call.
+    type Meta;
+
+    fn meta(&self, req: &R) -> Self::Meta;
+
+    fn on_response(&self, meta: &Self::Meta, resp: &Resp, latency: Duration);
+
+    fn on_error(&self, meta: &Self::Meta, err: &E, latency: Duration);
+}
+
+/// A set of hooks built from closures: a metadata extractor, an
+/// [`OnResponse`] and an [`OnError`] hook. Unset hooks do nothing.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use service_async::hooks::HookSet;
+///
+/// let hooks = HookSet::new(|req: &String| req.len())
+///     .on_error(|len: &usize, err: &std::io::Error, latency: Duration| {
+///         eprintln!("request of {len} bytes failed after {latency:?}: {err}");
+///     });
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct HookSet<X, OR = (), OE = ()> {
+    meta: X,
+    on_response: OR,
+    on_error: OE,
+}
+
+impl<X> HookSet<X> {
+    /// Create a hook set which takes the metadata of each request with `meta`.
+    #[inline]
+    pub const fn new(meta: X) -> Self {
+        HookSet {
+            meta,
+            on_response: (),
+            on_error: (),
+        }
+    }
+}
+
+impl<X, OR, OE> HookSet<X, OR, OE> {
+    #[inline]
+    pub fn on_response<OR2>(self, on_response: OR2) -> HookSet<X, OR2, OE> {
+        HookSet {
+            meta: self.meta,
+            on_response,
+            on_error: self.on_error,
+        }
+    }
+
+    #[inline]
+    pub fn on_error<OE2>(self, on_error: OE2) -> HookSet<X, OR, OE2> {
+        HookSet {
+            meta: self.meta,
+            on_response: self.on_response,
+            on_error,
+        }
+    }
+}
+
+impl<R, Resp, E, M, X, OR, OE> CallHooks<R, Resp, E> for HookSet<X, OR, OE>
+where
+    X: Fn(&R) -> M,
+    OR: OnResponse<M, Resp>,
+    OE: OnError<M, E>,
+{
+    type Meta = M;
+
+    #[inline]
+    fn meta(&self, req: &R) -> M {
+        (self.meta)(req)
+    }
+
+    #[inline]
+    fn on_response(&self, meta: &M, resp: &Resp, latency: Duration) {
+        self.on_response.on_response(meta, resp, latency)
+    }
+
+    #[inline]
+    fn on_error(&self, meta: &M, err: &E, latency: Duration) {
+        self.on_error.on_error(meta, err, latency)
+    }
+}
+
+/// A middleware which runs user hooks after each call, on success or failure.
+///
+/// It covers simple cross-cutting concerns, like audit logs or error counters,
+/// without a middleware of their own. Responses and errors pass through
+/// unchanged.
+///
+/// It is its own factory; the hooks are cloned into every service it makes.
+/// The clock `CL` which measures the latency is taken from the stack config
+/// through `Param<CL>`.
+pub struct Hooks<T, H, CL> {
+    hooks: H,
+    clock: CL,
+    inner: T,
+}
+
+impl<T, H, CL> Hooks<T, H, CL> {
+    pub fn layer<C>(hooks: H) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<CL>,
+        H: Clone,
+    {
+        layer_fn(move |c: &C, inner| Hooks {
+            hooks: hooks.clone(),
+            clock: c.param(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn hooks(&self) -> &H {
+        &self.hooks
+    }
+}
+
+impl<T, H, CL, R> Service<R> for Hooks<T, H, CL>
+where
+    T: Service<R>,
+    H: CallHooks<R, T::Response, T::Error>,
+    CL: Clock,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let meta = self.hooks.meta(&req);
+        let start = self.clock.now();
+        let res = self.inner.call(req).await;
+        let latency = self.clock.now().saturating_duration_since(start);
+        match &res {
+            Ok(resp) => self.hooks.on_response(&meta, resp, latency),
+            Err(e) => self.hooks.on_error(&meta, e, latency),
+        }
+        res
+    }
+}
+
+impl<F: MakeService, H: Clone, CL: Clone> MakeService for Hooks<F, H, CL> {
+    type Service = Hooks<F::Service, H, CL>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Hooks {
+            hooks: self.hooks.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, H: Clone, CL: Clone> AsyncMakeService for Hooks<F, H, CL> {
+    type Service = Hooks<F::Service, H, CL>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Hooks {
+            hooks: self.hooks.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(Hooks<_, H, CL> { hooks, clock });
+
+impl<T, H, CL> Layered for Hooks<T, H, CL> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Hooks<T, H, CL>);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
+    use super::{HookSet, Hooks};
+    use crate::{layer::FactoryLayer, test_util::block_on, time::MockTimer, Service};
+
+    /// Parses the request, taking 1ms of mock time per byte.
+    struct Parse(MockTimer);
+
+    impl Service<&'static str> for Parse {
+        type Response = u32;
+        type Error = std::num::ParseIntError;
+
+        async fn call(&self, req: &'static str) -> Result<u32, Self::Error> {
+            self.0.advance(Duration::from_millis(req.len() as u64));
+            req.parse()
+        }
+    }
+
+    #[test]
+    fn hooks_see_every_call() {
+        let timer = MockTimer::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let hooks = HookSet::new(|req: &&str| req.len())
+            .on_response({
+                let log = log.clone();
+                move |len: &usize, resp: &u32, latency: Duration| {
+                    log.borrow_mut()
+                        .push(format!("ok {len} {resp} {latency:?}"))
+                }
+            })
+            .on_error({
+                let log = log.clone();
+                move |len: &usize, _: &std::num::ParseIntError, latency: Duration| {
+                    log.borrow_mut().push(format!("err {len} {latency:?}"))
+                }
+            });
+        let svc = Hooks::layer(hooks).layer(&timer, Parse(timer.clone()));
+
+        assert_eq!(block_on(svc.call("42")), Ok(42));
+        assert!(block_on(svc.call("nope")).is_err());
+        assert_eq!(*log.borrow(), ["ok 2 42 2ms", "err 4 4ms"]);
+    }
+}
@@ -0,0 +1,225 @@
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "boxed-futures"))]
+use crate::AsyncMakeService;
+use crate::{
+    layer::{impl_wrap_inner, FactoryLayer},
+    metadata::short_type_name,
+    stack::FactoryStack,
+    MakeService, ServiceMetadata,
+};
+
+/// A make of one factory of an instrumented stack, see
+/// [`FactoryStack::instrument_makes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MakeEvent {
+    /// Short type name of the factory.
+    pub name: &'static str,
+    /// Whether the service was made against an old one, as on reloads.
+    pub reload: bool,
+    /// Time the make took, including the factories it wraps.
+    pub duration: Duration,
+    /// Whether the make succeeded.
+    pub ok: bool,
+}
+
+/// Receives the [`MakeEvent`]s of an instrumented stack, e.g. to log them.
+pub trait MakeObserver {
+    fn on_make(&self, event: &MakeEvent);
+}
+
+impl MakeObserver for () {
+    #[inline]
+    fn on_make(&self, _event: &MakeEvent) {}
+}
+
+impl<F: Fn(&MakeEvent)> MakeObserver for F {
+    #[inline]
+    fn on_make(&self, event: &MakeEvent) {
+        (self)(event)
+    }
+}
+
+/// A factory which times the makes of its inner factory and reports them to
+/// an observer. It makes the same service as the inner factory.
+///
+/// Async makes are timed by the wall clock, so they include the time other
+/// tasks ran meanwhile.
+#[derive(Debug, Clone)]
+pub struct Instrumented<F, O> {
+    observer: O,
+    inner: F,
+}
+
+impl<F, O> Instrumented<F, O> {
+    #[inline]
+    pub const fn new(inner: F, observer: O) -> Self {
+        Instrumented { observer, inner }
+    }
+
+    fn report<S, E>(&self, start: Instant, reload: bool, res: &Result<S, E>)
+    where
+        O: MakeObserver,
+    {
+        self.observer.on_make(&MakeEvent {
+            name: short_type_name(std::any::type_name::<F>()),
+            reload,
+            duration: start.elapsed(),
+            ok: res.is_ok(),
+        });
+    }
+}
+
+impl<F: MakeService, O: MakeObserver> MakeService for Instrumented<F, O> {
+    type Service = F::Service;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let start = Instant::now();
+        let res = self.inner.make_via_ref(old);
+        self.report(start, old.is_some(), &res);
+        res
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F: AsyncMakeService, O: MakeObserver> AsyncMakeService for Instrumented<F, O> {
+    type Service = F::Service;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let start = Instant::now();
+        let res = self.inner.make_via_ref(old).await;
+        self.report(start, old.is_some(), &res);
+        res
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+impl_wrap_inner!(Instrumented < _, O > { observer });
+
+/// A [`FactoryStack`] whose pushed layers are wrapped with [`Instrumented`].
+/// Created by [`FactoryStack::instrument_makes`].
+///
+/// Plain stacks are not wrapped, so instrumenting costs nothing unless it is
+/// switched on.
+pub struct InstrumentedStack<C, F, O> {
+    stack: FactoryStack<C, F>,
+    observer: O,
+}
+
+impl<C, F, O: Clone> InstrumentedStack<C, F, O> {
+    #[inline]
+    pub(crate) fn new(stack: FactoryStack<C, F>, observer: O) -> Self {
+        InstrumentedStack { stack, observer }
+    }
+
+    /// Push a new factory layer, timed by the observer.
+    pub fn push<L>(self, layer: L) -> InstrumentedStack<C, Instrumented<L::Factory, O>, O>
+    where
+        L: FactoryLayer<C, F>,
+    {
+        let observer = self.observer;
+        let stack = self
+            .stack
+            .push(layer)
+            .map_inner(|f| Instrumented::new(f, observer.clone()));
+        InstrumentedStack { stack, observer }
+    }
+
+    /// Stop instrumenting the layers pushed from now on.
+    #[inline]
+    pub fn into_stack(self) -> FactoryStack<C, F> {
+        self.stack
+    }
+}
+
+#[cfg(all(test, not(feature = "boxed-futures")))]
+mod tests {
+    use std::{cell::RefCell, convert::Infallible, rc::Rc};
+
+    use super::{Instrumented, MakeEvent};
+    use crate::{
+        layer::layer_fn, map::MapTargetService, stack::FactoryStack, test_util::block_on,
+        utils::CloneFactory, AsyncMakeService, MakeService,
+    };
+
+    /// Fails the make unless the config opens it.
+    struct Guard<F> {
+        open: bool,
+        inner: F,
+    }
+
+    impl<F: MakeService<Error = Infallible>> MakeService for Guard<F> {
+        type Service = F::Service;
+        type Error = &'static str;
+
+        fn make_via_ref(&self, old: Option<&F::Service>) -> Result<F::Service, &'static str> {
+            if !self.open {
+                return Err("closed");
+            }
+            Ok(self.inner.make_via_ref(old).unwrap_or_else(|e| match e {}))
+        }
+    }
+
+    type Events = Rc<RefCell<Vec<(&'static str, bool, bool)>>>;
+
+    fn observer(events: &Events) -> impl Fn(&MakeEvent) + Clone {
+        let events = events.clone();
+        move |e: &MakeEvent| events.borrow_mut().push((e.name, e.reload, e.ok))
+    }
+
+    #[test]
+    fn reports_the_makes_of_pushed_layers() {
+        let events = Events::default();
+        let factory = |open| {
+            FactoryStack::new(open)
+                .replace(CloneFactory::new(()))
+                .instrument_makes(observer(&events))
+                .push(layer_fn(|open: &bool, inner| Guard { open: *open, inner }))
+                .push(layer_fn(|_: &bool, inner| MapTargetService {
+                    f: (),
+                    inner,
+                }))
+                .into_stack()
+                .into_inner()
+        };
+        let svc = factory(true).make().unwrap();
+        factory(true).make_via_ref(Some(&svc)).unwrap();
+        assert!(factory(false).make().is_err());
+        assert_eq!(
+            *events.borrow(),
+            [
+                ("Guard", false, true),
+                ("MapTargetService", false, true),
+                ("Guard", true, true),
+                ("MapTargetService", true, true),
+                ("Guard", false, false),
+                ("MapTargetService", false, false),
+            ]
+        );
+
+        events.borrow_mut().clear();
+        let factory = Instrumented::new(CloneFactory::new(()), observer(&events));
+        block_on(AsyncMakeService::make(&factory)).unwrap();
+        assert_eq!(*events.borrow(), [("CloneFactory", false, true)]);
+    }
+}
@@ -1,6 +1,10 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::Arc};
 
-use crate::AsyncMakeServiceWrapper;
+use param::{Param, ParamError};
+
+#[cfg(not(feature = "boxed-futures"))]
+use crate::{AsyncMakeService, AsyncMakeServiceWrapper};
+use crate::{MakeService, ServiceMetadata};
 
 /// A trait for creating layered factory wrappers, enabling complex service compositions.
 ///
@@ -20,6 +24,73 @@ pub trait FactoryLayer<C, F> {
     fn layer(&self, config: &C, inner: F) -> Self::Factory;
 }
 
+/// Access to the inner service of a layered service.
+///
+/// Once built, a service chain is a nest of wrappers. Implementing `Layered`
+/// lets generic code walk the chain, e.g. to collect stats or health from
+/// a specific layer.
+///
+/// Services with two arms, like `Fallback` and `Split`, expose their primary arm.
+pub trait Layered {
+    /// The wrapped service.
+    type Inner;
+
+    /// Get a reference to the wrapped service.
+    fn inner(&self) -> &Self::Inner;
+}
+
+/// A layer factory which wraps an inner factory, so the inner factory of a
+/// composed stack can be taken out or replaced while keeping the outer layers.
+///
+/// Layer factories provided by this crate implement it. See
+/// [`FactoryStack::map_wrapped`](crate::stack::FactoryStack::map_wrapped) and
+/// [`FactoryStack::take_layer`](crate::stack::FactoryStack::take_layer).
+pub trait WrapInner {
+    /// The wrapped factory.
+    type Inner;
+    /// This factory with the wrapped factory replaced by `N`.
+    type Wrapped<N>;
+
+    /// Get a reference to the wrapped factory.
+    fn inner_factory(&self) -> &Self::Inner;
+
+    /// Take the wrapped factory out, dropping this layer.
+    fn into_inner_factory(self) -> Self::Inner;
+
+    /// Replace the wrapped factory with the result of `f`, keeping this layer.
+    fn map_inner<N>(self, f: impl FnOnce(Self::Inner) -> N) -> Self::Wrapped<N>;
+}
+
+// Implements `WrapInner` for a struct whose first generic parameter is the
+// type of its `inner` field; the other fields are listed to be moved over.
+macro_rules! impl_wrap_inner {
+    ($ty:ident<_ $(, $g:ident)*> { $($field:ident),* }) => {
+        impl<__F, $($g),*> $crate::layer::WrapInner for $ty<__F, $($g),*> {
+            type Inner = __F;
+            type Wrapped<__N> = $ty<__N, $($g),*>;
+
+            #[inline]
+            fn inner_factory(&self) -> &Self::Inner {
+                &self.inner
+            }
+
+            #[inline]
+            fn into_inner_factory(self) -> Self::Inner {
+                self.inner
+            }
+
+            #[inline]
+            fn map_inner<__N>(self, f: impl FnOnce(Self::Inner) -> __N) -> Self::Wrapped<__N> {
+                $ty {
+                    $($field: self.$field,)*
+                    inner: f(self.inner),
+                }
+            }
+        }
+    };
+}
+pub(crate) use impl_wrap_inner;
+
 /// Creates a `FactoryLayer` from a closure, simplifying the creation of custom layers.
 ///
 /// This function allows for easy creation of `FactoryLayer` implementations without
@@ -52,8 +123,367 @@ where
     }
 }
 
+/// Creates a `FactoryLayer` from a closure which takes a param of the config
+/// by value instead of `&C`.
+///
+/// The param is extracted with `C: Param<T>` once per layer, so the closure can
+/// store it in the factory directly. It works with any config providing `T`,
+/// and mixes with `layer_fn` layers in the same stack:
+///
+/// ```rust
+/// use service_async::{layer::layer_fn_owned, stack::FactoryStack, MakeService};
+///
+/// struct Timeout<F> {
+///     millis: u64,
+///     inner: F,
+/// }
+///
+/// impl<F: MakeService> MakeService for Timeout<F> {
+///     type Service = (u64, F::Service);
+///     type Error = F::Error;
+///
+///     fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, F::Error> {
+///         Ok((self.millis, self.inner.make()?))
+///     }
+/// }
+///
+/// let stack = FactoryStack::new(500u64)
+///     .push_clone_leaf(())
+///     .push(layer_fn_owned(|millis: u64, inner| Timeout { millis, inner }));
+/// assert_eq!(stack.make().unwrap().0, 500);
+/// ```
+pub const fn layer_fn_owned<T, FN>(f: FN) -> LayerFnOwned<T, FN> {
+    LayerFnOwned {
+        f,
+        marker: PhantomData,
+    }
+}
+
+/// A struct that wraps a closure taking an owned param to implement
+/// `FactoryLayer`. Created by [`layer_fn_owned`].
+pub struct LayerFnOwned<T, FN> {
+    f: FN,
+    marker: PhantomData<fn(T)>,
+}
+
+impl<C, T, F, FN, O> FactoryLayer<C, F> for LayerFnOwned<T, FN>
+where
+    C: Param<T>,
+    FN: Fn(T, F) -> O,
+{
+    type Factory = O;
+
+    #[inline]
+    fn layer(&self, config: &C, inner: F) -> Self::Factory {
+        (self.f)(config.param(), inner)
+    }
+}
+
+/// Creates a `FactoryLayer` from a fallible closure, which reads its params
+/// with [`TryParam`](crate::TryParam).
+///
+/// If the closure fails, the [`ParamError`] is returned by `make` and
+/// `validate` of the factory, converted into the error of the inner factory,
+/// instead of panicking while the stack is built. To migrate a layer from
+/// `Param`, bound the config on `TryParam` and replace `layer_fn` with
+/// `try_layer_fn`:
+///
+/// ```rust
+/// use service_async::{
+///     layer::try_layer_fn, stack::FactoryStack, MakeService, ParamError, TryParam,
+/// };
+///
+/// struct Config;
+///
+/// impl TryParam<u16> for Config {
+///     fn try_param(&self) -> Result<u16, ParamError> {
+///         Err(ParamError::missing::<u16>())
+///     }
+/// }
+///
+/// #[derive(Debug)]
+/// struct MakeError(ParamError);
+///
+/// impl From<ParamError> for MakeError {
+///     fn from(e: ParamError) -> Self {
+///         MakeError(e)
+///     }
+/// }
+///
+/// struct Leaf;
+///
+/// impl MakeService for Leaf {
+///     type Service = ();
+///     type Error = MakeError;
+///
+///     fn make_via_ref(&self, _old: Option<&()>) -> Result<(), MakeError> {
+///         Ok(())
+///     }
+/// }
+///
+/// let stack = FactoryStack::new(Config)
+///     .replace(Leaf)
+///     .push(try_layer_fn(|c: &Config, inner| {
+///         let _port: u16 = c.try_param()?;
+///         Ok(inner)
+///     }));
+/// assert!(stack.validate_all().is_err());
+/// assert!(stack.make().is_err());
+/// ```
+pub const fn try_layer_fn<C, FN>(f: FN) -> TryLayerFn<C, FN> {
+    TryLayerFn {
+        f,
+        marker: PhantomData,
+    }
+}
+
+/// A struct that wraps a fallible closure to implement `FactoryLayer`.
+/// Created by [`try_layer_fn`].
+pub struct TryLayerFn<C, FN> {
+    f: FN,
+    marker: PhantomData<fn(C)>,
+}
+
+impl<C, F, FN, O> FactoryLayer<C, F> for TryLayerFn<C, FN>
+where
+    FN: Fn(&C, F) -> Result<O, ParamError>,
+{
+    type Factory = ParamChecked<O>;
+
+    #[inline]
+    fn layer(&self, config: &C, inner: F) -> Self::Factory {
+        ParamChecked {
+            inner: (self.f)(config, inner),
+        }
+    }
+}
+
+/// A factory built by a [`try_layer_fn`] layer, or the [`ParamError`] which
+/// failed it.
+pub struct ParamChecked<F> {
+    inner: Result<F, ParamError>,
+}
+
+impl<F> ParamChecked<F> {
+    /// The factory, or the error of building it.
+    #[inline]
+    pub fn get(&self) -> Result<&F, &ParamError> {
+        self.inner.as_ref()
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> Result<F, ParamError> {
+        self.inner
+    }
+}
+
+impl<F: MakeService> MakeService for ParamChecked<F>
+where
+    F::Error: From<ParamError>,
+{
+    type Service = F::Service;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        match &self.inner {
+            Ok(f) => f.make_via_ref(old),
+            Err(e) => Err(e.clone().into()),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        match &self.inner {
+            Ok(f) => f.validate(),
+            Err(e) => Err(e.clone().into()),
+        }
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        match &self.inner {
+            Ok(f) => f.service_metadata(),
+            Err(e) => ServiceMetadata::of::<Self::Service>().with_config("error", e),
+        }
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F: AsyncMakeService> AsyncMakeService for ParamChecked<F>
+where
+    F::Error: From<ParamError>,
+{
+    type Service = F::Service;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        match &self.inner {
+            Ok(f) => f.make_via_ref(old).await,
+            Err(e) => Err(e.clone().into()),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        match &self.inner {
+            Ok(f) => f.validate(),
+            Err(e) => Err(e.clone().into()),
+        }
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        match &self.inner {
+            Ok(f) => f.service_metadata(),
+            Err(e) => ServiceMetadata::of::<Self::Service>().with_config("error", e),
+        }
+    }
+}
+
+impl<C, F, L: FactoryLayer<C, F> + ?Sized> FactoryLayer<C, F> for &L {
+    type Factory = L::Factory;
+
+    #[inline]
+    fn layer(&self, config: &C, inner: F) -> Self::Factory {
+        (**self).layer(config, inner)
+    }
+}
+
+impl<C, F, L: FactoryLayer<C, F> + ?Sized> FactoryLayer<C, F> for Arc<L> {
+    type Factory = L::Factory;
+
+    #[inline]
+    fn layer(&self, config: &C, inner: F) -> Self::Factory {
+        (**self).layer(config, inner)
+    }
+}
+
+/// A group of related layers, published and pushed as one unit with
+/// [`FactoryStack::push_bundle`](crate::stack::FactoryStack::push_bundle).
+///
+/// A bundle reads its own config slice, `Self::Config`, from the stack config
+/// through `Param`, so it does not constrain the rest of the config. Its layers
+/// are usually a tuple applied against that slice.
+///
+/// ```rust
+/// use service_async::{
+///     layer::{layer_fn, FactoryLayer, LayerBundle},
+///     stack::FactoryStack,
+///     MakeService, Param,
+/// };
+///
+/// #[derive(Clone)]
+/// struct HttpConfig {
+///     max_body: usize,
+/// }
+///
+/// struct Http;
+///
+/// impl<F> LayerBundle<F> for Http {
+///     type Config = HttpConfig;
+///     type Factory = F;
+///
+///     fn bundle(&self, config: &HttpConfig, inner: F) -> F {
+///         let decode = layer_fn(|c: &HttpConfig, inner| {
+///             assert!(c.max_body > 0);
+///             inner
+///         });
+///         let encode = layer_fn(|_: &HttpConfig, inner| inner);
+///         (decode, encode).layer(config, inner)
+///     }
+/// }
+///
+/// struct Config {
+///     http: HttpConfig,
+/// }
+///
+/// impl Param<HttpConfig> for Config {
+///     fn param(&self) -> HttpConfig {
+///         self.http.clone()
+///     }
+/// }
+///
+/// let stack = FactoryStack::new(Config {
+///     http: HttpConfig { max_body: 1024 },
+/// })
+/// .push_clone_leaf(())
+/// .push_bundle(Http);
+/// stack.make().unwrap();
+/// ```
+pub trait LayerBundle<F> {
+    /// The config slice of the bundle.
+    type Config;
+    /// The type of factory the bundle produces.
+    type Factory;
+
+    /// Apply all layers of the bundle to the inner factory.
+    fn bundle(&self, config: &Self::Config, inner: F) -> Self::Factory;
+}
+
+/// A tuple of layers is applied in order, as if each was pushed in turn:
+/// `stack.push((a, b))` is `stack.push(a).push(b)`, so `b` wraps `a`.
+macro_rules! impl_tuple_layer {
+    ($($l:ident)+) => {
+        impl_tuple_layer!(@chain [$($l)+] [] F; $($l)+);
+    };
+    (@chain [$($all:ident)+] [$($bounds:tt)*] $in:ty; $l:ident $($rest:ident)*) => {
+        impl_tuple_layer!(
+            @chain [$($all)+] [$($bounds)* $l: FactoryLayer<C, $in>,]
+            <$l as FactoryLayer<C, $in>>::Factory; $($rest)*
+        );
+    };
+    (@chain [$($all:ident)+] [$($bounds:tt)*] $out:ty;) => {
+        impl<C, F, $($all,)+> FactoryLayer<C, F> for ($($all,)+)
+        where
+            $($bounds)*
+        {
+            type Factory = $out;
+
+            #[inline]
+            #[allow(non_snake_case)]
+            fn layer(&self, config: &C, inner: F) -> Self::Factory {
+                let ($($all,)+) = self;
+                let f = inner;
+                $(let f = $all.layer(config, f);)+
+                f
+            }
+        }
+    };
+}
+
+impl_tuple_layer!(L1 L2);
+impl_tuple_layer!(L1 L2 L3);
+impl_tuple_layer!(L1 L2 L3 L4);
+impl_tuple_layer!(L1 L2 L3 L4 L5);
+impl_tuple_layer!(L1 L2 L3 L4 L5 L6);
+impl_tuple_layer!(L1 L2 L3 L4 L5 L6 L7);
+impl_tuple_layer!(L1 L2 L3 L4 L5 L6 L7 L8);
+
+/// A layer which marks the boundary between sync and async factories: it wraps
+/// a [`MakeService`](crate::MakeService) into an
+/// [`AsyncMakeService`](crate::AsyncMakeService), so async layers can be pushed
+/// on top of it.
+///
+/// See also [`FactoryStack::async_boundary`](crate::stack::FactoryStack::async_boundary).
+#[cfg(not(feature = "boxed-futures"))]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct LayerAsync;
 
+#[cfg(not(feature = "boxed-futures"))]
+impl LayerAsync {
+    #[inline]
+    pub const fn new() -> Self {
+        LayerAsync
+    }
+
+    /// Transform the sync factory before it is wrapped, e.g. to log or
+    /// instrument the factories crossing the boundary.
+    #[inline]
+    pub const fn with_transform<FN>(transform: FN) -> LayerAsyncWith<FN> {
+        LayerAsyncWith { transform }
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
 impl<C, F> FactoryLayer<C, F> for LayerAsync {
     type Factory = AsyncMakeServiceWrapper<F>;
 
@@ -62,3 +492,181 @@ impl<C, F> FactoryLayer<C, F> for LayerAsync {
         AsyncMakeServiceWrapper(inner)
     }
 }
+
+/// A [`LayerAsync`] which transforms the sync factory with a closure before
+/// wrapping it. Created by [`LayerAsync::with_transform`].
+#[cfg(not(feature = "boxed-futures"))]
+#[derive(Debug, Clone, Copy)]
+pub struct LayerAsyncWith<FN> {
+    transform: FN,
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<C, F, FN, O> FactoryLayer<C, F> for LayerAsyncWith<FN>
+where
+    FN: Fn(&C, F) -> O,
+{
+    type Factory = AsyncMakeServiceWrapper<O>;
+
+    #[inline]
+    fn layer(&self, config: &C, inner: F) -> Self::Factory {
+        AsyncMakeServiceWrapper((self.transform)(config, inner))
+    }
+}
+
+#[cfg(all(test, not(feature = "boxed-futures")))]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{
+        layer_fn, layer_fn_owned, try_layer_fn, FactoryLayer, LayerAsync, LayerBundle, Layered,
+    };
+    use crate::{
+        either::FlattenErr,
+        fallback::{AlwaysFallback, Fallback},
+        stack::FactoryStack,
+        test_util::block_on,
+        utils::CloneFactory,
+        AsyncMakeService, MakeService, Param, ParamError, Service, TryParam,
+    };
+
+    #[derive(Clone)]
+    struct Echo(u32);
+
+    impl Service<u32> for Echo {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<u32, Infallible> {
+            Ok(req + self.0)
+        }
+    }
+
+    #[test]
+    fn layered_walks_the_chain() {
+        let svc = FactoryStack::new(())
+            .push_clone_leaf(Fallback::new(Echo(1), Echo(2), AlwaysFallback))
+            .push(FlattenErr::layer())
+            .push_map_target(|req: u32| req * 10)
+            .make()
+            .unwrap();
+        let fallback: &Fallback<Echo, Echo> = svc.inner().inner();
+        assert_eq!(fallback.inner().0, 1);
+    }
+
+    #[test]
+    fn async_boundary() {
+        let factory = LayerAsync.layer(&(), CloneFactory::new(Echo(1)));
+        let svc = block_on(AsyncMakeService::make(&factory)).unwrap();
+        assert_eq!(block_on(svc.call(1)), Ok(2));
+
+        let stack = FactoryStack::new(10)
+            .push_clone_leaf(Echo(1))
+            .async_boundary_with(|c: &u32, f: CloneFactory<Echo>| {
+                CloneFactory::new(Echo(f.into_inner().0 + c))
+            });
+        let svc = block_on(stack.make_async()).unwrap();
+        assert_eq!(block_on(svc.call(1)), Ok(12));
+    }
+
+    fn tag<C>(
+        name: &'static str,
+    ) -> impl FactoryLayer<C, Vec<&'static str>, Factory = Vec<&'static str>> {
+        layer_fn(move |_: &C, mut inner: Vec<&'static str>| {
+            inner.push(name);
+            inner
+        })
+    }
+
+    #[test]
+    fn tuple_layers_apply_in_order() {
+        let stack = FactoryStack::new(()).replace(vec![]);
+        let tuple = stack.push((tag("a"), tag("b"), tag("c")));
+        assert_eq!(tuple.into_inner(), ["a", "b", "c"]);
+
+        let nested = FactoryStack::new(())
+            .replace(vec![])
+            .push(((tag("a"), tag("b")), tag("c")));
+        assert_eq!(nested.into_inner(), ["a", "b", "c"]);
+    }
+
+    /// Tags the factory with the names of its config slice.
+    struct Names;
+
+    impl LayerBundle<Vec<&'static str>> for Names {
+        type Config = (&'static str, &'static str);
+        type Factory = Vec<&'static str>;
+
+        fn bundle(&self, config: &Self::Config, inner: Vec<&'static str>) -> Self::Factory {
+            (tag(config.0), tag(config.1)).layer(config, inner)
+        }
+    }
+
+    #[test]
+    fn bundle_reads_its_config_slice() {
+        let stack = FactoryStack::new(("a", "b"))
+            .replace(vec!["leaf"])
+            .push_bundle(Names)
+            .push(tag("c"));
+        assert_eq!(stack.into_inner(), ["leaf", "a", "b", "c"]);
+    }
+
+    struct Port(Option<u16>);
+
+    impl TryParam<u16> for Port {
+        fn try_param(&self) -> Result<u16, ParamError> {
+            self.0.ok_or_else(ParamError::missing::<u16>)
+        }
+    }
+
+    /// Makes an [`Echo`] adding the port.
+    struct EchoPort(u16);
+
+    impl MakeService for EchoPort {
+        type Service = Echo;
+        type Error = ParamError;
+
+        fn make_via_ref(&self, _old: Option<&Echo>) -> Result<Echo, ParamError> {
+            Ok(Echo(self.0.into()))
+        }
+    }
+
+    #[test]
+    fn try_layer_fn_defers_the_error() {
+        let layer = try_layer_fn(|c: &Port, _: ()| Ok(EchoPort(c.try_param()?)));
+
+        let factory = layer.layer(&Port(Some(80)), ());
+        factory.validate().unwrap();
+        assert_eq!(block_on(factory.make().unwrap().call(1)), Ok(81));
+
+        let factory = layer.layer(&Port(None), ());
+        assert_eq!(factory.validate(), Err(ParamError::missing::<u16>()));
+        assert!(factory.make().is_err());
+        assert!(factory.get().is_err());
+    }
+
+    /// A config which is not `Clone`, providing a name.
+    struct Named(String);
+
+    impl Param<&'static str> for Named {
+        fn param(&self) -> &'static str {
+            if self.0.is_empty() {
+                "anonymous"
+            } else {
+                "named"
+            }
+        }
+    }
+
+    #[test]
+    fn layer_fn_owned_takes_a_param() {
+        let stack = FactoryStack::new(Named(String::new()))
+            .replace(vec![])
+            .push(tag("a"))
+            .push(layer_fn_owned(|name: &'static str, mut inner: Vec<_>| {
+                inner.push(name);
+                inner
+            }));
+        assert_eq!(stack.into_inner(), ["a", "anonymous"]);
+    }
+}
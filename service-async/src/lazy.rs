@@ -0,0 +1,367 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::poll_fn,
+    sync::{Arc, Mutex, MutexGuard, OnceLock, Weak},
+    task::{Poll, Waker},
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Service, ServiceMetadata,
+};
+
+/// Error returned by [`Lazy`].
+#[derive(Debug)]
+pub enum LazyError<ME, E> {
+    /// The service could not be made on first use.
+    Make(ME),
+    /// The service failed.
+    Inner(E),
+}
+
+impl<ME: Display, E: Display> Display for LazyError<ME, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LazyError::Make(e) => write!(f, "lazy make service error: {e}"),
+            LazyError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<ME: Error + 'static, E: Error + 'static> Error for LazyError<ME, E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LazyError::Make(e) => Some(e),
+            LazyError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A service made by its factory `F` on the first call instead of up front,
+/// for rarely used routes whose service is too costly to make on every reload.
+///
+/// Concurrent first calls wait for a single make. When it fails, the calls
+/// waiting for it retry, and the next call makes again. The factory makes the
+/// service against the one of the `Lazy` this one replaced on reload, if that
+/// one was made and is still alive: only a weak reference to it is kept, so it
+/// is dropped with the replaced `Lazy` rather than held until the first call.
+///
+/// [`init`](Self::init) makes the service right away, e.g. to warm up a route
+/// known to be hot; [`LazyFactory::eager`] does so on every async make.
+pub struct Lazy<S, F> {
+    factory: F,
+    cell: OnceLock<Arc<S>>,
+    state: Mutex<InitState<S>>,
+}
+
+struct InitState<S> {
+    initializing: bool,
+    prev: Weak<S>,
+    wakers: Vec<Waker>,
+}
+
+enum Turn<S> {
+    Ready,
+    Init(Option<Arc<S>>),
+}
+
+impl<S, F> Lazy<S, F> {
+    pub fn new(factory: F) -> Self {
+        Self::with_prev(factory, Weak::new())
+    }
+
+    fn with_prev(factory: F, prev: Weak<S>) -> Self {
+        Lazy {
+            factory,
+            cell: OnceLock::new(),
+            state: Mutex::new(InitState {
+                initializing: false,
+                prev,
+                wakers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Get the service, if it has been made.
+    #[inline]
+    pub fn get(&self) -> Option<&S> {
+        self.cell.get().map(|s| &**s)
+    }
+
+    #[inline]
+    pub fn is_initialized(&self) -> bool {
+        self.cell.get().is_some()
+    }
+
+    #[inline]
+    pub fn factory(&self) -> &F {
+        &self.factory
+    }
+
+    fn lock(&self) -> MutexGuard<'_, InitState<S>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The made service, or the one to make the service against.
+    fn current(&self) -> Weak<S> {
+        match self.cell.get() {
+            Some(svc) => Arc::downgrade(svc),
+            None => self.lock().prev.clone(),
+        }
+    }
+
+    /// Make the service now if it has not been made, and get it.
+    pub async fn init(&self) -> Result<&S, F::Error>
+    where
+        F: AsyncMakeService<Service = S>,
+    {
+        loop {
+            if let Some(svc) = self.get() {
+                return Ok(svc);
+            }
+            let turn = poll_fn(|cx| {
+                let mut state = self.lock();
+                if self.cell.get().is_some() {
+                    return Poll::Ready(Turn::Ready);
+                }
+                if !state.initializing {
+                    state.initializing = true;
+                    return Poll::Ready(Turn::Init(state.prev.upgrade()));
+                }
+                if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    state.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            })
+            .await;
+            let Turn::Init(prev) = turn else {
+                continue;
+            };
+            // Let the waiters in when the make ends, fails or is cancelled.
+            let _guard = InitGuard(self);
+            let svc = self.factory.make_via_ref(prev.as_deref()).await?;
+            let _ = self.cell.set(Arc::new(svc));
+            self.lock().prev = Weak::new();
+        }
+    }
+}
+
+struct InitGuard<'a, S, F>(&'a Lazy<S, F>);
+
+impl<S, F> Drop for InitGuard<'_, S, F> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.initializing = false;
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<S, F, R> Service<R> for Lazy<S, F>
+where
+    F: AsyncMakeService<Service = S>,
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = LazyError<F::Error, S::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let svc = self.init().await.map_err(LazyError::Make)?;
+        svc.call(req).await.map_err(LazyError::Inner)
+    }
+}
+
+/// A factory of [`Lazy`] services, which defer the make of the inner factory
+/// `F` to their first call.
+///
+/// Making a `Lazy` does no I/O, so it is a [`MakeService`] even though `F` is
+/// only an [`AsyncMakeService`]. `F` is validated on every make, so a bad
+/// config still fails the reload.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyFactory<F> {
+    eager: bool,
+    inner: F,
+}
+
+impl<F> LazyFactory<F> {
+    #[inline]
+    pub const fn new(inner: F) -> Self {
+        LazyFactory {
+            eager: false,
+            inner,
+        }
+    }
+
+    /// Make the inner service as soon as the `Lazy` is made asynchronously,
+    /// instead of on the first call. Sync makes stay lazy.
+    #[inline]
+    pub const fn eager(mut self, eager: bool) -> Self {
+        self.eager = eager;
+        self
+    }
+
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self> {
+        layer_fn(|_: &C, inner| LazyFactory::new(inner))
+    }
+}
+
+impl<F> MakeService for LazyFactory<F>
+where
+    F: AsyncMakeService + Clone,
+{
+    type Service = Lazy<F::Service, F>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.inner.validate()?;
+        Ok(Lazy::with_prev(
+            self.inner.clone(),
+            old.map_or_else(Weak::new, Lazy::current),
+        ))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F> AsyncMakeService for LazyFactory<F>
+where
+    F: AsyncMakeService + Clone,
+{
+    type Service = Lazy<F::Service, F>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let svc = MakeService::make_via_ref(self, old)?;
+        if self.eager {
+            svc.init().await?;
+        }
+        Ok(svc)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(LazyFactory<_> { eager });
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, convert::Infallible, pin::pin, rc::Rc};
+
+    use super::{LazyError, LazyFactory};
+    use crate::{
+        test_util::{block_on, poll_once},
+        yielding::yield_now,
+        AsyncMakeService, MakeService, Service,
+    };
+
+    struct Route {
+        generation: usize,
+    }
+
+    impl Service<u32> for Route {
+        type Response = (u32, usize);
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<(u32, usize), Infallible> {
+            Ok((req, self.generation))
+        }
+    }
+
+    /// Makes a [`Route`] after yielding once, counting its makes.
+    #[derive(Clone, Default)]
+    struct RouteFactory {
+        makes: Rc<Cell<usize>>,
+        fail: Rc<Cell<bool>>,
+    }
+
+    impl AsyncMakeService for RouteFactory {
+        type Service = Route;
+        type Error = &'static str;
+
+        async fn make_via_ref(&self, old: Option<&Route>) -> Result<Route, &'static str> {
+            yield_now().await;
+            self.makes.set(self.makes.get() + 1);
+            if self.fail.get() {
+                return Err("unreachable upstream");
+            }
+            Ok(Route {
+                generation: old.map_or(0, |o| o.generation + 1),
+            })
+        }
+    }
+
+    #[test]
+    fn makes_once_on_first_call() {
+        let inner = RouteFactory::default();
+        let factory = LazyFactory::new(inner.clone());
+        let svc = MakeService::make(&factory).unwrap();
+        assert!(!svc.is_initialized());
+        assert_eq!(inner.makes.get(), 0);
+
+        inner.fail.set(true);
+        assert!(matches!(
+            block_on(svc.call(1)),
+            Err(LazyError::Make("unreachable upstream"))
+        ));
+        inner.fail.set(false);
+
+        // Concurrent first calls share one make.
+        let mut first = pin!(svc.call(2));
+        let mut second = pin!(svc.call(3));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert!(poll_once(second.as_mut()).is_pending());
+        assert_eq!(block_on(first).ok(), Some((2, 0)));
+        assert_eq!(block_on(second).ok(), Some((3, 0)));
+        assert_eq!(inner.makes.get(), 2);
+        assert!(svc.is_initialized());
+
+        // A reload makes against the old service, on the first call again.
+        let new = MakeService::make_via_ref(&factory, Some(&svc)).unwrap();
+        assert!(new.get().is_none());
+        assert_eq!(block_on(new.call(4)).ok(), Some((4, 1)));
+        // An unused service passes on the one it was to be made against.
+        let unused = MakeService::make_via_ref(&factory, Some(&new)).unwrap();
+        let newer = MakeService::make_via_ref(&factory, Some(&unused)).unwrap();
+        assert_eq!(block_on(newer.call(5)).ok(), Some((5, 2)));
+    }
+
+    #[test]
+    fn replaced_service_is_not_kept_alive() {
+        let factory = LazyFactory::new(RouteFactory::default());
+        let old = MakeService::make(&factory).unwrap();
+        block_on(old.init()).unwrap();
+        let new = MakeService::make_via_ref(&factory, Some(&old)).unwrap();
+        let unused = MakeService::make_via_ref(&factory, Some(&new)).unwrap();
+
+        // Once the made service is dropped, the next one is made from scratch.
+        drop((old, new));
+        assert_eq!(block_on(unused.call(1)).ok(), Some((1, 0)));
+    }
+
+    #[test]
+    fn eager_makes_on_async_make() {
+        let inner = RouteFactory::default();
+        let factory = LazyFactory::new(inner.clone()).eager(true);
+        assert!(MakeService::make(&factory).unwrap().get().is_none());
+        let svc = block_on(AsyncMakeService::make(&factory)).unwrap();
+        assert_eq!(svc.get().map(|r| r.generation), Some(0));
+        assert_eq!(inner.makes.get(), 1);
+    }
+}
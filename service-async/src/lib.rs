@@ -8,12 +8,12 @@
 //! The Tower framework's `Service` trait, while powerful, presents some challenges:
 //!
 //! 1. Limited Capture Scope: As a future factory used serially and spawned for parallel
-//! execution, Tower's `Service` futures cannot capture `&self` or `&mut self`. This
-//! necessitates cloning and moving ownership into the future.
+//!    execution, Tower's `Service` futures cannot capture `&self` or `&mut self`. This
+//!    necessitates cloning and moving ownership into the future.
 //!
 //! 2. Complex Poll-Style Implementation: Tower's `Service` trait is defined in a
-//! poll-style, requiring manual state management. This often leads to verbose
-//! implementations using `Box<Pin<...>>` to leverage async/await syntax.
+//!    poll-style, requiring manual state management. This often leads to verbose
+//!    implementations using `Box<Pin<...>>` to leverage async/await syntax.
 //!
 //! These limitations often result in code patterns like:
 //!
@@ -45,10 +45,10 @@
 //! trait, designed to simplify implementation and improve performance:
 //!
 //! 1. Efficient Borrowing: By using `impl Trait` in the return position, futures
-//! can now capture `&self` or `&mut self`, eliminating unnecessary cloning.
+//!    can now capture `&self` or `&mut self`, eliminating unnecessary cloning.
 //!
 //! 2. Zero-Cost Abstractions: Utilizing `impl Trait` instead of `Box<dyn...>`
-//! allows for more inline code optimization, especially for operations not crossing await points.
+//!    allows for more inline code optimization, especially for operations not crossing await points.
 //!
 //! This approach combines the power of `impl Trait` with a refined  [`Service`](crate::Service)
 //! trait to offer both flexibility and performance improvements.
@@ -417,19 +417,162 @@
 //! }
 //! ```
 
-use std::future::Future;
+use std::{future::Future, rc::Rc, sync::Arc};
 
+/// Declare items which need `impl Trait` in trait methods, and are not available
+/// with the `boxed-futures` feature.
+macro_rules! cfg_native_async {
+    ($($item:item)*) => {
+        $(
+            #[cfg(not(feature = "boxed-futures"))]
+            $item
+        )*
+    };
+}
+
+/// Provides `TrackingAllocator` and `Traced`, which report the allocations made while making a stack.
+#[cfg(feature = "alloc-metrics")]
+pub mod alloc_metrics;
+/// Defines the `Classify` trait, which tells middleware whether a call failed and whether to retry it.
+pub mod classify;
+/// Provides `ConfigSnapshot`, a shared and versioned stack config.
+pub mod config;
 /// Provides the `Either` type for flexible service composition and conditional logic in layered architectures.
 pub mod either;
+/// Provides `Instrumented` and `FactoryStack::instrument_makes`, which time the make of each layer of a stack.
+pub mod instrument;
 /// Defines the `FactoryLayer` trait and utilities for creating composable factory wrappers in service architectures.
 pub mod layer;
+/// Provides `ServiceMetadata`, a description of the services a factory makes, for admin endpoints.
+pub mod metadata;
 /// Provides the `FactoryStack` for composing and managing complex, layered service architectures.
 pub mod stack;
+/// Defines the `Timer` and `Clock` traits, the time source of time-based middleware, with a mock for tests.
+pub mod time;
 /// Utilities to work with Serivices &  factories
 pub mod utils;
 
+cfg_native_async! {
+    /// Provides the `AdaptiveConcurrency` middleware, which adjusts its concurrency limit from observed latency.
+    pub mod adaptive;
+    /// Provides `AsyncParam`, for parameters which take I/O to resolve while making a service.
+    pub mod async_param;
+    /// Provides adapters between `Service` and the boxed-future service traits of crates built on `#[async_trait]`.
+    pub mod async_trait_compat;
+    /// Provides the `Authenticate` middleware, which sets the `Identity` of authenticated requests in their context.
+    pub mod auth;
+    /// Provides services which distribute requests over multiple backends.
+    pub mod balance;
+    /// Provides the `Batch` middleware, which passes requests to the inner service in batches.
+    pub mod batch;
+    /// Provides the `BlockingDetector` middleware, which reports long polls of the inner future in debug builds.
+    pub mod blocking;
+    /// Provides helpers for services which take borrowed, non-`'static` requests, and their boxed forms.
+    pub mod borrow;
+    /// Provides the `Budgeted` middleware, which fails calls exceeding a response size or time budget.
+    pub mod budget;
+    /// Provides `Buffer`, a cloneable handle which queues requests to a service owned by a worker task.
+    pub mod buffer;
+    /// Provides the `Bulkhead` middleware, which isolates concurrency limits per request key.
+    pub mod bulkhead;
+    /// Provides `Bus`, a typed pub/sub bus for sibling services of a stack to exchange events.
+    pub mod bus;
+    /// Provides the `FaultInject` middleware, which injects latency and errors for resilience tests.
+    #[cfg(feature = "chaos")]
+    pub mod chaos;
+    /// Provides `DynAnyService`, which erases the request and response types of a service to `Box<dyn Any>`.
+    pub mod dynamic;
+    /// Provides `DuplexService`, the shape of connection handlers, with adapters and a boxed form.
+    pub mod duplex;
+    /// Provides the `EpochGate` middleware, which detects services made from an outdated config.
+    pub mod epoch;
+    /// Provides `Framed`, which serves a framed connection from a stream of requests into a sink of responses.
+    pub mod framed;
+    /// Provides `Fallback`, which fails over to a second service at runtime, and `StaticFallback`, which serves degraded responses.
+    pub mod fallback;
+    /// Provides `Graveyard`, which parks replaced services until their calls finish and drops them in the background.
+    pub mod graveyard;
+    /// Provides the `Hooks` middleware, which runs user hooks on the response or error of each call.
+    pub mod hooks;
+    /// Provides `Lazy`, a service made by its factory on the first call.
+    pub mod lazy;
+    /// Provides `LoadMetrics` and the `LoadProbe` middleware, which tracks in-flight calls and latency.
+    pub mod load;
+    /// Provides `LocalShared`, which shares a service within one thread through `Rc<RefCell<_>>`.
+    pub mod local;
+    /// Provides the `Offload` middleware, which runs CPU-heavy work on a `BlockingSpawner`.
+    pub mod offload;
+    /// Provides `Overrides`, which override parameters of the config for a single make.
+    pub mod overrides;
+    /// Provides the `CatchPanic` middleware, which turns panics of the inner service into errors.
+    pub mod panic;
+    /// Provides `ParallelMake`, which makes the services of sibling factories concurrently.
+    pub mod parallel;
+    /// Provides `WorkerPool`, which runs one owned service per worker for services needing `&mut self`.
+    pub mod pool;
+    /// Provides the `PriorityQueue` middleware, which admits requests to the inner service by priority.
+    pub mod priority;
+    /// Provides the `ProtocolSwitch` service, which dispatches connections to chains by their first bytes.
+    pub mod protocol;
+    /// Provides `Pushback`, the retry-after and load hint of overloaded layers, and the `HonorPushback` middleware.
+    pub mod pushback;
+    /// Provides the `ReapIdle` trait and the `IdleReaper` driver, which releases idle resources of the live service.
+    pub mod reap;
+    /// Provides `ReloadController`, which rebuilds the service in a `ServiceSlot` from a config channel, and `ReloadGroup`, which swaps several services together.
+    pub mod reload;
+    /// Provides `RequestId` and the `SetRequestId` middleware, which gives each request a unique ID.
+    pub mod request_id;
+    /// Provides `ResilientMake`, which retries failed makes with backoff and can keep the old service.
+    pub mod resilient;
+    /// Provides the `Router` service, which sends requests to per-route services by a key of the request.
+    pub mod router;
+    /// Provides the `Scheduler` trait with FIFO, LIFO, deadline and weighted fair policies, and the `Scheduled` middleware.
+    pub mod scheduler;
+    /// Provides the `Scoped` middleware, which awaits or cancels the sub-tasks of a call when it ends.
+    pub mod scope;
+    /// Provides `Server`, which drives a listener with services made by a factory and reloads them.
+    pub mod serve;
+    /// Provides `service_fn_with_state`, which builds a service from an async closure and its state, and `ContextFactory`, which makes handler services from the config.
+    pub mod service_fn;
+    /// Provides the `Sharded` service, which routes requests over several instances of a service by key.
+    pub mod shard;
+    /// Provides the `Shutdown` trait, which releases the I/O resources of a replaced service, and `ShutdownCoordinator`, which shuts a stack down in phases.
+    pub mod shutdown;
+    /// Provides `BodyStream` and middleware for services which respond with a stream.
+    pub mod streaming;
+    /// Provides the `Split` service, which sends a share of requests to a second chain.
+    pub mod split;
+    /// Provides `CollectStats` and `Timed`, which return per-request timings with the response.
+    pub mod stats;
+    /// Provides the `Sticky` middleware, which routes the requests of a session to the same backend.
+    pub mod sticky;
+    /// Provides the `Toggle` middleware, which bypasses the inner service while switched off.
+    pub mod toggle;
+    /// Provides the `Transform` middleware, which decodes requests and encodes responses, e.g. for compression.
+    pub mod transform;
+    /// Provides `StackValidator`, which checks the order of the layers of a stack.
+    pub mod validator;
+    /// Provides the `MapRequestVersion` middleware, which converts `Versioned` requests to the version a stack serves.
+    pub mod version;
+    /// Provides the `Warmup` trait, which prepares a service made by a stack before it takes requests.
+    pub mod warmup;
+    /// Provides the `SlowRequestWatchdog` middleware, which reports calls slower than a threshold.
+    pub mod watchdog;
+    /// Provides `yield_now` and the `YieldEvery` middleware, which yields to the runtime every few calls.
+    pub mod yielding;
+    /// Provides the `TlsAccept` middleware, which accepts TLS on incoming connections.
+    #[cfg(feature = "tls")]
+    pub mod tls;
+    /// Provides adapters to `tower::Service` and `tower::MakeService`, for server frameworks built on tower, and `WrapTowerLayer`, which inserts tower middleware into a stack.
+    #[cfg(feature = "tower")]
+    pub mod tower;
+
+    mod random;
+    mod semaphore;
+}
+
 mod map;
-pub use map::MapTargetService;
+pub use map::{MapErrInto, MapTarget, MapTargetService};
 mod boxed;
 
 /// Trait for converting a service into a boxed service.
@@ -438,17 +581,39 @@ pub use boxed::BoxService;
 // A factory for creating boxed services.
 pub use boxed::BoxServiceFactory;
 
-/// A type-erased wrapper for asynchronous service factories.
-pub use boxed::BoxedAsyncMakeService;
+cfg_native_async! {
+    /// A type-erased wrapper for asynchronous service factories.
+    pub use boxed::BoxedAsyncMakeService;
+    /// Make a boxed service, migrating the state of an old boxed service asynchronously.
+    pub use boxed::make_via_downcast_async;
+}
 
 /// A type-erased wrapper for services, enabling dynamic dispatch.
 pub use boxed::BoxedService;
 
+/// Make a boxed service, migrating the state of an old boxed service of the same type.
+pub use boxed::make_via_downcast;
+
+/// A description of the services a factory makes.
+pub use metadata::ServiceMetadata;
+
 mod make_service;
 pub use make_service::{
-    ArcMakeBoxedService, ArcMakeService, AsyncMakeService, AsyncMakeServiceWrapper,
-    BoxedMakeBoxedService, BoxedMakeService, MakeService,
+    ArcMakeBoxedService, ArcMakeService, BoxedMakeBoxedService, BoxedMakeService, InspectMake,
+    MakeService, MakeServiceBatchExt, MapMakeErr, Zip,
 };
+cfg_native_async! {
+    pub use make_service::{
+        assert_async_service, assert_service, AsyncMakeService, AsyncMakeServiceBatchExt,
+        AsyncMakeServiceExt, AsyncMakeServiceOf, AsyncMakeServiceWrapper, MakeServiceOf,
+        ProofOfService,
+    };
+}
+
+mod compat;
+
+#[cfg(test)]
+mod test_util;
 
 /// Item of type T has been set in a certain_map slot.
 pub use param::Param;
@@ -476,6 +641,12 @@ pub use param::ParamRemove;
 /// from the slot, leaving it vacant.
 pub use param::ParamTake;
 
+/// Item of type T may be missing or invalid, and is extracted as a `Result`.
+pub use param::{ParamError, TryParam};
+
+/// Generates a typed context map with `Vacant`/`Occupied` slots implementing the `Param*` traits.
+pub use param::{context_map, Occupied, Slot, Vacant};
+
 /// This `Service` trait leverages `impl Trait` to offer a efficient and flexible
 /// approach to building asynchronous services in Rust. It addresses key challenges
 /// faced with Tower's `Service` trait:
@@ -496,6 +667,7 @@ pub use param::ParamTake;
 ///
 /// This design maintains reference relationships, incurring costs only when mutability
 /// is required, unlike Tower's shared ownership model where each share has an associated cost.
+#[cfg(not(feature = "boxed-futures"))]
 pub trait Service<Request> {
     /// The type of response returned by this service.
     type Response;
@@ -519,3 +691,103 @@ pub trait Service<Request> {
     /// response or an error.
     fn call(&self, req: Request) -> impl Future<Output = Result<Self::Response, Self::Error>>;
 }
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<S, Request> Service<Request> for Arc<S>
+where
+    S: Service<Request> + ?Sized,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    #[inline]
+    fn call(&self, req: Request) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        (**self).call(req)
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<S, Request> Service<Request> for Rc<S>
+where
+    S: Service<Request> + ?Sized,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    #[inline]
+    fn call(&self, req: Request) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        (**self).call(req)
+    }
+}
+
+/// The `Service` trait for compilers without `impl Trait` in trait methods.
+///
+/// With the `boxed-futures` feature, the future returned by `call` is named by the
+/// associated `Future` type. Use [`impl_service!`] to implement the trait in a way
+/// that compiles both with and without the feature.
+///
+/// The feature is not additive: `async fn call` impls do not compile with it, so
+/// only the final binary should enable it, never a library.
+#[cfg(feature = "boxed-futures")]
+pub trait Service<Request> {
+    /// The type of response returned by this service.
+    type Response;
+
+    /// The type of error that this service can produce.
+    type Error;
+
+    /// The future returned by `call`.
+    type Future<'a>: Future<Output = Result<Self::Response, Self::Error>>
+    where
+        Self: 'a,
+        Request: 'a;
+
+    /// Asynchronously process the request and return the response.
+    fn call<'a>(&'a self, req: Request) -> Self::Future<'a>
+    where
+        Request: 'a;
+}
+
+#[cfg(feature = "boxed-futures")]
+impl<S, Request> Service<Request> for Arc<S>
+where
+    S: Service<Request> + ?Sized,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future<'a>
+        = S::Future<'a>
+    where
+        Self: 'a,
+        Request: 'a;
+
+    #[inline]
+    fn call<'a>(&'a self, req: Request) -> Self::Future<'a>
+    where
+        Request: 'a,
+    {
+        (**self).call(req)
+    }
+}
+
+#[cfg(feature = "boxed-futures")]
+impl<S, Request> Service<Request> for Rc<S>
+where
+    S: Service<Request> + ?Sized,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future<'a>
+        = S::Future<'a>
+    where
+        Self: 'a,
+        Request: 'a;
+
+    #[inline]
+    fn call<'a>(&'a self, req: Request) -> Self::Future<'a>
+    where
+        Request: 'a,
+    {
+        (**self).call(req)
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    time::Clock,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Weight of a new latency sample in the moving average, as a shift: 1/8.
+const EWMA_SHIFT: u32 = 3;
+
+/// A snapshot of [`LoadMetrics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LoadSnapshot {
+    pub in_flight: usize,
+    pub queued: usize,
+    /// Exponentially weighted moving average of the call latency.
+    pub latency: Duration,
+}
+
+/// Load counters of a service, updated by [`LoadProbe`] and sampled by users,
+/// e.g. to report load upstream.
+///
+/// Clones share the counters, which are plain atomics, so sampling is cheap.
+#[derive(Debug, Clone, Default)]
+pub struct LoadMetrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    in_flight: AtomicUsize,
+    queued: AtomicUsize,
+    latency_nanos: AtomicU64,
+}
+
+impl LoadMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of calls in progress.
+    #[inline]
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests waiting to be processed.
+    #[inline]
+    pub fn queued(&self) -> usize {
+        self.inner.queued.load(Ordering::Relaxed)
+    }
+
+    /// Moving average of the call latency, zero before the first call.
+    #[inline]
+    pub fn latency(&self) -> Duration {
+        Duration::from_nanos(self.inner.latency_nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn snapshot(&self) -> LoadSnapshot {
+        LoadSnapshot {
+            in_flight: self.in_flight(),
+            queued: self.queued(),
+            latency: self.latency(),
+        }
+    }
+
+    /// Fold a latency sample into the moving average.
+    pub fn record_latency(&self, latency: Duration) {
+        let sample = latency.as_nanos().min(u64::MAX as u128) as u64;
+        let _ = self
+            .inner
+            .latency_nanos
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+                Some(if avg == 0 {
+                    sample
+                } else {
+                    avg - (avg >> EWMA_SHIFT) + (sample >> EWMA_SHIFT)
+                })
+            });
+    }
+
+    /// Count a call as in flight until the guard is dropped.
+    pub fn track_in_flight(&self) -> LoadGuard {
+        self.inner.in_flight.fetch_add(1, Ordering::Relaxed);
+        LoadGuard {
+            metrics: self.clone(),
+            queued: false,
+        }
+    }
+
+    /// Count a request as queued until the guard is dropped. For middleware
+    /// which holds requests back, like a queue in front of a limit.
+    pub fn track_queued(&self) -> LoadGuard {
+        self.inner.queued.fetch_add(1, Ordering::Relaxed);
+        LoadGuard {
+            metrics: self.clone(),
+            queued: true,
+        }
+    }
+}
+
+/// Decrements a counter of [`LoadMetrics`] when dropped.
+#[derive(Debug)]
+pub struct LoadGuard {
+    metrics: LoadMetrics,
+    queued: bool,
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        let counter = if self.queued {
+            &self.metrics.inner.queued
+        } else {
+            &self.metrics.inner.in_flight
+        };
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A middleware which updates [`LoadMetrics`] with the in-flight calls and
+/// latency of its inner service.
+///
+/// Calls waiting in a queue below it count as in flight. The metrics are kept
+/// across reloads. It is its own factory; see also
+/// [`FactoryStack::push_load_probe`](crate::stack::FactoryStack::push_load_probe).
+///
+/// The clock `CL` which measures the latency is taken from the stack config
+/// through `Param<CL>`.
+pub struct LoadProbe<T, CL> {
+    metrics: LoadMetrics,
+    clock: CL,
+    inner: T,
+}
+
+impl<T, CL> LoadProbe<T, CL> {
+    pub fn layer<C>(metrics: LoadMetrics) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<CL>,
+    {
+        layer_fn(move |c: &C, inner| LoadProbe {
+            metrics: metrics.clone(),
+            clock: c.param(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn metrics(&self) -> &LoadMetrics {
+        &self.metrics
+    }
+}
+
+impl<T, CL, R> Service<R> for LoadProbe<T, CL>
+where
+    T: Service<R>,
+    CL: Clock,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let _guard = self.metrics.track_in_flight();
+        let start = self.clock.now();
+        let res = self.inner.call(req).await;
+        self.metrics
+            .record_latency(self.clock.now().saturating_duration_since(start));
+        res
+    }
+}
+
+impl<F: MakeService, CL: Clone> MakeService for LoadProbe<F, CL> {
+    type Service = LoadProbe<F::Service, CL>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(LoadProbe {
+            metrics: self.metrics.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, CL: Clone> AsyncMakeService for LoadProbe<F, CL> {
+    type Service = LoadProbe<F::Service, CL>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(LoadProbe {
+            metrics: self.metrics.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(LoadProbe<_, CL> { metrics, clock });
+
+impl<T, CL> Layered for LoadProbe<T, CL> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(LoadProbe<T, CL>);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin, time::Duration};
+
+    use super::{LoadMetrics, LoadProbe, LoadSnapshot};
+    use crate::{
+        layer::FactoryLayer,
+        stack::FactoryStack,
+        test_util::{block_on, poll_once},
+        time::MockTimer,
+        yielding::yield_now,
+        MakeService, Service,
+    };
+
+    /// Moves the mock time forward while handling a call.
+    #[derive(Clone)]
+    struct Advance(MockTimer, Duration);
+
+    impl Service<()> for Advance {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, _req: ()) -> Result<(), Infallible> {
+            yield_now().await;
+            self.0.advance(self.1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn latency_from_clock() {
+        let timer = MockTimer::new();
+        let metrics = LoadMetrics::new();
+        let probe = LoadProbe::<_, MockTimer>::layer(metrics.clone())
+            .layer(&timer, Advance(timer.clone(), Duration::from_millis(30)));
+
+        block_on(probe.call(())).unwrap();
+        assert_eq!(metrics.latency(), Duration::from_millis(30));
+        assert_eq!(metrics.in_flight(), 0);
+    }
+
+    #[test]
+    fn counters_and_moving_average() {
+        let metrics = LoadMetrics::new();
+        metrics.record_latency(Duration::from_millis(80));
+        metrics.record_latency(Duration::from_millis(160));
+        assert_eq!(metrics.latency(), Duration::from_millis(90));
+
+        let queued = metrics.track_queued();
+        let in_flight = metrics.clone().track_in_flight();
+        let snapshot = LoadSnapshot {
+            in_flight: 1,
+            queued: 1,
+            latency: Duration::from_millis(90),
+        };
+        assert_eq!(metrics.snapshot(), snapshot);
+        drop((queued, in_flight));
+        assert_eq!((metrics.in_flight(), metrics.queued()), (0, 0));
+    }
+
+    #[test]
+    fn probe_survives_reload() {
+        let timer = MockTimer::new();
+        let (stack, metrics) = FactoryStack::new(timer.clone())
+            .push_clone_leaf(Advance(timer.clone(), Duration::from_millis(8)))
+            .push_load_probe::<MockTimer>();
+        let old = stack.make().unwrap();
+        let svc = stack.into_inner().make_via_ref(Some(&old)).unwrap();
+
+        let mut call = pin!(svc.call(()));
+        assert!(poll_once(call.as_mut()).is_pending());
+        assert_eq!(old.metrics().in_flight(), 1);
+        assert!(poll_once(call.as_mut()).is_ready());
+        assert_eq!(metrics.snapshot().in_flight, 0);
+        assert_eq!(metrics.latency(), Duration::from_millis(8));
+    }
+}
@@ -0,0 +1,193 @@
+use std::{cell::RefCell, error::Error, fmt::Display, rc::Rc};
+
+use crate::{
+    either::{BoxError, EitherExt},
+    Service,
+};
+
+/// A service shared within one thread, called through an exclusive borrow.
+///
+/// `LocalShared` is the `Rc<RefCell<S>>` counterpart of sharing a service with
+/// `Arc`, for thread-per-core runtimes where services are `!Send`. Clones share
+/// the same service. Each call holds a mutable borrow of the service until its
+/// future completes, so the service is never observed while [`with_mut`] is
+/// modifying it, and at most one call runs at a time.
+///
+/// # Reentrancy
+///
+/// A call made while another call on the same `LocalShared` is still in flight,
+/// including a call from within the service itself, does not panic. It fails
+/// with [`LocalSharedError::Busy`] instead. [`with_mut`] panics in that case,
+/// like `RefCell::borrow_mut`; use [`try_with_mut`] to check first.
+///
+/// [`with_mut`]: LocalShared::with_mut
+/// [`try_with_mut`]: LocalShared::try_with_mut
+pub struct LocalShared<S> {
+    inner: Rc<RefCell<S>>,
+}
+
+impl<S> LocalShared<S> {
+    #[inline]
+    pub fn new(svc: S) -> Self {
+        LocalShared {
+            inner: Rc::new(RefCell::new(svc)),
+        }
+    }
+
+    /// Whether a call is in flight.
+    #[inline]
+    pub fn is_busy(&self) -> bool {
+        self.inner.try_borrow_mut().is_err()
+    }
+
+    /// Modify the shared service.
+    ///
+    /// # Panics
+    /// Panics if a call is in flight.
+    pub fn with_mut<T>(&self, f: impl FnOnce(&mut S) -> T) -> T {
+        f(&mut self.inner.borrow_mut())
+    }
+
+    /// Modify the shared service, or return `None` if a call is in flight.
+    pub fn try_with_mut<T>(&self, f: impl FnOnce(&mut S) -> T) -> Option<T> {
+        let mut svc = self.inner.try_borrow_mut().ok()?;
+        Some(f(&mut svc))
+    }
+
+    /// Take the service out if this is the only handle to it.
+    pub fn try_unwrap(self) -> Result<S, Self> {
+        Rc::try_unwrap(self.inner)
+            .map(RefCell::into_inner)
+            .map_err(|inner| LocalShared { inner })
+    }
+}
+
+impl<S> Clone for LocalShared<S> {
+    #[inline]
+    fn clone(&self) -> Self {
+        LocalShared {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S> From<S> for LocalShared<S> {
+    #[inline]
+    fn from(svc: S) -> Self {
+        Self::new(svc)
+    }
+}
+
+impl<S, R> Service<R> for LocalShared<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = LocalSharedError<S::Error>;
+
+    // Holding the borrow across the await is the point: it is the reentrancy guard.
+    #[allow(clippy::await_holding_refcell_ref)]
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let svc = self
+            .inner
+            .try_borrow_mut()
+            .map_err(|_| LocalSharedError::Busy)?;
+        svc.call(req).await.map_err(LocalSharedError::Inner)
+    }
+}
+
+/// Error returned by [`LocalShared`].
+#[derive(Debug)]
+pub enum LocalSharedError<E> {
+    /// Another call on the shared service was still in flight.
+    Busy,
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for LocalSharedError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LocalSharedError::Busy => f.write_str("shared service is busy"),
+            LocalSharedError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for LocalSharedError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LocalSharedError::Busy => None,
+            LocalSharedError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<E: Error + Send + Sync + 'static> EitherExt for LocalSharedError<E> {
+    #[inline]
+    fn flatten_error(self) -> BoxError {
+        Box::new(self)
+    }
+
+    #[inline]
+    fn as_dyn_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin, rc::Rc};
+
+    use super::{LocalShared, LocalSharedError};
+    use crate::{
+        test_util::{block_on, poll_once},
+        utils::CloneFactory,
+        yielding::yield_now,
+        MakeService, Service,
+    };
+
+    /// Adds its offset to the request, yielding once before answering.
+    #[derive(Clone)]
+    struct Offset(u32);
+
+    impl Service<u32> for Offset {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<u32, Infallible> {
+            yield_now().await;
+            Ok(req + self.0)
+        }
+    }
+
+    #[test]
+    fn clones_share_the_service() {
+        let svc = LocalShared::new(Offset(1));
+        let other = svc.clone();
+        other.with_mut(|s| s.0 = 10);
+        assert!(matches!(block_on(svc.call(1)), Ok(11)));
+        drop(other);
+        assert_eq!(svc.try_unwrap().ok().unwrap().0, 10);
+    }
+
+    #[test]
+    fn concurrent_call_is_busy() {
+        let svc = LocalShared::new(Offset(1));
+        let mut first = pin!(svc.call(1));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert!(svc.is_busy());
+        assert!(svc.try_with_mut(|_| ()).is_none());
+        assert!(matches!(block_on(svc.call(2)), Err(LocalSharedError::Busy)));
+
+        assert!(matches!(block_on(first), Ok(2)));
+        assert!(!svc.is_busy());
+    }
+
+    #[test]
+    fn rc_forwards() {
+        let factory = Rc::new(CloneFactory::new(Offset(3)));
+        let svc = Rc::new(factory.make().unwrap());
+        assert_eq!(block_on(svc.call(1)), Ok(4));
+    }
+}
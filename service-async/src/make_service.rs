@@ -1,4 +1,15 @@
-use std::{future::Future, sync::Arc};
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hash},
+    rc::Rc,
+    sync::Arc,
+};
+#[cfg(not(feature = "boxed-futures"))]
+use std::{future::Future, marker::PhantomData};
+
+use crate::metadata::ServiceMetadata;
+#[cfg(not(feature = "boxed-futures"))]
+use crate::Service;
 
 /// A trait implemented by service factories to create instances of services that implement the [`Service`](crate::Service) trait.
 ///
@@ -79,6 +90,42 @@ pub trait MakeService {
     fn make(&self) -> Result<Self::Service, Self::Error> {
         self.make_via_ref(None)
     }
+
+    /// Creates a new service in an `Arc`, to be shared by multiple workers or tasks.
+    fn make_shared(&self) -> Result<Arc<Self::Service>, Self::Error> {
+        self.make_shared_via_ref(None)
+    }
+
+    /// Creates a new shared service, migrating state from the shared `old` one.
+    ///
+    /// The old service is only borrowed: holders of its `Arc` keep using it
+    /// until they switch to the new one, so in-flight requests drain on the
+    /// old chain. Plain `make_via_ref` also takes a shared service as
+    /// `old.map(|s| &**s)`.
+    fn make_shared_via_ref(
+        &self,
+        old: Option<&Arc<Self::Service>>,
+    ) -> Result<Arc<Self::Service>, Self::Error> {
+        self.make_via_ref(old.map(|o| &**o)).map(Arc::new)
+    }
+
+    /// Checks the factory's configuration without making a service.
+    ///
+    /// Factories which can fail on bad config (e.g. an unparsable address or a
+    /// missing certificate) should check it here, so that errors are caught at
+    /// startup or before a reload rather than on `make`. Factories wrapping other
+    /// factories forward to them. The default implementation accepts everything.
+    fn validate(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Describes the service this factory makes, for admin endpoints and logs.
+    ///
+    /// Layers add themselves on top of the metadata of their inner factories.
+    /// The default implementation names the service after its type.
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+    }
 }
 
 impl<T: MakeService + ?Sized> MakeService for &T {
@@ -87,6 +134,14 @@ impl<T: MakeService + ?Sized> MakeService for &T {
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
         (*self).make_via_ref(old)
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        (*self).validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        (*self).service_metadata()
+    }
 }
 
 impl<T: MakeService + ?Sized> MakeService for Arc<T> {
@@ -95,6 +150,30 @@ impl<T: MakeService + ?Sized> MakeService for Arc<T> {
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
         self.as_ref().make_via_ref(old)
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.as_ref().validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.as_ref().service_metadata()
+    }
+}
+
+impl<T: MakeService + ?Sized> MakeService for Rc<T> {
+    type Service = T::Service;
+    type Error = T::Error;
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.as_ref().make_via_ref(old)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.as_ref().validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.as_ref().service_metadata()
+    }
 }
 
 impl<T: MakeService + ?Sized> MakeService for Box<T> {
@@ -103,6 +182,243 @@ impl<T: MakeService + ?Sized> MakeService for Box<T> {
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
         self.as_ref().make_via_ref(old)
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.as_ref().validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.as_ref().service_metadata()
+    }
+}
+
+/// A map of factories makes a map of services with the same keys.
+///
+/// On `make_via_ref` each service is made against the old service of the same
+/// key; services of removed keys are dropped and added keys are made from
+/// scratch. It suits config driven deployments with one chain per listener or
+/// route.
+impl<K, F, H> MakeService for HashMap<K, F, H>
+where
+    K: Eq + Hash + Clone,
+    F: MakeService,
+    H: BuildHasher + Clone,
+{
+    type Service = HashMap<K, F::Service, H>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let mut svcs = HashMap::with_capacity_and_hasher(self.len(), self.hasher().clone());
+        for (key, factory) in self {
+            let old = old.and_then(|o| o.get(key));
+            svcs.insert(key.clone(), factory.make_via_ref(old)?);
+        }
+        Ok(svcs)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.values().try_for_each(F::validate)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.values().fold(
+            ServiceMetadata::of::<Self::Service>().with_resource("entries", self.len() as u64),
+            |meta, factory| meta.with_inner(factory.service_metadata()),
+        )
+    }
+}
+
+/// Helpers to make one service per worker from a single factory.
+///
+/// Each worker owns its own instance, and on reload worker `i` migrates the
+/// state of its own old instance: `make_via_refs(&olds)[i]` is made with
+/// `make_via_ref(Some(olds[i]))`.
+pub trait MakeServiceBatchExt: MakeService {
+    /// Make `n` services from scratch.
+    fn make_n(&self, n: usize) -> Result<Vec<Self::Service>, Self::Error> {
+        (0..n).map(|_| self.make()).collect()
+    }
+
+    /// Make a service for each of `olds`, migrating state pairwise.
+    fn make_via_refs(&self, olds: &[&Self::Service]) -> Result<Vec<Self::Service>, Self::Error> {
+        olds.iter()
+            .map(|old| self.make_via_ref(Some(old)))
+            .collect()
+    }
+}
+
+impl<T: MakeService + ?Sized> MakeServiceBatchExt for T {}
+
+/// A factory of `Service<R>`, usable as a bound in generic code.
+///
+/// Unlike `F: MakeService, F::Service: Service<R>`, the bound on the service is
+/// implied wherever `F: MakeServiceOf<R>` holds, so code built on top of this
+/// crate can take "a stack which makes a `Service<R>`" without naming it.
+/// It is implemented for every such factory. It needs Rust 1.79, so it is not
+/// available with the `boxed-futures` feature.
+#[cfg(not(feature = "boxed-futures"))]
+pub trait MakeServiceOf<R>: MakeService<Service: Service<R>> {}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F, R> MakeServiceOf<R> for F
+where
+    F: MakeService + ?Sized,
+    F::Service: Service<R>,
+{
+}
+
+/// An async factory of `Service<R>`, usable as a bound in generic code.
+///
+/// See [`MakeServiceOf`].
+#[cfg(not(feature = "boxed-futures"))]
+pub trait AsyncMakeServiceOf<R>: AsyncMakeService<Service: Service<R>> {}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F, R> AsyncMakeServiceOf<R> for F
+where
+    F: AsyncMakeService + ?Sized,
+    F::Service: Service<R>,
+{
+}
+
+/// A factory checked to make a `Service<R>`. Returned by [`assert_service`]
+/// and [`assert_async_service`].
+///
+/// It is a factory itself, forwarding to the checked one.
+#[cfg(not(feature = "boxed-futures"))]
+pub struct ProofOfService<F, R> {
+    factory: F,
+    _marker: PhantomData<fn(R)>,
+}
+
+/// Check at compile time that `factory` makes a `Service<R>`.
+///
+/// ```
+/// use std::convert::Infallible;
+///
+/// use service_async::{assert_service, MakeService, MakeServiceOf, Service};
+///
+/// struct Echo;
+///
+/// impl Service<String> for Echo {
+///     type Response = String;
+///     type Error = Infallible;
+///
+///     async fn call(&self, req: String) -> Result<String, Infallible> {
+///         Ok(req)
+///     }
+/// }
+///
+/// struct EchoFactory;
+///
+/// impl MakeService for EchoFactory {
+///     type Service = Echo;
+///     type Error = Infallible;
+///
+///     fn make_via_ref(&self, _old: Option<&Echo>) -> Result<Echo, Infallible> {
+///         Ok(Echo)
+///     }
+/// }
+///
+/// // Generic code only needs the bound to make and call the service.
+/// async fn serve_one<F: MakeServiceOf<String>>(factory: F) {
+///     let svc = factory.make().ok().unwrap();
+///     let _ = svc.call("hello".to_string()).await;
+/// }
+///
+/// let factory = assert_service::<String, _>(EchoFactory);
+/// # use std::{future::Future, pin::pin, task::{Context, Waker}};
+/// # let done = pin!(serve_one(factory)).poll(&mut Context::from_waker(Waker::noop()));
+/// # assert!(done.is_ready());
+/// ```
+#[cfg(not(feature = "boxed-futures"))]
+#[inline]
+pub fn assert_service<R, F>(factory: F) -> ProofOfService<F, R>
+where
+    F: MakeServiceOf<R>,
+{
+    ProofOfService {
+        factory,
+        _marker: PhantomData,
+    }
+}
+
+/// Check at compile time that the async `factory` makes a `Service<R>`.
+#[cfg(not(feature = "boxed-futures"))]
+#[inline]
+pub fn assert_async_service<R, F>(factory: F) -> ProofOfService<F, R>
+where
+    F: AsyncMakeServiceOf<R>,
+{
+    ProofOfService {
+        factory,
+        _marker: PhantomData,
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F, R> ProofOfService<F, R> {
+    #[inline]
+    pub fn factory(&self) -> &F {
+        &self.factory
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> F {
+        self.factory
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F: Clone, R> Clone for ProofOfService<F, R> {
+    #[inline]
+    fn clone(&self) -> Self {
+        ProofOfService {
+            factory: self.factory.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F: MakeService, R> MakeService for ProofOfService<F, R> {
+    type Service = F::Service;
+    type Error = F::Error;
+
+    #[inline]
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.factory.make_via_ref(old)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.factory.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.factory.service_metadata()
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<F: AsyncMakeService, R> AsyncMakeService for ProofOfService<F, R> {
+    type Service = F::Service;
+    type Error = F::Error;
+
+    #[inline]
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.factory.make_via_ref(old).await
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.factory.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.factory.service_metadata()
+    }
 }
 
 /// A boxed trait object of `MakeService` that enables type erasure for service factories.
@@ -185,6 +501,7 @@ pub type ArcMakeBoxedService<Req, Resp, SE, ME> =
 ///
 /// In this example, `MyAsyncServiceFactory` implements `AsyncMakeService` to create `MyAsyncService`
 /// instances asynchronously, demonstrating how to reuse a connection pool or create a new one when needed.
+#[cfg(not(feature = "boxed-futures"))]
 pub trait AsyncMakeService {
     /// The type of service this factory creates.
     type Service;
@@ -219,8 +536,41 @@ pub trait AsyncMakeService {
     fn make(&self) -> impl Future<Output = Result<Self::Service, Self::Error>> {
         self.make_via_ref(None)
     }
+
+    /// Asynchronously creates a new service in an `Arc`.
+    ///
+    /// See [`MakeService::make_shared`].
+    fn make_shared(&self) -> impl Future<Output = Result<Arc<Self::Service>, Self::Error>> {
+        self.make_shared_via_ref(None)
+    }
+
+    /// Asynchronously creates a new shared service, migrating state from the
+    /// shared `old` one.
+    ///
+    /// See [`MakeService::make_shared_via_ref`].
+    fn make_shared_via_ref(
+        &self,
+        old: Option<&Arc<Self::Service>>,
+    ) -> impl Future<Output = Result<Arc<Self::Service>, Self::Error>> {
+        async move { self.make_via_ref(old.map(|o| &**o)).await.map(Arc::new) }
+    }
+
+    /// Checks the factory's configuration without making a service.
+    ///
+    /// See [`MakeService::validate`]. The default implementation accepts everything.
+    fn validate(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Describes the service this factory makes.
+    ///
+    /// See [`MakeService::service_metadata`].
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+    }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<T: AsyncMakeService + ?Sized> AsyncMakeService for &T {
     type Service = T::Service;
     type Error = T::Error;
@@ -230,8 +580,17 @@ impl<T: AsyncMakeService + ?Sized> AsyncMakeService for &T {
     ) -> Result<Self::Service, Self::Error> {
         (*self).make_via_ref(old).await
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        (*self).validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        (*self).service_metadata()
+    }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<T: AsyncMakeService + ?Sized> AsyncMakeService for Arc<T> {
     type Service = T::Service;
     type Error = T::Error;
@@ -241,8 +600,37 @@ impl<T: AsyncMakeService + ?Sized> AsyncMakeService for Arc<T> {
     ) -> Result<Self::Service, Self::Error> {
         self.as_ref().make_via_ref(old).await
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.as_ref().validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.as_ref().service_metadata()
+    }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
+impl<T: AsyncMakeService + ?Sized> AsyncMakeService for Rc<T> {
+    type Service = T::Service;
+    type Error = T::Error;
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.as_ref().make_via_ref(old).await
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.as_ref().validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.as_ref().service_metadata()
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
 impl<T: AsyncMakeService + ?Sized> AsyncMakeService for Box<T> {
     type Service = T::Service;
     type Error = T::Error;
@@ -252,13 +640,341 @@ impl<T: AsyncMakeService + ?Sized> AsyncMakeService for Box<T> {
     ) -> Result<Self::Service, Self::Error> {
         self.as_ref().make_via_ref(old).await
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.as_ref().validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.as_ref().service_metadata()
+    }
+}
+
+/// See the [`MakeService`] implementation of `HashMap`.
+#[cfg(not(feature = "boxed-futures"))]
+impl<K, F, H> AsyncMakeService for HashMap<K, F, H>
+where
+    K: Eq + Hash + Clone,
+    F: AsyncMakeService,
+    H: BuildHasher + Clone,
+{
+    type Service = HashMap<K, F::Service, H>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let mut svcs = HashMap::with_capacity_and_hasher(self.len(), self.hasher().clone());
+        for (key, factory) in self {
+            let old = old.and_then(|o| o.get(key));
+            svcs.insert(key.clone(), factory.make_via_ref(old).await?);
+        }
+        Ok(svcs)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.values().try_for_each(F::validate)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.values().fold(
+            ServiceMetadata::of::<Self::Service>().with_resource("entries", self.len() as u64),
+            |meta, factory| meta.with_inner(factory.service_metadata()),
+        )
+    }
+}
+
+/// The async counterpart of [`MakeServiceBatchExt`]. Services are made one
+/// after another.
+#[cfg(not(feature = "boxed-futures"))]
+pub trait AsyncMakeServiceBatchExt: AsyncMakeService {
+    /// Make `n` services from scratch.
+    fn make_n(&self, n: usize) -> impl Future<Output = Result<Vec<Self::Service>, Self::Error>> {
+        async move {
+            let mut svcs = Vec::with_capacity(n);
+            for _ in 0..n {
+                svcs.push(self.make().await?);
+            }
+            Ok(svcs)
+        }
+    }
+
+    /// Make a service for each of `olds`, migrating state pairwise.
+    fn make_via_refs(
+        &self,
+        olds: &[&Self::Service],
+    ) -> impl Future<Output = Result<Vec<Self::Service>, Self::Error>> {
+        async move {
+            let mut svcs = Vec::with_capacity(olds.len());
+            for old in olds {
+                svcs.push(self.make_via_ref(Some(old)).await?);
+            }
+            Ok(svcs)
+        }
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<T: AsyncMakeService + ?Sized> AsyncMakeServiceBatchExt for T {}
+
+/// Combinators for [`AsyncMakeService`].
+///
+/// They let several factories share one reload path, e.g. a server and its
+/// metrics endpoint made together with [`zip`](Self::zip).
+#[cfg(not(feature = "boxed-futures"))]
+pub trait AsyncMakeServiceExt: AsyncMakeService + Sized {
+    /// Make a service with both `self` and `other`, producing a tuple.
+    ///
+    /// Both factories must share an error type; align them with
+    /// [`map_make_err`](Self::map_make_err) first if needed.
+    fn zip<B>(self, other: B) -> Zip<Self, B>
+    where
+        B: AsyncMakeService<Error = Self::Error>,
+    {
+        Zip {
+            first: self,
+            second: other,
+        }
+    }
+
+    /// Map the error returned by making or validating.
+    fn map_make_err<FN, E>(self, f: FN) -> MapMakeErr<Self, FN>
+    where
+        FN: Fn(Self::Error) -> E,
+    {
+        MapMakeErr { f, inner: self }
+    }
+
+    /// Call `f` on each service made, e.g. to register it.
+    fn inspect_make<FN>(self, f: FN) -> InspectMake<Self, FN>
+    where
+        FN: Fn(&Self::Service),
+    {
+        InspectMake { f, inner: self }
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<T: AsyncMakeService> AsyncMakeServiceExt for T {}
+
+/// A factory which makes a service with each of two factories. Created by
+/// [`AsyncMakeServiceExt::zip`].
+#[derive(Debug, Clone, Copy)]
+pub struct Zip<A, B> {
+    pub(crate) first: A,
+    pub(crate) second: B,
+}
+
+impl<A, B> Zip<A, B> {
+    #[inline]
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A, B> MakeService for Zip<A, B>
+where
+    A: MakeService,
+    B: MakeService<Error = A::Error>,
+{
+    type Service = (A::Service, B::Service);
+    type Error = A::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok((
+            self.first.make_via_ref(old.map(|o| &o.0))?,
+            self.second.make_via_ref(old.map(|o| &o.1))?,
+        ))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.first.validate()?;
+        self.second.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::new("Zip")
+            .with_inner(self.first.service_metadata())
+            .with_inner(self.second.service_metadata())
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<A, B> AsyncMakeService for Zip<A, B>
+where
+    A: AsyncMakeService,
+    B: AsyncMakeService<Error = A::Error>,
+{
+    type Service = (A::Service, B::Service);
+    type Error = A::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok((
+            self.first.make_via_ref(old.map(|o| &o.0)).await?,
+            self.second.make_via_ref(old.map(|o| &o.1)).await?,
+        ))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.first.validate()?;
+        self.second.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::new("Zip")
+            .with_inner(self.first.service_metadata())
+            .with_inner(self.second.service_metadata())
+    }
 }
 
+/// A factory which maps the error of its inner factory. Created by
+/// [`AsyncMakeServiceExt::map_make_err`].
+#[derive(Debug, Clone, Copy)]
+pub struct MapMakeErr<T, FN> {
+    f: FN,
+    inner: T,
+}
+
+impl<T, FN, E> MakeService for MapMakeErr<T, FN>
+where
+    T: MakeService,
+    FN: Fn(T::Error) -> E,
+{
+    type Service = T::Service;
+    type Error = E;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.inner.make_via_ref(old).map_err(&self.f)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate().map_err(&self.f)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<T, FN, E> AsyncMakeService for MapMakeErr<T, FN>
+where
+    T: AsyncMakeService,
+    FN: Fn(T::Error) -> E,
+{
+    type Service = T::Service;
+    type Error = E;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.inner.make_via_ref(old).await.map_err(&self.f)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate().map_err(&self.f)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+crate::layer::impl_wrap_inner!(MapMakeErr < _, FN > { f });
+
+/// A factory which calls a closure on each service made by its inner
+/// factory. Created by [`AsyncMakeServiceExt::inspect_make`].
+#[derive(Debug, Clone, Copy)]
+pub struct InspectMake<T, FN> {
+    f: FN,
+    inner: T,
+}
+
+impl<T, FN> MakeService for InspectMake<T, FN>
+where
+    T: MakeService,
+    FN: Fn(&T::Service),
+{
+    type Service = T::Service;
+    type Error = T::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let svc = self.inner.make_via_ref(old)?;
+        (self.f)(&svc);
+        Ok(svc)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<T, FN> AsyncMakeService for InspectMake<T, FN>
+where
+    T: AsyncMakeService,
+    FN: Fn(&T::Service),
+{
+    type Service = T::Service;
+    type Error = T::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let svc = self.inner.make_via_ref(old).await?;
+        (self.f)(&svc);
+        Ok(svc)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+crate::layer::impl_wrap_inner!(InspectMake < _, FN > { f });
+
 /// Impl AsyncMakeService where T: MakeService.
+#[cfg(not(feature = "boxed-futures"))]
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct AsyncMakeServiceWrapper<T>(pub T);
 
+#[cfg(not(feature = "boxed-futures"))]
+impl<T> crate::layer::WrapInner for AsyncMakeServiceWrapper<T> {
+    type Inner = T;
+    type Wrapped<N> = AsyncMakeServiceWrapper<N>;
+
+    #[inline]
+    fn inner_factory(&self) -> &Self::Inner {
+        &self.0
+    }
+
+    #[inline]
+    fn into_inner_factory(self) -> Self::Inner {
+        self.0
+    }
+
+    #[inline]
+    fn map_inner<N>(self, f: impl FnOnce(Self::Inner) -> N) -> Self::Wrapped<N> {
+        AsyncMakeServiceWrapper(f(self.0))
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
 impl<T: MakeService> AsyncMakeService for AsyncMakeServiceWrapper<T> {
     type Service = <T as MakeService>::Service;
     type Error = <T as MakeService>::Error;
@@ -272,4 +988,135 @@ impl<T: MakeService> AsyncMakeService for AsyncMakeServiceWrapper<T> {
     async fn make(&self) -> Result<Self::Service, Self::Error> {
         <T as MakeService>::make(&self.0)
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.0.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        <T as MakeService>::service_metadata(&self.0)
+    }
+}
+
+#[cfg(all(test, not(feature = "boxed-futures")))]
+mod tests {
+    use std::{cell::Cell, collections::HashMap, convert::Infallible, sync::Arc};
+
+    use super::{
+        assert_async_service, assert_service, AsyncMakeService, AsyncMakeServiceBatchExt,
+        AsyncMakeServiceExt, AsyncMakeServiceWrapper, MakeService, MakeServiceBatchExt,
+    };
+    use crate::{test_util::block_on, Service};
+
+    struct Echo(usize);
+
+    impl Service<String> for Echo {
+        type Response = (String, usize);
+        type Error = Infallible;
+
+        async fn call(&self, req: String) -> Result<Self::Response, Infallible> {
+            Ok((req, self.0))
+        }
+    }
+
+    /// Counts the services made through it.
+    struct EchoFactory;
+
+    impl MakeService for EchoFactory {
+        type Service = Echo;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, old: Option<&Echo>) -> Result<Echo, Infallible> {
+            Ok(Echo(old.map_or(0, |o| o.0 + 1)))
+        }
+    }
+
+    #[test]
+    fn proof_forwards_to_factory() {
+        let factory = assert_service::<String, _>(EchoFactory);
+        let svc = MakeService::make(&factory).unwrap();
+        let svc = MakeService::make_via_ref(&factory, Some(&svc)).unwrap();
+        assert_eq!(block_on(svc.call("hi".into())), Ok(("hi".into(), 1)));
+        assert!(MakeService::validate(&factory).is_ok());
+
+        let factory = assert_async_service::<String, _>(AsyncMakeServiceWrapper(EchoFactory));
+        let svc = block_on(AsyncMakeService::make(&factory)).unwrap();
+        let svc = block_on(AsyncMakeService::make_via_ref(&factory, Some(&svc))).unwrap();
+        assert_eq!(block_on(svc.call("hi".into())), Ok(("hi".into(), 1)));
+    }
+
+    #[test]
+    fn make_shared() {
+        let old = EchoFactory.make_shared().unwrap();
+        let held = old.clone();
+        let new = EchoFactory.make_shared_via_ref(Some(&old)).unwrap();
+        assert!(!Arc::ptr_eq(&old, &new));
+        assert_eq!((held.0, new.0), (0, 1));
+
+        let factory = AsyncMakeServiceWrapper(EchoFactory);
+        let new = block_on(AsyncMakeService::make_shared_via_ref(&factory, Some(&new))).unwrap();
+        assert_eq!(new.0, 2);
+    }
+
+    #[test]
+    fn make_one_per_worker() {
+        let svcs = MakeServiceBatchExt::make_n(&EchoFactory, 3).unwrap();
+        assert_eq!(svcs.iter().map(|s| s.0).collect::<Vec<_>>(), [0, 0, 0]);
+        let olds = [&Echo(5), &svcs[0]];
+        let svcs = MakeServiceBatchExt::make_via_refs(&EchoFactory, &olds).unwrap();
+        assert_eq!(svcs.iter().map(|s| s.0).collect::<Vec<_>>(), [6, 1]);
+
+        let factory = AsyncMakeServiceWrapper(EchoFactory);
+        let svcs = block_on(AsyncMakeServiceBatchExt::make_n(&factory, 2)).unwrap();
+        assert_eq!(svcs.len(), 2);
+        let olds = [&svcs[0], &Echo(5)];
+        let svcs = block_on(AsyncMakeServiceBatchExt::make_via_refs(&factory, &olds)).unwrap();
+        assert_eq!(svcs.iter().map(|s| s.0).collect::<Vec<_>>(), [1, 6]);
+    }
+
+    /// Fails to make, as if its config were invalid.
+    struct Invalid;
+
+    impl MakeService for Invalid {
+        type Service = Echo;
+        type Error = &'static str;
+
+        fn make_via_ref(&self, _old: Option<&Echo>) -> Result<Echo, &'static str> {
+            Err("invalid")
+        }
+    }
+
+    #[test]
+    fn combinators() {
+        let made = Cell::new(0);
+        let factory = AsyncMakeServiceWrapper(EchoFactory)
+            .inspect_make(|_: &Echo| made.set(made.get() + 1))
+            .map_make_err(|e: Infallible| -> String { match e {} })
+            .zip(AsyncMakeServiceWrapper(Invalid).map_make_err(String::from));
+        assert_eq!(block_on(factory.make()).err().as_deref(), Some("invalid"));
+        assert_eq!(made.get(), 1);
+
+        let pair = AsyncMakeServiceWrapper(EchoFactory).zip(AsyncMakeServiceWrapper(EchoFactory));
+        let old = (Echo(3), Echo(7));
+        let new = block_on(pair.make_via_ref(Some(&old))).unwrap();
+        assert_eq!((new.0 .0, new.1 .0), (4, 8));
+    }
+
+    #[test]
+    fn map_of_factories_migrates_by_key() {
+        let factories = HashMap::from([("a", EchoFactory), ("b", EchoFactory)]);
+        let old = HashMap::from([("a", Echo(4)), ("c", Echo(9))]);
+        let svcs = MakeService::make_via_ref(&factories, Some(&old)).unwrap();
+        let mut gens = svcs.iter().map(|(k, s)| (*k, s.0)).collect::<Vec<_>>();
+        gens.sort();
+        assert_eq!(gens, [("a", 5), ("b", 0)]);
+
+        let factories = HashMap::from([("a", AsyncMakeServiceWrapper(EchoFactory))]);
+        let svcs = block_on(AsyncMakeService::make_via_ref(&factories, Some(&svcs))).unwrap();
+        assert_eq!(svcs.len(), 1);
+        assert_eq!(block_on(svcs["a"].call("hi".into())), Ok(("hi".into(), 6)));
+
+        let factories = HashMap::from([("a", Invalid)]);
+        assert_eq!(MakeService::make(&factories).err(), Some("invalid"));
+    }
 }
@@ -1,6 +1,13 @@
+#[cfg(not(feature = "boxed-futures"))]
 use std::future::Future;
+use std::marker::PhantomData;
 
-use super::{AsyncMakeService, MakeService, Service};
+#[cfg(not(feature = "boxed-futures"))]
+use super::AsyncMakeService;
+use super::{
+    layer::{impl_wrap_inner, Layered},
+    MakeService, Service, ServiceMetadata,
+};
 
 pub trait MapTarget<T> {
     type Target;
@@ -25,6 +32,7 @@ pub struct MapTargetService<T, F> {
     pub inner: T,
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<T, F, R> Service<R> for MapTargetService<T, F>
 where
     F: MapTarget<R>,
@@ -41,6 +49,22 @@ where
     }
 }
 
+#[cfg(feature = "boxed-futures")]
+impl<T, F, R> Service<R> for MapTargetService<T, F>
+where
+    F: MapTarget<R>,
+    T: Service<F::Target>,
+{
+    crate::impl_service! {
+        type Response = T::Response;
+        type Error = T::Error;
+
+        async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+            self.inner.call(self.f.map_target(req)).await
+        }
+    }
+}
+
 impl<FAC, F> MakeService for MapTargetService<FAC, F>
 where
     FAC: MakeService,
@@ -52,14 +76,20 @@ where
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
         Ok(MapTargetService {
             f: self.f.clone(),
-            inner: self
-                .inner
-                .make_via_ref(old.map(|o| &o.inner))
-                .map_err(Into::into)?,
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
         })
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<FAC, F> AsyncMakeService for MapTargetService<FAC, F>
 where
     FAC: AsyncMakeService,
@@ -74,11 +104,136 @@ where
     ) -> Result<Self::Service, Self::Error> {
         Ok(MapTargetService {
             f: self.f.clone(),
-            inner: self
-                .inner
-                .make_via_ref(old.map(|o| &o.inner))
-                .await
-                .map_err(Into::into)?,
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
         })
     }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(MapTargetService < _, F > { f });
+
+impl<T, F> Layered for MapTargetService<T, F> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+/// A service which converts the error of the inner service into `E` with
+/// `From`, so stacks with different error types can be boxed into the same
+/// [`BoxedService`](crate::BoxedService) type.
+pub struct MapErrInto<T, E> {
+    pub inner: T,
+    _marker: PhantomData<fn() -> E>,
+}
+
+impl<T, E> MapErrInto<T, E> {
+    #[inline]
+    pub const fn new(inner: T) -> Self {
+        MapErrInto {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Clone, E> Clone for MapErrInto<T, E> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone())
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<T, E, R> Service<R> for MapErrInto<T, E>
+where
+    T: Service<R>,
+    E: From<T::Error>,
+{
+    type Response = T::Response;
+
+    type Error = E;
+
+    #[inline]
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        self.inner.call(req).await.map_err(E::from)
+    }
+}
+
+#[cfg(feature = "boxed-futures")]
+impl<T, E, R> Service<R> for MapErrInto<T, E>
+where
+    T: Service<R>,
+    E: From<T::Error>,
+{
+    crate::impl_service! {
+        type Response = T::Response;
+        type Error = E;
+
+        async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+            self.inner.call(req).await.map_err(E::from)
+        }
+    }
+}
+
+impl<FAC: MakeService, E> MakeService for MapErrInto<FAC, E> {
+    type Service = MapErrInto<FAC::Service, E>;
+    type Error = FAC::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(MapErrInto::new(
+            self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        ))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<FAC: AsyncMakeService, E> AsyncMakeService for MapErrInto<FAC, E> {
+    type Service = MapErrInto<FAC::Service, E>;
+    type Error = FAC::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(MapErrInto::new(
+            self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        ))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(MapErrInto < _, E > { _marker });
+
+impl<T, E> Layered for MapErrInto<T, E> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
 }
@@ -0,0 +1,79 @@
+use std::fmt::Display;
+
+/// A description of a service a factory makes, with the services it wraps.
+///
+/// It is returned by `MakeService::service_metadata`, so admin endpoints can
+/// show what a stack currently deploys. Layers describe themselves and nest the
+/// metadata of their inner factories; leaves default to their type name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceMetadata {
+    /// Short name, e.g. the type name without path and generics.
+    pub name: &'static str,
+    /// Config values which are worth showing, as key-value pairs.
+    pub config: Vec<(&'static str, String)>,
+    /// Resources held by the service, like backends or partitions.
+    pub resources: Vec<(&'static str, u64)>,
+    /// Metadata of the wrapped services.
+    pub inner: Vec<ServiceMetadata>,
+}
+
+impl ServiceMetadata {
+    pub const fn new(name: &'static str) -> Self {
+        ServiceMetadata {
+            name,
+            config: Vec::new(),
+            resources: Vec::new(),
+            inner: Vec::new(),
+        }
+    }
+
+    /// Metadata named after the type `T`, e.g. `Hooks` for
+    /// `service_async::hooks::Hooks<F, H>`.
+    pub fn of<T: ?Sized>() -> Self {
+        Self::new(short_type_name(std::any::type_name::<T>()))
+    }
+
+    /// Add a config value.
+    pub fn with_config(mut self, key: &'static str, value: impl Display) -> Self {
+        self.config.push((key, value.to_string()));
+        self
+    }
+
+    /// Add a resource count.
+    pub fn with_resource(mut self, key: &'static str, count: u64) -> Self {
+        self.resources.push((key, count));
+        self
+    }
+
+    /// Add the metadata of a wrapped service.
+    pub fn with_inner(mut self, inner: ServiceMetadata) -> Self {
+        self.inner.push(inner);
+        self
+    }
+
+    fn fmt_indented(&self, f: &mut std::fmt::Formatter<'_>, depth: usize) -> std::fmt::Result {
+        write!(f, "{:indent$}{}", "", self.name, indent = 2 * depth)?;
+        for (key, value) in &self.config {
+            write!(f, " {key}={value}")?;
+        }
+        for (key, count) in &self.resources {
+            write!(f, " {key}:{count}")?;
+        }
+        writeln!(f)?;
+        self.inner
+            .iter()
+            .try_for_each(|inner| inner.fmt_indented(f, depth + 1))
+    }
+}
+
+impl Display for ServiceMetadata {
+    /// Print the services as a tree, one per line.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+pub(crate) fn short_type_name(name: &'static str) -> &'static str {
+    let base = name.split('<').next().unwrap_or(name);
+    base.rsplit("::").next().unwrap_or(base)
+}
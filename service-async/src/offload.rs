@@ -0,0 +1,359 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Service, ServiceMetadata,
+};
+
+/// Runs blocking or CPU-heavy closures off the async runtime, e.g. on tokio's
+/// blocking pool or a rayon pool.
+///
+/// ```ignore
+/// #[derive(Clone)]
+/// struct Tokio;
+///
+/// impl BlockingSpawner for Tokio {
+///     async fn spawn_blocking<F, T>(&self, f: F) -> Option<T>
+///     where
+///         F: FnOnce() -> T + Send + 'static,
+///         T: Send + 'static,
+///     {
+///         tokio::task::spawn_blocking(f).await.ok()
+///     }
+/// }
+/// ```
+pub trait BlockingSpawner {
+    /// Run `f` and wait for its result, or `None` if it panicked or could not
+    /// be run.
+    fn spawn_blocking<F, T>(&self, f: F) -> impl Future<Output = Option<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+}
+
+impl<S: BlockingSpawner> BlockingSpawner for Arc<S> {
+    #[inline]
+    fn spawn_blocking<F, T>(&self, f: F) -> impl Future<Output = Option<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        (**self).spawn_blocking(f)
+    }
+}
+
+/// A [`BlockingSpawner`] which runs each closure on a new OS thread.
+///
+/// It needs no runtime support, but pays for a thread per call; prefer the
+/// pool of your runtime when there is one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadSpawner;
+
+struct Slot<T> {
+    value: Option<T>,
+    done: bool,
+    waker: Option<Waker>,
+}
+
+impl BlockingSpawner for ThreadSpawner {
+    fn spawn_blocking<F, T>(&self, f: F) -> impl Future<Output = Option<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Slot {
+            value: None,
+            done: false,
+            waker: None,
+        }));
+        let thread_slot = slot.clone();
+        let spawned = std::thread::Builder::new().spawn(move || {
+            let value = catch_unwind(AssertUnwindSafe(f)).ok();
+            let mut slot = thread_slot.lock().unwrap_or_else(|e| e.into_inner());
+            slot.value = value;
+            slot.done = true;
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+        poll_fn(move |cx| {
+            if spawned.is_err() {
+                return Poll::Ready(None);
+            }
+            let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+            if slot.done {
+                Poll::Ready(slot.value.take())
+            } else {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Error returned by [`Offload`] and [`OffloadFn`].
+#[derive(Debug)]
+pub enum OffloadError<E> {
+    /// The offloaded closure panicked or could not be spawned.
+    Canceled,
+    /// The service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for OffloadError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OffloadError::Canceled => write!(f, "offloaded work canceled"),
+            OffloadError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for OffloadError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OffloadError::Canceled => None,
+            OffloadError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A middleware which runs the CPU-heavy part of handling a request on a
+/// [`BlockingSpawner`], then passes its output to the inner service.
+///
+/// `f` maps the request to the request of the inner service, e.g. by
+/// decompressing or verifying it; the inner service stays async. It is its own
+/// factory.
+pub struct Offload<T, SP, FN> {
+    spawner: SP,
+    f: Arc<FN>,
+    inner: T,
+}
+
+impl<T, SP, FN> Offload<T, SP, FN> {
+    pub fn layer<C>(spawner: SP, f: FN) -> impl FactoryLayer<C, T, Factory = Offload<T, SP, FN>>
+    where
+        SP: Clone,
+    {
+        let f = Arc::new(f);
+        layer_fn(move |_: &C, inner| Offload {
+            spawner: spawner.clone(),
+            f: f.clone(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn spawner(&self) -> &SP {
+        &self.spawner
+    }
+}
+
+impl<T, SP, FN, R, R2> Service<R> for Offload<T, SP, FN>
+where
+    SP: BlockingSpawner,
+    FN: Fn(R) -> R2 + Send + Sync + 'static,
+    R: Send + 'static,
+    R2: Send + 'static,
+    T: Service<R2>,
+{
+    type Response = T::Response;
+    type Error = OffloadError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let f = self.f.clone();
+        let req = self
+            .spawner
+            .spawn_blocking(move || f(req))
+            .await
+            .ok_or(OffloadError::Canceled)?;
+        self.inner.call(req).await.map_err(OffloadError::Inner)
+    }
+}
+
+impl<F: MakeService, SP: Clone, FN> MakeService for Offload<F, SP, FN> {
+    type Service = Offload<F::Service, SP, FN>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Offload {
+            spawner: self.spawner.clone(),
+            f: self.f.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, SP: Clone, FN> AsyncMakeService for Offload<F, SP, FN> {
+    type Service = Offload<F::Service, SP, FN>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Offload {
+            spawner: self.spawner.clone(),
+            f: self.f.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(Offload<_, SP, FN> { spawner, f });
+
+impl<T, SP, FN> Layered for Offload<T, SP, FN> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Offload<T, SP, FN>);
+
+/// A service which runs a blocking function on a [`BlockingSpawner`] for each
+/// request. Created by [`offload_fn`].
+///
+/// Use it as the leaf of a stack with `push_clone_leaf`.
+pub struct OffloadFn<SP, FN> {
+    spawner: SP,
+    f: Arc<FN>,
+}
+
+/// Create a service which calls the blocking `f` on `spawner`.
+pub fn offload_fn<SP, FN>(spawner: SP, f: FN) -> OffloadFn<SP, FN> {
+    OffloadFn {
+        spawner,
+        f: Arc::new(f),
+    }
+}
+
+impl<SP: Clone, FN> Clone for OffloadFn<SP, FN> {
+    fn clone(&self) -> Self {
+        OffloadFn {
+            spawner: self.spawner.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+impl<SP, FN, R, Resp, E> Service<R> for OffloadFn<SP, FN>
+where
+    SP: BlockingSpawner,
+    FN: Fn(R) -> Result<Resp, E> + Send + Sync + 'static,
+    R: Send + 'static,
+    Resp: Send + 'static,
+    E: Send + 'static,
+{
+    type Response = Resp;
+    type Error = OffloadError<E>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let f = self.f.clone();
+        self.spawner
+            .spawn_blocking(move || f(req))
+            .await
+            .ok_or(OffloadError::Canceled)?
+            .map_err(OffloadError::Inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin, task::Poll, thread};
+
+    use super::{offload_fn, BlockingSpawner, Offload, OffloadError, ThreadSpawner};
+    use crate::{
+        layer::FactoryLayer,
+        test_util::{block_on, WakeCounter},
+        Service,
+    };
+
+    /// Runs closures in place, or refuses to run them.
+    #[derive(Clone)]
+    struct Inline(bool);
+
+    impl BlockingSpawner for Inline {
+        async fn spawn_blocking<F, T>(&self, f: F) -> Option<T>
+        where
+            F: FnOnce() -> T + Send + 'static,
+            T: Send + 'static,
+        {
+            self.0.then(f)
+        }
+    }
+
+    struct Len;
+
+    impl Service<Vec<u8>> for Len {
+        type Response = usize;
+        type Error = Infallible;
+
+        async fn call(&self, req: Vec<u8>) -> Result<usize, Infallible> {
+            Ok(req.len())
+        }
+    }
+
+    #[test]
+    fn offload_then_call_inner() {
+        let inflate = |req: &'static str| req.repeat(3).into_bytes();
+        let svc = Offload::layer(Inline(true), inflate).layer(&(), Len);
+        assert!(matches!(block_on(svc.call("ab")), Ok(6)));
+
+        let svc = Offload::layer(Inline(false), inflate).layer(&(), Len);
+        assert!(matches!(
+            block_on(svc.call("ab")),
+            Err(OffloadError::Canceled)
+        ));
+
+        let svc = offload_fn(Inline(true), |n: u32| n.checked_sub(1).ok_or("underflow"));
+        assert!(matches!(block_on(svc.call(1)), Ok(0)));
+        assert!(matches!(
+            block_on(svc.call(0)),
+            Err(OffloadError::Inner("underflow"))
+        ));
+    }
+
+    #[test]
+    fn thread_spawner_wakes_the_caller() {
+        let waker = WakeCounter::new();
+        let mut fut = pin!(ThreadSpawner.spawn_blocking(|| thread::current().id()));
+        let id = loop {
+            let woken = waker.count();
+            if let Poll::Ready(id) = waker.poll(fut.as_mut()) {
+                break id;
+            }
+            while waker.count() == woken {
+                thread::yield_now();
+            }
+        };
+        assert_ne!(id, Some(thread::current().id()));
+        assert!(id.is_some());
+    }
+}
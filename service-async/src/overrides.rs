@@ -0,0 +1,209 @@
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::HashMap,
+    future::{poll_fn, Future},
+    pin::pin,
+    rc::Rc,
+};
+
+use param::ParamMaybeRef;
+
+use crate::{AsyncMakeService, MakeService};
+
+/// A typed bag of parameters which override those of the stack config for
+/// one make, e.g. a drain timeout tweaked through an admin API.
+///
+/// It holds at most one value per type. Layers which honour an override look
+/// up the type of their parameter with [`current`] or [`current_or`] while
+/// making their service; the bag is visible to the whole chain during the
+/// make, so layers which do not recognize an override leave it to the inner
+/// ones.
+#[derive(Clone, Default)]
+pub struct Overrides {
+    values: HashMap<TypeId, Rc<dyn Any>>,
+}
+
+impl Overrides {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an override, replacing the one of the same type.
+    pub fn with<T: 'static>(mut self, value: T) -> Self {
+        self.insert(value);
+        self
+    }
+
+    /// Add an override, replacing the one of the same type.
+    pub fn insert<T: 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Rc::new(value));
+    }
+
+    /// Remove the override of type `T`.
+    pub fn remove<T: 'static>(&mut self) {
+        self.values.remove(&TypeId::of::<T>());
+    }
+
+    #[inline]
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl<T: 'static> ParamMaybeRef<T> for Overrides {
+    #[inline]
+    fn param_maybe_ref(&self) -> Option<&T> {
+        self.get()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Overrides>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with `overrides` visible to [`current`].
+fn scope<T>(overrides: &Rc<Overrides>, f: impl FnOnce() -> T) -> T {
+    struct Restore(Option<Rc<Overrides>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let prev = self.0.take();
+            CURRENT.with(|c| *c.borrow_mut() = prev);
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|c| c.borrow_mut().replace(overrides.clone())));
+    f()
+}
+
+/// The override of type `T` of the make in progress, if any.
+///
+/// Called by factories in `make_via_ref`. Outside of
+/// [`make_via_ref_with`](MakeWithOverrides::make_via_ref_with) there is none.
+pub fn current<T: Clone + 'static>() -> Option<T> {
+    CURRENT.with(|c| c.borrow().as_ref().and_then(|o| o.get().cloned()))
+}
+
+/// The override of type `T` of the make in progress, or `value` from the
+/// factory if there is none.
+#[inline]
+pub fn current_or<T: Clone + 'static>(value: T) -> T {
+    current().unwrap_or(value)
+}
+
+/// Make a service with some parameters overridden.
+///
+/// Implemented for every [`MakeService`]. The stack config is left as it is,
+/// so the next plain make uses its parameters again.
+pub trait MakeWithOverrides: MakeService {
+    fn make_via_ref_with(
+        &self,
+        old: Option<&Self::Service>,
+        overrides: &Overrides,
+    ) -> Result<Self::Service, Self::Error> {
+        scope(&Rc::new(overrides.clone()), || self.make_via_ref(old))
+    }
+}
+
+impl<F: MakeService + ?Sized> MakeWithOverrides for F {}
+
+/// Make a service with some parameters overridden. The async counterpart of
+/// [`MakeWithOverrides`].
+///
+/// The overrides are visible while the make is polled, so other tasks on the
+/// thread do not see them.
+pub trait AsyncMakeWithOverrides: AsyncMakeService {
+    fn make_via_ref_with(
+        &self,
+        old: Option<&Self::Service>,
+        overrides: &Overrides,
+    ) -> impl Future<Output = Result<Self::Service, Self::Error>> {
+        let overrides = Rc::new(overrides.clone());
+        async move {
+            let mut make = pin!(self.make_via_ref(old));
+            poll_fn(|cx| scope(&overrides, || make.as_mut().poll(cx))).await
+        }
+    }
+}
+
+impl<F: AsyncMakeService + ?Sized> AsyncMakeWithOverrides for F {}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin};
+
+    use super::{current, current_or, AsyncMakeWithOverrides, MakeWithOverrides, Overrides};
+    use crate::{
+        test_util::{block_on, poll_once},
+        yielding::yield_now,
+        AsyncMakeService, MakeService, ParamMaybeRef,
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Timeout(u32);
+
+    /// Makes its timeout, or the overridden one.
+    struct Factory(Timeout);
+
+    impl MakeService for Factory {
+        type Service = Timeout;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, _old: Option<&Timeout>) -> Result<Timeout, Infallible> {
+            Ok(current_or(self.0))
+        }
+    }
+
+    impl AsyncMakeService for Factory {
+        type Service = Timeout;
+        type Error = Infallible;
+
+        async fn make_via_ref(&self, _old: Option<&Timeout>) -> Result<Timeout, Infallible> {
+            yield_now().await;
+            Ok(current_or(self.0))
+        }
+    }
+
+    #[test]
+    fn overrides_one_make() {
+        let overrides = Overrides::new().with(Timeout(5)).with("other");
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(
+            ParamMaybeRef::<Timeout>::param_maybe_ref(&overrides),
+            Some(&Timeout(5))
+        );
+
+        let factory = Factory(Timeout(1));
+        let made = MakeWithOverrides::make_via_ref_with(&factory, None, &overrides);
+        assert_eq!(made, Ok(Timeout(5)));
+        assert_eq!(MakeService::make(&factory), Ok(Timeout(1)));
+        assert_eq!(current::<Timeout>(), None);
+
+        let mut make = pin!(AsyncMakeWithOverrides::make_via_ref_with(
+            &factory, None, &overrides
+        ));
+        assert!(poll_once(make.as_mut()).is_pending());
+        // Not visible between polls.
+        assert_eq!(current::<Timeout>(), None);
+        assert_eq!(block_on(make), Ok(Timeout(5)));
+
+        let mut others = overrides.clone();
+        others.remove::<Timeout>();
+        let made = MakeWithOverrides::make_via_ref_with(&factory, None, &others);
+        assert_eq!(made, Ok(Timeout(1)));
+    }
+}
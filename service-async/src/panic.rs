@@ -0,0 +1,270 @@
+use std::{
+    any::Any,
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    panic::{catch_unwind, AssertUnwindSafe},
+    pin::pin,
+    task::Poll,
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Service, ServiceMetadata,
+};
+
+/// A panic caught while calling a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanicError {
+    /// The panic message, if it was a string.
+    pub message: Option<String>,
+}
+
+impl PanicError {
+    fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(s) => Some(*s),
+            Err(payload) => payload.downcast_ref::<&str>().map(|s| s.to_string()),
+        };
+        PanicError { message }
+    }
+}
+
+impl Display for PanicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.message {
+            Some(msg) => write!(f, "service panicked: {msg}"),
+            None => f.write_str("service panicked"),
+        }
+    }
+}
+
+impl Error for PanicError {}
+
+/// Error returned by [`CatchPanic`].
+#[derive(Debug)]
+pub enum CatchPanicError<E> {
+    /// The inner service panicked.
+    Panicked(PanicError),
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for CatchPanicError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CatchPanicError::Panicked(e) => e.fmt(f),
+            CatchPanicError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for CatchPanicError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CatchPanicError::Panicked(e) => Some(e),
+            CatchPanicError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// Receives the panics caught by [`CatchPanic`], e.g. to raise an alert.
+pub trait PanicHandler {
+    fn on_panic(&self, err: &PanicError);
+}
+
+impl PanicHandler for () {
+    #[inline]
+    fn on_panic(&self, _err: &PanicError) {}
+}
+
+impl<F: Fn(&PanicError)> PanicHandler for F {
+    #[inline]
+    fn on_panic(&self, err: &PanicError) {
+        (self)(err)
+    }
+}
+
+/// A middleware which catches panics of the inner service, both when the call
+/// starts and while its future is polled, and returns them as
+/// [`CatchPanicError::Panicked`].
+///
+/// In thread-per-core deployments a panic would otherwise take down the worker
+/// with every connection it serves. The inner service must be left in a usable
+/// state by a panic: state behind a `RefCell` or a poisoned lock may not be.
+pub struct CatchPanic<T, H = ()> {
+    handler: H,
+    inner: T,
+}
+
+impl<T> CatchPanic<T> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = Self> {
+        layer_fn(|_: &C, inner| CatchPanic { handler: (), inner })
+    }
+}
+
+impl<T, H> CatchPanic<T, H> {
+    /// Catch panics and pass them to `handler`.
+    pub fn layer_with<C>(handler: H) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        H: Clone,
+    {
+        layer_fn(move |_: &C, inner| CatchPanic {
+            handler: handler.clone(),
+            inner,
+        })
+    }
+
+    fn caught(&self, payload: Box<dyn Any + Send>) -> PanicError
+    where
+        H: PanicHandler,
+    {
+        let err = PanicError::from_payload(payload);
+        self.handler.on_panic(&err);
+        err
+    }
+}
+
+impl<T, H, R> Service<R> for CatchPanic<T, H>
+where
+    T: Service<R>,
+    H: PanicHandler,
+{
+    type Response = T::Response;
+    type Error = CatchPanicError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let fut = catch_unwind(AssertUnwindSafe(|| self.inner.call(req)))
+            .map_err(|p| CatchPanicError::Panicked(self.caught(p)))?;
+        let mut fut = pin!(fut);
+        let res = poll_fn(|cx| match catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(p) => Poll::Ready(Err(p)),
+        })
+        .await;
+        match res {
+            Ok(res) => res.map_err(CatchPanicError::Inner),
+            Err(p) => Err(CatchPanicError::Panicked(self.caught(p))),
+        }
+    }
+}
+
+impl<F: MakeService, H: Clone> MakeService for CatchPanic<F, H> {
+    type Service = CatchPanic<F::Service, H>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(CatchPanic {
+            handler: self.handler.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, H: Clone> AsyncMakeService for CatchPanic<F, H> {
+    type Service = CatchPanic<F::Service, H>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(CatchPanic {
+            handler: self.handler.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(CatchPanic<_, H> { handler });
+
+impl<T, H> Layered for CatchPanic<T, H> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(CatchPanic<T, H>);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, future::Future, rc::Rc};
+
+    use super::{CatchPanic, CatchPanicError, PanicError};
+    use crate::{layer::FactoryLayer, test_util::block_on, yielding::yield_now, Service};
+
+    /// Panics when the call starts on 0, while polled on 1, and fails on 2.
+    struct Fragile;
+
+    impl Service<u32> for Fragile {
+        type Response = u32;
+        type Error = &'static str;
+
+        fn call(&self, req: u32) -> impl Future<Output = Result<u32, &'static str>> {
+            if req == 0 {
+                panic!("refused {req}");
+            }
+            async move {
+                yield_now().await;
+                match req {
+                    1 => panic!("boom"),
+                    2 => Err("failed"),
+                    _ => Ok(req),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn catches_panics_when_calling_and_polling() {
+        let caught = Rc::new(RefCell::new(Vec::new()));
+        let handler = {
+            let caught = caught.clone();
+            move |e: &PanicError| caught.borrow_mut().push(e.clone())
+        };
+        let svc = CatchPanic::layer_with(handler).layer(&(), Fragile);
+
+        assert_eq!(block_on(svc.call(3)).unwrap(), 3);
+        assert!(matches!(
+            block_on(svc.call(2)),
+            Err(CatchPanicError::Inner("failed"))
+        ));
+        for req in [0, 1] {
+            assert!(matches!(
+                block_on(svc.call(req)),
+                Err(CatchPanicError::Panicked(_))
+            ));
+        }
+        let messages: Vec<_> = caught.borrow().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            ["service panicked: refused 0", "service panicked: boom",]
+        );
+
+        let svc = CatchPanic::layer().layer(&(), Fragile);
+        assert!(matches!(
+            block_on(svc.call(1)),
+            Err(CatchPanicError::Panicked(PanicError { message: Some(m) })) if m == "boom"
+        ));
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    future::{poll_fn, Future},
+    hash::{BuildHasher, Hash},
+    pin::pin,
+    task::Poll,
+};
+
+use crate::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, ServiceMetadata, Zip,
+};
+
+/// Run `futs` concurrently and collect their outputs in order.
+///
+/// It returns the first error, dropping the futures still running.
+pub async fn try_join_all<I, T, E>(futs: I) -> Result<Vec<T>, E>
+where
+    I: IntoIterator,
+    I::Item: Future<Output = Result<T, E>>,
+{
+    let mut futs: Vec<_> = futs.into_iter().map(|f| Some(Box::pin(f))).collect();
+    let mut outputs: Vec<Option<T>> = futs.iter().map(|_| None).collect();
+    poll_fn(|cx| {
+        let mut done = true;
+        for (slot, output) in futs.iter_mut().zip(outputs.iter_mut()) {
+            let Some(fut) = slot else { continue };
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(Ok(v)) => {
+                    *output = Some(v);
+                    *slot = None;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => done = false,
+            }
+        }
+        if done {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await?;
+    Ok(outputs.into_iter().flatten().collect())
+}
+
+/// Run `a` and `b` concurrently, returning the first error.
+pub async fn try_join<A, B, T, U, E>(a: A, b: B) -> Result<(T, U), E>
+where
+    A: Future<Output = Result<T, E>>,
+    B: Future<Output = Result<U, E>>,
+{
+    let (mut a, mut b) = (pin!(a), pin!(b));
+    let (mut out_a, mut out_b) = (None, None);
+    poll_fn(|cx| {
+        if out_a.is_none() {
+            if let Poll::Ready(res) = a.as_mut().poll(cx) {
+                out_a = Some(res?);
+            }
+        }
+        if out_b.is_none() {
+            if let Poll::Ready(res) = b.as_mut().poll(cx) {
+                out_b = Some(res?);
+            }
+        }
+        match (out_a.take(), out_b.take()) {
+            (Some(a), Some(b)) => Poll::Ready(Ok((a, b))),
+            (a, b) => {
+                (out_a, out_b) = (a, b);
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+/// Make a service for each factory of a map concurrently, migrating each from
+/// the old service of the same key.
+pub(crate) async fn make_map<K, F, H>(
+    factories: &HashMap<K, F, H>,
+    old: Option<&HashMap<K, F::Service, H>>,
+) -> Result<HashMap<K, F::Service, H>, F::Error>
+where
+    K: Eq + Hash + Clone,
+    F: AsyncMakeService,
+    H: BuildHasher + Clone,
+{
+    let made = try_join_all(factories.iter().map(|(key, factory)| async move {
+        let svc = factory.make_via_ref(old.and_then(|o| o.get(key))).await?;
+        Ok((key.clone(), svc))
+    }))
+    .await?;
+    let mut svcs = HashMap::with_capacity_and_hasher(made.len(), factories.hasher().clone());
+    svcs.extend(made);
+    Ok(svcs)
+}
+
+/// A factory which makes the services of its sibling factories concurrently,
+/// e.g. the routes of a [`RouterFactory`](crate::router::RouterFactory) or the
+/// backends of a balancer.
+///
+/// Making a wide stack whose leaves do slow I/O, like loading certificates or
+/// resolving upstreams, then takes about as long as its slowest leaf. Each child
+/// is still made with `make_via_ref` against its own old service, exactly as
+/// the inner factory would do sequentially, and the first error fails the make.
+///
+/// It is implemented for `HashMap`s of factories, [`Zip`], and the router and
+/// balancer factories of this crate. Made synchronously it is the same as the
+/// inner factory.
+#[derive(Debug, Clone, Copy)]
+pub struct ParallelMake<F> {
+    inner: F,
+}
+
+impl<F> ParallelMake<F> {
+    #[inline]
+    pub const fn new(inner: F) -> Self {
+        ParallelMake { inner }
+    }
+
+    /// Make the children of the inner factory of a stack concurrently.
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self> {
+        layer_fn(|_: &C, inner| ParallelMake { inner })
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &F {
+        &self.inner
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: MakeService> MakeService for ParallelMake<F> {
+    type Service = F::Service;
+    type Error = F::Error;
+
+    #[inline]
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.inner.make_via_ref(old)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+impl<K, F, H> AsyncMakeService for ParallelMake<HashMap<K, F, H>>
+where
+    K: Eq + Hash + Clone,
+    F: AsyncMakeService,
+    H: BuildHasher + Clone,
+{
+    type Service = HashMap<K, F::Service, H>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        make_map(&self.inner, old).await
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        AsyncMakeService::validate(&self.inner)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        AsyncMakeService::service_metadata(&self.inner)
+    }
+}
+
+impl<A, B> AsyncMakeService for ParallelMake<Zip<A, B>>
+where
+    A: AsyncMakeService,
+    B: AsyncMakeService<Error = A::Error>,
+{
+    type Service = (A::Service, B::Service);
+    type Error = A::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        try_join(
+            self.inner.first.make_via_ref(old.map(|o| &o.0)),
+            self.inner.second.make_via_ref(old.map(|o| &o.1)),
+        )
+        .await
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        AsyncMakeService::validate(&self.inner)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        AsyncMakeService::service_metadata(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::HashMap,
+        future::{pending, poll_fn, ready},
+        rc::Rc,
+        task::Poll,
+    };
+
+    use super::{try_join, try_join_all, ParallelMake};
+    use crate::{test_util::block_on, yielding::yield_now, AsyncMakeService, AsyncMakeServiceExt};
+
+    /// Makes a service only once `n` makes have started, so sequential makes
+    /// never complete.
+    #[derive(Clone)]
+    struct Barrier {
+        name: &'static str,
+        started: Rc<Cell<usize>>,
+        n: usize,
+    }
+
+    impl AsyncMakeService for Barrier {
+        type Service = (&'static str, usize);
+        type Error = &'static str;
+
+        async fn make_via_ref(
+            &self,
+            old: Option<&Self::Service>,
+        ) -> Result<Self::Service, &'static str> {
+            if self.name.is_empty() {
+                return Err("unnamed");
+            }
+            self.started.set(self.started.get() + 1);
+            poll_fn(|cx| {
+                if self.started.get() >= self.n {
+                    return Poll::Ready(());
+                }
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            })
+            .await;
+            Ok((self.name, old.map_or(0, |o| o.1 + 1)))
+        }
+    }
+
+    fn barriers(names: &[&'static str]) -> Vec<Barrier> {
+        let started = Rc::new(Cell::new(0));
+        names
+            .iter()
+            .map(|&name| Barrier {
+                name,
+                started: started.clone(),
+                n: names.len(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn joins_in_order_and_fails_fast() {
+        let ok = try_join_all((0..3).map(|i| async move {
+            for _ in 0..3 - i {
+                yield_now().await;
+            }
+            Ok::<_, ()>(i)
+        }));
+        assert_eq!(block_on(ok), Ok(vec![0, 1, 2]));
+        // The first error is returned without waiting for the other futures.
+        let failed = try_join_all((0..2).map(|i| async move {
+            if i == 1 {
+                return Err(i);
+            }
+            pending::<()>().await;
+            Ok(())
+        }));
+        assert_eq!(block_on(failed), Err(1));
+        assert_eq!(
+            block_on(try_join(ready(Ok::<_, ()>(1)), ready(Ok(2)))),
+            Ok((1, 2))
+        );
+    }
+
+    #[test]
+    fn makes_children_concurrently() {
+        let factories: HashMap<_, _> = barriers(&["a", "b", "c"])
+            .into_iter()
+            .map(|b| (b.name, b))
+            .collect();
+        let old = HashMap::from([("a", ("a", 4))]);
+        let svcs = block_on(ParallelMake::new(factories).make_via_ref(Some(&old))).unwrap();
+        assert_eq!((svcs["a"], svcs["b"], svcs.len()), (("a", 5), ("b", 0), 3));
+
+        let zipped = |names| {
+            let mut factories = barriers(names).into_iter();
+            let (a, b) = (factories.next().unwrap(), factories.next().unwrap());
+            ParallelMake::new(a.zip(b))
+        };
+        let svcs = block_on(zipped(&["a", "b"]).make()).unwrap();
+        assert_eq!(svcs, (("a", 0), ("b", 0)));
+        assert_eq!(block_on(zipped(&["a", ""]).make()), Err("unnamed"));
+    }
+}
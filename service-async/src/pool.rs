@@ -0,0 +1,497 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    pin::Pin,
+    rc::Rc,
+    task::{Poll, Waker},
+};
+
+use crate::{serve::Spawn, shutdown::Shutdown, MakeService, MakeServiceBatchExt, Service};
+
+/// A service which needs exclusive access to itself to handle a request.
+///
+/// This is the "Exclusive Mutable Access" model of the README. Such a service
+/// can not be shared, so [`WorkerPool`] gives each worker its own instance.
+/// It is implemented for every [`Service`].
+pub trait ServiceMut<Request> {
+    /// Responses given by the service.
+    type Response;
+    /// Errors produced by the service.
+    type Error;
+
+    /// Process the request and return the response asynchronously.
+    fn call(&mut self, req: Request) -> impl Future<Output = Result<Self::Response, Self::Error>>;
+}
+
+impl<T: Service<R>, R> ServiceMut<R> for T {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    #[inline]
+    fn call(&mut self, req: R) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        Service::call(self, req)
+    }
+}
+
+/// Error returned by [`WorkerPool`].
+#[derive(Debug)]
+pub enum WorkerPoolError<E> {
+    /// The worker task has stopped, e.g. because its runtime shut down.
+    Closed,
+    /// The worker's service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for WorkerPoolError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkerPoolError::Closed => write!(f, "worker closed"),
+            WorkerPoolError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for WorkerPoolError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WorkerPoolError::Closed => None,
+            WorkerPoolError::Inner(e) => Some(e),
+        }
+    }
+}
+
+pub(crate) type Task<S> =
+    Box<dyn for<'a> FnOnce(&'a mut S) -> Pin<Box<dyn Future<Output = ()> + 'a>>>;
+
+pub(crate) fn task<S, FN>(f: FN) -> Task<S>
+where
+    FN: for<'a> FnOnce(&'a mut S) -> Pin<Box<dyn Future<Output = ()> + 'a>> + 'static,
+{
+    Box::new(f)
+}
+
+struct Queue<S> {
+    tasks: VecDeque<Task<S>>,
+    /// Tasks queued or running.
+    load: usize,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+pub(crate) struct Worker<S> {
+    queue: Rc<RefCell<Queue<S>>>,
+}
+
+impl<S> Worker<S> {
+    /// Spawn a worker task which owns `svc`.
+    pub(crate) fn spawn(svc: S, spawner: &impl Spawn) -> Self
+    where
+        S: 'static,
+    {
+        let queue = Rc::new(RefCell::new(Queue {
+            tasks: VecDeque::new(),
+            load: 0,
+            closed: false,
+            waker: None,
+        }));
+        spawner.spawn(Box::pin(run_worker(svc, queue.clone())));
+        Worker { queue }
+    }
+
+    /// Number of tasks waiting, not counting the running one.
+    pub(crate) fn queued(&self) -> usize {
+        self.queue.borrow().tasks.len()
+    }
+
+    pub(crate) fn push(&self, task: Task<S>) -> bool {
+        let mut queue = self.queue.borrow_mut();
+        if queue.closed {
+            return false;
+        }
+        queue.tasks.push_back(task);
+        queue.load += 1;
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    fn load(&self) -> usize {
+        self.queue.borrow().load
+    }
+
+    /// Refuse new tasks. The worker stops once the queued ones are done.
+    fn close(&self) {
+        let mut queue = self.queue.borrow_mut();
+        queue.closed = true;
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Shut the service down after the tasks already queued, refusing new
+    /// ones. The receiver gets a value once it is shut down.
+    pub(crate) fn shutdown(&self) -> Receiver<()>
+    where
+        S: Shutdown + 'static,
+    {
+        let (tx, rx) = oneshot();
+        self.push(task(move |svc: &mut S| {
+            Box::pin(async move {
+                svc.shutdown().await;
+                tx.send(());
+            })
+        }));
+        self.close();
+        rx
+    }
+}
+
+impl<S> Drop for Worker<S> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+async fn run_worker<S>(mut svc: S, queue: Rc<RefCell<Queue<S>>>) {
+    loop {
+        let next = poll_fn(|cx| {
+            let mut q = queue.borrow_mut();
+            match q.tasks.pop_front() {
+                Some(task) => Poll::Ready(Some(task)),
+                None if q.closed => Poll::Ready(None),
+                None => {
+                    q.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+        let Some(task) = next else {
+            return;
+        };
+        task(&mut svc).await;
+        queue.borrow_mut().load -= 1;
+    }
+}
+
+/// Sends a single value to a [`Receiver`].
+pub(crate) struct Sender<T> {
+    slot: Rc<RefCell<Slot<T>>>,
+}
+
+pub(crate) struct Receiver<T> {
+    slot: Rc<RefCell<Slot<T>>>,
+}
+
+struct Slot<T> {
+    value: Option<T>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+pub(crate) fn oneshot<T>() -> (Sender<T>, Receiver<T>) {
+    let slot = Rc::new(RefCell::new(Slot {
+        value: None,
+        closed: false,
+        waker: None,
+    }));
+    (Sender { slot: slot.clone() }, Receiver { slot })
+}
+
+impl<T> Sender<T> {
+    pub(crate) fn send(self, value: T) {
+        self.slot.borrow_mut().value = Some(value);
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut slot = self.slot.borrow_mut();
+        slot.closed = true;
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the value, or `None` if the sender was dropped without one.
+    pub(crate) fn recv(self) -> impl Future<Output = Option<T>> {
+        poll_fn(move |cx| {
+            let mut slot = self.slot.borrow_mut();
+            if let Some(value) = slot.value.take() {
+                Poll::Ready(Some(value))
+            } else if slot.closed {
+                Poll::Ready(None)
+            } else {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Runs one owned service per worker task and routes requests to them.
+///
+/// Each worker is a task on the current thread, spawned with a [`Spawn`], and
+/// handles its requests one at a time with `&mut` access to its own service
+/// (see [`ServiceMut`]). A request goes to the worker with the fewest queued
+/// requests. Dropping the pool stops the workers once their queues drain.
+/// Its [`Shutdown`] shuts down the service of every worker after the requests
+/// queued to it, refusing new ones.
+///
+/// [`reload`](Self::reload) rebuilds each worker's service from a new factory
+/// with `make_via_ref`, so it can migrate the state of the old one.
+pub struct WorkerPool<F: MakeService> {
+    workers: Vec<Worker<F::Service>>,
+}
+
+impl<F> WorkerPool<F>
+where
+    F: MakeService,
+    F::Service: 'static,
+{
+    /// Make `workers` services with `factory` and spawn a worker task for each.
+    pub fn new(factory: &F, workers: usize, spawner: impl Spawn) -> Result<Self, F::Error> {
+        let workers = factory
+            .make_n(workers)?
+            .into_iter()
+            .map(|svc| Worker::spawn(svc, &spawner))
+            .collect();
+        Ok(WorkerPool { workers })
+    }
+
+    /// Number of workers.
+    #[inline]
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Rebuild the service of every worker with `factory`.
+    ///
+    /// A worker rebuilds after the requests already queued to it. Workers which
+    /// fail to rebuild keep their old service, and the first error is returned.
+    pub async fn reload(&self, factory: F) -> Result<(), F::Error>
+    where
+        F: 'static,
+    {
+        factory.validate()?;
+        let factory = Rc::new(factory);
+        let replies: Vec<_> = self
+            .workers
+            .iter()
+            .map(|worker| {
+                let (tx, rx) = oneshot();
+                let factory = factory.clone();
+                worker.push(task(move |svc: &mut F::Service| {
+                    Box::pin(async move {
+                        match factory.make_via_ref(Some(&*svc)) {
+                            Ok(new) => {
+                                *svc = new;
+                                tx.send(Ok(()));
+                            }
+                            Err(e) => tx.send(Err(e)),
+                        }
+                    })
+                }));
+                rx
+            })
+            .collect();
+
+        let mut result = Ok(());
+        for rx in replies {
+            if let Some(Err(e)) = rx.recv().await {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    fn pick(&self) -> Option<&Worker<F::Service>> {
+        self.workers.iter().min_by_key(|w| w.load())
+    }
+}
+
+impl<F> Shutdown for WorkerPool<F>
+where
+    F: MakeService,
+    F::Service: Shutdown + 'static,
+{
+    async fn shutdown(&self) {
+        let replies: Vec<_> = self.workers.iter().map(Worker::shutdown).collect();
+        for rx in replies {
+            rx.recv().await;
+        }
+    }
+}
+
+impl<F, R> Service<R> for WorkerPool<F>
+where
+    F: MakeService,
+    F::Service: ServiceMut<R> + 'static,
+    R: 'static,
+    <F::Service as ServiceMut<R>>::Response: 'static,
+    <F::Service as ServiceMut<R>>::Error: 'static,
+{
+    type Response = <F::Service as ServiceMut<R>>::Response;
+    type Error = WorkerPoolError<<F::Service as ServiceMut<R>>::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let worker = self.pick().ok_or(WorkerPoolError::Closed)?;
+        let (tx, rx) = oneshot();
+        let pushed = worker.push(task(move |svc: &mut F::Service| {
+            Box::pin(async move { tx.send(ServiceMut::call(svc, req).await) })
+        }));
+        if !pushed {
+            return Err(WorkerPoolError::Closed);
+        }
+        rx.recv()
+            .await
+            .ok_or(WorkerPoolError::Closed)?
+            .map_err(WorkerPoolError::Inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, convert::Infallible, pin::pin, rc::Rc};
+
+    use super::{ServiceMut, WorkerPool, WorkerPoolError};
+    use crate::{
+        shutdown::Shutdown,
+        test_util::{poll_once, LocalExecutor},
+        utils::CloneFactory,
+        yielding::yield_now,
+        MakeService, Service,
+    };
+
+    /// Counts its calls, which need `&mut` access.
+    struct Counter {
+        id: u32,
+        generation: u32,
+        calls: u32,
+    }
+
+    impl ServiceMut<()> for Counter {
+        /// The id, generation and calls of the worker's service.
+        type Response = (u32, u32, u32);
+        type Error = ();
+
+        async fn call(&mut self, _: ()) -> Result<Self::Response, ()> {
+            self.calls += 1;
+            yield_now().await;
+            Ok((self.id, self.generation, self.calls))
+        }
+    }
+
+    /// Makes counters, keeping the id and calls of the old one. Generations
+    /// over 9 are invalid.
+    struct Factory {
+        generation: u32,
+        next_id: Cell<u32>,
+    }
+
+    impl Factory {
+        fn new(generation: u32) -> Self {
+            Factory {
+                generation,
+                next_id: Cell::new(0),
+            }
+        }
+    }
+
+    impl MakeService for Factory {
+        type Service = Counter;
+        type Error = &'static str;
+
+        fn make_via_ref(&self, old: Option<&Counter>) -> Result<Counter, &'static str> {
+            let (id, calls) = old.map_or_else(
+                || (self.next_id.replace(self.next_id.get() + 1), 0),
+                |o| (o.id, o.calls),
+            );
+            Ok(Counter {
+                id,
+                generation: self.generation,
+                calls,
+            })
+        }
+
+        fn validate(&self) -> Result<(), &'static str> {
+            (self.generation < 10).then_some(()).ok_or("invalid")
+        }
+    }
+
+    #[test]
+    fn routes_to_the_least_loaded_worker() {
+        let ex = LocalExecutor::new();
+        let pool = WorkerPool::new(&Factory::new(0), 2, ex.spawner()).unwrap();
+        assert_eq!(pool.workers(), 2);
+
+        let mut first = pin!(pool.call(()));
+        let mut second = pin!(pool.call(()));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert!(poll_once(second.as_mut()).is_pending());
+        assert_eq!(ex.block_on(first).unwrap(), (0, 0, 1));
+        assert_eq!(ex.block_on(second).unwrap(), (1, 0, 1));
+        assert_eq!(ex.block_on(pool.call(())).unwrap(), (0, 0, 2));
+    }
+
+    #[test]
+    fn reload_migrates_each_worker() {
+        let ex = LocalExecutor::new();
+        let pool = WorkerPool::new(&Factory::new(0), 2, ex.spawner()).unwrap();
+        ex.block_on(pool.call(())).unwrap();
+
+        assert_eq!(ex.block_on(pool.reload(Factory::new(10))), Err("invalid"));
+        assert_eq!(ex.block_on(pool.call(())).unwrap(), (0, 0, 2));
+        ex.block_on(pool.reload(Factory::new(1))).unwrap();
+        assert_eq!(ex.block_on(pool.call(())).unwrap(), (0, 1, 3));
+
+        drop(pool);
+        ex.run_tasks();
+        assert_eq!(ex.pending_tasks(), 0);
+    }
+
+    /// Counts the shutdowns of all its clones.
+    #[derive(Clone, Default)]
+    struct Closing(Rc<Cell<usize>>);
+
+    impl Service<()> for Closing {
+        type Response = usize;
+        type Error = Infallible;
+
+        async fn call(&self, _req: ()) -> Result<usize, Infallible> {
+            Ok(self.0.get())
+        }
+    }
+
+    impl Shutdown for Closing {
+        async fn shutdown(&self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn shutdown_closes_every_worker() {
+        let ex = LocalExecutor::new();
+        let closing = Closing::default();
+        let pool = WorkerPool::new(&CloneFactory::new(closing.clone()), 2, ex.spawner()).unwrap();
+        let mut queued = Box::pin(pool.call(()));
+        assert!(poll_once(queued.as_mut()).is_pending());
+
+        ex.block_on(pool.shutdown());
+        assert_eq!(closing.0.get(), 2);
+        assert_eq!(ex.block_on(queued).ok(), Some(0));
+        assert!(matches!(
+            ex.block_on(pool.call(())),
+            Err(WorkerPoolError::Closed)
+        ));
+        assert_eq!(ex.pending_tasks(), 0);
+    }
+}
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    reload,
+    scheduler::Admission,
+    semaphore::Semaphore,
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+pub use crate::scheduler::Fairness;
+
+/// Priority of a request. Higher values are admitted first.
+///
+/// Requests passed to [`PriorityQueue`] provide it through `Param<Priority>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Priority(pub u8);
+
+/// Configuration of [`PriorityQueue`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriorityQueueConfig {
+    /// Max number of requests the inner service handles at the same time.
+    pub max_concurrency: usize,
+    /// How queued requests are picked when a slot frees up.
+    pub fairness: Fairness,
+}
+
+/// A middleware which limits concurrent calls to the inner service and admits
+/// queued requests by their [`Priority`].
+///
+/// Requests over the limit wait in a queue until a call finishes. The queue and
+/// the in-flight count are kept across reloads, so a reload with a new limit
+/// applies to requests which are already waiting. Within a
+/// [`ServiceSlot::begin`](crate::reload::ServiceSlot::begin) transaction the
+/// new limit is applied when it commits.
+pub struct PriorityQueue<T> {
+    limiter: Arc<Semaphore>,
+    // The config the limiter was last configured with.
+    config: PriorityQueueConfig,
+    inner: T,
+}
+
+impl<T> PriorityQueue<T> {
+    /// Current concurrency limit.
+    pub fn max_concurrency(&self) -> usize {
+        self.limiter.capacity()
+    }
+
+    /// Number of calls being processed by the inner service.
+    pub fn in_flight(&self) -> usize {
+        self.limiter.in_use()
+    }
+
+    /// Number of requests waiting to be admitted.
+    pub fn queued(&self) -> usize {
+        self.limiter.queued()
+    }
+
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = PriorityQueueFactory<T>>
+    where
+        C: Param<PriorityQueueConfig>,
+    {
+        layer_fn(|c: &C, inner| PriorityQueueFactory {
+            config: c.param(),
+            inner,
+        })
+    }
+}
+
+impl<T, R> Service<R> for PriorityQueue<T>
+where
+    T: Service<R>,
+    R: Param<Priority>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let _permit = self.limiter.acquire(Admission::with_priority(req.param().0)).await;
+        self.inner.call(req).await
+    }
+}
+
+/// Factory of [`PriorityQueue`].
+pub struct PriorityQueueFactory<F> {
+    config: PriorityQueueConfig,
+    inner: F,
+}
+
+impl<F> PriorityQueueFactory<F> {
+    fn limiter<S>(&self, old: Option<&PriorityQueue<S>>) -> Arc<Semaphore> {
+        let PriorityQueueConfig {
+            max_concurrency,
+            fairness,
+        } = self.config;
+        match old {
+            // Reconfiguring resets the state of the scheduler, so it is only
+            // done when the config changed.
+            Some(old) if old.config == self.config => old.limiter.clone(),
+            Some(old) => {
+                let limiter = old.limiter.clone();
+                reload::on_commit(move || limiter.reconfigure(max_concurrency, fairness));
+                old.limiter.clone()
+            }
+            None => Arc::new(Semaphore::new(max_concurrency, fairness)),
+        }
+    }
+}
+
+impl<F: MakeService> MakeService for PriorityQueueFactory<F> {
+    type Service = PriorityQueue<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(PriorityQueue {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            limiter: self.limiter(old),
+            config: self.config,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("max_concurrency", self.config.max_concurrency)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for PriorityQueueFactory<F> {
+    type Service = PriorityQueue<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(PriorityQueue {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            limiter: self.limiter(old),
+            config: self.config,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("max_concurrency", self.config.max_concurrency)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(PriorityQueueFactory<_> { config });
+
+impl<T> Layered for PriorityQueue<T> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(PriorityQueue<T>);
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        convert::Infallible,
+        future::poll_fn,
+        pin::pin,
+        rc::Rc,
+        task::Poll,
+    };
+
+    use super::{Fairness, Priority, PriorityQueue, PriorityQueueConfig};
+    use crate::{
+        layer::FactoryLayer,
+        test_util::{block_on, poll_once},
+        utils::CloneFactory,
+        MakeService, Param, Service,
+    };
+
+    struct Req(u8);
+
+    impl Param<Priority> for Req {
+        fn param(&self) -> Priority {
+            Priority(self.0)
+        }
+    }
+
+    /// Logs the requests it starts, and holds them until released.
+    #[derive(Clone, Default)]
+    struct Held {
+        started: Rc<RefCell<Vec<u8>>>,
+        released: Rc<Cell<bool>>,
+    }
+
+    impl Service<Req> for Held {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, req: Req) -> Result<(), Infallible> {
+            self.started.borrow_mut().push(req.0);
+            poll_fn(|_| match self.released.get() {
+                true => Poll::Ready(Ok(())),
+                false => Poll::Pending,
+            })
+            .await
+        }
+    }
+
+    fn config(max_concurrency: usize) -> PriorityQueueConfig {
+        PriorityQueueConfig {
+            max_concurrency,
+            fairness: Fairness::Strict,
+        }
+    }
+
+    #[test]
+    fn admits_by_priority() {
+        let held = Held::default();
+        let factory = PriorityQueue::layer().layer(&config(1), CloneFactory::new(held.clone()));
+        let svc = factory.make().unwrap();
+
+        let mut first = pin!(svc.call(Req(0)));
+        let mut low = pin!(svc.call(Req(1)));
+        let mut high = pin!(svc.call(Req(9)));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert!(poll_once(low.as_mut()).is_pending());
+        assert!(poll_once(high.as_mut()).is_pending());
+        assert_eq!((svc.in_flight(), svc.queued()), (1, 2));
+
+        // A reload applies the new limit to the requests already queued.
+        let svc = PriorityQueue::layer()
+            .layer(&config(2), CloneFactory::new(held.clone()))
+            .make_via_ref(Some(&svc))
+            .unwrap();
+        assert_eq!(svc.max_concurrency(), 2);
+        assert!(poll_once(low.as_mut()).is_pending());
+        assert!(poll_once(high.as_mut()).is_pending());
+        assert_eq!(*held.started.borrow(), [0, 9]);
+
+        held.released.set(true);
+        block_on(first).unwrap();
+        block_on(high).unwrap();
+        block_on(low).unwrap();
+        assert_eq!(*held.started.borrow(), [0, 9, 1]);
+        assert_eq!(svc.in_flight(), 0);
+    }
+}
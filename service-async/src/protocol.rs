@@ -0,0 +1,295 @@
+use std::future::Future;
+
+use crate::{
+    layer::{layer_fn, FactoryLayer, Layered},
+    shutdown::Shutdown,
+    AsyncMakeService, MakeService, Service, ServiceMetadata,
+};
+
+/// A connection whose first bytes can be read without consuming them, so the
+/// service it is handed to still reads them.
+pub trait Peekable {
+    type Error;
+
+    /// Copy the next bytes of the connection into `buf` without consuming
+    /// them. It waits until `buf` is full, and only returns fewer bytes when
+    /// the connection is closed before.
+    fn peek(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>>;
+}
+
+impl<T: Peekable + ?Sized> Peekable for &mut T {
+    type Error = T::Error;
+
+    #[inline]
+    fn peek(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>> {
+        (**self).peek(buf)
+    }
+}
+
+/// Recognizes a protocol by the first bytes of a connection.
+pub trait Detect {
+    /// Number of bytes to peek.
+    fn peek_len(&self) -> usize;
+
+    /// Whether the connection speaks the protocol. `prefix` is shorter than
+    /// `peek_len` if the connection was closed before.
+    fn detect(&self, prefix: &[u8]) -> bool;
+}
+
+/// Detects connections starting with the given bytes, e.g. the HTTP/2
+/// connection preface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix(pub &'static [u8]);
+
+impl Detect for Prefix {
+    #[inline]
+    fn peek_len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    fn detect(&self, prefix: &[u8]) -> bool {
+        prefix == self.0
+    }
+}
+
+/// A detector which peeks `peek_len` bytes and passes them to `f`. Created by
+/// [`detect_fn`].
+#[derive(Debug, Clone, Copy)]
+pub struct DetectFn<F> {
+    peek_len: usize,
+    f: F,
+}
+
+/// Create a detector which calls `f` on the first `peek_len` bytes.
+#[inline]
+pub const fn detect_fn<F: Fn(&[u8]) -> bool>(peek_len: usize, f: F) -> DetectFn<F> {
+    DetectFn { peek_len, f }
+}
+
+impl<F: Fn(&[u8]) -> bool> Detect for DetectFn<F> {
+    #[inline]
+    fn peek_len(&self) -> usize {
+        self.peek_len
+    }
+
+    #[inline]
+    fn detect(&self, prefix: &[u8]) -> bool {
+        (self.f)(prefix)
+    }
+}
+
+/// A service which peeks the first bytes of a connection and dispatches it to
+/// the `matched` chain if the detector recognizes them, or to `other`.
+///
+/// Both chains are built by their own factories and keep their state across
+/// reloads through `make_via_ref`. They return the same response and error
+/// types, and errors from peeking are converted into that error with `From`.
+/// More than two protocols are handled by nesting switches, e.g. the `other`
+/// chain of an HTTP/2 switch being a TLS switch over an HTTP/1 chain:
+///
+/// ```ignore
+/// FactoryStack::new(config)
+///     .push(http1_factory_layer)
+///     .push(ProtocolSwitch::layer(tls_factory, detect_fn(1, |b| b == [0x16])))
+///     .push(ProtocolSwitch::layer(h2_factory, Prefix(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")))
+/// ```
+pub struct ProtocolSwitch<M, O, D> {
+    matched: M,
+    other: O,
+    detector: D,
+}
+
+impl<M, O, D> ProtocolSwitch<M, O, D> {
+    /// The chain of connections recognized by the detector.
+    #[inline]
+    pub fn matched(&self) -> &M {
+        &self.matched
+    }
+
+    /// The chain of other connections.
+    #[inline]
+    pub fn other(&self) -> &O {
+        &self.other
+    }
+
+    /// Wrap the inner factory as the `other` chain, with the given factory as
+    /// the `matched` chain.
+    pub fn layer<C>(
+        matched: M,
+        detector: D,
+    ) -> impl FactoryLayer<C, O, Factory = ProtocolSwitchFactory<M, O, D>>
+    where
+        M: Clone,
+        D: Clone,
+    {
+        layer_fn(move |_: &C, other| ProtocolSwitchFactory {
+            matched: matched.clone(),
+            other,
+            detector: detector.clone(),
+        })
+    }
+}
+
+impl<M, O, D, IO> Service<IO> for ProtocolSwitch<M, O, D>
+where
+    IO: Peekable,
+    M: Service<IO>,
+    M::Error: From<IO::Error>,
+    O: Service<IO, Response = M::Response, Error = M::Error>,
+    D: Detect,
+{
+    type Response = M::Response;
+    type Error = M::Error;
+
+    async fn call(&self, mut io: IO) -> Result<Self::Response, Self::Error> {
+        let mut prefix = vec![0; self.detector.peek_len()];
+        let n = io.peek(&mut prefix).await?;
+        if self.detector.detect(&prefix[..n]) {
+            self.matched.call(io).await
+        } else {
+            self.other.call(io).await
+        }
+    }
+}
+
+/// Factory of [`ProtocolSwitch`].
+pub struct ProtocolSwitchFactory<FM, FO, D> {
+    matched: FM,
+    other: FO,
+    detector: D,
+}
+
+impl<FM, FO, D> MakeService for ProtocolSwitchFactory<FM, FO, D>
+where
+    FM: MakeService,
+    FO: MakeService<Error = FM::Error>,
+    D: Clone,
+{
+    type Service = ProtocolSwitch<FM::Service, FO::Service, D>;
+    type Error = FM::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(ProtocolSwitch {
+            matched: self.matched.make_via_ref(old.map(|o| &o.matched))?,
+            other: self.other.make_via_ref(old.map(|o| &o.other))?,
+            detector: self.detector.clone(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.matched.validate()?;
+        self.other.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_inner(self.matched.service_metadata())
+            .with_inner(self.other.service_metadata())
+    }
+}
+
+impl<FM, FO, D> AsyncMakeService for ProtocolSwitchFactory<FM, FO, D>
+where
+    FM: AsyncMakeService,
+    FO: AsyncMakeService<Error = FM::Error>,
+    D: Clone,
+{
+    type Service = ProtocolSwitch<FM::Service, FO::Service, D>;
+    type Error = FM::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(ProtocolSwitch {
+            matched: self.matched.make_via_ref(old.map(|o| &o.matched)).await?,
+            other: self.other.make_via_ref(old.map(|o| &o.other)).await?,
+            detector: self.detector.clone(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.matched.validate()?;
+        self.other.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_inner(self.matched.service_metadata())
+            .with_inner(self.other.service_metadata())
+    }
+}
+
+impl<M, O, D> Layered for ProtocolSwitch<M, O, D> {
+    type Inner = O;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.other
+    }
+}
+
+impl<M: Shutdown, O: Shutdown, D> Shutdown for ProtocolSwitch<M, O, D> {
+    async fn shutdown(&self) {
+        self.matched.shutdown().await;
+        self.other.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_fn, Peekable, Prefix, ProtocolSwitch};
+    use crate::{
+        layer::FactoryLayer, test_util::block_on, utils::CloneFactory, MakeService, Service,
+    };
+
+    /// A connection over the given bytes; an empty one fails to peek.
+    struct Conn(&'static [u8]);
+
+    impl Peekable for Conn {
+        type Error = &'static str;
+
+        async fn peek(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+            if self.0.is_empty() {
+                return Err("reset");
+            }
+            let n = buf.len().min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            Ok(n)
+        }
+    }
+
+    /// Answers with its name and the whole connection.
+    #[derive(Clone)]
+    struct Named(&'static str);
+
+    impl Service<Conn> for Named {
+        type Response = (&'static str, &'static [u8]);
+        type Error = &'static str;
+
+        async fn call(&self, conn: Conn) -> Result<Self::Response, &'static str> {
+            Ok((self.0, conn.0))
+        }
+    }
+
+    #[test]
+    fn dispatches_by_prefix() {
+        let h2 = ProtocolSwitch::layer(CloneFactory::new(Named("h2")), Prefix(b"PRI *"));
+        let tls = ProtocolSwitch::layer(
+            CloneFactory::new(Named("tls")),
+            detect_fn(1, |b| b == [0x16]),
+        );
+        let factory = h2.layer(&(), tls.layer(&(), CloneFactory::new(Named("h1"))));
+        let svc = MakeService::make(&factory).unwrap();
+
+        let call = |bytes| block_on(svc.call(Conn(bytes)));
+        assert_eq!(call(b"PRI * HTTP/2.0"), Ok(("h2", &b"PRI * HTTP/2.0"[..])));
+        assert_eq!(call(b"\x16\x03\x01"), Ok(("tls", &b"\x16\x03\x01"[..])));
+        assert_eq!(call(b"GET / HTTP/1.1"), Ok(("h1", &b"GET / HTTP/1.1"[..])));
+        // Closed before the whole prefix.
+        assert_eq!(call(b"PRI"), Ok(("h1", &b"PRI"[..])));
+        assert_eq!(call(b""), Err("reset"));
+        assert_eq!(svc.matched().0, "h2");
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    convert::Infallible,
+    error::Error,
+    fmt::Display,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    either::Either,
+    fallback::FallbackPolicy,
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    time::Clock,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// A signal from a layer which refused or failed a call because it is
+/// overloaded, telling the layers above when to try again.
+///
+/// Middleware of this crate which shed load, like
+/// [`Bulkhead`](crate::bulkhead::Bulkhead) and
+/// [`AdaptiveConcurrency`](crate::adaptive::AdaptiveConcurrency), attach one
+/// to their rejections through [`PushbackHint`], so outer layers back off in
+/// step instead of each guessing.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pushback {
+    /// How long to wait before calling again, if known.
+    pub retry_after: Option<Duration>,
+    /// How loaded the layer is, from 0 (idle) to 1 (saturated), if known.
+    pub load: Option<f64>,
+}
+
+impl Pushback {
+    /// A pushback asking to wait for `delay`.
+    #[inline]
+    pub const fn retry_after(delay: Duration) -> Self {
+        Pushback {
+            retry_after: Some(delay),
+            load: None,
+        }
+    }
+
+    /// A pushback of a saturated layer.
+    #[inline]
+    pub const fn saturated() -> Self {
+        Pushback {
+            retry_after: None,
+            load: Some(1.0),
+        }
+    }
+
+    #[inline]
+    pub const fn with_load(mut self, load: f64) -> Self {
+        self.load = Some(load);
+        self
+    }
+}
+
+impl Display for Pushback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("overloaded")?;
+        if let Some(delay) = self.retry_after {
+            write!(f, ", retry after {delay:?}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Pushback {}
+
+/// Errors which may carry a [`Pushback`].
+///
+/// Errors of middleware forward the hint of the inner error, so it reaches the
+/// top of the stack wherever it was raised.
+pub trait PushbackHint {
+    fn pushback(&self) -> Option<Pushback>;
+}
+
+impl PushbackHint for Pushback {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        Some(*self)
+    }
+}
+
+impl PushbackHint for Infallible {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match *self {}
+    }
+}
+
+impl<A: PushbackHint, B: PushbackHint> PushbackHint for Either<A, B> {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match self {
+            Either::Left(e) => e.pushback(),
+            Either::Right(e) => e.pushback(),
+        }
+    }
+}
+
+/// A [`FallbackPolicy`] which falls back when the primary service pushes back.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OnPushback;
+
+impl<E: PushbackHint> FallbackPolicy<E> for OnPushback {
+    #[inline]
+    fn should_fallback(&self, err: &E) -> bool {
+        err.pushback().is_some()
+    }
+}
+
+/// Error returned by [`HonorPushback`].
+#[derive(Debug)]
+pub enum PushbackError<E> {
+    /// The inner service asked to wait, and the delay has not passed yet. It
+    /// holds the remaining delay.
+    BackingOff(Pushback),
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for PushbackError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushbackError::BackingOff(p) => write!(f, "backing off: {p}"),
+            PushbackError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for PushbackError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PushbackError::BackingOff(p) => Some(p),
+            PushbackError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<E: PushbackHint> PushbackHint for PushbackError<E> {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match self {
+            PushbackError::BackingOff(p) => Some(*p),
+            PushbackError::Inner(e) => e.pushback(),
+        }
+    }
+}
+
+/// A middleware which honours the `retry_after` of the [`Pushback`] of its
+/// inner service: until the delay has passed, calls fail fast with
+/// [`PushbackError::BackingOff`] without reaching the inner service.
+///
+/// Placed around each backend of a balancer, a backend which pushed back is
+/// skipped cheaply, and with the [`OnPushback`] policy a
+/// [`Fallback`](crate::fallback::Fallback) serves its calls meanwhile. The
+/// deadline is kept across reloads. The clock `CL` is taken from the stack
+/// config through `Param<CL>`.
+pub struct HonorPushback<T, CL> {
+    until: Arc<Mutex<Option<Instant>>>,
+    clock: CL,
+    inner: T,
+}
+
+impl<T, CL> HonorPushback<T, CL> {
+    pub fn layer<C: Param<CL>>() -> impl FactoryLayer<C, T, Factory = Self> {
+        layer_fn(|c: &C, inner| HonorPushback {
+            until: Default::default(),
+            clock: c.param(),
+            inner,
+        })
+    }
+
+    fn until(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.until.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T, CL: Clock> HonorPushback<T, CL> {
+    /// The delay left before calls reach the inner service again.
+    pub fn backing_off(&self) -> Option<Duration> {
+        let until = (*self.until())?;
+        until
+            .checked_duration_since(self.clock.now())
+            .filter(|d| !d.is_zero())
+    }
+
+    fn back_off(&self, delay: Duration) {
+        let deadline = self.clock.now() + delay;
+        let mut until = self.until();
+        if until.is_none_or(|u| u < deadline) {
+            *until = Some(deadline);
+        }
+    }
+}
+
+impl<T, CL, R> Service<R> for HonorPushback<T, CL>
+where
+    T: Service<R>,
+    T::Error: PushbackHint,
+    CL: Clock,
+{
+    type Response = T::Response;
+    type Error = PushbackError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        if let Some(remaining) = self.backing_off() {
+            return Err(PushbackError::BackingOff(Pushback::retry_after(remaining)));
+        }
+        self.inner.call(req).await.map_err(|e| {
+            if let Some(delay) = e.pushback().and_then(|p| p.retry_after) {
+                self.back_off(delay);
+            }
+            PushbackError::Inner(e)
+        })
+    }
+}
+
+impl<F: MakeService, CL: Clone> MakeService for HonorPushback<F, CL> {
+    type Service = HonorPushback<F::Service, CL>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(HonorPushback {
+            until: old.map(|o| o.until.clone()).unwrap_or_default(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, CL: Clone> AsyncMakeService for HonorPushback<F, CL> {
+    type Service = HonorPushback<F::Service, CL>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(HonorPushback {
+            until: old.map(|o| o.until.clone()).unwrap_or_default(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(HonorPushback<_, CL> { until, clock });
+
+impl<T, CL> Layered for HonorPushback<T, CL> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(HonorPushback<T, CL>);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, time::Duration};
+
+    use super::{HonorPushback, OnPushback, Pushback, PushbackError, PushbackHint};
+    use crate::{
+        either::Either, fallback::FallbackPolicy, layer::FactoryLayer, test_util::block_on,
+        time::MockTimer, utils::CloneFactory, MakeService, Service,
+    };
+
+    /// Pushes back with the delay of the request, if any, and counts calls.
+    #[derive(Clone, Default)]
+    struct Backend(Rc<Cell<usize>>);
+
+    impl Service<Option<u64>> for Backend {
+        type Response = ();
+        type Error = Pushback;
+
+        async fn call(&self, req: Option<u64>) -> Result<(), Pushback> {
+            self.0.set(self.0.get() + 1);
+            match req {
+                Some(millis) => Err(Pushback::retry_after(Duration::from_millis(millis))),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn honors_retry_after_across_reloads() {
+        let timer = MockTimer::new();
+        let backend = Backend::default();
+        let factory = HonorPushback::<_, MockTimer>::layer()
+            .layer(&timer, CloneFactory::new(backend.clone()));
+        let svc = factory.make().unwrap();
+
+        assert!(block_on(svc.call(None)).is_ok());
+        let err = block_on(svc.call(Some(10))).unwrap_err();
+        assert!(matches!(err, PushbackError::Inner(_)));
+        assert_eq!(svc.backing_off(), Some(Duration::from_millis(10)));
+
+        timer.advance(Duration::from_millis(4));
+        match block_on(svc.call(None)) {
+            Err(PushbackError::BackingOff(p)) => {
+                assert_eq!(p.retry_after, Some(Duration::from_millis(6)))
+            }
+            _ => panic!("expected to back off"),
+        }
+        assert_eq!(backend.0.get(), 2);
+
+        // A reload keeps the deadline.
+        let svc = factory.make_via_ref(Some(&svc)).unwrap();
+        assert_eq!(svc.backing_off(), Some(Duration::from_millis(6)));
+        timer.advance(Duration::from_millis(6));
+        assert_eq!(svc.backing_off(), None);
+        assert!(block_on(svc.call(None)).is_ok());
+        assert_eq!(backend.0.get(), 3);
+    }
+
+    #[test]
+    fn hints_reach_the_top() {
+        let saturated = Pushback::saturated().with_load(0.5);
+        assert_eq!(saturated.load, Some(0.5));
+        assert_eq!(
+            Pushback::retry_after(Duration::from_secs(1)).to_string(),
+            "overloaded, retry after 1s"
+        );
+
+        let err: Either<PushbackError<Pushback>, Pushback> =
+            Either::Left(PushbackError::Inner(saturated));
+        assert_eq!(err.pushback(), Some(saturated));
+        assert!(FallbackPolicy::should_fallback(&OnPushback, &err));
+        let err: PushbackError<std::convert::Infallible> =
+            PushbackError::BackingOff(Pushback::default());
+        assert!(err.pushback().is_some());
+        assert_eq!(err.to_string(), "backing off: overloaded");
+    }
+}
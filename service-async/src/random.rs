@@ -0,0 +1,68 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+}
+
+/// A fast, non-cryptographic random number (xorshift64*), seeded per thread.
+pub(crate) fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+/// Hash a value with a seed. The result is stable across processes.
+pub(crate) fn seeded_hash<T: Hash + ?Sized>(seed: u64, value: &T) -> u64 {
+    let mut hasher = SeededHasher(FNV_OFFSET ^ mix(seed));
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// FNV-1a, finished with [`mix`] to spread the bits of short keys. Unlike
+/// `DefaultHasher`, its output is fixed, so placements do not change across
+/// Rust releases.
+struct SeededHasher(u64);
+
+impl Hasher for SeededHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        mix(self.0)
+    }
+}
+
+/// The finaliser of splitmix64.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::seeded_hash;
+
+    /// Placements depend on these values, so they must not change.
+    #[test]
+    fn hash_is_fixed() {
+        assert_eq!(seeded_hash(0, "user-1"), 0x89e8_3712_c3aa_c3c8);
+        assert_eq!(seeded_hash(7, "user-1"), 0x70d4_8c5b_8ae7_ca62);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{future::Future, ops::Deref, rc::Rc, sync::Arc, time::Duration};
+
+use crate::{either::Either, layer::Layered, serve::Spawn, time::Timer};
+
+/// A service which holds resources that should be released once idle, e.g. a
+/// connection pool or a cache with expiring entries.
+///
+/// [`IdleReaper`] calls `reap` periodically on the live service, so such
+/// services do not each need a background task.
+///
+/// Layered services reap their inner service: the chain is walked through
+/// [`Layered::inner`] down to the leaf, which implements `ReapIdle` itself.
+/// Leaves without anything to reap implement it with an empty body.
+pub trait ReapIdle {
+    /// Release the resources which are idle.
+    fn reap(&self) -> impl Future<Output = ()>;
+}
+
+impl<T> ReapIdle for T
+where
+    T: Layered,
+    T::Inner: ReapIdle,
+{
+    #[inline]
+    fn reap(&self) -> impl Future<Output = ()> {
+        self.inner().reap()
+    }
+}
+
+impl<A: ReapIdle, B: ReapIdle> ReapIdle for Either<A, B> {
+    async fn reap(&self) {
+        match self {
+            Either::Left(a) => a.reap().await,
+            Either::Right(b) => b.reap().await,
+        }
+    }
+}
+
+impl<T: ReapIdle + ?Sized> ReapIdle for Arc<T> {
+    #[inline]
+    fn reap(&self) -> impl Future<Output = ()> {
+        (**self).reap()
+    }
+}
+
+impl<T: ReapIdle + ?Sized> ReapIdle for Rc<T> {
+    #[inline]
+    fn reap(&self) -> impl Future<Output = ()> {
+        (**self).reap()
+    }
+}
+
+/// A driver which calls [`ReapIdle::reap`] on the live service every
+/// `interval`.
+///
+/// `live` returns the service to reap on each round, so it follows reloads,
+/// e.g. `move || Some(slot.get())` for a
+/// [`ServiceSlot`](crate::reload::ServiceSlot), or `move || weak.upgrade()`
+/// for a service held in an `Rc`. The reaper stops once it returns `None`.
+pub struct IdleReaper<G, TM> {
+    live: G,
+    interval: Duration,
+    timer: TM,
+}
+
+impl<G, TM> IdleReaper<G, TM> {
+    pub fn new(live: G, interval: Duration, timer: TM) -> Self {
+        IdleReaper {
+            live,
+            interval,
+            timer,
+        }
+    }
+
+    /// Reap the live service every interval until there is none.
+    pub async fn run<P>(self)
+    where
+        G: Fn() -> Option<P>,
+        P: Deref,
+        P::Target: ReapIdle,
+        TM: Timer,
+    {
+        loop {
+            self.timer.sleep(self.interval).await;
+            match (self.live)() {
+                Some(svc) => svc.reap().await,
+                None => return,
+            }
+        }
+    }
+
+    /// Run the reaper in the background, on a task spawned with `spawner`.
+    pub fn spawn<P>(self, spawner: &impl Spawn)
+    where
+        G: Fn() -> Option<P> + 'static,
+        P: Deref + 'static,
+        P::Target: ReapIdle,
+        TM: Timer + 'static,
+    {
+        spawner.spawn(Box::pin(self.run()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, time::Duration};
+
+    use super::{IdleReaper, ReapIdle};
+    use crate::{
+        either::Either,
+        layer::{FactoryLayer, Layered},
+        test_util::{block_on, LocalExecutor},
+        time::MockTimer,
+        toggle::{Toggle, ToggleSwitch},
+    };
+
+    /// Counts the rounds of reaping.
+    #[derive(Default)]
+    struct Cache(Cell<usize>);
+
+    impl ReapIdle for Cache {
+        async fn reap(&self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn reaps_the_leaf_until_the_service_is_gone() {
+        let svc = Rc::new(
+            Toggle::layer(async |()| Ok::<_, ()>(()))
+                .layer(&ToggleSwitch::new(true), Cache::default()),
+        );
+        let timer = MockTimer::new();
+        let weak = Rc::downgrade(&svc);
+        let executor = LocalExecutor::new();
+        IdleReaper::new(
+            move || weak.upgrade(),
+            Duration::from_secs(1),
+            timer.clone(),
+        )
+        .spawn(&executor.spawner());
+
+        executor.run_tasks();
+        assert_eq!(svc.inner().0.get(), 0);
+        timer.advance(Duration::from_secs(1));
+        executor.run_tasks();
+        timer.advance(Duration::from_secs(1));
+        executor.run_tasks();
+        assert_eq!(svc.inner().0.get(), 2);
+
+        drop(svc);
+        assert_eq!(executor.pending_tasks(), 1);
+        timer.advance(Duration::from_secs(1));
+        executor.run_tasks();
+        assert_eq!(executor.pending_tasks(), 0);
+    }
+
+    #[test]
+    fn reaps_either_branch() {
+        let svc: Either<Cache, Rc<Cache>> = Either::Right(Rc::default());
+        block_on(svc.reap());
+        let Either::Right(cache) = svc else {
+            unreachable!()
+        };
+        assert_eq!(cache.0.get(), 1);
+    }
+}
@@ -0,0 +1,1080 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    hash::Hash,
+    pin::pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Poll, Waker},
+};
+
+use crate::{
+    parallel::make_map, serve::Reload, shutdown::Shutdown, AsyncMakeService, Service,
+};
+
+/// Create a watch channel holding the latest value sent, starting with `init`.
+///
+/// Receivers only observe the latest value: values sent while a receiver is not
+/// waiting are coalesced.
+pub fn watch<T>(init: T) -> (WatchSender<T>, WatchReceiver<T>) {
+    let shared = Arc::new(Watch {
+        state: Mutex::new(WatchState {
+            value: init,
+            version: 0,
+            senders: 1,
+            wakers: Vec::new(),
+        }),
+    });
+    (
+        WatchSender {
+            shared: shared.clone(),
+        },
+        WatchReceiver { shared, seen: 0 },
+    )
+}
+
+struct Watch<T> {
+    state: Mutex<WatchState<T>>,
+}
+
+struct WatchState<T> {
+    value: T,
+    version: u64,
+    senders: usize,
+    wakers: Vec<Waker>,
+}
+
+impl<T> Watch<T> {
+    fn lock(&self) -> MutexGuard<'_, WatchState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T> WatchState<T> {
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Sending half of a [`watch`] channel.
+pub struct WatchSender<T> {
+    shared: Arc<Watch<T>>,
+}
+
+impl<T> WatchSender<T> {
+    /// Replace the value and notify the receivers.
+    pub fn send(&self, value: T) {
+        let mut state = self.shared.lock();
+        state.value = value;
+        state.version += 1;
+        state.wake_all();
+    }
+
+    /// Create a receiver which sees values sent from now on.
+    pub fn subscribe(&self) -> WatchReceiver<T> {
+        let seen = self.shared.lock().version;
+        WatchReceiver {
+            shared: self.shared.clone(),
+            seen,
+        }
+    }
+}
+
+impl<T> Clone for WatchSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        WatchSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for WatchSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.wake_all();
+        }
+    }
+}
+
+/// Receiving half of a [`watch`] channel.
+pub struct WatchReceiver<T> {
+    shared: Arc<Watch<T>>,
+    seen: u64,
+}
+
+impl<T: Clone> WatchReceiver<T> {
+    /// Get the latest value, and mark it as seen.
+    pub fn get(&mut self) -> T {
+        let state = self.shared.lock();
+        self.seen = state.version;
+        state.value.clone()
+    }
+
+    /// Wait for a value newer than the last seen one.
+    ///
+    /// Returns `None` once all senders are dropped. It is cancel safe.
+    pub fn changed(&mut self) -> impl Future<Output = Option<T>> + '_ {
+        poll_fn(|cx| {
+            let mut state = self.shared.lock();
+            if state.version != self.seen {
+                self.seen = state.version;
+                return Poll::Ready(Some(state.value.clone()));
+            }
+            if state.senders == 0 {
+                return Poll::Ready(None);
+            }
+            if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                state.wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+}
+
+impl<T> Clone for WatchReceiver<T> {
+    fn clone(&self) -> Self {
+        WatchReceiver {
+            shared: self.shared.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T: Clone> Reload<T> for WatchReceiver<T> {
+    #[inline]
+    fn next(&mut self) -> impl Future<Output = Option<T>> {
+        self.changed()
+    }
+}
+
+type CommitHook = Box<dyn FnOnce()>;
+
+thread_local! {
+    // Hooks registered by the reload whose make is being polled, if any.
+    static STAGED: RefCell<Option<Vec<CommitHook>>> = const { RefCell::new(None) };
+}
+
+/// Run `hook` when the reload making the service in progress commits.
+///
+/// Called by factories in `make_via_ref` to defer changes to state they share
+/// with the old service, e.g. the limits of a limiter kept across reloads, so
+/// a reload which fails or is rolled back leaves the old service as it was.
+/// Outside of [`ServiceSlot::begin`] and [`ReloadGroup::begin`], e.g. in a
+/// plain `make_via_ref`, the hook runs right away.
+pub fn on_commit(hook: impl FnOnce() + 'static) {
+    let hook: CommitHook = Box::new(hook);
+    let now = STAGED.with(|s| match &mut *s.borrow_mut() {
+        Some(hooks) => {
+            hooks.push(hook);
+            None
+        }
+        None => Some(hook),
+    });
+    if let Some(hook) = now {
+        hook();
+    }
+}
+
+/// Run `make`, collecting the hooks passed to [`on_commit`] while it is
+/// polled, so other tasks on the thread do not register into them.
+async fn staged<T>(make: impl Future<Output = T>) -> (T, Vec<CommitHook>) {
+    struct Scope<'a> {
+        hooks: &'a mut Vec<CommitHook>,
+        prev: Option<Vec<CommitHook>>,
+    }
+
+    impl Drop for Scope<'_> {
+        fn drop(&mut self) {
+            let prev = self.prev.take();
+            *self.hooks = STAGED
+                .with(|s| std::mem::replace(&mut *s.borrow_mut(), prev))
+                .unwrap_or_default();
+        }
+    }
+
+    let mut make = pin!(make);
+    let mut hooks = Vec::new();
+    let out = poll_fn(|cx| {
+        let prev = STAGED.with(|s| s.borrow_mut().replace(std::mem::take(&mut hooks)));
+        let _scope = Scope {
+            hooks: &mut hooks,
+            prev,
+        };
+        make.as_mut().poll(cx)
+    })
+    .await;
+    (out, hooks)
+}
+
+fn run_hooks(hooks: Vec<CommitHook>) {
+    for hook in hooks {
+        hook();
+    }
+}
+
+/// A shared slot holding the live service, which can be swapped at runtime.
+///
+/// Calls through the slot use the service which is live when the call starts;
+/// a swap does not affect calls in flight. Clones share the same slot.
+pub struct ServiceSlot<S> {
+    current: Arc<Mutex<Arc<S>>>,
+}
+
+impl<S> ServiceSlot<S> {
+    pub fn new(svc: S) -> Self {
+        ServiceSlot {
+            current: Arc::new(Mutex::new(Arc::new(svc))),
+        }
+    }
+
+    /// Get the live service.
+    pub fn get(&self) -> Arc<S> {
+        self.current
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replace the live service, returning the old one.
+    pub fn swap(&self, svc: S) -> Arc<S> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *current, Arc::new(svc))
+    }
+
+    /// Replace the live service, and shut the old one down.
+    pub async fn swap_and_shutdown(&self, svc: S) -> Arc<S>
+    where
+        S: Shutdown,
+    {
+        let old = self.swap(svc);
+        old.shutdown().await;
+        old
+    }
+
+    /// Make a new service with `factory` against the live one, without
+    /// swapping it in yet.
+    ///
+    /// Changes the factories defer with [`on_commit`] are applied when the
+    /// transaction commits.
+    pub async fn begin<F>(&self, factory: &F) -> Result<ReloadTransaction<'_, S>, F::Error>
+    where
+        F: AsyncMakeService<Service = S>,
+    {
+        factory.validate()?;
+        let old = self.get();
+        let (new, hooks) = staged(factory.make_via_ref(Some(&old))).await;
+        Ok(ReloadTransaction {
+            slot: self,
+            old,
+            new: new?,
+            hooks,
+        })
+    }
+
+    /// Make a new service with `factory`, check it with `test`, and swap it in
+    /// only if both succeed. On failure the live service keeps serving.
+    pub async fn reload_checked<F, T>(
+        &self,
+        factory: &F,
+        test: &T,
+    ) -> Result<Arc<S>, ReloadError<F::Error, T::Error>>
+    where
+        F: AsyncMakeService<Service = S>,
+        T: SmokeTest<S>,
+    {
+        let txn = self.begin(factory).await.map_err(ReloadError::Make)?;
+        txn.check(test).await.map_err(ReloadError::SmokeTest)?;
+        Ok(txn.commit())
+    }
+}
+
+/// A new service made for a [`ServiceSlot`] and not swapped in yet. Created by
+/// [`ServiceSlot::begin`].
+///
+/// [`commit`](Self::commit) swaps it in; dropping the transaction, or
+/// [`rollback`](Self::rollback), discards it and leaves the live service
+/// serving as it was.
+pub struct ReloadTransaction<'a, S> {
+    slot: &'a ServiceSlot<S>,
+    old: Arc<S>,
+    new: S,
+    hooks: Vec<CommitHook>,
+}
+
+impl<S> ReloadTransaction<'_, S> {
+    /// The new service, e.g. to try it before committing.
+    #[inline]
+    pub fn service(&self) -> &S {
+        &self.new
+    }
+
+    /// The service which was live when the transaction began.
+    #[inline]
+    pub fn old(&self) -> &Arc<S> {
+        &self.old
+    }
+
+    /// Run `test` against the new service.
+    pub async fn check<T: SmokeTest<S>>(&self, test: &T) -> Result<(), T::Error> {
+        test.check(&self.new).await
+    }
+
+    /// Swap the new service in, returning the one it replaced.
+    pub fn commit(self) -> Arc<S> {
+        run_hooks(self.hooks);
+        self.slot.swap(self.new)
+    }
+
+    /// Swap the new service in, and shut the one it replaced down.
+    pub async fn commit_and_shutdown(self) -> Arc<S>
+    where
+        S: Shutdown,
+    {
+        run_hooks(self.hooks);
+        self.slot.swap_and_shutdown(self.new).await
+    }
+
+    /// Discard the new service.
+    #[inline]
+    pub fn rollback(self) {}
+}
+
+/// A check of a new service before [`ServiceSlot::reload_checked`] swaps it in.
+pub trait SmokeTest<S> {
+    type Error;
+
+    fn check(&self, svc: &S) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+impl<S> SmokeTest<S> for () {
+    type Error = Infallible;
+
+    #[inline]
+    async fn check(&self, _svc: &S) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// A [`SmokeTest`] which calls the service with a request from `f` and fails
+/// if the call fails. Created by [`smoke_call`].
+#[derive(Debug, Clone, Copy)]
+pub struct SmokeCall<FN>(FN);
+
+/// Create a [`SmokeTest`] which calls the service with the request `f` returns.
+#[inline]
+pub const fn smoke_call<FN>(f: FN) -> SmokeCall<FN> {
+    SmokeCall(f)
+}
+
+impl<S, FN, R> SmokeTest<S> for SmokeCall<FN>
+where
+    FN: Fn() -> R,
+    S: Service<R>,
+{
+    type Error = S::Error;
+
+    async fn check(&self, svc: &S) -> Result<(), Self::Error> {
+        svc.call((self.0)()).await.map(|_| ())
+    }
+}
+
+/// Error of [`ServiceSlot::reload_checked`].
+#[derive(Debug)]
+pub enum ReloadError<M, T> {
+    /// Making the new service failed.
+    Make(M),
+    /// The new service failed the smoke test.
+    SmokeTest(T),
+}
+
+impl<M: Display, T: Display> Display for ReloadError<M, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReloadError::Make(e) => write!(f, "make service error: {e}"),
+            ReloadError::SmokeTest(e) => write!(f, "smoke test error: {e}"),
+        }
+    }
+}
+
+impl<M: Error + 'static, T: Error + 'static> Error for ReloadError<M, T> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReloadError::Make(e) => Some(e),
+            ReloadError::SmokeTest(e) => Some(e),
+        }
+    }
+}
+
+impl<S> Clone for ServiceSlot<S> {
+    fn clone(&self) -> Self {
+        ServiceSlot {
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<S, R> Service<R> for ServiceSlot<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        self.get().call(req).await
+    }
+}
+
+/// The services of a [`ReloadGroup`] made by one reload.
+#[derive(Debug)]
+pub struct Generation<K, S> {
+    id: u64,
+    services: HashMap<K, S>,
+}
+
+impl<K: Eq + Hash, S> Generation<K, S> {
+    /// Number of reloads committed before this generation; the initial one is 0.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get the service named `key`.
+    #[inline]
+    pub fn get(&self, key: &K) -> Option<&S> {
+        self.services.get(key)
+    }
+
+    #[inline]
+    pub fn services(&self) -> &HashMap<K, S> {
+        &self.services
+    }
+}
+
+/// A set of named services which are rebuilt and swapped together.
+///
+/// Interdependent services, e.g. a route table and the upstream pools it
+/// points to, must not run in mixed generations. A reload makes every service
+/// of the group from its factory, concurrently and each against its own live
+/// service, and swaps all of them in at once only if every make succeeds.
+///
+/// A request using several services of the group takes a
+/// [`snapshot`](Self::snapshot) first, so it sees one generation throughout.
+/// Services of different types can share a group as an
+/// [`Either`](crate::either::Either) or a boxed service. Clones share the same
+/// group.
+pub struct ReloadGroup<K, S> {
+    current: Arc<Mutex<Arc<Generation<K, S>>>>,
+}
+
+impl<K: Eq + Hash + Clone, S> ReloadGroup<K, S> {
+    pub fn new(services: HashMap<K, S>) -> Self {
+        ReloadGroup {
+            current: Arc::new(Mutex::new(Arc::new(Generation { id: 0, services }))),
+        }
+    }
+
+    /// Make the initial services with `factories`.
+    pub async fn start<F>(factories: &HashMap<K, F>) -> Result<Self, F::Error>
+    where
+        F: AsyncMakeService<Service = S>,
+    {
+        for factory in factories.values() {
+            factory.validate()?;
+        }
+        Ok(Self::new(make_map(factories, None).await?))
+    }
+
+    /// Get the live generation.
+    pub fn snapshot(&self) -> Arc<Generation<K, S>> {
+        self.current
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// A service calling the live service named `key`.
+    #[inline]
+    pub fn slot(&self, key: K) -> GroupSlot<K, S> {
+        GroupSlot {
+            group: self.clone(),
+            key,
+        }
+    }
+
+    /// Make a new generation with `factories` against the live one, without
+    /// swapping it in yet.
+    ///
+    /// Every factory is validated before any is made. Services missing from
+    /// `factories` are not carried over: the new generation holds exactly the
+    /// services `factories` makes. As for [`ServiceSlot::begin`], changes
+    /// deferred with [`on_commit`] are applied when the transaction commits.
+    pub async fn begin<F>(
+        &self,
+        factories: &HashMap<K, F>,
+    ) -> Result<GroupTransaction<'_, K, S>, F::Error>
+    where
+        F: AsyncMakeService<Service = S>,
+    {
+        for factory in factories.values() {
+            factory.validate()?;
+        }
+        let old = self.snapshot();
+        let (services, hooks) = staged(make_map(factories, Some(&old.services))).await;
+        Ok(GroupTransaction {
+            group: self,
+            old,
+            services: services?,
+            hooks,
+        })
+    }
+
+    /// Make a new generation with `factories` and swap it in, or keep the live
+    /// one serving if any make fails.
+    pub async fn reload<F>(
+        &self,
+        factories: &HashMap<K, F>,
+    ) -> Result<Arc<Generation<K, S>>, F::Error>
+    where
+        F: AsyncMakeService<Service = S>,
+    {
+        Ok(self.begin(factories).await?.commit())
+    }
+
+    /// Make a new generation with `factories`, check it with `test`, and swap
+    /// it in only if both succeed.
+    pub async fn reload_checked<F, T>(
+        &self,
+        factories: &HashMap<K, F>,
+        test: &T,
+    ) -> Result<Arc<Generation<K, S>>, ReloadError<F::Error, T::Error>>
+    where
+        F: AsyncMakeService<Service = S>,
+        T: SmokeTest<HashMap<K, S>>,
+    {
+        let txn = self.begin(factories).await.map_err(ReloadError::Make)?;
+        txn.check(test).await.map_err(ReloadError::SmokeTest)?;
+        Ok(txn.commit())
+    }
+}
+
+impl<K, S> Clone for ReloadGroup<K, S> {
+    fn clone(&self) -> Self {
+        ReloadGroup {
+            current: self.current.clone(),
+        }
+    }
+}
+
+/// A new generation made for a [`ReloadGroup`] and not swapped in yet.
+/// Created by [`ReloadGroup::begin`].
+///
+/// [`commit`](Self::commit) swaps every service in at once; dropping the
+/// transaction discards all of them.
+pub struct GroupTransaction<'a, K, S> {
+    group: &'a ReloadGroup<K, S>,
+    old: Arc<Generation<K, S>>,
+    services: HashMap<K, S>,
+    hooks: Vec<CommitHook>,
+}
+
+impl<K, S> GroupTransaction<'_, K, S> {
+    /// The new services, e.g. to try them before committing.
+    #[inline]
+    pub fn services(&self) -> &HashMap<K, S> {
+        &self.services
+    }
+
+    /// The generation which was live when the transaction began.
+    #[inline]
+    pub fn old(&self) -> &Arc<Generation<K, S>> {
+        &self.old
+    }
+
+    /// Run `test` against the new services.
+    pub async fn check<T: SmokeTest<HashMap<K, S>>>(&self, test: &T) -> Result<(), T::Error> {
+        test.check(&self.services).await
+    }
+
+    /// Swap the new generation in, returning the one it replaced.
+    ///
+    /// A generation committed by another reload since this one began is
+    /// replaced as well.
+    pub fn commit(self) -> Arc<Generation<K, S>> {
+        run_hooks(self.hooks);
+        let mut current = self.group.current.lock().unwrap_or_else(|e| e.into_inner());
+        let id = current.id + 1;
+        std::mem::replace(
+            &mut *current,
+            Arc::new(Generation {
+                id,
+                services: self.services,
+            }),
+        )
+    }
+
+    /// Discard the new services.
+    #[inline]
+    pub fn rollback(self) {}
+}
+
+/// Error returned by [`GroupSlot`].
+#[derive(Debug)]
+pub enum GroupSlotError<E> {
+    /// The live generation has no service of the slot's name.
+    NotFound,
+    /// The service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for GroupSlotError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupSlotError::NotFound => write!(f, "service not found in reload group"),
+            GroupSlotError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for GroupSlotError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GroupSlotError::NotFound => None,
+            GroupSlotError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A service calling one named service of a [`ReloadGroup`]. Created by
+/// [`ReloadGroup::slot`].
+///
+/// Like [`ServiceSlot`], a call uses the generation which is live when it
+/// starts. Requests which use several services of the group should call them
+/// through one [`ReloadGroup::snapshot`] instead.
+pub struct GroupSlot<K, S> {
+    group: ReloadGroup<K, S>,
+    key: K,
+}
+
+impl<K: Clone, S> Clone for GroupSlot<K, S> {
+    fn clone(&self) -> Self {
+        GroupSlot {
+            group: self.group.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<K, S, R> Service<R> for GroupSlot<K, S>
+where
+    K: Eq + Hash + Clone,
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = GroupSlotError<S::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let generation = self.group.snapshot();
+        let svc = generation.get(&self.key).ok_or(GroupSlotError::NotFound)?;
+        svc.call(req).await.map_err(GroupSlotError::Inner)
+    }
+}
+
+type ErrorHook<E> = Box<dyn Fn(E)>;
+
+/// Rebuilds the service in a [`ServiceSlot`] when a new config arrives.
+///
+/// For each config received from the [`watch`] channel, `build` constructs
+/// the factory (usually a `FactoryStack`), which makes the new service with
+/// `make_via_ref` against the live one, and the result is swapped into the slot.
+/// When making fails, the live service is kept and the error is passed to
+/// [`on_error`](ReloadController::on_error).
+///
+/// For services implementing [`Shutdown`], [`run_and_shutdown`](Self::run_and_shutdown)
+/// also shuts each replaced service down.
+pub struct ReloadController<C, B, F: AsyncMakeService> {
+    build: B,
+    configs: WatchReceiver<C>,
+    slot: ServiceSlot<F::Service>,
+    on_error: ErrorHook<F::Error>,
+}
+
+impl<C, B, F> ReloadController<C, B, F>
+where
+    C: Clone,
+    B: Fn(&C) -> F,
+    F: AsyncMakeService,
+{
+    /// Make the initial service from the latest config, and return the slot
+    /// holding it with the controller which keeps it up to date.
+    pub async fn start(
+        build: B,
+        mut configs: WatchReceiver<C>,
+    ) -> Result<(Self, ServiceSlot<F::Service>), F::Error> {
+        let svc = build(&configs.get()).make().await?;
+        let slot = ServiceSlot::new(svc);
+        let controller = ReloadController {
+            build,
+            configs,
+            slot: slot.clone(),
+            on_error: Box::new(|_| {}),
+        };
+        Ok((controller, slot))
+    }
+
+    /// Handle errors of making the service on reload.
+    pub fn on_error(mut self, f: impl Fn(F::Error) + 'static) -> Self {
+        self.on_error = Box::new(f);
+        self
+    }
+
+    /// Rebuild the service with a config right away.
+    pub async fn reload(&self, config: &C) -> Result<(), F::Error> {
+        self.slot.begin(&(self.build)(config)).await?.commit();
+        Ok(())
+    }
+
+    /// Rebuild the service with a config right away, swapping it in only if
+    /// it passes `test`. See [`ServiceSlot::reload_checked`].
+    pub async fn reload_checked<T: SmokeTest<F::Service>>(
+        &self,
+        config: &C,
+        test: &T,
+    ) -> Result<(), ReloadError<F::Error, T::Error>> {
+        self.slot
+            .reload_checked(&(self.build)(config), test)
+            .await
+            .map(|_| ())
+    }
+
+    /// Apply configs from the channel until all its senders are dropped.
+    pub async fn run(mut self) {
+        while let Some(config) = self.configs.changed().await {
+            if let Err(e) = self.reload(&config).await {
+                (self.on_error)(e);
+            }
+        }
+    }
+}
+
+impl<C, B, F> ReloadController<C, B, F>
+where
+    C: Clone,
+    B: Fn(&C) -> F,
+    F: AsyncMakeService,
+    F::Service: Shutdown,
+{
+    /// Rebuild the service with a config right away, and shut the replaced one
+    /// down. See [`ReloadTransaction::commit_and_shutdown`].
+    pub async fn reload_and_shutdown(&self, config: &C) -> Result<(), F::Error> {
+        self.slot
+            .begin(&(self.build)(config))
+            .await?
+            .commit_and_shutdown()
+            .await;
+        Ok(())
+    }
+
+    /// Apply configs from the channel until all its senders are dropped, and
+    /// shut each replaced service down.
+    pub async fn run_and_shutdown(mut self) {
+        while let Some(config) = self.configs.changed().await {
+            if let Err(e) = self.reload_and_shutdown(&config).await {
+                (self.on_error)(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::HashMap,
+        convert::Infallible,
+        pin::pin,
+        rc::Rc,
+    };
+
+    use super::{
+        smoke_call, watch, GroupSlotError, ReloadController, ReloadError, ReloadGroup, ServiceSlot,
+        SmokeTest,
+    };
+    use crate::{
+        layer::FactoryLayer,
+        priority::{Fairness, PriorityQueue, PriorityQueueConfig},
+        shutdown::Shutdown,
+        test_util::{block_on, poll_once},
+        utils::CloneFactory,
+        AsyncMakeService, Service,
+    };
+
+    /// A service made from config `config`, against `generation` older ones.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Versioned {
+        config: u32,
+        generation: usize,
+        closed: Cell<bool>,
+    }
+
+    impl Shutdown for Versioned {
+        async fn shutdown(&self) {
+            self.closed.set(true);
+        }
+    }
+
+    impl Service<()> for Versioned {
+        type Response = (u32, usize);
+        type Error = Infallible;
+
+        async fn call(&self, _req: ()) -> Result<(u32, usize), Infallible> {
+            Ok((self.config, self.generation))
+        }
+    }
+
+    /// Makes [`Versioned`] services; config 0 is invalid.
+    struct Factory(u32);
+
+    impl AsyncMakeService for Factory {
+        type Service = Versioned;
+        type Error = &'static str;
+
+        async fn make_via_ref(&self, old: Option<&Versioned>) -> Result<Versioned, &'static str> {
+            if self.0 == 0 {
+                return Err("invalid config");
+            }
+            Ok(Versioned {
+                config: self.0,
+                generation: old.map_or(0, |o| o.generation + 1),
+                closed: Cell::new(false),
+            })
+        }
+    }
+
+    #[test]
+    fn watch_coalesces_and_closes() {
+        let (tx, mut rx) = watch(1);
+        let mut late = tx.subscribe();
+        assert_eq!(rx.get(), 1);
+        assert!(poll_once(pin!(rx.changed())).is_pending());
+
+        tx.send(2);
+        tx.send(3);
+        assert_eq!(block_on(rx.changed()), Some(3));
+        assert_eq!(block_on(late.changed()), Some(3));
+        assert!(poll_once(pin!(rx.changed())).is_pending());
+
+        let tx2 = tx.clone();
+        drop(tx);
+        tx2.send(4);
+        drop(tx2);
+        assert_eq!(block_on(rx.changed()), Some(4));
+        assert_eq!(block_on(rx.changed()), None);
+    }
+
+    #[test]
+    fn controller_applies_configs() {
+        let (tx, rx) = watch(1);
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let start = ReloadController::start(|c: &u32| Factory(*c), rx);
+        let (controller, slot) = block_on(start).unwrap();
+        let controller = controller.on_error({
+            let errors = errors.clone();
+            move |e| errors.borrow_mut().push(e)
+        });
+        let mut run = pin!(controller.run());
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert_eq!(block_on(slot.call(())), Ok((1, 0)));
+
+        tx.send(2);
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert_eq!(block_on(slot.call(())), Ok((2, 1)));
+
+        // A config which fails to make keeps the live service.
+        tx.send(0);
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert_eq!(block_on(slot.call(())), Ok((2, 1)));
+        assert_eq!(*errors.borrow(), ["invalid config"]);
+
+        tx.send(3);
+        tx.send(4);
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert_eq!(block_on(slot.call(())), Ok((4, 2)));
+
+        drop(tx);
+        assert!(poll_once(run).is_ready());
+    }
+
+    #[test]
+    fn controller_shuts_replaced_services_down() {
+        let (tx, rx) = watch(1);
+        let start = ReloadController::start(|c: &u32| Factory(*c), rx);
+        let (controller, slot) = block_on(start).unwrap();
+        let mut run = pin!(controller.run_and_shutdown());
+        assert!(poll_once(run.as_mut()).is_pending());
+        let first = slot.get();
+
+        tx.send(2);
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert!(first.closed.get());
+        assert_eq!(block_on(slot.call(())), Ok((2, 1)));
+        assert!(!slot.get().closed.get());
+
+        drop(tx);
+        assert!(poll_once(run).is_ready());
+    }
+
+    /// Rejects services made from a config below the minimum.
+    struct MinConfig(u32);
+
+    impl SmokeTest<Versioned> for MinConfig {
+        type Error = &'static str;
+
+        async fn check(&self, svc: &Versioned) -> Result<(), &'static str> {
+            (svc.config >= self.0).then_some(()).ok_or("config too old")
+        }
+    }
+
+    #[test]
+    fn transactions_commit_or_roll_back() {
+        let slot = ServiceSlot::new(block_on(Factory(1).make()).unwrap());
+
+        let txn = block_on(slot.begin(&Factory(2))).unwrap();
+        assert_eq!(txn.service().generation, 1);
+        assert_eq!(txn.old().config, 1);
+        block_on(txn.check(&smoke_call(|| ()))).unwrap();
+        assert_eq!(block_on(slot.call(())), Ok((1, 0)));
+        txn.rollback();
+        assert_eq!(block_on(slot.call(())), Ok((1, 0)));
+
+        let txn = block_on(slot.begin(&Factory(3))).unwrap();
+        let old = txn.commit();
+        assert_eq!(old.config, 1);
+        assert_eq!(block_on(slot.call(())), Ok((3, 1)));
+        assert!(block_on(slot.begin(&Factory(0))).is_err());
+    }
+
+    #[test]
+    fn reload_checked_keeps_the_live_service() {
+        let slot = ServiceSlot::new(block_on(Factory(5).make()).unwrap());
+        let reload = |config| block_on(slot.reload_checked(&Factory(config), &MinConfig(5)));
+
+        assert!(matches!(
+            reload(0),
+            Err(ReloadError::Make("invalid config"))
+        ));
+        assert!(matches!(
+            reload(4),
+            Err(ReloadError::SmokeTest("config too old"))
+        ));
+        assert_eq!(block_on(slot.call(())), Ok((5, 0)));
+        assert_eq!(reload(6).unwrap().config, 5);
+        assert_eq!(block_on(slot.call(())), Ok((6, 1)));
+    }
+
+    impl SmokeTest<HashMap<&str, Versioned>> for MinConfig {
+        type Error = &'static str;
+
+        async fn check(&self, services: &HashMap<&str, Versioned>) -> Result<(), &'static str> {
+            for svc in services.values() {
+                SmokeTest::<Versioned>::check(self, svc).await?;
+            }
+            Ok(())
+        }
+    }
+
+    fn factories(configs: &[(&'static str, u32)]) -> HashMap<&'static str, Factory> {
+        configs.iter().map(|&(k, c)| (k, Factory(c))).collect()
+    }
+
+    #[test]
+    fn groups_swap_all_services_or_none() {
+        let start = factories(&[("routes", 1), ("pools", 1)]);
+        let group = block_on(ReloadGroup::start(&start)).unwrap();
+        let reload = |configs| block_on(group.reload(&factories(configs)));
+        let routes = group.slot("routes");
+        let first = group.snapshot();
+        assert_eq!(first.id(), 0);
+        assert_eq!(block_on(routes.call(())).ok(), Some((1, 0)));
+
+        // One failed make keeps every live service.
+        assert_eq!(
+            reload(&[("routes", 2), ("pools", 0)]).unwrap_err(),
+            "invalid config"
+        );
+        let checked = factories(&[("routes", 5), ("pools", 4)]);
+        assert!(matches!(
+            block_on(group.reload_checked(&checked, &MinConfig(5))),
+            Err(ReloadError::SmokeTest("config too old"))
+        ));
+        assert_eq!(group.snapshot().id(), 0);
+        assert_eq!(block_on(routes.call(())).ok(), Some((1, 0)));
+
+        assert_eq!(reload(&[("routes", 2), ("pools", 2)]).unwrap().id(), 0);
+        let second = group.snapshot();
+        assert_eq!(second.id(), 1);
+        assert_eq!(second.get(&"pools").unwrap().generation, 1);
+        assert_eq!(block_on(routes.call(())).ok(), Some((2, 1)));
+        // An older snapshot still sees its own generation.
+        assert_eq!(first.get(&"pools").unwrap().config, 1);
+    }
+
+    #[test]
+    fn group_transactions_commit_or_roll_back() {
+        let start = factories(&[("routes", 1), ("pools", 1)]);
+        let group = block_on(ReloadGroup::start(&start)).unwrap();
+        let pools = group.slot("pools");
+
+        let only_routes = factories(&[("routes", 2)]);
+        let txn = block_on(group.begin(&only_routes)).unwrap();
+        assert_eq!(txn.services().len(), 1);
+        assert_eq!(txn.old().id(), 0);
+        txn.rollback();
+        assert_eq!(block_on(pools.call(())).ok(), Some((1, 0)));
+
+        // Services missing from the factories are dropped.
+        let txn = block_on(group.begin(&only_routes)).unwrap();
+        txn.commit();
+        assert_eq!(group.snapshot().services().len(), 1);
+        assert!(matches!(
+            block_on(pools.call(())),
+            Err(GroupSlotError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn shared_limits_change_on_commit_only() {
+        let queue = |max_concurrency| {
+            let config = PriorityQueueConfig {
+                max_concurrency,
+                fairness: Fairness::Strict,
+            };
+            PriorityQueue::layer().layer(&config, CloneFactory::new(()))
+        };
+        let slot = ServiceSlot::new(block_on(queue(1).make()).unwrap());
+
+        let txn = block_on(slot.begin(&queue(4))).unwrap();
+        assert_eq!(txn.service().max_concurrency(), 1);
+        txn.rollback();
+        assert_eq!(slot.get().max_concurrency(), 1);
+
+        let mut group = HashMap::new();
+        group.insert("queue", block_on(queue(1).make()).unwrap());
+        let group = ReloadGroup::new(group);
+        drop(block_on(group.begin(&HashMap::from([("queue", queue(4))]))).unwrap());
+        assert_eq!(group.snapshot().get(&"queue").unwrap().max_concurrency(), 1);
+
+        block_on(slot.begin(&queue(4))).unwrap().commit();
+        assert_eq!(slot.get().max_concurrency(), 4);
+        block_on(group.reload(&HashMap::from([("queue", queue(4))]))).unwrap();
+        assert_eq!(group.snapshot().get(&"queue").unwrap().max_concurrency(), 4);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, ParamSet, Service, ServiceMetadata,
+};
+
+/// A unique ID of a request, set in the request context by [`SetRequestId`].
+///
+/// Layers below read it through `Param<RequestId>` to tag their logs, metrics
+/// or traces. It is a plain `u128`, so it is copied rather than allocated, and
+/// displays as 32 hex digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct RequestId(pub u128);
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl From<u128> for RequestId {
+    #[inline]
+    fn from(id: u128) -> Self {
+        RequestId(id)
+    }
+}
+
+/// A source of [`RequestId`]s.
+pub trait RequestIdGenerator {
+    fn next_id(&self) -> RequestId;
+}
+
+impl<G: RequestIdGenerator + ?Sized> RequestIdGenerator for Arc<G> {
+    #[inline]
+    fn next_id(&self) -> RequestId {
+        (**self).next_id()
+    }
+}
+
+impl<F: Fn() -> RequestId> RequestIdGenerator for F {
+    #[inline]
+    fn next_id(&self) -> RequestId {
+        (self)()
+    }
+}
+
+/// A snowflake style [`RequestIdGenerator`].
+///
+/// An ID is made of the time in milliseconds since the Unix epoch (48 bits),
+/// a worker ID (16 bits) and a sequence number (64 bits). IDs are unique across
+/// workers with different worker IDs, and roughly ordered by time. Clones share
+/// the sequence.
+#[derive(Debug, Clone)]
+pub struct Snowflake {
+    worker: u16,
+    seq: Arc<AtomicU64>,
+}
+
+impl Snowflake {
+    pub fn new(worker: u16) -> Self {
+        Snowflake {
+            worker,
+            seq: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    #[inline]
+    pub const fn worker(&self) -> u16 {
+        self.worker
+    }
+}
+
+impl RequestIdGenerator for Snowflake {
+    fn next_id(&self) -> RequestId {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis()) as u64;
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        RequestId(
+            (u128::from(millis & ((1 << 48) - 1)) << 80)
+                | (u128::from(self.worker) << 64)
+                | u128::from(seq),
+        )
+    }
+}
+
+/// A middleware which sets a new [`RequestId`] in the context of each request.
+///
+/// It serves `(R, CX)` requests: the inner service is called with the context
+/// with a `RequestId` set through `ParamSet<RequestId>`.
+///
+/// It is its own factory; the generator is cloned into every service it makes,
+/// so use a generator whose clones share state, like [`Snowflake`].
+pub struct SetRequestId<T, G> {
+    generator: G,
+    inner: T,
+}
+
+impl<T, G> SetRequestId<T, G> {
+    pub fn layer<C>(generator: G) -> impl FactoryLayer<C, T, Factory = SetRequestId<T, G>>
+    where
+        G: Clone,
+    {
+        layer_fn(move |_: &C, inner| SetRequestId {
+            generator: generator.clone(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn generator(&self) -> &G {
+        &self.generator
+    }
+}
+
+impl<T, G, R, CX> Service<(R, CX)> for SetRequestId<T, G>
+where
+    G: RequestIdGenerator,
+    CX: ParamSet<RequestId>,
+    T: Service<(R, CX::Transformed)>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    #[inline]
+    fn call(
+        &self,
+        (req, cx): (R, CX),
+    ) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.inner.call((req, cx.param_set(self.generator.next_id())))
+    }
+}
+
+impl<F: MakeService, G: Clone> MakeService for SetRequestId<F, G> {
+    type Service = SetRequestId<F::Service, G>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(SetRequestId {
+            generator: self.generator.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, G: Clone> AsyncMakeService for SetRequestId<F, G> {
+    type Service = SetRequestId<F::Service, G>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(SetRequestId {
+            generator: self.generator.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(SetRequestId<_, G> { generator });
+
+impl<T, G> Layered for SetRequestId<T, G> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(SetRequestId<T, G>);
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{RequestId, RequestIdGenerator, SetRequestId, Snowflake};
+    use crate::{layer::FactoryLayer, test_util::block_on, ParamSet, Service};
+
+    struct Cx;
+
+    impl ParamSet<RequestId> for Cx {
+        type Transformed = RequestId;
+
+        fn param_set(self, id: RequestId) -> RequestId {
+            id
+        }
+    }
+
+    struct Inner;
+
+    impl Service<(&'static str, RequestId)> for Inner {
+        type Response = RequestId;
+        type Error = Infallible;
+
+        async fn call(&self, (_, id): (&'static str, RequestId)) -> Result<RequestId, Infallible> {
+            Ok(id)
+        }
+    }
+
+    #[test]
+    fn snowflake() {
+        let gen = Snowflake::new(7);
+        let shared = gen.clone();
+        let (a, b) = (gen.next_id(), shared.next_id());
+        assert_ne!(a, b);
+        assert_eq!((a.0 >> 64) as u16, 7);
+        assert_eq!(b.0 as u64, a.0 as u64 + 1);
+        assert_eq!(RequestId(0xab).to_string(), format!("{:0>32}", "ab"));
+    }
+
+    #[test]
+    fn sets_a_new_id_per_request() {
+        let svc = SetRequestId::layer(Snowflake::new(1)).layer(&(), Inner);
+        let a = block_on(svc.call(("a", Cx))).unwrap();
+        let b = block_on(svc.call(("b", Cx))).unwrap();
+        assert_ne!(a, b);
+        assert_eq!((a.0 >> 64) as u16, 1);
+    }
+}
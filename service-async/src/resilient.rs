@@ -0,0 +1,325 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer},
+    time::Timer,
+    AsyncMakeService, Param, ServiceMetadata,
+};
+
+/// Configuration of [`ResilientMake`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResilientMakeConfig {
+    /// Max number of makes, including the first one.
+    pub max_attempts: u32,
+    /// Time to wait before the first retry. It doubles on each retry.
+    pub initial_backoff: Duration,
+    /// Cap of the time to wait between retries.
+    pub max_backoff: Duration,
+    /// Keep serving with the old service when every attempt failed, instead
+    /// of failing the make. Only applies on reloads, as there is no old
+    /// service on the first make.
+    pub keep_old: bool,
+}
+
+impl Default for ResilientMakeConfig {
+    fn default() -> Self {
+        ResilientMakeConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            keep_old: false,
+        }
+    }
+}
+
+/// What [`ResilientMake`] does after a failed make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MakeRetryAction {
+    /// Make again after the backoff.
+    Retry(Duration),
+    /// Give up and keep the old service.
+    KeepOld,
+    /// Give up and return the error.
+    GiveUp,
+}
+
+/// A failed make of the factory wrapped by [`ResilientMake`].
+#[derive(Debug)]
+pub struct MakeFailure<'a, E> {
+    /// The attempt which failed, starting from 1.
+    pub attempt: u32,
+    pub error: &'a E,
+    pub action: MakeRetryAction,
+}
+
+/// Decides which make errors of [`ResilientMake`] are worth a retry, and
+/// receives the failures, e.g. to raise an alert.
+///
+/// Closures taking a [`MakeFailure`] retry every error.
+pub trait MakeRetryHook<E> {
+    /// Whether the error may go away on retry, e.g. a DNS hiccup, unlike a
+    /// bad config.
+    #[inline]
+    fn is_transient(&self, _err: &E) -> bool {
+        true
+    }
+
+    #[inline]
+    fn on_failure(&self, _failure: &MakeFailure<'_, E>) {}
+}
+
+impl<E> MakeRetryHook<E> for () {}
+
+impl<E, F: Fn(&MakeFailure<'_, E>)> MakeRetryHook<E> for F {
+    #[inline]
+    fn on_failure(&self, failure: &MakeFailure<'_, E>) {
+        (self)(failure)
+    }
+}
+
+/// A factory which retries the makes of its inner factory with exponential
+/// backoff, for transient failures like a DNS hiccup while building a client.
+///
+/// With [`keep_old`](ResilientMakeConfig::keep_old), a reload which fails
+/// every attempt keeps the old service instead of failing, so reload drivers
+/// do not need to handle it. The service is shared in an `Arc` for that.
+///
+/// Retrying waits, so it only makes asynchronously. The time source `TM` is
+/// taken from the stack config through `Param<TM>`.
+pub struct ResilientMake<F, TM, H = ()> {
+    config: ResilientMakeConfig,
+    timer: TM,
+    hook: H,
+    inner: F,
+}
+
+impl<F, TM> ResilientMake<F, TM> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self>
+    where
+        C: Param<ResilientMakeConfig> + Param<TM>,
+    {
+        Self::layer_with(())
+    }
+}
+
+impl<F, TM, H> ResilientMake<F, TM, H> {
+    /// Retry with `hook` deciding which errors are transient and receiving
+    /// the failures.
+    pub fn layer_with<C>(hook: H) -> impl FactoryLayer<C, F, Factory = Self>
+    where
+        C: Param<ResilientMakeConfig> + Param<TM>,
+        H: Clone,
+    {
+        layer_fn(move |c: &C, inner| ResilientMake {
+            config: Param::<ResilientMakeConfig>::param(c),
+            timer: Param::<TM>::param(c),
+            hook: hook.clone(),
+            inner,
+        })
+    }
+
+    fn action<E>(&self, attempt: u32, err: &E, has_old: bool) -> MakeRetryAction
+    where
+        H: MakeRetryHook<E>,
+    {
+        if attempt < self.config.max_attempts && self.hook.is_transient(err) {
+            let backoff = self
+                .config
+                .initial_backoff
+                .saturating_mul(1 << (attempt - 1).min(31))
+                .min(self.config.max_backoff);
+            MakeRetryAction::Retry(backoff)
+        } else if self.config.keep_old && has_old {
+            MakeRetryAction::KeepOld
+        } else {
+            MakeRetryAction::GiveUp
+        }
+    }
+}
+
+impl<F, TM, H> AsyncMakeService for ResilientMake<F, TM, H>
+where
+    F: AsyncMakeService,
+    TM: Timer,
+    H: MakeRetryHook<F::Error>,
+{
+    type Service = Arc<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let mut attempt = 1;
+        loop {
+            let error = match self.inner.make_via_ref(old.map(|o| &**o)).await {
+                Ok(svc) => return Ok(Arc::new(svc)),
+                Err(e) => e,
+            };
+            let action = self.action(attempt, &error, old.is_some());
+            self.hook.on_failure(&MakeFailure {
+                attempt,
+                error: &error,
+                action,
+            });
+            match (action, old) {
+                (MakeRetryAction::Retry(backoff), _) => self.timer.sleep(backoff).await,
+                (MakeRetryAction::KeepOld, Some(old)) => return Ok(old.clone()),
+                _ => return Err(error),
+            }
+            attempt += 1;
+        }
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(ResilientMake<_, TM, H> { config, timer, hook });
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        pin::pin,
+        rc::Rc,
+        sync::Arc,
+        task::Poll,
+        time::Duration,
+    };
+
+    use super::{MakeFailure, MakeRetryAction, MakeRetryHook, ResilientMake, ResilientMakeConfig};
+    use crate::{
+        layer::FactoryLayer,
+        test_util::{block_on, poll_once},
+        time::MockTimer,
+        AsyncMakeService, Param,
+    };
+
+    struct Config(ResilientMakeConfig, MockTimer);
+
+    impl Param<ResilientMakeConfig> for Config {
+        fn param(&self) -> ResilientMakeConfig {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    fn config(timer: &MockTimer) -> Config {
+        let config = ResilientMakeConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(150),
+            keep_old: true,
+        };
+        Config(config, timer.clone())
+    }
+
+    #[derive(Debug)]
+    struct Client {
+        generation: usize,
+    }
+
+    /// Fails to make a [`Client`] while `failures` is not 0, counting down.
+    #[derive(Default)]
+    struct Flaky {
+        failures: Cell<u32>,
+    }
+
+    impl AsyncMakeService for Flaky {
+        type Service = Client;
+        type Error = &'static str;
+
+        async fn make_via_ref(&self, old: Option<&Client>) -> Result<Client, &'static str> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err("dns hiccup");
+            }
+            Ok(Client {
+                generation: old.map_or(0, |o| o.generation + 1),
+            })
+        }
+    }
+
+    #[test]
+    fn retries_with_backoff_and_keeps_the_old_service() {
+        let timer = MockTimer::new();
+        let actions = Rc::new(RefCell::new(Vec::new()));
+        let hook = {
+            let actions = actions.clone();
+            move |f: &MakeFailure<'_, &'static str>| actions.borrow_mut().push(f.action)
+        };
+        let factory = ResilientMake::<_, MockTimer, _>::layer_with(hook)
+            .layer(&config(&timer), Flaky::default());
+
+        factory.inner.failures.set(2);
+        let mut make = pin!(factory.make());
+        assert!(poll_once(make.as_mut()).is_pending());
+        timer.advance(Duration::from_millis(100));
+        assert!(poll_once(make.as_mut()).is_pending());
+        timer.advance(Duration::from_millis(150));
+        let old = match poll_once(make) {
+            Poll::Ready(Ok(svc)) => svc,
+            _ => panic!("the third attempt should succeed"),
+        };
+        assert_eq!(
+            *actions.borrow(),
+            [
+                MakeRetryAction::Retry(Duration::from_millis(100)),
+                MakeRetryAction::Retry(Duration::from_millis(150)),
+            ]
+        );
+
+        // Every attempt of the reload fails.
+        actions.borrow_mut().clear();
+        factory.inner.failures.set(3);
+        let mut reload = pin!(factory.make_via_ref(Some(&old)));
+        let kept = loop {
+            if let Poll::Ready(res) = poll_once(reload.as_mut()) {
+                break res.unwrap();
+            }
+            timer.advance(Duration::from_millis(150));
+        };
+        assert!(Arc::ptr_eq(&old, &kept));
+        assert_eq!(actions.borrow().len(), 3);
+        assert_eq!(actions.borrow()[2], MakeRetryAction::KeepOld);
+        let new = block_on(factory.make_via_ref(Some(&old))).unwrap();
+        assert!(!Arc::ptr_eq(&old, &new));
+        assert_eq!(new.generation, 1);
+    }
+
+    /// Treats every error as permanent.
+    #[derive(Clone)]
+    struct Permanent;
+
+    impl MakeRetryHook<&'static str> for Permanent {
+        fn is_transient(&self, _err: &&'static str) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let timer = MockTimer::new();
+        let factory = ResilientMake::<_, MockTimer, _>::layer_with(Permanent)
+            .layer(&config(&timer), Flaky::default());
+        let old = block_on(factory.make()).unwrap();
+
+        factory.inner.failures.set(1);
+        let kept = block_on(factory.make_via_ref(Some(&old))).unwrap();
+        assert!(Arc::ptr_eq(&old, &kept));
+        factory.inner.failures.set(1);
+        assert_eq!(block_on(factory.make()).unwrap_err(), "dns hiccup");
+        assert_eq!(timer.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,318 @@
+use std::{collections::HashMap, error::Error, fmt::Display, hash::Hash};
+
+use crate::{
+    parallel::{make_map, try_join, ParallelMake},
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Error returned by [`Router`].
+#[derive(Debug)]
+pub enum RouterError<E> {
+    /// No route matches the key of the request, and there is no fallback route.
+    NotFound,
+    /// The service of the route failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for RouterError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouterError::NotFound => write!(f, "no route found"),
+            RouterError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for RouterError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RouterError::NotFound => None,
+            RouterError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A service which sends each request to the service of its route.
+///
+/// The route key `K`, e.g. a path prefix, an SNI name or a header value, is
+/// taken from the request through `Param<K>`. Requests without a matching
+/// route go to the fallback route if there is one.
+///
+/// It is made by a [`RouterFactory`], built with a [`RouterBuilder`]. On
+/// reload, the service of each route is made against the old service of the
+/// same key, so routes keep their state; removed routes are dropped.
+pub struct Router<K, S> {
+    routes: HashMap<K, S>,
+    fallback: Option<S>,
+}
+
+impl<K, S> Router<K, S> {
+    /// Get the service of a route.
+    #[inline]
+    pub fn route(&self, key: &K) -> Option<&S>
+    where
+        K: Eq + Hash,
+    {
+        self.routes.get(key)
+    }
+
+    #[inline]
+    pub fn fallback(&self) -> Option<&S> {
+        self.fallback.as_ref()
+    }
+}
+
+impl<K, S, R> Service<R> for Router<K, S>
+where
+    K: Eq + Hash,
+    R: Param<K>,
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = RouterError<S::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let svc = self
+            .routes
+            .get(&req.param())
+            .or(self.fallback.as_ref())
+            .ok_or(RouterError::NotFound)?;
+        svc.call(req).await.map_err(RouterError::Inner)
+    }
+}
+
+/// Builder of a [`RouterFactory`], with a factory per route.
+///
+/// All routes have the same factory type; routes with different stacks can
+/// be boxed, e.g. with `FactoryStack::into_boxed_service`.
+pub struct RouterBuilder<K, F> {
+    routes: HashMap<K, F>,
+    fallback: Option<F>,
+}
+
+impl<K, F> RouterBuilder<K, F> {
+    #[inline]
+    pub fn new() -> Self {
+        RouterBuilder {
+            routes: HashMap::new(),
+            fallback: None,
+        }
+    }
+}
+
+impl<K, F> Default for RouterBuilder<K, F> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, F> RouterBuilder<K, F> {
+    /// Add a route, replacing the route of the same key.
+    pub fn route(mut self, key: K, factory: F) -> Self {
+        self.routes.insert(key, factory);
+        self
+    }
+
+    /// Set the route of requests which match no other route.
+    pub fn fallback(mut self, factory: F) -> Self {
+        self.fallback = Some(factory);
+        self
+    }
+
+    pub fn build(self) -> RouterFactory<K, F> {
+        RouterFactory {
+            routes: self.routes,
+            fallback: self.fallback,
+        }
+    }
+}
+
+/// Factory of [`Router`]. Created with a [`RouterBuilder`].
+pub struct RouterFactory<K, F> {
+    routes: HashMap<K, F>,
+    fallback: Option<F>,
+}
+
+impl<K, F> RouterFactory<K, F> {
+    /// Start building a factory.
+    #[inline]
+    pub fn builder() -> RouterBuilder<K, F> {
+        RouterBuilder::new()
+    }
+
+    #[inline]
+    pub fn routes(&self) -> &HashMap<K, F> {
+        &self.routes
+    }
+}
+
+impl<K, F> MakeService for RouterFactory<K, F>
+where
+    K: Eq + Hash + Clone,
+    F: MakeService,
+{
+    type Service = Router<K, F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let fallback = match &self.fallback {
+            Some(f) => Some(f.make_via_ref(old.and_then(|o| o.fallback.as_ref()))?),
+            None => None,
+        };
+        Ok(Router {
+            routes: self.routes.make_via_ref(old.map(|o| &o.routes))?,
+            fallback,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.routes.validate()?;
+        self.fallback.iter().try_for_each(F::validate)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        let meta = ServiceMetadata::of::<Self::Service>()
+            .with_resource("routes", self.routes.len() as u64);
+        let meta = self.routes.values().fold(meta, |meta, factory| {
+            meta.with_inner(factory.service_metadata())
+        });
+        match &self.fallback {
+            Some(f) => meta.with_inner(f.service_metadata()),
+            None => meta,
+        }
+    }
+}
+
+impl<K, F> AsyncMakeService for RouterFactory<K, F>
+where
+    K: Eq + Hash + Clone,
+    F: AsyncMakeService,
+{
+    type Service = Router<K, F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let fallback = match &self.fallback {
+            Some(f) => Some(f.make_via_ref(old.and_then(|o| o.fallback.as_ref())).await?),
+            None => None,
+        };
+        Ok(Router {
+            routes: self.routes.make_via_ref(old.map(|o| &o.routes)).await?,
+            fallback,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        AsyncMakeService::validate(&self.routes)?;
+        self.fallback.iter().try_for_each(F::validate)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        let meta = ServiceMetadata::of::<Self::Service>()
+            .with_resource("routes", self.routes.len() as u64);
+        let meta = self.routes.values().fold(meta, |meta, factory| {
+            meta.with_inner(factory.service_metadata())
+        });
+        match &self.fallback {
+            Some(f) => meta.with_inner(f.service_metadata()),
+            None => meta,
+        }
+    }
+}
+
+/// Makes the routes and the fallback concurrently.
+impl<K, F> AsyncMakeService for ParallelMake<RouterFactory<K, F>>
+where
+    K: Eq + Hash + Clone,
+    F: AsyncMakeService,
+{
+    type Service = Router<K, F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let factory = self.get_ref();
+        let fallback = async {
+            match &factory.fallback {
+                Some(f) => f
+                    .make_via_ref(old.and_then(|o| o.fallback.as_ref()))
+                    .await
+                    .map(Some),
+                None => Ok(None),
+            }
+        };
+        let routes = make_map(&factory.routes, old.map(|o| &o.routes));
+        let (routes, fallback) = try_join(routes, fallback).await?;
+        Ok(Router { routes, fallback })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        AsyncMakeService::validate(self.get_ref())
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        AsyncMakeService::service_metadata(self.get_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{RouterError, RouterFactory};
+    use crate::{test_util::block_on, MakeService, Service};
+
+    /// Answers with its name and how many services it replaced.
+    struct Named(&'static str, usize);
+
+    impl Service<&'static str> for Named {
+        type Response = (&'static str, usize);
+        type Error = Infallible;
+
+        async fn call(&self, _req: &'static str) -> Result<Self::Response, Infallible> {
+            Ok((self.0, self.1))
+        }
+    }
+
+    struct NamedFactory(&'static str);
+
+    impl MakeService for NamedFactory {
+        type Service = Named;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, old: Option<&Named>) -> Result<Named, Infallible> {
+            Ok(Named(self.0, old.map_or(0, |o| o.1 + 1)))
+        }
+    }
+
+    #[test]
+    fn routes_by_key_and_keeps_routes_on_reload() {
+        let factory = RouterFactory::builder()
+            .route("/a", NamedFactory("a"))
+            .route("/b", NamedFactory("b"))
+            .build();
+        let svc = factory.make().unwrap();
+        assert_eq!(block_on(svc.call("/a")).unwrap(), ("a", 0));
+        assert_eq!(block_on(svc.call("/b")).unwrap(), ("b", 0));
+        assert!(matches!(
+            block_on(svc.call("/c")),
+            Err(RouterError::NotFound)
+        ));
+
+        let factory = RouterFactory::builder()
+            .route("/a", NamedFactory("a"))
+            .fallback(NamedFactory("fallback"))
+            .build();
+        let svc = factory.make_via_ref(Some(&svc)).unwrap();
+        assert_eq!(block_on(svc.call("/a")).unwrap(), ("a", 1));
+        assert_eq!(block_on(svc.call("/b")).unwrap(), ("fallback", 0));
+        assert!(svc.route(&"/b").is_none());
+        assert_eq!(factory.routes().len(), 1);
+    }
+}
@@ -0,0 +1,846 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    pushback::{Pushback, PushbackHint},
+    reload,
+    semaphore::Semaphore,
+    shutdown::impl_shutdown_inner,
+    time::{Clock, SystemClock},
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// What a [`Scheduler`] knows of a request before it is admitted.
+///
+/// Requests passed to [`Scheduled`] provide it through `Param<Admission>`.
+/// Fields a scheduler does not use may be left to their defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Admission {
+    /// Higher values are admitted first by [`Fairness`].
+    pub priority: u8,
+    /// When the response is of no use anymore, for [`EarliestDeadline`].
+    pub deadline: Option<Instant>,
+    /// The flow the request belongs to, e.g. a tenant, for [`WeightedFair`].
+    pub flow: u64,
+}
+
+impl Admission {
+    #[inline]
+    pub const fn with_priority(priority: u8) -> Self {
+        Admission {
+            priority,
+            deadline: None,
+            flow: 0,
+        }
+    }
+}
+
+/// A request waiting for admission.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ticket {
+    /// Increases in the order requests were queued.
+    pub seq: u64,
+    pub admission: Admission,
+    /// When the request was queued.
+    pub queued_at: Instant,
+    /// Number of requests admitted before this one was queued.
+    pub admitted_before: u64,
+}
+
+/// Picks the next request to admit when a limiter frees a slot.
+///
+/// The queue is in no particular order; use [`Ticket::seq`] for the order of
+/// arrival. `admitted` is the number of requests admitted so far, so
+/// [`Ticket::admitted_before`] tells how many were admitted ahead of a ticket.
+///
+/// Every ticket is passed to [`queued`](Self::queued), then to
+/// [`admitted`](Self::admitted) or [`cancelled`](Self::cancelled). Requests
+/// admitted without waiting go through both at once. A scheduler installed by
+/// a reload may see tickets it was not given.
+pub trait Scheduler {
+    /// Pick the index of the ticket to admit among `queue`, which is not empty.
+    fn pick(&mut self, queue: &[Ticket], admitted: u64) -> usize;
+
+    /// Called when a request joins the queue.
+    #[inline]
+    fn queued(&mut self, _ticket: &Ticket) {}
+
+    /// Called when a request is admitted.
+    #[inline]
+    fn admitted(&mut self, _ticket: &Ticket) {}
+
+    /// Called when a queued request gives up.
+    #[inline]
+    fn cancelled(&mut self, _ticket: &Ticket) {}
+}
+
+impl<S: Scheduler + ?Sized> Scheduler for Box<S> {
+    #[inline]
+    fn pick(&mut self, queue: &[Ticket], admitted: u64) -> usize {
+        (**self).pick(queue, admitted)
+    }
+
+    #[inline]
+    fn queued(&mut self, ticket: &Ticket) {
+        (**self).queued(ticket)
+    }
+
+    #[inline]
+    fn admitted(&mut self, ticket: &Ticket) {
+        (**self).admitted(ticket)
+    }
+
+    #[inline]
+    fn cancelled(&mut self, ticket: &Ticket) {
+        (**self).cancelled(ticket)
+    }
+}
+
+fn min_by_key<K: Ord>(queue: &[Ticket], key: impl Fn(&Ticket) -> K) -> usize {
+    queue
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, t)| (key(t), t.seq))
+        .map_or(0, |(idx, _)| idx)
+}
+
+/// Admits requests in the order they arrived.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fifo;
+
+impl Scheduler for Fifo {
+    #[inline]
+    fn pick(&mut self, queue: &[Ticket], _admitted: u64) -> usize {
+        min_by_key(queue, |_| ())
+    }
+}
+
+/// Admits the latest request first, which keeps latency low for most requests
+/// under overload at the expense of the oldest ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lifo;
+
+impl Scheduler for Lifo {
+    #[inline]
+    fn pick(&mut self, queue: &[Ticket], _admitted: u64) -> usize {
+        min_by_key(queue, |t| u64::MAX - t.seq)
+    }
+}
+
+/// Admits the request with the earliest [`Admission::deadline`] first.
+/// Requests without one come last, in the order they arrived.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EarliestDeadline;
+
+impl Scheduler for EarliestDeadline {
+    #[inline]
+    fn pick(&mut self, queue: &[Ticket], _admitted: u64) -> usize {
+        min_by_key(queue, |t| (t.admission.deadline.is_none(), t.admission.deadline))
+    }
+}
+
+/// How queued requests are picked by [`Admission::priority`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fairness {
+    /// Always admit the highest priority first, FIFO within the same priority.
+    #[default]
+    Strict,
+    /// Like `Strict`, but a queued acquirer gains one priority level every `step`
+    /// admissions it has been waiting for, so low priorities can not starve.
+    Aging { step: u64 },
+}
+
+impl Scheduler for Fairness {
+    fn pick(&mut self, queue: &[Ticket], admitted: u64) -> usize {
+        let effective = |t: &Ticket| {
+            let priority = t.admission.priority as u64;
+            match *self {
+                Fairness::Strict => priority,
+                Fairness::Aging { step } => {
+                    priority + (admitted - t.admitted_before) / step.max(1)
+                }
+            }
+        };
+        min_by_key(queue, |t| u64::MAX - effective(t))
+    }
+}
+
+/// Shares admissions between [`Admission::flow`]s in proportion to their
+/// weights, with start-time fair queueing. Flows without a weight have
+/// weight 1.
+///
+/// A flow which was idle does not get to catch up on the share it did not use.
+#[derive(Debug, Clone, Default)]
+pub struct WeightedFair {
+    weights: HashMap<u64, u32>,
+    // Virtual finish time of the last request queued by each flow.
+    finish: HashMap<u64, u64>,
+    // Virtual (start, finish) times of the queued requests, by ticket.
+    tags: HashMap<u64, (u64, u64)>,
+    now: u64,
+}
+
+const FAIR_SCALE: u64 = 1 << 20;
+
+impl WeightedFair {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the weight of a flow. A weight of 0 is taken as 1.
+    pub fn weight(mut self, flow: u64, weight: u32) -> Self {
+        self.weights.insert(flow, weight.max(1));
+        self
+    }
+
+    fn tag(&self, ticket: &Ticket) -> (u64, u64) {
+        if let Some(tag) = self.tags.get(&ticket.seq) {
+            return *tag;
+        }
+        let flow = ticket.admission.flow;
+        let weight = self.weights.get(&flow).copied().unwrap_or(1) as u64;
+        let start = self.finish.get(&flow).map_or(self.now, |f| (*f).max(self.now));
+        (start, start + FAIR_SCALE / weight)
+    }
+}
+
+impl Scheduler for WeightedFair {
+    fn pick(&mut self, queue: &[Ticket], _admitted: u64) -> usize {
+        min_by_key(queue, |t| self.tag(t).1)
+    }
+
+    fn queued(&mut self, ticket: &Ticket) {
+        let tag = self.tag(ticket);
+        self.finish.insert(ticket.admission.flow, tag.1);
+        self.tags.insert(ticket.seq, tag);
+    }
+
+    fn admitted(&mut self, ticket: &Ticket) {
+        let (start, _) = self.tag(ticket);
+        self.tags.remove(&ticket.seq);
+        self.now = self.now.max(start);
+        let now = self.now;
+        self.finish.retain(|_, f| *f > now);
+    }
+
+    fn cancelled(&mut self, ticket: &Ticket) {
+        self.tags.remove(&ticket.seq);
+    }
+}
+
+/// Starvation protection for another scheduler: a request which has waited
+/// for `max_wait` is admitted before any other, oldest first.
+///
+/// The wait is measured with the clock `CL`, which must be the clock of the
+/// limiter stamping [`Ticket::queued_at`].
+#[derive(Debug, Clone, Copy)]
+pub struct MaxWait<S, CL = SystemClock> {
+    max_wait: Duration,
+    clock: CL,
+    inner: S,
+}
+
+impl<S> MaxWait<S> {
+    #[inline]
+    pub const fn new(inner: S, max_wait: Duration) -> Self {
+        Self::with_clock(inner, max_wait, SystemClock)
+    }
+}
+
+impl<S, CL> MaxWait<S, CL> {
+    #[inline]
+    pub const fn with_clock(inner: S, max_wait: Duration, clock: CL) -> Self {
+        MaxWait {
+            max_wait,
+            clock,
+            inner,
+        }
+    }
+}
+
+impl<S: Scheduler, CL: Clock> Scheduler for MaxWait<S, CL> {
+    fn pick(&mut self, queue: &[Ticket], admitted: u64) -> usize {
+        let now = self.clock.now();
+        let starved = queue
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| now.saturating_duration_since(t.queued_at) >= self.max_wait)
+            .min_by_key(|(_, t)| t.seq);
+        match starved {
+            Some((idx, _)) => idx,
+            None => self.inner.pick(queue, admitted),
+        }
+    }
+
+    #[inline]
+    fn queued(&mut self, ticket: &Ticket) {
+        self.inner.queued(ticket)
+    }
+
+    #[inline]
+    fn admitted(&mut self, ticket: &Ticket) {
+        self.inner.admitted(ticket)
+    }
+
+    #[inline]
+    fn cancelled(&mut self, ticket: &Ticket) {
+        self.inner.cancelled(ticket)
+    }
+}
+
+/// Receives the admissions of an [`Observed`] scheduler, e.g. to record
+/// queueing delay per flow.
+pub trait SchedulerObserver {
+    fn admitted(&self, ticket: &Ticket, waited: Duration);
+}
+
+impl SchedulerObserver for () {
+    #[inline]
+    fn admitted(&self, _ticket: &Ticket, _waited: Duration) {}
+}
+
+impl<F: Fn(&Ticket, Duration)> SchedulerObserver for F {
+    #[inline]
+    fn admitted(&self, ticket: &Ticket, waited: Duration) {
+        (self)(ticket, waited)
+    }
+}
+
+/// A scheduler which reports every admission to an observer.
+///
+/// The wait is measured with the clock `CL`, which must be the clock of the
+/// limiter stamping [`Ticket::queued_at`].
+#[derive(Debug, Clone, Copy)]
+pub struct Observed<S, O, CL = SystemClock> {
+    observer: O,
+    clock: CL,
+    inner: S,
+}
+
+impl<S, O> Observed<S, O> {
+    #[inline]
+    pub const fn new(inner: S, observer: O) -> Self {
+        Self::with_clock(inner, observer, SystemClock)
+    }
+}
+
+impl<S, O, CL> Observed<S, O, CL> {
+    #[inline]
+    pub const fn with_clock(inner: S, observer: O, clock: CL) -> Self {
+        Observed {
+            observer,
+            clock,
+            inner,
+        }
+    }
+}
+
+impl<S: Scheduler, O: SchedulerObserver, CL: Clock> Scheduler for Observed<S, O, CL> {
+    #[inline]
+    fn pick(&mut self, queue: &[Ticket], admitted: u64) -> usize {
+        self.inner.pick(queue, admitted)
+    }
+
+    #[inline]
+    fn queued(&mut self, ticket: &Ticket) {
+        self.inner.queued(ticket)
+    }
+
+    fn admitted(&mut self, ticket: &Ticket) {
+        let waited = self.clock.now().saturating_duration_since(ticket.queued_at);
+        self.observer.admitted(ticket, waited);
+        self.inner.admitted(ticket)
+    }
+
+    #[inline]
+    fn cancelled(&mut self, ticket: &Ticket) {
+        self.inner.cancelled(ticket)
+    }
+}
+
+/// Configuration of [`Scheduled`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledConfig {
+    /// Max number of requests the inner service handles at the same time.
+    pub max_concurrency: usize,
+    /// Max number of requests waiting for admission. Requests beyond it are rejected.
+    pub max_queue: usize,
+}
+
+/// Error returned by [`Scheduled`].
+#[derive(Debug)]
+pub enum ScheduledError<E> {
+    /// The queue is full.
+    Rejected,
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for ScheduledError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduledError::Rejected => f.write_str("scheduler queue is full"),
+            ScheduledError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ScheduledError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScheduledError::Rejected => None,
+            ScheduledError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<E: PushbackHint> PushbackHint for ScheduledError<E> {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match self {
+            ScheduledError::Rejected => Some(Pushback::saturated()),
+            ScheduledError::Inner(e) => e.pushback(),
+        }
+    }
+}
+
+/// A middleware which limits concurrent calls to the inner service and admits
+/// queued requests in the order chosen by a [`Scheduler`].
+///
+/// Requests describe themselves to the scheduler through `Param<Admission>`.
+/// The queue and the in-flight count are kept across reloads; a reload
+/// installs a fresh clone of the scheduler of the new factory, when it commits
+/// within a [`ServiceSlot::begin`](crate::reload::ServiceSlot::begin)
+/// transaction. Queued requests
+/// are stamped with the clock `CL`, taken from the stack config through
+/// `Param<CL>`.
+pub struct Scheduled<T> {
+    limiter: Arc<Semaphore>,
+    max_queue: usize,
+    inner: T,
+}
+
+impl<T> Scheduled<T> {
+    pub fn layer<C, S, CL>(
+        scheduler: S,
+    ) -> impl FactoryLayer<C, T, Factory = ScheduledFactory<T, S, CL>>
+    where
+        C: Param<ScheduledConfig> + Param<CL>,
+        S: Clone,
+    {
+        layer_fn(move |c: &C, inner| ScheduledFactory {
+            config: Param::<ScheduledConfig>::param(c),
+            scheduler: scheduler.clone(),
+            clock: Param::<CL>::param(c),
+            inner,
+        })
+    }
+
+    /// Number of calls being processed by the inner service.
+    pub fn in_flight(&self) -> usize {
+        self.limiter.in_use()
+    }
+
+    /// Number of requests waiting to be admitted.
+    pub fn queued(&self) -> usize {
+        self.limiter.queued()
+    }
+}
+
+impl<T, R> Service<R> for Scheduled<T>
+where
+    T: Service<R>,
+    R: Param<Admission>,
+{
+    type Response = T::Response;
+    type Error = ScheduledError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let Some(acquire) = self.limiter.acquire_bounded(req.param(), self.max_queue) else {
+            return Err(ScheduledError::Rejected);
+        };
+        let _permit = acquire.await;
+        self.inner.call(req).await.map_err(ScheduledError::Inner)
+    }
+}
+
+/// Factory of [`Scheduled`].
+pub struct ScheduledFactory<F, S, CL> {
+    config: ScheduledConfig,
+    scheduler: S,
+    clock: CL,
+    inner: F,
+}
+
+impl<F, S, CL> ScheduledFactory<F, S, CL>
+where
+    S: Scheduler + Clone + Send + 'static,
+    CL: Clock + Clone + Send + 'static,
+{
+    fn limiter<T>(&self, old: Option<&Scheduled<T>>) -> Arc<Semaphore> {
+        let capacity = self.config.max_concurrency;
+        match old {
+            Some(old) => {
+                let (limiter, scheduler) = (old.limiter.clone(), self.scheduler.clone());
+                reload::on_commit(move || limiter.reconfigure(capacity, scheduler));
+                old.limiter.clone()
+            }
+            None => Arc::new(Semaphore::with_clock(
+                capacity,
+                self.scheduler.clone(),
+                self.clock.clone(),
+            )),
+        }
+    }
+}
+
+impl<F, S, CL> MakeService for ScheduledFactory<F, S, CL>
+where
+    F: MakeService,
+    S: Scheduler + Clone + Send + 'static,
+    CL: Clock + Clone + Send + 'static,
+{
+    type Service = Scheduled<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Scheduled {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            max_queue: self.config.max_queue,
+            limiter: self.limiter(old),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        scheduled_metadata::<Self::Service>(&self.config).with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F, S, CL> AsyncMakeService for ScheduledFactory<F, S, CL>
+where
+    F: AsyncMakeService,
+    S: Scheduler + Clone + Send + 'static,
+    CL: Clock + Clone + Send + 'static,
+{
+    type Service = Scheduled<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Scheduled {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            max_queue: self.config.max_queue,
+            limiter: self.limiter(old),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        scheduled_metadata::<Self::Service>(&self.config).with_inner(self.inner.service_metadata())
+    }
+}
+
+fn scheduled_metadata<S>(config: &ScheduledConfig) -> ServiceMetadata {
+    ServiceMetadata::of::<S>()
+        .with_config("max_concurrency", config.max_concurrency)
+        .with_config("max_queue", config.max_queue)
+}
+
+impl_wrap_inner!(ScheduledFactory<_, S, CL> { config, scheduler, clock });
+
+impl<T> Layered for Scheduled<T> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Scheduled<T>);
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        pin::{pin, Pin},
+        sync::{Arc, Mutex},
+        task::Poll,
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        Admission, EarliestDeadline, Fairness, Fifo, Lifo, MaxWait, Observed, Scheduled,
+        ScheduledConfig, ScheduledError, Scheduler, Ticket, WeightedFair,
+    };
+    use crate::{
+        layer::FactoryLayer,
+        reload::ServiceSlot,
+        semaphore::{Acquire, Permit, Semaphore},
+        test_util::{block_on, poll_once},
+        time::MockTimer,
+        utils::CloneFactory,
+        MakeService, Param, Service,
+    };
+
+    /// A semaphore of one permit, which is held so every acquirer queues.
+    struct Queue<'a> {
+        sem: &'a Semaphore,
+        held: Option<Permit<'a>>,
+        waiting: Vec<(usize, Pin<Box<Acquire<'a>>>)>,
+        pushed: usize,
+    }
+
+    impl<'a> Queue<'a> {
+        fn new(sem: &'a Semaphore) -> Self {
+            let held = match poll_once(pin!(sem.acquire(Admission::default()))) {
+                Poll::Ready(permit) => permit,
+                Poll::Pending => panic!("the semaphore is not free"),
+            };
+            Queue {
+                sem,
+                held: Some(held),
+                waiting: Vec::new(),
+                pushed: 0,
+            }
+        }
+
+        /// Queue an acquirer, labelled by the order it was pushed in.
+        fn push(&mut self, admission: Admission) {
+            let mut acquire = Box::pin(self.sem.acquire(admission));
+            assert!(poll_once(acquire.as_mut()).is_pending());
+            self.waiting.push((self.pushed, acquire));
+            self.pushed += 1;
+        }
+
+        /// Release the held permit and return the label of the acquirer it
+        /// was granted to.
+        fn release(&mut self) -> usize {
+            self.held = None;
+            let mut granted = None;
+            self.waiting
+                .retain_mut(|(label, acquire)| match poll_once(acquire.as_mut()) {
+                    Poll::Ready(permit) => {
+                        assert!(granted.is_none(), "two acquirers were granted");
+                        granted = Some((*label, permit));
+                        false
+                    }
+                    Poll::Pending => true,
+                });
+            let (label, permit) = granted.expect("no acquirer was granted");
+            self.held = Some(permit);
+            label
+        }
+
+        fn drain(&mut self) -> Vec<usize> {
+            (0..self.waiting.len()).map(|_| self.release()).collect()
+        }
+    }
+
+    fn order(scheduler: impl Scheduler + Send + 'static, admissions: &[Admission]) -> Vec<usize> {
+        let sem = Semaphore::new(1, scheduler);
+        let mut queue = Queue::new(&sem);
+        for admission in admissions {
+            queue.push(*admission);
+        }
+        queue.drain()
+    }
+
+    fn flow(flow: u64) -> Admission {
+        Admission {
+            flow,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fifo_and_lifo() {
+        let admissions = [Admission::default(); 3];
+        assert_eq!(order(Fifo, &admissions), [0, 1, 2]);
+        assert_eq!(order(Lifo, &admissions), [2, 1, 0]);
+    }
+
+    #[test]
+    fn earliest_deadline() {
+        let now = Instant::now();
+        let deadline = |secs| Admission {
+            deadline: Some(now + Duration::from_secs(secs)),
+            ..Default::default()
+        };
+        let admissions = [Admission::default(), deadline(2), deadline(1), deadline(2)];
+        assert_eq!(order(EarliestDeadline, &admissions), [2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn strict_priority() {
+        let admissions = [1, 3, 2, 3].map(Admission::with_priority);
+        assert_eq!(order(Fairness::Strict, &admissions), [1, 3, 2, 0]);
+    }
+
+    #[test]
+    fn aging_admits_low_priority() {
+        // A low priority request waits while high priority ones keep arriving.
+        let starve = |fairness: Fairness| {
+            let sem = Semaphore::new(1, fairness);
+            let mut queue = Queue::new(&sem);
+            queue.push(Admission::with_priority(0));
+            (1..=4)
+                .map(|_| {
+                    queue.push(Admission::with_priority(2));
+                    queue.release()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(starve(Fairness::Strict), [1, 2, 3, 4]);
+        assert_eq!(starve(Fairness::Aging { step: 1 }), [1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn weighted_fair() {
+        // Flow 1 has twice the weight of flow 2, so it gets two of the first
+        // three admissions even though flow 2 has requests queued too.
+        let scheduler = WeightedFair::new().weight(1, 2);
+        let admissions = [flow(1), flow(1), flow(1), flow(2), flow(2), flow(2)];
+        assert_eq!(order(scheduler, &admissions), [0, 1, 3, 2, 4, 5]);
+    }
+
+    #[test]
+    fn weighted_fair_forgets_cancelled() {
+        let sem = Semaphore::new(1, WeightedFair::new());
+        let mut queue = Queue::new(&sem);
+        queue.push(flow(1));
+        queue.push(flow(1));
+        queue.push(flow(2));
+        // Cancel both requests of flow 1.
+        queue.waiting.retain(|(label, _)| *label == 2);
+        queue.push(flow(1));
+        assert_eq!(queue.drain(), [2, 3]);
+        assert_eq!(sem.queued(), 0);
+    }
+
+    #[test]
+    fn max_wait_admits_starved() {
+        let timer = MockTimer::new();
+        let scheduler = MaxWait::with_clock(Lifo, Duration::from_secs(10), timer.clone());
+        let sem = Semaphore::with_clock(1, scheduler, timer.clone());
+        let mut queue = Queue::new(&sem);
+        queue.push(Admission::default());
+        timer.advance(Duration::from_secs(5));
+        queue.push(Admission::default());
+        queue.push(Admission::default());
+
+        assert_eq!(queue.release(), 2);
+        timer.advance(Duration::from_secs(5));
+        assert_eq!(queue.release(), 0);
+        assert_eq!(queue.release(), 1);
+    }
+
+    #[test]
+    fn observed_reports_wait() {
+        let timer = MockTimer::new();
+        let waits = Arc::new(Mutex::new(Vec::new()));
+        let observer = {
+            let waits = waits.clone();
+            move |ticket: &Ticket, waited| waits.lock().unwrap().push((ticket.seq, waited))
+        };
+        let scheduler = Observed::with_clock(Fifo, observer, timer.clone());
+        let sem = Semaphore::with_clock(1, scheduler, timer.clone());
+        let mut queue = Queue::new(&sem);
+        queue.push(Admission::default());
+        timer.advance(Duration::from_secs(3));
+        queue.release();
+
+        let waits = waits.lock().unwrap();
+        assert_eq!(*waits, [(0, Duration::ZERO), (1, Duration::from_secs(3))]);
+    }
+
+    #[derive(Clone)]
+    struct Never;
+
+    impl Service<Admission> for Never {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, _req: Admission) -> Result<(), Infallible> {
+            std::future::pending().await
+        }
+    }
+
+    struct Config(ScheduledConfig, MockTimer);
+
+    impl Param<ScheduledConfig> for Config {
+        fn param(&self) -> ScheduledConfig {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    #[test]
+    fn scheduled_rejects_when_queue_is_full() {
+        let config = Config(
+            ScheduledConfig {
+                max_concurrency: 1,
+                max_queue: 1,
+            },
+            MockTimer::new(),
+        );
+        let svc = Scheduled::layer::<_, _, MockTimer>(Fifo)
+            .layer(&config, CloneFactory::new(Never))
+            .make()
+            .unwrap();
+
+        let mut running = pin!(svc.call(Admission::default()));
+        let mut queued = pin!(svc.call(Admission::default()));
+        assert!(poll_once(running.as_mut()).is_pending());
+        assert!(poll_once(queued.as_mut()).is_pending());
+        assert_eq!((svc.in_flight(), svc.queued()), (1, 1));
+        assert!(matches!(
+            poll_once(pin!(svc.call(Admission::default()))),
+            Poll::Ready(Err(ScheduledError::Rejected))
+        ));
+    }
+
+    #[test]
+    fn scheduled_resizes_when_the_reload_commits() {
+        let factory = |max_concurrency| {
+            let config = ScheduledConfig {
+                max_concurrency,
+                max_queue: 4,
+            };
+            Scheduled::layer::<_, _, MockTimer>(Fifo)
+                .layer(&Config(config, MockTimer::new()), CloneFactory::new(Never))
+        };
+        let slot = ServiceSlot::new(factory(1).make().unwrap());
+        let svc = slot.get();
+        let mut running = pin!(svc.call(Admission::default()));
+        let mut queued = pin!(svc.call(Admission::default()));
+        assert!(poll_once(running.as_mut()).is_pending());
+        assert!(poll_once(queued.as_mut()).is_pending());
+
+        block_on(slot.begin(&factory(2))).unwrap().rollback();
+        assert_eq!((svc.in_flight(), svc.queued()), (1, 1));
+        block_on(slot.begin(&factory(2))).unwrap().commit();
+        assert_eq!((svc.in_flight(), svc.queued()), (2, 0));
+    }
+}
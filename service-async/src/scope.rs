@@ -0,0 +1,295 @@
+use std::{
+    cell::RefCell,
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use param::ParamSet;
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Service, ServiceMetadata,
+};
+
+/// What [`Scoped`] does with sub-tasks still running when the call completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScopePolicy {
+    /// Wait for them before returning the response.
+    #[default]
+    Wait,
+    /// Cancel them by dropping them.
+    Cancel,
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+#[derive(Default)]
+struct ScopeState {
+    tasks: Vec<Task>,
+    closed: bool,
+}
+
+/// Spawns sub-tasks into the scope of a call made through [`Scoped`].
+///
+/// The tasks are driven by the call itself, not by the runtime, so they never
+/// outlive it: they are awaited or cancelled when it completes, and cancelled
+/// when it is dropped. Clones share the scope.
+#[derive(Clone, Default)]
+pub struct ScopeHandle {
+    state: Rc<RefCell<ScopeState>>,
+}
+
+impl std::fmt::Debug for ScopeHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.borrow();
+        f.debug_struct("ScopeHandle")
+            .field("tasks", &state.tasks.len())
+            .field("closed", &state.closed)
+            .finish()
+    }
+}
+
+impl ScopeHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawn `fut` into the scope.
+    ///
+    /// Returns `false` and drops `fut` if the scope is closed, i.e. its call
+    /// has completed or was dropped.
+    pub fn spawn(&self, fut: impl Future<Output = ()> + 'static) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.closed {
+            return false;
+        }
+        state.tasks.push(Box::pin(fut));
+        true
+    }
+
+    /// Number of sub-tasks still running.
+    pub fn len(&self) -> usize {
+        self.state.borrow().tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.borrow().closed
+    }
+
+    /// Poll every sub-task once, dropping those which completed.
+    fn poll_tasks(&self, cx: &mut Context<'_>) {
+        // Tasks may spawn more tasks while polled, so poll them outside the borrow.
+        let mut tasks = std::mem::take(&mut self.state.borrow_mut().tasks);
+        tasks.retain_mut(|task| task.as_mut().poll(cx).is_pending());
+        let mut state = self.state.borrow_mut();
+        if !state.tasks.is_empty() {
+            cx.waker().wake_by_ref();
+        }
+        tasks.append(&mut state.tasks);
+        state.tasks = tasks;
+    }
+
+    /// Close the scope and cancel the remaining sub-tasks.
+    fn close(&self) {
+        let tasks = {
+            let mut state = self.state.borrow_mut();
+            state.closed = true;
+            std::mem::take(&mut state.tasks)
+        };
+        drop(tasks);
+    }
+}
+
+struct CloseOnDrop<'a>(&'a ScopeHandle);
+
+impl Drop for CloseOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// A middleware which gives each call a [`ScopeHandle`] to spawn sub-tasks
+/// into, so fanned-out work can not leak past the call.
+///
+/// It handles `(R, CX)` requests and sets a fresh [`ScopeHandle`] in `CX`.
+/// Sub-tasks run concurrently with the call on the same task; when the call
+/// completes they are awaited or cancelled according to the [`ScopePolicy`].
+/// It is its own factory.
+pub struct Scoped<T> {
+    policy: ScopePolicy,
+    inner: T,
+}
+
+impl<T> Scoped<T> {
+    pub fn layer<C>(policy: ScopePolicy) -> impl FactoryLayer<C, T, Factory = Scoped<T>> {
+        layer_fn(move |_: &C, inner| Scoped { policy, inner })
+    }
+
+    #[inline]
+    pub fn policy(&self) -> ScopePolicy {
+        self.policy
+    }
+}
+
+impl<T, R, CX> Service<(R, CX)> for Scoped<T>
+where
+    CX: ParamSet<ScopeHandle>,
+    T: Service<(R, CX::Transformed)>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, (req, cx): (R, CX)) -> Result<Self::Response, Self::Error> {
+        let scope = ScopeHandle::new();
+        let _guard = CloseOnDrop(&scope);
+        let mut call = pin!(self.inner.call((req, cx.param_set(scope.clone()))));
+        let mut result = None;
+        poll_fn(|cx| {
+            if result.is_none() {
+                if let Poll::Ready(res) = call.as_mut().poll(cx) {
+                    result = Some(res);
+                }
+            }
+            scope.poll_tasks(cx);
+            match (&result, self.policy) {
+                (Some(_), ScopePolicy::Cancel) => Poll::Ready(()),
+                (Some(_), ScopePolicy::Wait) if scope.is_empty() => Poll::Ready(()),
+                _ => Poll::Pending,
+            }
+        })
+        .await;
+        result.expect("scoped call completed")
+    }
+}
+
+impl<F: MakeService> MakeService for Scoped<F> {
+    type Service = Scoped<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Scoped {
+            policy: self.policy,
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for Scoped<F> {
+    type Service = Scoped<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Scoped {
+            policy: self.policy,
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(Scoped<_> { policy });
+
+impl<T> Layered for Scoped<T> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Scoped<T>);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, convert::Infallible, rc::Rc};
+
+    use param::context_map;
+
+    use super::{ScopeHandle, ScopePolicy, Scoped};
+    use crate::{
+        layer::FactoryLayer,
+        test_util::{block_on, poll_once},
+        yielding::yield_now,
+        ParamRef, Service,
+    };
+
+    context_map! {
+        struct Context {
+            scope: ScopeHandle,
+        }
+    }
+
+    /// Spawns a sub-task which logs after a few yields, and returns the scope.
+    struct FanOut(Rc<RefCell<Vec<&'static str>>>);
+
+    impl<CX: ParamRef<ScopeHandle>> Service<((), CX)> for FanOut {
+        type Response = ScopeHandle;
+        type Error = Infallible;
+
+        async fn call(&self, (_, cx): ((), CX)) -> Result<ScopeHandle, Infallible> {
+            let log = self.0.clone();
+            let scope = cx.param_ref().clone();
+            assert!(scope.spawn(async move {
+                for _ in 0..3 {
+                    yield_now().await;
+                }
+                log.borrow_mut().push("sub-task");
+            }));
+            self.0.borrow_mut().push("call");
+            Ok(scope)
+        }
+    }
+
+    #[test]
+    fn wait_for_sub_tasks() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let svc = Scoped::layer(ScopePolicy::Wait).layer(&(), FanOut(log.clone()));
+        let scope = block_on(svc.call(((), Context::new()))).unwrap();
+        assert_eq!(*log.borrow(), ["call", "sub-task"]);
+        assert!(scope.is_closed());
+        assert!(!scope.spawn(async {}));
+    }
+
+    #[test]
+    fn cancel_sub_tasks() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let svc = Scoped::layer(ScopePolicy::Cancel).layer(&(), FanOut(log.clone()));
+        let scope = block_on(svc.call(((), Context::new()))).unwrap();
+        assert_eq!(*log.borrow(), ["call"]);
+        assert!(scope.is_closed() && scope.is_empty());
+
+        // Dropping the call cancels them too.
+        log.borrow_mut().clear();
+        let svc = Scoped::layer(ScopePolicy::Wait).layer(&(), FanOut(log.clone()));
+        let mut call = Box::pin(svc.call(((), Context::new())));
+        assert!(poll_once(call.as_mut()).is_pending());
+        drop(call);
+        assert_eq!(*log.borrow(), ["call"]);
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    scheduler::{Admission, Scheduler, Ticket},
+    time::{Clock, SystemClock},
+};
+
+/// An async semaphore whose waiters are admitted in the order picked by a
+/// [`Scheduler`].
+///
+/// The capacity can be changed while permits are held; shrinking it only
+/// takes effect as permits are released.
+#[derive(Debug)]
+pub(crate) struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    capacity: usize,
+    in_use: usize,
+    scheduler: Box<dyn Scheduler + Send>,
+    // Stamps the tickets when they are queued.
+    clock: Box<dyn Clock + Send>,
+    // Tickets and wakers of the waiters, at the same indices.
+    tickets: Vec<Ticket>,
+    wakers: Vec<Option<Waker>>,
+    granted: Vec<u64>,
+    next_id: u64,
+    admitted: u64,
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("State")
+            .field("capacity", &self.capacity)
+            .field("in_use", &self.in_use)
+            .field("tickets", &self.tickets)
+            .field("admitted", &self.admitted)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Semaphore {
+    pub(crate) fn new(capacity: usize, scheduler: impl Scheduler + Send + 'static) -> Self {
+        Self::with_clock(capacity, scheduler, SystemClock)
+    }
+
+    /// A semaphore stamping the tickets with the time of `clock`.
+    pub(crate) fn with_clock(
+        capacity: usize,
+        scheduler: impl Scheduler + Send + 'static,
+        clock: impl Clock + Send + 'static,
+    ) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                capacity,
+                in_use: 0,
+                scheduler: Box::new(scheduler),
+                clock: Box::new(clock),
+                tickets: Vec::new(),
+                wakers: Vec::new(),
+                granted: Vec::new(),
+                next_id: 0,
+                admitted: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Update capacity and scheduler, admitting waiters if capacity grew.
+    pub(crate) fn reconfigure(&self, capacity: usize, scheduler: impl Scheduler + Send + 'static) {
+        let mut state = self.lock();
+        state.scheduler = Box::new(scheduler);
+        state.capacity = capacity;
+        state.grant();
+    }
+
+    /// Update capacity, admitting waiters if it grew.
+    pub(crate) fn resize(&self, capacity: usize) {
+        let mut state = self.lock();
+        state.capacity = capacity;
+        state.grant();
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Number of permits currently held.
+    pub(crate) fn in_use(&self) -> usize {
+        self.lock().in_use
+    }
+
+    /// Number of acquirers waiting for a permit.
+    pub(crate) fn queued(&self) -> usize {
+        self.lock().tickets.len()
+    }
+
+    /// Wait for a permit.
+    pub(crate) fn acquire(&self, admission: Admission) -> Acquire<'_> {
+        Acquire {
+            sem: self,
+            admission,
+            state: AcquireState::Init,
+        }
+    }
+
+    /// Take a permit or join the queue, unless `max_queued` acquirers are
+    /// already waiting.
+    pub(crate) fn acquire_bounded(
+        &self,
+        admission: Admission,
+        max_queued: usize,
+    ) -> Option<Acquire<'_>> {
+        let mut state = self.lock();
+        let acquire_state = if state.try_take(admission) {
+            AcquireState::Ready
+        } else if state.tickets.len() < max_queued {
+            AcquireState::Queued(state.enqueue(admission, None))
+        } else {
+            return None;
+        };
+        Some(Acquire {
+            sem: self,
+            admission,
+            state: acquire_state,
+        })
+    }
+
+    fn release(&self) {
+        let mut state = self.lock();
+        state.in_use -= 1;
+        state.grant();
+    }
+}
+
+impl State {
+    fn ticket(&mut self, admission: Admission) -> Ticket {
+        let seq = self.next_id;
+        self.next_id += 1;
+        Ticket {
+            seq,
+            admission,
+            queued_at: self.clock.now(),
+            admitted_before: self.admitted,
+        }
+    }
+
+    fn try_take(&mut self, admission: Admission) -> bool {
+        if self.tickets.is_empty() && self.in_use < self.capacity {
+            let ticket = self.ticket(admission);
+            self.in_use += 1;
+            self.admitted += 1;
+            self.scheduler.queued(&ticket);
+            self.scheduler.admitted(&ticket);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn enqueue(&mut self, admission: Admission, waker: Option<Waker>) -> u64 {
+        let ticket = self.ticket(admission);
+        self.scheduler.queued(&ticket);
+        self.tickets.push(ticket);
+        self.wakers.push(waker);
+        ticket.seq
+    }
+
+    fn grant(&mut self) {
+        while self.in_use < self.capacity && !self.tickets.is_empty() {
+            let idx = self.scheduler.pick(&self.tickets, self.admitted);
+            let ticket = self.tickets.swap_remove(idx);
+            let waker = self.wakers.swap_remove(idx);
+            self.in_use += 1;
+            self.admitted += 1;
+            self.scheduler.admitted(&ticket);
+            self.granted.push(ticket.seq);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(idx) = self.tickets.iter().position(|t| t.seq == id) {
+            let ticket = self.tickets.swap_remove(idx);
+            self.wakers.swap_remove(idx);
+            self.scheduler.cancelled(&ticket);
+        }
+    }
+
+    fn take_granted(&mut self, id: u64) -> bool {
+        match self.granted.iter().position(|g| *g == id) {
+            Some(idx) => {
+                self.granted.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Future returned by [`Semaphore::acquire`].
+pub(crate) struct Acquire<'a> {
+    sem: &'a Semaphore,
+    admission: Admission,
+    state: AcquireState,
+}
+
+enum AcquireState {
+    Init,
+    Queued(u64),
+    Ready,
+    Done,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Permit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let sem = self.sem;
+        match self.state {
+            AcquireState::Init => {
+                let mut state = sem.lock();
+                if state.try_take(self.admission) {
+                    drop(state);
+                    self.state = AcquireState::Done;
+                    return Poll::Ready(Permit { sem });
+                }
+                let id = state.enqueue(self.admission, Some(cx.waker().clone()));
+                drop(state);
+                self.state = AcquireState::Queued(id);
+                Poll::Pending
+            }
+            AcquireState::Queued(id) => {
+                let mut state = sem.lock();
+                if state.take_granted(id) {
+                    drop(state);
+                    self.state = AcquireState::Done;
+                    return Poll::Ready(Permit { sem });
+                }
+                if let Some(idx) = state.tickets.iter().position(|t| t.seq == id) {
+                    match &mut state.wakers[idx] {
+                        Some(waker) => waker.clone_from(cx.waker()),
+                        waker @ None => *waker = Some(cx.waker().clone()),
+                    }
+                }
+                Poll::Pending
+            }
+            AcquireState::Ready => {
+                self.state = AcquireState::Done;
+                Poll::Ready(Permit { sem })
+            }
+            AcquireState::Done => panic!("Acquire polled after completion"),
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        match self.state {
+            AcquireState::Queued(id) => {
+                let mut state = self.sem.lock();
+                if state.take_granted(id) {
+                    // The permit was handed to us but never observed; pass it on.
+                    state.in_use -= 1;
+                    state.grant();
+                } else {
+                    state.remove(id);
+                }
+            }
+            AcquireState::Ready => self.sem.release(),
+            AcquireState::Init | AcquireState::Done => {}
+        }
+    }
+}
+
+/// A held permit, released on drop.
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    sem: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.sem.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use super::Semaphore;
+    use crate::{
+        scheduler::{Admission, Fifo},
+        test_util::{poll_once, WakeCounter},
+    };
+
+    #[test]
+    fn acquire_up_to_capacity() {
+        let sem = Semaphore::new(2, Fifo);
+        let a = poll_once(pin!(sem.acquire(Admission::default())));
+        let b = poll_once(pin!(sem.acquire(Admission::default())));
+        assert!(a.is_ready() && b.is_ready());
+        assert_eq!(sem.in_use(), 2);
+
+        let mut c = pin!(sem.acquire(Admission::default()));
+        assert!(poll_once(c.as_mut()).is_pending());
+        assert_eq!(sem.queued(), 1);
+
+        drop(a);
+        assert_eq!(sem.queued(), 0);
+        let c_permit = poll_once(c.as_mut());
+        assert!(c_permit.is_ready());
+        assert_eq!(sem.in_use(), 2);
+    }
+
+    #[test]
+    fn release_wakes_waiter() {
+        let sem = Semaphore::new(1, Fifo);
+        let permit = poll_once(pin!(sem.acquire(Admission::default())));
+        let waker = WakeCounter::new();
+        let mut queued = pin!(sem.acquire(Admission::default()));
+        assert!(waker.poll(queued.as_mut()).is_pending());
+        assert_eq!(waker.count(), 0);
+
+        drop(permit);
+        assert_eq!(waker.count(), 1);
+        assert!(waker.poll(queued.as_mut()).is_ready());
+    }
+
+    #[test]
+    fn cancel_queued_acquire() {
+        let sem = Semaphore::new(1, Fifo);
+        let permit = poll_once(pin!(sem.acquire(Admission::default())));
+        let mut a = Box::pin(sem.acquire(Admission::default()));
+        let mut b = pin!(sem.acquire(Admission::default()));
+        assert!(poll_once(a.as_mut()).is_pending());
+        assert!(poll_once(b.as_mut()).is_pending());
+
+        drop(a);
+        assert_eq!(sem.queued(), 1);
+        drop(permit);
+        let b_permit = poll_once(b.as_mut());
+        assert!(b_permit.is_ready());
+        assert_eq!(sem.in_use(), 1);
+    }
+
+    #[test]
+    fn cancel_granted_acquire_passes_permit_on() {
+        let sem = Semaphore::new(1, Fifo);
+        let permit = poll_once(pin!(sem.acquire(Admission::default())));
+        let mut a = Box::pin(sem.acquire(Admission::default()));
+        let mut b = pin!(sem.acquire(Admission::default()));
+        assert!(poll_once(a.as_mut()).is_pending());
+        assert!(poll_once(b.as_mut()).is_pending());
+
+        // The permit goes to `a`, which is dropped before it sees it.
+        drop(permit);
+        drop(a);
+        let b_permit = poll_once(b.as_mut());
+        assert!(b_permit.is_ready());
+        assert_eq!(sem.in_use(), 1);
+        assert_eq!(sem.queued(), 0);
+    }
+
+    #[test]
+    fn resize_grow_admits_waiters() {
+        let sem = Semaphore::new(1, Fifo);
+        let _permit = poll_once(pin!(sem.acquire(Admission::default())));
+        let mut a = pin!(sem.acquire(Admission::default()));
+        let mut b = pin!(sem.acquire(Admission::default()));
+        assert!(poll_once(a.as_mut()).is_pending());
+        assert!(poll_once(b.as_mut()).is_pending());
+
+        sem.resize(3);
+        assert_eq!(sem.capacity(), 3);
+        let a_permit = poll_once(a.as_mut());
+        assert!(a_permit.is_ready());
+        let b_permit = poll_once(b.as_mut());
+        assert!(b_permit.is_ready());
+        assert_eq!(sem.in_use(), 3);
+    }
+
+    #[test]
+    fn resize_shrink_waits_for_releases() {
+        let sem = Semaphore::new(3, Fifo);
+        let a = poll_once(pin!(sem.acquire(Admission::default())));
+        let b = poll_once(pin!(sem.acquire(Admission::default())));
+        let c = poll_once(pin!(sem.acquire(Admission::default())));
+        let mut queued = pin!(sem.acquire(Admission::default()));
+        assert!(poll_once(queued.as_mut()).is_pending());
+
+        // Held permits are kept; the queue waits until usage is below the new capacity.
+        sem.resize(1);
+        assert_eq!(sem.in_use(), 3);
+        drop(a);
+        drop(b);
+        assert!(poll_once(queued.as_mut()).is_pending());
+        assert_eq!(sem.in_use(), 1);
+        drop(c);
+        let queued_permit = poll_once(queued.as_mut());
+        assert!(queued_permit.is_ready());
+        assert_eq!(sem.in_use(), 1);
+    }
+
+    #[test]
+    fn bounded_queue_rejects() {
+        let sem = Semaphore::new(1, Fifo);
+        // A free permit is taken even if no acquirer may wait.
+        let permit = poll_once(pin!(sem.acquire_bounded(Admission::default(), 0).unwrap()));
+        assert!(permit.is_ready());
+        assert!(sem.acquire_bounded(Admission::default(), 0).is_none());
+
+        let mut queued = pin!(sem.acquire_bounded(Admission::default(), 1).unwrap());
+        assert_eq!(sem.queued(), 1);
+        assert!(sem.acquire_bounded(Admission::default(), 1).is_none());
+        assert_eq!(sem.queued(), 1);
+
+        drop(permit);
+        let queued_permit = poll_once(queued.as_mut());
+        assert!(queued_permit.is_ready());
+    }
+}
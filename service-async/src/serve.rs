@@ -0,0 +1,542 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    rc::Rc,
+    task::Poll,
+    time::Duration,
+};
+
+use crate::{time::Timer, AsyncMakeService, Service};
+
+/// A source of incoming connections.
+///
+/// `accept` must be cancel safe: the [`Server`] drops a pending `accept` future
+/// when a reload arrives first.
+pub trait Listener {
+    type Conn;
+    type Error;
+
+    fn accept(&self) -> impl Future<Output = Result<Self::Conn, Self::Error>>;
+}
+
+/// Spawns a connection task on the current thread's runtime.
+///
+/// It is implemented for closures, e.g. `|fut| { monoio::spawn(fut); }` or
+/// `|fut| { tokio::task::spawn_local(fut); }`.
+pub trait Spawn {
+    fn spawn(&self, fut: Pin<Box<dyn Future<Output = ()>>>);
+}
+
+impl<T: Fn(Pin<Box<dyn Future<Output = ()>>>)> Spawn for T {
+    #[inline]
+    fn spawn(&self, fut: Pin<Box<dyn Future<Output = ()>>>) {
+        (self)(fut)
+    }
+}
+
+/// A source of new factories which replace the running one.
+///
+/// `next` returns `None` once no more reloads will come, and must be cancel safe
+/// like [`Listener::accept`].
+pub trait Reload<F> {
+    fn next(&mut self) -> impl Future<Output = Option<F>>;
+}
+
+/// A [`Reload`] which never reloads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoReload;
+
+impl<F> Reload<F> for NoReload {
+    #[inline]
+    async fn next(&mut self) -> Option<F> {
+        None
+    }
+}
+
+/// Which connections share a service made by the [`Server`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ServiceScope {
+    /// All connections of the server share one service, rebuilt on reload.
+    #[default]
+    Worker,
+    /// Each connection gets its own service, made from the current factory.
+    Connection,
+}
+
+/// What the [`Server`] does when accepting a connection fails, as decided by
+/// the hook passed to [`on_accept_error`](Server::on_accept_error).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptAction {
+    /// Wait for the duration, then keep serving. E.g. when the process runs out
+    /// of file descriptors, until connections in flight close some.
+    Backoff(Duration),
+    /// Stop the server, returning the error.
+    Fatal,
+}
+
+/// Error returned by [`Server::run`].
+#[derive(Debug)]
+pub enum ServeError<L, M> {
+    /// Accepting a connection failed with an error found fatal.
+    Accept(L),
+    /// Making the initial service failed.
+    Make(M),
+}
+
+impl<L: Display, M: Display> Display for ServeError<L, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServeError::Accept(e) => write!(f, "accept failed: {e}"),
+            ServeError::Make(e) => write!(f, "make service failed: {e}"),
+        }
+    }
+}
+
+impl<L: Error + 'static, M: Error + 'static> Error for ServeError<L, M> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServeError::Accept(e) => Some(e),
+            ServeError::Make(e) => Some(e),
+        }
+    }
+}
+
+type ErrorHook<E> = Box<dyn Fn(E)>;
+
+type AcceptErrorHook<E> = Box<dyn Fn(&E) -> Option<Pin<Box<dyn Future<Output = ()>>>>>;
+
+/// Drives a [`Listener`]: calls a service made by an [`AsyncMakeService`] with
+/// each accepted connection, in a task of its own.
+///
+/// When the [`Reload`] source yields a new factory, the service is rebuilt with
+/// `make_via_ref` against the running one, and new connections are served by
+/// the new service. Connections in flight keep the service they started with.
+/// If the new factory fails to make a service, the old one keeps serving and
+/// the error is passed to [`on_make_error`](Server::on_make_error).
+///
+/// Errors of accepting a connection are passed to
+/// [`on_accept_error`](Server::on_accept_error), which backs off and keeps
+/// serving, or stops the server. Without it, the first one stops the server.
+///
+/// The server runs on one thread, so services and connections do not need to be
+/// `Send`. Run a server per worker thread to use more cores.
+///
+/// ```rust
+/// use std::{convert::Infallible, future::Future, io};
+/// use service_async::{
+///     serve::{Listener, Server},
+///     utils::CloneFactory,
+///     Service,
+/// };
+///
+/// struct Conns;
+///
+/// impl Listener for Conns {
+///     type Conn = u32;
+///     type Error = io::Error;
+///
+///     async fn accept(&self) -> io::Result<u32> {
+///         Err(io::ErrorKind::ConnectionAborted.into())
+///     }
+/// }
+///
+/// #[derive(Clone)]
+/// struct Echo;
+///
+/// impl Service<u32> for Echo {
+///     type Response = u32;
+///     type Error = Infallible;
+///
+///     async fn call(&self, conn: u32) -> Result<u32, Infallible> {
+///         Ok(conn)
+///     }
+/// }
+///
+/// fn run(spawn: impl Fn(std::pin::Pin<Box<dyn Future<Output = ()>>>)) -> impl Future {
+///     Server::new(Conns, CloneFactory::new(Echo), spawn).run()
+/// }
+/// ```
+pub struct Server<L: Listener, F: AsyncMakeService, SP, R = NoReload> {
+    listener: L,
+    factory: F,
+    spawner: SP,
+    reload: R,
+    scope: ServiceScope,
+    on_make_error: ErrorHook<F::Error>,
+    on_accept_error: AcceptErrorHook<L::Error>,
+}
+
+impl<L: Listener, F: AsyncMakeService, SP> Server<L, F, SP> {
+    pub fn new(listener: L, factory: F, spawner: SP) -> Self {
+        Server {
+            listener,
+            factory,
+            spawner,
+            reload: NoReload,
+            scope: ServiceScope::Worker,
+            on_make_error: Box::new(|_| {}),
+            on_accept_error: Box::new(|_| None),
+        }
+    }
+}
+
+impl<L: Listener, F: AsyncMakeService, SP, R> Server<L, F, SP, R> {
+    /// Reload the service from factories yielded by `reload`.
+    pub fn reload<R2: Reload<F>>(self, reload: R2) -> Server<L, F, SP, R2> {
+        Server {
+            listener: self.listener,
+            factory: self.factory,
+            spawner: self.spawner,
+            reload,
+            scope: self.scope,
+            on_make_error: self.on_make_error,
+            on_accept_error: self.on_accept_error,
+        }
+    }
+
+    pub fn scope(mut self, scope: ServiceScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Handle errors of making a service on reload, or for a connection with
+    /// [`ServiceScope::Connection`].
+    pub fn on_make_error(mut self, f: impl Fn(F::Error) + 'static) -> Self {
+        self.on_make_error = Box::new(f);
+        self
+    }
+
+    /// Handle errors of accepting a connection: `f` decides whether to back off
+    /// for a while with `timer` and keep serving, or to stop the server. No
+    /// connection is accepted while backing off.
+    pub fn on_accept_error<TM>(
+        mut self,
+        timer: TM,
+        f: impl Fn(&L::Error) -> AcceptAction + 'static,
+    ) -> Self
+    where
+        TM: Timer + 'static,
+        TM::Sleep: 'static,
+    {
+        self.on_accept_error = Box::new(move |e| match f(e) {
+            AcceptAction::Backoff(duration) => Some(Box::pin(timer.sleep(duration))),
+            AcceptAction::Fatal => None,
+        });
+        self
+    }
+
+    /// Serve connections until accepting one fails with an error found fatal.
+    pub async fn run(self) -> Result<(), ServeError<L::Error, F::Error>>
+    where
+        L::Conn: 'static,
+        F: 'static,
+        F::Service: Service<L::Conn> + 'static,
+        F::Error: 'static,
+        SP: Spawn,
+        R: Reload<F>,
+    {
+        let Server {
+            listener,
+            factory,
+            spawner,
+            mut reload,
+            scope,
+            on_make_error,
+            on_accept_error,
+        } = self;
+        let on_make_error: Rc<dyn Fn(F::Error)> = on_make_error.into();
+        let mut factory = Rc::new(factory);
+        let mut svc = Rc::new(factory.make().await.map_err(ServeError::Make)?);
+        let mut reloading = true;
+
+        loop {
+            match next_event(&listener, &mut reload, reloading).await {
+                Event::Accept(Err(e)) => match on_accept_error(&e) {
+                    Some(backoff) => backoff.await,
+                    None => return Err(ServeError::Accept(e)),
+                },
+                Event::Accept(Ok(conn)) => match scope {
+                    ServiceScope::Worker => {
+                        let svc = svc.clone();
+                        spawner.spawn(Box::pin(async move {
+                            let _ = svc.call(conn).await;
+                        }));
+                    }
+                    ServiceScope::Connection => {
+                        let factory = factory.clone();
+                        let on_make_error = on_make_error.clone();
+                        spawner.spawn(Box::pin(async move {
+                            match factory.make().await {
+                                Ok(svc) => {
+                                    let _ = svc.call(conn).await;
+                                }
+                                Err(e) => on_make_error(e),
+                            }
+                        }));
+                    }
+                },
+                Event::Reload(Some(new)) => match new.make_via_ref(Some(&svc)).await {
+                    Ok(new_svc) => {
+                        svc = Rc::new(new_svc);
+                        factory = Rc::new(new);
+                    }
+                    Err(e) => on_make_error(e),
+                },
+                Event::Reload(None) => reloading = false,
+            }
+        }
+    }
+}
+
+enum Event<C, F> {
+    Accept(C),
+    Reload(Option<F>),
+}
+
+async fn next_event<L: Listener, F, R: Reload<F>>(
+    listener: &L,
+    reload: &mut R,
+    reloading: bool,
+) -> Event<Result<L::Conn, L::Error>, F> {
+    let mut accept = pin!(listener.accept());
+    if !reloading {
+        return Event::Accept(accept.await);
+    }
+    let mut next = pin!(reload.next());
+    poll_fn(|cx| {
+        if let Poll::Ready(f) = next.as_mut().poll(cx) {
+            return Poll::Ready(Event::Reload(f));
+        }
+        accept.as_mut().poll(cx).map(Event::Accept)
+    })
+    .await
+}
+
+/// Serve connections of `listener` with services made by `factory`, until
+/// accepting one fails. See [`Server`] for reloads, backing off on accept
+/// errors and other options.
+pub async fn serve<L, F, SP>(
+    listener: L,
+    factory: F,
+    spawner: SP,
+) -> Result<(), ServeError<L::Error, F::Error>>
+where
+    L: Listener,
+    L::Conn: 'static,
+    F: AsyncMakeService + 'static,
+    F::Service: Service<L::Conn> + 'static,
+    F::Error: 'static,
+    SP: Spawn,
+{
+    Server::new(listener, factory, spawner).run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell, collections::VecDeque, convert::Infallible, future::poll_fn, pin::pin,
+        rc::Rc, task::Poll, time::Duration,
+    };
+
+    use super::{AcceptAction, Listener, Reload, ServeError, Server, ServiceScope};
+    use crate::{
+        test_util::{poll_once, LocalExecutor},
+        time::MockTimer,
+        AsyncMakeService, Service,
+    };
+
+    enum Step {
+        Conn(u32),
+        Reload(u32),
+        Fail(&'static str),
+    }
+
+    /// Connections and reloads in the order the server sees them. Accepting
+    /// fails once the script is over.
+    #[derive(Clone)]
+    struct Script(Rc<RefCell<VecDeque<Step>>>);
+
+    impl Script {
+        fn new(steps: impl IntoIterator<Item = Step>) -> Self {
+            Script(Rc::new(RefCell::new(steps.into_iter().collect())))
+        }
+    }
+
+    impl Listener for Script {
+        type Conn = u32;
+        type Error = &'static str;
+
+        async fn accept(&self) -> Result<u32, &'static str> {
+            poll_fn(|_| {
+                let mut steps = self.0.borrow_mut();
+                match steps.front() {
+                    Some(Step::Conn(conn)) => {
+                        let conn = *conn;
+                        steps.pop_front();
+                        Poll::Ready(Ok(conn))
+                    }
+                    Some(Step::Reload(_)) => Poll::Pending,
+                    Some(Step::Fail(e)) => {
+                        let e = *e;
+                        steps.pop_front();
+                        Poll::Ready(Err(e))
+                    }
+                    None => Poll::Ready(Err("closed")),
+                }
+            })
+            .await
+        }
+    }
+
+    impl Reload<Recorder> for Script {
+        async fn next(&mut self) -> Option<Recorder> {
+            poll_fn(|_| {
+                let mut steps = self.0.borrow_mut();
+                match steps.front() {
+                    Some(Step::Reload(tag)) => {
+                        let tag = *tag;
+                        steps.pop_front();
+                        Poll::Ready(Some(Recorder::new(tag)))
+                    }
+                    _ => Poll::Pending,
+                }
+            })
+            .await
+        }
+    }
+
+    type Log = Rc<RefCell<Vec<(u32, usize, u32)>>>;
+
+    /// Logs the connections it serves with its tag and the number of services
+    /// it was made against. It is its own factory; a tag of 0 fails to make.
+    #[derive(Clone)]
+    struct Recorder {
+        tag: u32,
+        generation: usize,
+        log: Log,
+    }
+
+    impl Recorder {
+        fn new(tag: u32) -> Self {
+            Recorder {
+                tag,
+                generation: 0,
+                log: Log::default(),
+            }
+        }
+    }
+
+    impl Service<u32> for Recorder {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, conn: u32) -> Result<(), Infallible> {
+            self.log
+                .borrow_mut()
+                .push((self.tag, self.generation, conn));
+            Ok(())
+        }
+    }
+
+    impl AsyncMakeService for Recorder {
+        type Service = Recorder;
+        type Error = &'static str;
+
+        async fn make_via_ref(&self, old: Option<&Recorder>) -> Result<Recorder, &'static str> {
+            if self.tag == 0 {
+                return Err("bad config");
+            }
+            Ok(Recorder {
+                tag: self.tag,
+                generation: old.map_or(0, |o| o.generation + 1),
+                // Share the log of the first service.
+                log: old.map_or_else(|| self.log.clone(), |o| o.log.clone()),
+            })
+        }
+    }
+
+    fn run(script: Script, scope: ServiceScope) -> (Vec<(u32, usize, u32)>, Vec<&'static str>) {
+        let factory = Recorder::new(1);
+        let log = factory.log.clone();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let on_make_error = {
+            let errors = errors.clone();
+            move |e| errors.borrow_mut().push(e)
+        };
+        let executor = LocalExecutor::new();
+        let server = Server::new(script.clone(), factory, executor.spawner())
+            .reload(script)
+            .scope(scope)
+            .on_make_error(on_make_error);
+        assert!(matches!(
+            executor.block_on(server.run()),
+            Err(ServeError::Accept("closed"))
+        ));
+        executor.run_tasks();
+        assert_eq!(executor.pending_tasks(), 0);
+        let log = log.borrow().clone();
+        let errors = errors.borrow().clone();
+        (log, errors)
+    }
+
+    #[test]
+    fn worker_scope_reloads() {
+        let script = Script::new([Step::Conn(1), Step::Reload(2), Step::Conn(2)]);
+        let (log, errors) = run(script, ServiceScope::Worker);
+        assert_eq!(log, [(1, 0, 1), (2, 1, 2)]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_old_service() {
+        let script = Script::new([Step::Reload(0), Step::Conn(1)]);
+        let (log, errors) = run(script, ServiceScope::Worker);
+        assert_eq!(log, [(1, 0, 1)]);
+        assert_eq!(errors, ["bad config"]);
+    }
+
+    #[test]
+    fn connection_scope_makes_a_service_per_connection() {
+        let script = Script::new([Step::Conn(1), Step::Conn(2)]);
+        let (log, _) = run(script, ServiceScope::Connection);
+        assert_eq!(log, [(1, 0, 1), (1, 0, 2)]);
+    }
+
+    #[test]
+    fn accept_errors_back_off() {
+        let script = Script::new([Step::Conn(1), Step::Fail("busy"), Step::Conn(2)]);
+        let factory = Recorder::new(1);
+        let log = factory.log.clone();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let timer = MockTimer::new();
+        let executor = LocalExecutor::new();
+        let server =
+            Server::new(script, factory, executor.spawner()).on_accept_error(timer.clone(), {
+                let errors = errors.clone();
+                move |e| {
+                    errors.borrow_mut().push(*e);
+                    match *e {
+                        "busy" => AcceptAction::Backoff(Duration::from_millis(10)),
+                        _ => AcceptAction::Fatal,
+                    }
+                }
+            });
+        let mut run = pin!(server.run());
+        assert!(poll_once(run.as_mut()).is_pending());
+        executor.run_tasks();
+        assert_eq!(*log.borrow(), [(1, 0, 1)]);
+        assert_eq!(*errors.borrow(), ["busy"]);
+
+        // The server keeps serving after the backoff, until a fatal error.
+        timer.advance(Duration::from_millis(10));
+        assert!(matches!(
+            poll_once(run.as_mut()),
+            Poll::Ready(Err(ServeError::Accept("closed")))
+        ));
+        executor.run_tasks();
+        assert_eq!(*log.borrow(), [(1, 0, 1), (1, 0, 2)]);
+        assert_eq!(*errors.borrow(), ["busy", "closed"]);
+    }
+}
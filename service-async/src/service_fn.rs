@@ -0,0 +1,321 @@
+use std::{convert::Infallible, marker::PhantomData, sync::Arc};
+
+use crate::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// A service which passes a reference to its state into an async closure.
+/// Created by [`service_fn_with_state`].
+pub struct ServiceFnWithState<S, F> {
+    state: S,
+    f: Arc<F>,
+}
+
+/// Create a service which stores `state` and calls `f(&state, req)` for each
+/// request.
+///
+/// `f` is an async closure, so the future it returns may borrow the state
+/// across awaits, which a closure returning an `async move` block can not do.
+///
+/// ```
+/// use std::{cell::Cell, convert::Infallible};
+///
+/// use service_async::{service_fn::service_fn_with_state, Service};
+///
+/// # async fn run() {
+/// let svc = service_fn_with_state(Cell::new(0), async |hits: &Cell<u32>, name: &str| {
+///     hits.set(hits.get() + 1);
+///     Ok::<_, Infallible>(format!("hello {name}, #{}", hits.get()))
+/// });
+/// assert_eq!(svc.call("foo").await.unwrap(), "hello foo, #1");
+/// # }
+/// ```
+pub fn service_fn_with_state<S, F>(state: S, f: F) -> ServiceFnWithState<S, F> {
+    ServiceFnWithState {
+        state,
+        f: Arc::new(f),
+    }
+}
+
+impl<S, F> ServiceFnWithState<S, F> {
+    #[inline]
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    #[inline]
+    pub fn into_state(self) -> S {
+        self.state
+    }
+}
+
+impl<S: Clone, F> Clone for ServiceFnWithState<S, F> {
+    fn clone(&self) -> Self {
+        ServiceFnWithState {
+            state: self.state.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+impl<S, F, R, Resp, E> Service<R> for ServiceFnWithState<S, F>
+where
+    F: AsyncFn(&S, R) -> Result<Resp, E>,
+{
+    type Response = Resp;
+    type Error = E;
+
+    #[inline]
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        (self.f)(&self.state, req).await
+    }
+}
+
+/// A factory of [`ServiceFnWithState`], which makes the state of each
+/// service with a state factory. Created by [`make_service_fn_with_state`].
+///
+/// The state factory gets the state of the old service on reloads, so it can
+/// carry state over. All services made share `f`.
+pub struct MakeServiceFnWithState<SF, F, S> {
+    make_state: SF,
+    f: Arc<F>,
+    _marker: PhantomData<fn() -> S>,
+}
+
+/// Create a factory of services which call `f(&state, req)`, with the state
+/// made by `make_state(old_state)`.
+pub fn make_service_fn_with_state<SF, F, S>(
+    make_state: SF,
+    f: F,
+) -> MakeServiceFnWithState<SF, F, S> {
+    MakeServiceFnWithState {
+        make_state,
+        f: Arc::new(f),
+        _marker: PhantomData,
+    }
+}
+
+impl<SF: Clone, F, S> Clone for MakeServiceFnWithState<SF, F, S> {
+    fn clone(&self) -> Self {
+        MakeServiceFnWithState {
+            make_state: self.make_state.clone(),
+            f: self.f.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<SF, F, S, E> MakeService for MakeServiceFnWithState<SF, F, S>
+where
+    SF: Fn(Option<&S>) -> Result<S, E>,
+{
+    type Service = ServiceFnWithState<S, F>;
+    type Error = E;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(ServiceFnWithState {
+            state: (self.make_state)(old.map(|o| &o.state))?,
+            f: self.f.clone(),
+        })
+    }
+}
+
+impl<SF, F, S, E> AsyncMakeService for MakeServiceFnWithState<SF, F, S>
+where
+    SF: Fn(Option<&S>) -> Result<S, E>,
+{
+    type Service = ServiceFnWithState<S, F>;
+    type Error = E;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        MakeService::make_via_ref(self, old)
+    }
+}
+
+/// A service which calls a handler with its context, `handler(&cx, req)`.
+///
+/// The handler is a plain `async fn` taking the context by reference, so
+/// handler-style code joins a stack without a struct per handler. Make it
+/// with [`service_fn_with_state`], or from the stack config with
+/// [`ContextFactory::layer`].
+pub type ContextService<Cx, H> = ServiceFnWithState<Cx, H>;
+
+/// A leaf factory of [`ContextService`]s, whose context is taken from the
+/// stack config.
+///
+/// ```
+/// use std::convert::Infallible;
+///
+/// use service_async::{
+///     service_fn::ContextFactory, stack::FactoryStack, MakeService, Param, Service,
+/// };
+///
+/// #[derive(Clone)]
+/// struct Greeting(String);
+///
+/// struct Config {
+///     greeting: String,
+/// }
+///
+/// impl Param<Greeting> for Config {
+///     fn param(&self) -> Greeting {
+///         Greeting(self.greeting.clone())
+///     }
+/// }
+///
+/// async fn greet(cx: &Greeting, name: &str) -> Result<String, Infallible> {
+///     Ok(format!("{}, {name}", cx.0))
+/// }
+///
+/// # async fn run() {
+/// let config = Config {
+///     greeting: "hello".to_string(),
+/// };
+/// let svc = FactoryStack::new(config)
+///     .push(ContextFactory::layer(greet))
+///     .make()
+///     .unwrap();
+/// assert_eq!(svc.call("foo").await.unwrap(), "hello, foo");
+/// # }
+/// ```
+pub struct ContextFactory<Cx, H> {
+    cx: Cx,
+    handler: Arc<H>,
+}
+
+impl<Cx, H> ContextFactory<Cx, H> {
+    #[inline]
+    pub fn new(cx: Cx, handler: H) -> Self {
+        ContextFactory {
+            cx,
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// Make services calling `handler`, with the context taken from the
+    /// config by `Param<Cx>`.
+    pub fn layer<C>(handler: H) -> impl FactoryLayer<C, (), Factory = Self>
+    where
+        C: Param<Cx>,
+    {
+        let handler = Arc::new(handler);
+        layer_fn(move |c: &C, ()| ContextFactory {
+            cx: c.param(),
+            handler: handler.clone(),
+        })
+    }
+}
+
+impl<Cx: Clone, H> Clone for ContextFactory<Cx, H> {
+    fn clone(&self) -> Self {
+        ContextFactory {
+            cx: self.cx.clone(),
+            handler: self.handler.clone(),
+        }
+    }
+}
+
+impl<Cx: Clone, H> MakeService for ContextFactory<Cx, H> {
+    type Service = ContextService<Cx, H>;
+    type Error = Infallible;
+
+    fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(ServiceFnWithState {
+            state: self.cx.clone(),
+            f: self.handler.clone(),
+        })
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+    }
+}
+
+impl<Cx: Clone, H> AsyncMakeService for ContextFactory<Cx, H> {
+    type Service = ContextService<Cx, H>;
+    type Error = Infallible;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        MakeService::make_via_ref(self, old)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, convert::Infallible};
+
+    use super::{make_service_fn_with_state, service_fn_with_state, ContextFactory};
+    use crate::{
+        stack::FactoryStack, test_util::block_on, yielding::yield_now, AsyncMakeService,
+        MakeService, Service,
+    };
+
+    #[test]
+    fn state_is_borrowed_across_awaits() {
+        let svc = service_fn_with_state(Cell::new(0), async |hits: &Cell<u32>, n: u32| {
+            yield_now().await;
+            hits.set(hits.get() + n);
+            Ok::<_, Infallible>(hits.get())
+        });
+        assert_eq!(block_on(svc.call(2)), Ok(2));
+        assert_eq!(block_on(svc.call(3)), Ok(5));
+        assert_eq!(svc.into_state().get(), 5);
+    }
+
+    #[test]
+    fn state_carries_over_reloads() {
+        let factory = make_service_fn_with_state(
+            |old: Option<&Cell<u32>>| match old {
+                Some(old) if old.get() > 9 => Err("overflow"),
+                old => Ok(Cell::new(old.map_or(0, Cell::get))),
+            },
+            async |total: &Cell<u32>, n: u32| {
+                total.set(total.get() + n);
+                Ok::<_, Infallible>(total.get())
+            },
+        );
+        let svc = MakeService::make(&factory).unwrap();
+        block_on(svc.call(4)).unwrap();
+        let svc = MakeService::make_via_ref(&factory, Some(&svc)).unwrap();
+        assert_eq!(svc.state().get(), 4);
+        assert_eq!(block_on(svc.call(6)), Ok(10));
+        assert!(MakeService::make_via_ref(&factory, Some(&svc)).is_err());
+    }
+
+    #[derive(Clone)]
+    struct Prefix(&'static str);
+
+    async fn greet(cx: &Prefix, name: &str) -> Result<String, Infallible> {
+        yield_now().await;
+        Ok(format!("{}{name}", cx.0))
+    }
+
+    #[test]
+    fn context_comes_from_the_config() {
+        let factory = |prefix| {
+            FactoryStack::new(Prefix(prefix))
+                .push(ContextFactory::layer(greet))
+                .into_inner()
+        };
+        let svc = MakeService::make(&factory("hi ")).unwrap();
+        assert_eq!(block_on(svc.call("foo")).unwrap(), "hi foo");
+        // A new config gives the services made after it a new context.
+        let svc = MakeService::make_via_ref(&factory("bye "), Some(&svc)).unwrap();
+        assert_eq!(svc.state().0, "bye ");
+
+        let factory = ContextFactory::new(Prefix("hello "), greet);
+        let svc = block_on(AsyncMakeService::make(&factory.clone())).unwrap();
+        assert_eq!(block_on(svc.call("bar")).unwrap(), "hello bar");
+    }
+}
@@ -0,0 +1,196 @@
+use std::{hash::Hash, marker::PhantomData};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer},
+    random::seeded_hash,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+const SHARD_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// Number of shards of [`Sharded`], extracted from the stack config.
+///
+/// Zero is treated as one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardCount(pub usize);
+
+/// A service which owns several instances of the inner service and routes each
+/// request to one of them by the hash of its key.
+///
+/// It spreads contention on internally mutable state, e.g. a `Mutex` inside
+/// the service, over the shards while keeping the `&self` call signature.
+/// The key `Q` is taken from the request through `Param<Q>`; requests with the
+/// same key always go to the same shard as long as the shard count is the same.
+///
+/// On reload, each shard is rebuilt with `make_via_ref` against the old shard
+/// of the same index, so per-shard state is kept.
+pub struct Sharded<S, Q> {
+    shards: Vec<S>,
+    _marker: PhantomData<fn(Q)>,
+}
+
+impl<S, Q> Sharded<S, Q> {
+    pub fn layer<C>() -> impl FactoryLayer<C, S, Factory = ShardedFactory<S, Q>>
+    where
+        C: Param<ShardCount>,
+    {
+        layer_fn(|c: &C, inner| ShardedFactory {
+            shards: c.param().0.max(1),
+            inner,
+            _marker: PhantomData,
+        })
+    }
+
+    #[inline]
+    pub fn shards(&self) -> &[S] {
+        &self.shards
+    }
+
+    /// Get the index of the shard a request key is routed to.
+    pub fn shard_of(&self, key: &Q) -> usize
+    where
+        Q: Hash,
+    {
+        (seeded_hash(SHARD_SEED, key) % self.shards.len() as u64) as usize
+    }
+}
+
+impl<S, Q, R> Service<R> for Sharded<S, Q>
+where
+    S: Service<R>,
+    R: Param<Q>,
+    Q: Hash,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let idx = self.shard_of(&req.param());
+        self.shards[idx].call(req).await
+    }
+}
+
+/// Factory of [`Sharded`], which makes every shard with the same inner factory.
+pub struct ShardedFactory<F, Q> {
+    shards: usize,
+    inner: F,
+    _marker: PhantomData<fn(Q)>,
+}
+
+impl<F: MakeService, Q> MakeService for ShardedFactory<F, Q> {
+    type Service = Sharded<F::Service, Q>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let shards = (0..self.shards)
+            .map(|idx| {
+                let old = old.and_then(|o| o.shards.get(idx));
+                self.inner.make_via_ref(old)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Sharded {
+            shards,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_resource("shards", self.shards as u64)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, Q> AsyncMakeService for ShardedFactory<F, Q> {
+    type Service = Sharded<F::Service, Q>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let mut shards = Vec::with_capacity(self.shards);
+        for idx in 0..self.shards {
+            let old = old.and_then(|o| o.shards.get(idx));
+            shards.push(self.inner.make_via_ref(old).await?);
+        }
+        Ok(Sharded {
+            shards,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_resource("shards", self.shards as u64)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(ShardedFactory<_, Q> { shards, _marker });
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, convert::Infallible, rc::Rc};
+
+    use super::{ShardCount, Sharded};
+    use crate::{layer::FactoryLayer, test_util::block_on, MakeService, Service};
+
+    /// Records the keys it is called with.
+    #[derive(Default)]
+    struct Shard(Rc<RefCell<Vec<u32>>>);
+
+    impl Service<u32> for Shard {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<(), Infallible> {
+            self.0.borrow_mut().push(req);
+            Ok(())
+        }
+    }
+
+    /// Makes shards which keep the log of the old shard.
+    struct ShardFactory;
+
+    impl MakeService for ShardFactory {
+        type Service = Shard;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, old: Option<&Shard>) -> Result<Shard, Infallible> {
+            Ok(Shard(old.map(|o| o.0.clone()).unwrap_or_default()))
+        }
+    }
+
+    #[test]
+    fn routes_by_key_and_keeps_shards_on_reload() {
+        let factory = Sharded::<_, u32>::layer().layer(&ShardCount(4), ShardFactory);
+        let svc = factory.make().unwrap();
+        assert_eq!(svc.shards().len(), 4);
+        for key in (0..32).chain(0..32) {
+            block_on(svc.call(key)).unwrap();
+        }
+        for (idx, shard) in svc.shards().iter().enumerate() {
+            let keys = shard.0.borrow();
+            assert!(keys.iter().all(|k| svc.shard_of(k) == idx));
+        }
+        assert!(svc.shards().iter().all(|s| !s.0.borrow().is_empty()));
+
+        let reloaded = factory.make_via_ref(Some(&svc)).unwrap();
+        for (old, new) in svc.shards().iter().zip(reloaded.shards()) {
+            assert!(Rc::ptr_eq(&old.0, &new.0));
+        }
+        assert_eq!(reloaded.shard_of(&7), svc.shard_of(&7));
+
+        let single = Sharded::<_, u32>::layer().layer(&ShardCount(0), ShardFactory);
+        assert_eq!(single.make().unwrap().shards().len(), 1);
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    ops::Deref,
+    pin::{pin, Pin},
+    rc::Rc,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+use crate::{
+    either::{Either, FlattenErr},
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    pushback::{Pushback, PushbackHint},
+    time::Timer,
+    AsyncMakeService, MakeService, MapErrInto, MapTargetService, Param, Service, ServiceMetadata,
+};
+
+/// A service which holds I/O resources to release before it is dropped, e.g.
+/// a pool of connections to flush and close.
+///
+/// `Drop` can not await, so a replaced service is shut down explicitly, e.g.
+/// with [`ServiceSlot::swap_and_shutdown`](crate::reload::ServiceSlot::swap_and_shutdown)
+/// or [`ReloadTransaction::commit_and_shutdown`](crate::reload::ReloadTransaction::commit_and_shutdown).
+/// Calls still in flight may hold the service while it shuts down, so it
+/// should keep serving them.
+///
+/// The middlewares of the crate shut down the services they wrap, down to the
+/// leaf, which implements `Shutdown` itself. Leaves without resources implement
+/// it with an empty body. Middlewares with two arms shut both down, and ones
+/// owning a worker task, like [`Buffer`](crate::buffer::Buffer), first flush the
+/// requests queued to it.
+pub trait Shutdown {
+    fn shutdown(&self) -> impl Future<Output = ()>;
+}
+
+// Implements `Shutdown` for a middleware by shutting down the service returned
+// by its `Layered::inner`.
+macro_rules! impl_shutdown_inner {
+    ($ty:ident<$($g:ident),*>) => {
+        impl<$($g),*> $crate::shutdown::Shutdown for $ty<$($g),*>
+        where
+            <Self as $crate::layer::Layered>::Inner: $crate::shutdown::Shutdown,
+        {
+            #[inline]
+            fn shutdown(&self) -> impl ::std::future::Future<Output = ()> {
+                $crate::shutdown::Shutdown::shutdown($crate::layer::Layered::inner(self))
+            }
+        }
+    };
+}
+pub(crate) use impl_shutdown_inner;
+
+impl_shutdown_inner!(MapTargetService<T, F>);
+impl_shutdown_inner!(MapErrInto<T, E>);
+impl_shutdown_inner!(FlattenErr<T>);
+
+impl<A: Shutdown, B: Shutdown> Shutdown for Either<A, B> {
+    async fn shutdown(&self) {
+        match self {
+            Either::Left(a) => a.shutdown().await,
+            Either::Right(b) => b.shutdown().await,
+        }
+    }
+}
+
+impl<T: Shutdown + ?Sized> Shutdown for Arc<T> {
+    #[inline]
+    fn shutdown(&self) -> impl Future<Output = ()> {
+        (**self).shutdown()
+    }
+}
+
+impl<T: Shutdown + ?Sized> Shutdown for Rc<T> {
+    #[inline]
+    fn shutdown(&self) -> impl Future<Output = ()> {
+        (**self).shutdown()
+    }
+}
+
+/// The phases of a graceful shutdown run by a [`ShutdownCoordinator`], in
+/// the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ShutdownPhase {
+    /// The outer layers refuse new requests.
+    StopAccepting,
+    /// The requests in flight complete.
+    Drain,
+    /// The leaves release their resources, e.g. close their pools.
+    Close,
+}
+
+impl Display for ShutdownPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShutdownPhase::StopAccepting => write!(f, "stop accepting"),
+            ShutdownPhase::Drain => write!(f, "drain"),
+            ShutdownPhase::Close => write!(f, "close"),
+        }
+    }
+}
+
+struct DrainState {
+    stopped: bool,
+    in_flight: usize,
+    wakers: Vec<Waker>,
+}
+
+/// A handle shared by [`Draining`] services, to stop them accepting requests
+/// and wait for the requests in flight. Clones share the same state.
+#[derive(Clone)]
+pub struct DrainHandle(Arc<Mutex<DrainState>>);
+
+impl DrainHandle {
+    pub fn new() -> Self {
+        DrainHandle(Arc::new(Mutex::new(DrainState {
+            stopped: false,
+            in_flight: 0,
+            wakers: Vec::new(),
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DrainState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Refuse new requests from now on.
+    pub fn stop(&self) {
+        self.lock().stopped = true;
+    }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.lock().stopped
+    }
+
+    /// Number of requests in flight.
+    #[inline]
+    pub fn in_flight(&self) -> usize {
+        self.lock().in_flight
+    }
+
+    /// Wait until no request is in flight.
+    pub fn drained(&self) -> impl Future<Output = ()> + 'static {
+        let handle = self.clone();
+        poll_fn(move |cx| {
+            let mut state = handle.lock();
+            if state.in_flight == 0 {
+                return Poll::Ready(());
+            }
+            state.wakers.push(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+
+    fn enter(&self) -> Option<InFlight<'_>> {
+        let mut state = self.lock();
+        if state.stopped {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(InFlight(self))
+    }
+}
+
+impl Default for DrainHandle {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct InFlight<'a>(&'a DrainHandle);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            for waker in state.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Error returned by [`Draining`].
+#[derive(Debug)]
+pub enum DrainError<E> {
+    /// The service is shutting down and refuses new requests.
+    Stopped,
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for DrainError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DrainError::Stopped => write!(f, "service is shutting down"),
+            DrainError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for DrainError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DrainError::Stopped => None,
+            DrainError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A stopped service pushes back, so callers go to another instance.
+impl<E: PushbackHint> PushbackHint for DrainError<E> {
+    #[inline]
+    fn pushback(&self) -> Option<Pushback> {
+        match self {
+            DrainError::Stopped => Some(Pushback::saturated()),
+            DrainError::Inner(e) => e.pushback(),
+        }
+    }
+}
+
+/// A middleware which counts the requests in flight on a [`DrainHandle`], and
+/// refuses new ones with [`DrainError::Stopped`] once the handle is stopped.
+///
+/// The handle is taken from the config, so it is shared by the services made
+/// on reload and in-flight requests of replaced services are waited for too.
+/// Put it outermost, so requests are refused before any inner layer runs.
+pub struct Draining<T> {
+    handle: DrainHandle,
+    inner: T,
+}
+
+impl<T> Draining<T> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<DrainHandle>,
+    {
+        layer_fn(|c: &C, inner| Draining {
+            handle: c.param(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn handle(&self) -> &DrainHandle {
+        &self.handle
+    }
+}
+
+impl<T, R> Service<R> for Draining<T>
+where
+    T: Service<R>,
+{
+    type Response = T::Response;
+    type Error = DrainError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let _in_flight = self.handle.enter().ok_or(DrainError::Stopped)?;
+        self.inner.call(req).await.map_err(DrainError::Inner)
+    }
+}
+
+impl<F: MakeService> MakeService for Draining<F> {
+    type Service = Draining<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Draining {
+            handle: self.handle.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for Draining<F> {
+    type Service = Draining<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Draining {
+            handle: self.handle.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(Draining<_> { handle });
+
+impl<T> Layered for Draining<T> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Draining<T>);
+
+type ShutdownStep = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Result of [`ShutdownCoordinator::run`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ShutdownReport {
+    /// The phases which did not complete within the timeout.
+    pub timed_out: Vec<ShutdownPhase>,
+}
+
+impl ShutdownReport {
+    /// Whether every phase completed in time.
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.timed_out.is_empty()
+    }
+}
+
+/// Runs the graceful shutdown of a stack in [`ShutdownPhase`] order: the
+/// outermost layers stop accepting, then the requests in flight drain, then
+/// the leaves close their resources through their [`Shutdown`] impls.
+///
+/// Each phase completes before the next one starts, so pools are not closed
+/// under requests still draining. Steps of a phase run in the order they were
+/// added, which should be from the outermost layer in. A phase which does
+/// not complete within `phase_timeout` is abandoned, and the next one starts.
+pub struct ShutdownCoordinator<TM> {
+    timer: TM,
+    phase_timeout: Duration,
+    steps: Vec<(ShutdownPhase, ShutdownStep)>,
+}
+
+impl<TM> ShutdownCoordinator<TM> {
+    pub fn new(timer: TM, phase_timeout: Duration) -> Self {
+        ShutdownCoordinator {
+            timer,
+            phase_timeout,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Add a step to run in `phase`.
+    pub fn step(mut self, phase: ShutdownPhase, step: impl Future<Output = ()> + 'static) -> Self {
+        self.steps.push((phase, Box::pin(step)));
+        self
+    }
+
+    /// Stop the [`Draining`] services of `handle` accepting requests.
+    pub fn stop_accepting(self, handle: &DrainHandle) -> Self {
+        let handle = handle.clone();
+        self.step(ShutdownPhase::StopAccepting, async move { handle.stop() })
+    }
+
+    /// Wait for the requests in flight through the [`Draining`] services of
+    /// `handle`.
+    pub fn drain(self, handle: &DrainHandle) -> Self {
+        self.step(ShutdownPhase::Drain, handle.drained())
+    }
+
+    /// Shut down `svc`, e.g. the live service of a
+    /// [`ServiceSlot`](crate::reload::ServiceSlot).
+    pub fn close<P>(self, svc: P) -> Self
+    where
+        P: Deref + 'static,
+        P::Target: Shutdown,
+    {
+        self.step(ShutdownPhase::Close, async move { svc.shutdown().await })
+    }
+
+    /// Run the phases in order.
+    pub async fn run(mut self) -> ShutdownReport
+    where
+        TM: Timer,
+    {
+        // The sort is stable, so steps of a phase keep the order they were added.
+        self.steps.sort_by_key(|(phase, _)| *phase);
+        let mut report = ShutdownReport::default();
+        let mut steps = self.steps.into_iter().peekable();
+        while let Some((phase, _)) = steps.peek() {
+            let phase = *phase;
+            let mut current = Vec::new();
+            while let Some((_, step)) = steps.next_if(|(p, _)| *p == phase) {
+                current.push(step);
+            }
+            let mut run = pin!(async move {
+                for step in current {
+                    step.await;
+                }
+            });
+            let mut sleep = pin!(self.timer.sleep(self.phase_timeout));
+            let completed = poll_fn(|cx| {
+                if run.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(true);
+                }
+                sleep.as_mut().poll(cx).map(|_| false)
+            })
+            .await;
+            if !completed {
+                report.timed_out.push(phase);
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        convert::Infallible,
+        pin::pin,
+        rc::Rc,
+        task::Poll,
+        time::Duration,
+    };
+
+    use super::{
+        DrainError, DrainHandle, Draining, Shutdown, ShutdownCoordinator, ShutdownPhase,
+        ShutdownReport,
+    };
+    use crate::{
+        either::Either,
+        layer::FactoryLayer,
+        reload::ServiceSlot,
+        test_util::{block_on, poll_once},
+        time::{MockTimer, Timer},
+        AsyncMakeService, MapTargetService, Service,
+    };
+
+    /// Counts how many times it was shut down.
+    #[derive(Default)]
+    struct Pool {
+        generation: usize,
+        closed: Cell<usize>,
+    }
+
+    impl Shutdown for Pool {
+        async fn shutdown(&self) {
+            self.closed.set(self.closed.get() + 1);
+        }
+    }
+
+    type Wrapped = MapTargetService<Pool, ()>;
+
+    fn layered(generation: usize) -> Wrapped {
+        MapTargetService {
+            f: (),
+            inner: Pool {
+                generation,
+                ..Default::default()
+            },
+        }
+    }
+
+    struct Factory;
+
+    impl AsyncMakeService for Factory {
+        type Service = Wrapped;
+        type Error = Infallible;
+
+        async fn make_via_ref(&self, old: Option<&Wrapped>) -> Result<Wrapped, Infallible> {
+            Ok(layered(old.map_or(0, |o| o.inner.generation + 1)))
+        }
+    }
+
+    #[test]
+    fn replaced_services_are_shut_down() {
+        let slot = ServiceSlot::new(layered(0));
+        let old = block_on(slot.swap_and_shutdown(layered(1)));
+        assert_eq!((old.inner.generation, old.inner.closed.get()), (0, 1));
+        assert_eq!(slot.get().inner.closed.get(), 0);
+
+        let tx = block_on(slot.begin(&Factory)).unwrap();
+        let old = block_on(tx.commit_and_shutdown());
+        assert_eq!((old.inner.generation, old.inner.closed.get()), (1, 1));
+        let live = slot.get();
+        assert_eq!((live.inner.generation, live.inner.closed.get()), (2, 0));
+
+        let either: Either<Pool, Wrapped> = Either::Right(layered(0));
+        block_on(either.shutdown());
+        let Either::Right(svc) = either else {
+            unreachable!()
+        };
+        assert_eq!(svc.inner.closed.get(), 1);
+    }
+
+    /// Takes the mock time asked by the request.
+    struct Slow(MockTimer);
+
+    impl Service<u64> for Slow {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, millis: u64) -> Result<(), Infallible> {
+            self.0.sleep(Duration::from_millis(millis)).await;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn draining_refuses_new_calls_and_waits() {
+        let timer = MockTimer::new();
+        let handle = DrainHandle::new();
+        let svc = Draining::layer().layer(&handle, Slow(timer.clone()));
+
+        let mut call = pin!(svc.call(5));
+        assert!(poll_once(call.as_mut()).is_pending());
+        assert_eq!(handle.in_flight(), 1);
+        handle.stop();
+        assert!(matches!(block_on(svc.call(0)), Err(DrainError::Stopped)));
+
+        let mut drained = pin!(handle.drained());
+        assert!(poll_once(drained.as_mut()).is_pending());
+        timer.advance(Duration::from_millis(5));
+        assert!(matches!(poll_once(call.as_mut()), Poll::Ready(Ok(()))));
+        assert_eq!(poll_once(drained.as_mut()), Poll::Ready(()));
+    }
+
+    #[test]
+    fn coordinator_runs_phases_in_order() {
+        let timer = MockTimer::new();
+        let handle = DrainHandle::new();
+        let svc = Draining::layer().layer(&handle, Slow(timer.clone()));
+        let pool = Rc::new(Pool::default());
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let record = |name| {
+            let log = log.clone();
+            async move { log.borrow_mut().push(name) }
+        };
+
+        // A call which never completes keeps the drain phase from ending.
+        let mut stuck = pin!(svc.call(u64::MAX));
+        assert!(poll_once(stuck.as_mut()).is_pending());
+        let coordinator = ShutdownCoordinator::new(timer.clone(), Duration::from_millis(10))
+            .close(pool.clone())
+            .step(ShutdownPhase::Close, record("close"))
+            .drain(&handle)
+            .step(ShutdownPhase::Drain, record("drain"))
+            .stop_accepting(&handle)
+            .step(ShutdownPhase::StopAccepting, record("stop"));
+        let mut run = pin!(coordinator.run());
+        assert!(poll_once(run.as_mut()).is_pending());
+        assert!(handle.is_stopped());
+        assert_eq!(*log.borrow(), ["stop"]);
+        assert_eq!(pool.closed.get(), 0);
+
+        timer.advance(Duration::from_millis(10));
+        let Poll::Ready(report) = poll_once(run.as_mut()) else {
+            panic!("shutdown did not complete");
+        };
+        assert_eq!(
+            report,
+            ShutdownReport {
+                timed_out: vec![ShutdownPhase::Drain]
+            }
+        );
+        // The drain step was abandoned with the rest of its phase.
+        assert_eq!(*log.borrow(), ["stop", "close"]);
+        assert_eq!(pool.closed.get(), 1);
+    }
+}
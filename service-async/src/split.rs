@@ -0,0 +1,291 @@
+use std::{hash::Hash, marker::PhantomData};
+
+use crate::{
+    layer::{layer_fn, FactoryLayer, Layered},
+    random,
+    shutdown::Shutdown,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// The share of requests sent to the `B` arm of a [`Split`], from `0.0` to `1.0`.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct SplitRatio(pub f64);
+
+const BUCKETS: u64 = 10_000;
+
+impl SplitRatio {
+    fn buckets(self) -> u64 {
+        (self.0.clamp(0.0, 1.0) * BUCKETS as f64).round() as u64
+    }
+}
+
+/// Picks the bucket of a request. Requests in the lower `ratio` share of
+/// buckets go to the `B` arm.
+pub trait SplitStrategy<R> {
+    fn bucket(&self, req: &R) -> u64;
+}
+
+/// Split requests randomly.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomSplit;
+
+impl<R> SplitStrategy<R> for RandomSplit {
+    #[inline]
+    fn bucket(&self, _req: &R) -> u64 {
+        random::next_u64()
+    }
+}
+
+/// Split requests by the seeded hash of a key taken from the request through
+/// `Param<K>`, so the same key always goes to the same arm for a given ratio.
+#[derive(Debug)]
+pub struct HashSplit<K> {
+    seed: u64,
+    _marker: PhantomData<fn() -> K>,
+}
+
+impl<K> HashSplit<K> {
+    #[inline]
+    pub const fn new(seed: u64) -> Self {
+        HashSplit {
+            seed,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K> Clone for HashSplit<K> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for HashSplit<K> {}
+
+impl<R, K> SplitStrategy<R> for HashSplit<K>
+where
+    R: Param<K>,
+    K: Hash,
+{
+    #[inline]
+    fn bucket(&self, req: &R) -> u64 {
+        random::seeded_hash(self.seed, &req.param())
+    }
+}
+
+/// A service which sends a share of requests to `b` and the rest to `a`.
+///
+/// The share comes from `Param<SplitRatio>` and is updated on reload, while
+/// both arms keep their state through `make_via_ref`. Useful for canary
+/// rollouts of a new chain.
+pub struct Split<A, B, S = RandomSplit> {
+    a: A,
+    b: B,
+    strategy: S,
+    b_buckets: u64,
+}
+
+impl<A, B, S> Split<A, B, S> {
+    /// The `A` arm.
+    #[inline]
+    pub fn a(&self) -> &A {
+        &self.a
+    }
+
+    /// The `B` arm.
+    #[inline]
+    pub fn b(&self) -> &B {
+        &self.b
+    }
+
+    /// The share of requests sent to the `B` arm.
+    #[inline]
+    pub fn ratio(&self) -> SplitRatio {
+        SplitRatio(self.b_buckets as f64 / BUCKETS as f64)
+    }
+
+    /// Wrap the inner factory as the `A` arm, with the given factory as the `B` arm.
+    pub fn layer<C>(b: B, strategy: S) -> impl FactoryLayer<C, A, Factory = SplitFactory<A, B, S>>
+    where
+        C: Param<SplitRatio>,
+        B: Clone,
+        S: Clone,
+    {
+        layer_fn(move |c: &C, a| SplitFactory {
+            a,
+            b: b.clone(),
+            strategy: strategy.clone(),
+            ratio: c.param(),
+        })
+    }
+}
+
+impl<A, B, S, R> Service<R> for Split<A, B, S>
+where
+    A: Service<R>,
+    B: Service<R, Response = A::Response, Error = A::Error>,
+    S: SplitStrategy<R>,
+{
+    type Response = A::Response;
+    type Error = A::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        if self.strategy.bucket(&req) % BUCKETS < self.b_buckets {
+            self.b.call(req).await
+        } else {
+            self.a.call(req).await
+        }
+    }
+}
+
+/// Factory of [`Split`].
+pub struct SplitFactory<FA, FB, S> {
+    a: FA,
+    b: FB,
+    strategy: S,
+    ratio: SplitRatio,
+}
+
+impl<FA, FB, S> MakeService for SplitFactory<FA, FB, S>
+where
+    FA: MakeService,
+    FB: MakeService<Error = FA::Error>,
+    S: Clone,
+{
+    type Service = Split<FA::Service, FB::Service, S>;
+    type Error = FA::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Split {
+            a: self.a.make_via_ref(old.map(|o| &o.a))?,
+            b: self.b.make_via_ref(old.map(|o| &o.b))?,
+            strategy: self.strategy.clone(),
+            b_buckets: self.ratio.buckets(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.a.validate()?;
+        self.b.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("ratio", self.ratio.0)
+            .with_inner(self.a.service_metadata())
+            .with_inner(self.b.service_metadata())
+    }
+}
+
+impl<FA, FB, S> AsyncMakeService for SplitFactory<FA, FB, S>
+where
+    FA: AsyncMakeService,
+    FB: AsyncMakeService<Error = FA::Error>,
+    S: Clone,
+{
+    type Service = Split<FA::Service, FB::Service, S>;
+    type Error = FA::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Split {
+            a: self.a.make_via_ref(old.map(|o| &o.a)).await?,
+            b: self.b.make_via_ref(old.map(|o| &o.b)).await?,
+            strategy: self.strategy.clone(),
+            b_buckets: self.ratio.buckets(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.a.validate()?;
+        self.b.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("ratio", self.ratio.0)
+            .with_inner(self.a.service_metadata())
+            .with_inner(self.b.service_metadata())
+    }
+}
+
+impl<A, B, S> Layered for Split<A, B, S> {
+    type Inner = A;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.a
+    }
+}
+
+impl<A: Shutdown, B: Shutdown, S> Shutdown for Split<A, B, S> {
+    async fn shutdown(&self) {
+        self.a.shutdown().await;
+        self.b.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{HashSplit, RandomSplit, Split, SplitRatio, SplitStrategy};
+    use crate::{
+        layer::FactoryLayer, test_util::block_on, utils::CloneFactory, MakeService, Service,
+    };
+
+    #[derive(Clone)]
+    struct Arm(char);
+
+    impl Service<u32> for Arm {
+        type Response = char;
+        type Error = Infallible;
+
+        async fn call(&self, _req: u32) -> Result<char, Infallible> {
+            Ok(self.0)
+        }
+    }
+
+    fn split<S: SplitStrategy<u32> + Clone>(ratio: f64, strategy: S) -> Split<Arm, Arm, S> {
+        Split::layer(CloneFactory::new(Arm('b')), strategy)
+            .layer(&SplitRatio(ratio), CloneFactory::new(Arm('a')))
+            .make()
+            .unwrap()
+    }
+
+    fn count_b<S>(svc: &Split<Arm, Arm, S>) -> usize
+    where
+        S: SplitStrategy<u32>,
+    {
+        (0..1000)
+            .filter(|&req| block_on(svc.call(req)) == Ok('b'))
+            .count()
+    }
+
+    #[test]
+    fn ratio_bounds() {
+        assert_eq!(count_b(&split(0.0, RandomSplit)), 0);
+        assert_eq!(count_b(&split(1.0, RandomSplit)), 1000);
+        assert_eq!(split(2.0, RandomSplit).ratio(), SplitRatio(1.0));
+    }
+
+    #[test]
+    fn random_split_follows_ratio() {
+        let b = count_b(&split(0.3, RandomSplit));
+        assert!((200..400).contains(&b), "{b} of 1000 requests sent to b");
+    }
+
+    #[test]
+    fn hash_split_is_sticky() {
+        let svc = split(0.5, HashSplit::<u32>::new(42));
+        let again = split(0.5, HashSplit::<u32>::new(42));
+        for req in 0..100 {
+            assert_eq!(block_on(svc.call(req)), block_on(again.call(req)));
+        }
+        let b = count_b(&svc);
+        assert!((350..650).contains(&b), "{b} of 1000 requests sent to b");
+    }
+}
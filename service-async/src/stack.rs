@@ -1,10 +1,30 @@
 use std::sync::Arc;
 
-use crate::{AsyncMakeServiceWrapper, BoxedAsyncMakeService};
+#[cfg(not(feature = "boxed-futures"))]
+use crate::{
+    assert_async_service, assert_service,
+    borrow::ScopedBoxServiceFactory,
+    buffer::{Buffer, BufferConfig, BufferFactory},
+    layer::LayerAsync,
+    load::{LoadMetrics, LoadProbe},
+    serve::Spawn,
+    validator::{StackValidator, Validated},
+    warmup::{Warmup, WarmupError},
+    AsyncMakeService, AsyncMakeServiceOf, AsyncMakeServiceWrapper, BoxedAsyncMakeService,
+    MakeServiceOf, ProofOfService,
+};
+
+#[cfg(feature = "alloc-metrics")]
+use crate::alloc_metrics::{trace, AllocReport, AllocationTracker, ThreadAllocations, Traced};
 
 use super::{
-    boxed::BoxServiceFactory, layer::FactoryLayer, ArcMakeService, AsyncMakeService,
-    BoxedMakeService, MakeService, MapTargetService, Service,
+    boxed::BoxServiceFactory,
+    config::ConfigSnapshot,
+    either::{Either, EitherUnified},
+    instrument::InstrumentedStack,
+    layer::{FactoryLayer, LayerBundle, WrapInner},
+    utils::{ArcFactory, CloneFactory},
+    ArcMakeService, BoxedMakeService, MakeService, MapErrInto, MapTargetService, Param, Service,
 };
 /// A powerful abstraction for creating complex service chains by managing a stack of service factories.
 ///
@@ -72,6 +92,36 @@ impl<C> FactoryStack<C, ()> {
     pub const fn new(config: C) -> Self {
         FactoryStack { config, inner: () }
     }
+
+    /// Seed the stack with a service which is cloned on every make.
+    #[inline]
+    pub fn push_clone_leaf<S: Clone>(self, svc: S) -> FactoryStack<C, CloneFactory<S>> {
+        self.replace(CloneFactory::new(svc))
+    }
+
+    /// Seed the stack with a service shared by every make as `Arc<S>`.
+    #[inline]
+    pub fn push_arc_leaf<S>(self, svc: S) -> FactoryStack<C, ArcFactory<S>> {
+        self.replace(ArcFactory::new(svc))
+    }
+}
+
+impl<C> FactoryStack<ConfigSnapshot<C>, ()> {
+    /// Create a stack over a [`ConfigSnapshot`] of `config`, so all layers
+    /// share one copy of it. Use [`FactoryStack::new`] to start from an
+    /// existing snapshot.
+    #[inline]
+    pub fn new_shared(config: C) -> Self {
+        FactoryStack::new(ConfigSnapshot::new(config))
+    }
+
+    /// Create a stack over a config which is already shared, e.g. a large
+    /// config which is not `Clone`. Layers can keep a handle to it with
+    /// [`ConfigSnapshot::share`] instead of copying values out.
+    #[inline]
+    pub fn new_arc(config: Arc<C>) -> Self {
+        FactoryStack::new(ConfigSnapshot::from_arc(config))
+    }
 }
 
 impl<C, F> FactoryStack<C, F> {
@@ -97,7 +147,25 @@ impl<C, F> FactoryStack<C, F> {
         }
     }
 
+    /// Push a layer whose factory is an [`Either`], e.g. an optional layer,
+    /// converting the make errors of both sides into `E`.
+    ///
+    /// The error type of the stack then does not depend on which side the
+    /// layer picked. See [`EitherUnified`].
+    #[inline]
+    pub fn push_unified<E, L, A, B>(self, layer: L) -> FactoryStack<C, EitherUnified<A, B, E>>
+    where
+        L: FactoryLayer<C, F, Factory = Either<A, B>>,
+    {
+        let inner = layer.layer(&self.config, self.inner).unified();
+        FactoryStack {
+            config: self.config,
+            inner,
+        }
+    }
+
     /// Convert the factory to an async factory.
+    #[cfg(not(feature = "boxed-futures"))]
     #[inline]
     pub fn into_async(self) -> FactoryStack<C, AsyncMakeServiceWrapper<F>> {
         let inner = AsyncMakeServiceWrapper(self.inner);
@@ -107,6 +175,125 @@ impl<C, F> FactoryStack<C, F> {
         }
     }
 
+    /// Push all layers of a [`LayerBundle`], against its config slice.
+    #[inline]
+    pub fn push_bundle<B>(self, bundle: B) -> FactoryStack<C, B::Factory>
+    where
+        B: LayerBundle<F>,
+        C: Param<B::Config>,
+    {
+        let inner = bundle.bundle(&self.config.param(), self.inner);
+        FactoryStack {
+            config: self.config,
+            inner,
+        }
+    }
+
+    /// Push a [`LayerAsync`], the boundary between the sync factories below and
+    /// the async ones pushed above.
+    ///
+    /// It is the same as [`into_async`](FactoryStack::into_async), spelled as a layer.
+    #[cfg(not(feature = "boxed-futures"))]
+    #[inline]
+    pub fn async_boundary(self) -> FactoryStack<C, AsyncMakeServiceWrapper<F>> {
+        self.push(LayerAsync)
+    }
+
+    /// Push a [`LayerAsync`] which transforms the sync factory before wrapping it.
+    /// See [`LayerAsync::with_transform`].
+    #[cfg(not(feature = "boxed-futures"))]
+    #[inline]
+    pub fn async_boundary_with<FN, O>(
+        self,
+        transform: FN,
+    ) -> FactoryStack<C, AsyncMakeServiceWrapper<O>>
+    where
+        FN: Fn(&C, F) -> O,
+    {
+        self.push(LayerAsync::with_transform(transform))
+    }
+
+    /// Push a [`LoadProbe`] with new [`LoadMetrics`], returned alongside the
+    /// stack so they can be sampled. The clock `CL` is taken from the config
+    /// through `Param<CL>`.
+    #[cfg(not(feature = "boxed-futures"))]
+    pub fn push_load_probe<CL>(self) -> (FactoryStack<C, LoadProbe<F, CL>>, LoadMetrics)
+    where
+        C: Param<CL>,
+    {
+        let metrics = LoadMetrics::new();
+        (self.push(LoadProbe::layer(metrics.clone())), metrics)
+    }
+
+    /// Host the service of the stack in a worker task spawned with `spawner`,
+    /// and make clones of a [`Buffer`] handle to it.
+    ///
+    /// This hosts a service called through `&mut` or holding `!Sync` state
+    /// wherever a shareable one is expected. The queue capacity is taken from
+    /// the config through `Param<BufferConfig>`. On reload the new service is
+    /// handed to a new worker, and the old one drains; see [`BufferFactory`].
+    #[cfg(not(feature = "boxed-futures"))]
+    pub fn push_buffered_clone<SP>(self, spawner: SP) -> FactoryStack<C, BufferFactory<F, SP>>
+    where
+        C: Param<BufferConfig>,
+        SP: Spawn + Clone,
+    {
+        self.push(Buffer::layer(spawner))
+    }
+
+    /// Box the services made by the stack for requests of a [`RequestFamily`](crate::borrow::RequestFamily),
+    /// which need not be `'static`.
+    #[cfg(not(feature = "boxed-futures"))]
+    pub fn into_scoped_boxed_service<Fam, Resp, E>(
+        self,
+    ) -> FactoryStack<C, ScopedBoxServiceFactory<F, Fam, Resp, E>> {
+        FactoryStack {
+            config: self.config,
+            inner: ScopedBoxServiceFactory::new(self.inner),
+        }
+    }
+
+    /// Wrap the stack so the order of its layers is checked against
+    /// `validator` on `validate` and `make`.
+    #[cfg(not(feature = "boxed-futures"))]
+    pub fn validated(self, validator: StackValidator) -> FactoryStack<C, Validated<F>> {
+        FactoryStack {
+            config: self.config,
+            inner: validator.wrap(self.inner),
+        }
+    }
+
+    /// Transform the inner factory of the stack, e.g. to wrap it in a type
+    /// which is not a layer.
+    #[inline]
+    pub fn map_inner<NF>(self, f: impl FnOnce(F) -> NF) -> FactoryStack<C, NF> {
+        FactoryStack {
+            config: self.config,
+            inner: f(self.inner),
+        }
+    }
+
+    /// Transform the factory wrapped by the outermost layer, keeping the layer.
+    ///
+    /// Nest calls of [`WrapInner::map_inner`] in `f` to reach deeper, e.g. to
+    /// replace the leaf factory of a composed stack.
+    #[inline]
+    pub fn map_wrapped<NF>(self, f: impl FnOnce(F::Inner) -> NF) -> FactoryStack<C, F::Wrapped<NF>>
+    where
+        F: WrapInner,
+    {
+        self.map_inner(|inner| inner.map_inner(f))
+    }
+
+    /// Remove the outermost layer of the stack.
+    #[inline]
+    pub fn take_layer(self) -> FactoryStack<C, F::Inner>
+    where
+        F: WrapInner,
+    {
+        self.map_inner(WrapInner::into_inner_factory)
+    }
+
     /// Push a new factory of service to map the request type.
     #[inline]
     pub fn push_map_target<M: Clone>(self, f: M) -> FactoryStack<C, MapTargetService<F, M>> {
@@ -119,6 +306,18 @@ impl<C, F> FactoryStack<C, F> {
         }
     }
 
+    /// Push a new factory of service to convert the error into `E`.
+    ///
+    /// Call it before [`into_boxed_service`](Self::into_boxed_service) so
+    /// stacks whose errors differ box into the same service type.
+    #[inline]
+    pub fn push_map_err_into<E>(self) -> FactoryStack<C, MapErrInto<F, E>> {
+        FactoryStack {
+            config: self.config,
+            inner: MapErrInto::new(self.inner),
+        }
+    }
+
     /// Convert the factory to factory of [`BoxedService`](crate::BoxedService).
     /// Works for MakeService and AsyncMakeService.
     #[inline]
@@ -156,6 +355,7 @@ impl<C, F> FactoryStack<C, F> {
 
     /// Convert the factory to a fixed type factory(Box dyn).
     /// Only works for AsyncMakeService.
+    #[cfg(not(feature = "boxed-futures"))]
     #[inline]
     pub fn into_async_boxed_factory(
         self,
@@ -196,6 +396,7 @@ impl<C, F> FactoryStack<C, F> {
 
     /// Convert the factory to a fixed type factory(Arc Box dyn).
     /// Only works for AsyncMakeService.
+    #[cfg(not(feature = "boxed-futures"))]
     #[allow(clippy::type_complexity)]
     #[inline]
     pub fn into_async_arc_factory(
@@ -233,6 +434,7 @@ impl<C, F> FactoryStack<C, F> {
     }
 
     /// Check if the stack is an async factory of `Service<R>`.
+    #[cfg(not(feature = "boxed-futures"))]
     #[inline]
     pub fn check_async_make_svc<R>(self) -> Self
     where
@@ -242,6 +444,53 @@ impl<C, F> FactoryStack<C, F> {
         self
     }
 
+    /// Take the factory of the stack, checked to make a `Service<R>`.
+    ///
+    /// Unlike [`check_make_svc`](Self::check_make_svc), the result carries the
+    /// check into generic code; see [`MakeServiceOf`](crate::MakeServiceOf).
+    #[cfg(not(feature = "boxed-futures"))]
+    #[inline]
+    pub fn assert_service<R>(self) -> ProofOfService<F, R>
+    where
+        F: MakeServiceOf<R>,
+    {
+        assert_service(self.inner)
+    }
+
+    /// Take the async factory of the stack, checked to make a `Service<R>`.
+    #[cfg(not(feature = "boxed-futures"))]
+    #[inline]
+    pub fn assert_async_service<R>(self) -> ProofOfService<F, R>
+    where
+        F: AsyncMakeServiceOf<R>,
+    {
+        assert_async_service(self.inner)
+    }
+
+    /// Time the makes of each layer pushed from now on, and report them to
+    /// `observer`, to find the layers which slow down reloads.
+    #[inline]
+    pub fn instrument_makes<O: Clone>(self, observer: O) -> InstrumentedStack<C, F, O> {
+        InstrumentedStack::new(self, observer)
+    }
+
+    /// Wrap the current factory to record the allocations it makes, so they
+    /// show up as a layer in [`make_traced`](Self::make_traced).
+    #[cfg(feature = "alloc-metrics")]
+    #[inline]
+    pub fn traced(self) -> FactoryStack<C, Traced<F>> {
+        FactoryStack {
+            config: self.config,
+            inner: Traced::new(self.inner),
+        }
+    }
+
+    /// Get the config the layers are built with.
+    #[inline]
+    pub fn config(&self) -> &C {
+        &self.config
+    }
+
     /// Get the inner factory.
     #[inline]
     pub fn into_inner(self) -> F {
@@ -264,8 +513,56 @@ where
     pub fn make(&self) -> Result<F::Service, F::Error> {
         self.inner.make()
     }
+
+    /// Make a service in an `Arc`, to be shared by workers.
+    #[inline]
+    pub fn make_shared(&self) -> Result<Arc<F::Service>, F::Error> {
+        self.inner.make_shared()
+    }
+
+    /// Validate the config of every layer without making a service.
+    ///
+    /// Layers provided by this crate forward the check to the factories they wrap.
+    #[inline]
+    pub fn validate_all(&self) -> Result<(), F::Error> {
+        self.inner.validate()
+    }
+
+    /// Make a service and report the allocations made, in total and per
+    /// factory wrapped with [`traced`](Self::traced).
+    ///
+    /// Counts come from [`TrackingAllocator`](crate::alloc_metrics::TrackingAllocator),
+    /// which must be the global allocator.
+    #[cfg(feature = "alloc-metrics")]
+    #[inline]
+    pub fn make_traced(&self) -> (Result<F::Service, F::Error>, AllocReport) {
+        self.make_traced_with(None, ThreadAllocations)
+    }
+
+    /// Like [`make_traced`](Self::make_traced), making the service against an
+    /// old one as a reload does.
+    #[cfg(feature = "alloc-metrics")]
+    #[inline]
+    pub fn make_via_ref_traced(
+        &self,
+        old: Option<&F::Service>,
+    ) -> (Result<F::Service, F::Error>, AllocReport) {
+        self.make_traced_with(old, ThreadAllocations)
+    }
+
+    /// Like [`make_via_ref_traced`](Self::make_via_ref_traced), with counts
+    /// from another tracker.
+    #[cfg(feature = "alloc-metrics")]
+    pub fn make_traced_with(
+        &self,
+        old: Option<&F::Service>,
+        tracker: impl AllocationTracker + 'static,
+    ) -> (Result<F::Service, F::Error>, AllocReport) {
+        trace(std::rc::Rc::new(tracker), || self.inner.make_via_ref(old))
+    }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
 impl<C, F> FactoryStack<C, F>
 where
     F: AsyncMakeService,
@@ -275,4 +572,267 @@ where
     pub async fn make_async(&self) -> Result<F::Service, F::Error> {
         self.inner.make().await
     }
+
+    /// Make a service in an `Arc` in async, to be shared by workers.
+    #[inline]
+    pub async fn make_shared_async(&self) -> Result<Arc<F::Service>, F::Error> {
+        self.inner.make_shared().await
+    }
+
+    /// Validate the config of every layer of an async stack without making a service.
+    #[inline]
+    pub fn validate_all_async(&self) -> Result<(), F::Error> {
+        AsyncMakeService::validate(&self.inner)
+    }
+
+    /// Make a service in async and [warm it up](Warmup), so it is ready for
+    /// requests once returned.
+    pub async fn make_warmed_async(
+        &self,
+    ) -> Result<F::Service, WarmupError<F::Error, <F::Service as Warmup>::Error>>
+    where
+        F::Service: Warmup,
+    {
+        self.make_warmed_via_ref_async(None).await
+    }
+
+    /// Make a service in async from the old one and warm it up, before it
+    /// replaces the old one.
+    pub async fn make_warmed_via_ref_async(
+        &self,
+        old: Option<&F::Service>,
+    ) -> Result<F::Service, WarmupError<F::Error, <F::Service as Warmup>::Error>>
+    where
+        F::Service: Warmup,
+    {
+        let svc = self
+            .inner
+            .make_via_ref(old)
+            .await
+            .map_err(WarmupError::Make)?;
+        svc.warmup().await.map_err(WarmupError::Warmup)?;
+        Ok(svc)
+    }
+}
+
+/// Build a [`FactoryStack`] from a config and a list of layers, innermost
+/// first.
+///
+/// `stack!(config => a, b, c)` expands to
+/// `FactoryStack::new(config).push(a).push(b).push(c)`. A layer written as
+/// `when cond => layer` is only pushed when `cond` holds, as an `Option` layer,
+/// so the stack type is the same either way. Any other layer is taken as an
+/// expression, including one starting with `if`.
+///
+/// ```
+/// use std::convert::Infallible;
+///
+/// use service_async::{
+///     layer::layer_fn,
+///     stack,
+///     utils::CloneFactory,
+///     yielding::{YieldBudget, YieldEvery},
+///     MakeService, Service,
+/// };
+///
+/// #[derive(Clone)]
+/// struct Echo;
+///
+/// impl Service<u32> for Echo {
+///     type Response = u32;
+///     type Error = Infallible;
+///
+///     async fn call(&self, req: u32) -> Result<u32, Infallible> {
+///         Ok(req)
+///     }
+/// }
+///
+/// let cooperative = true;
+/// let stack = stack!(YieldBudget(16) =>
+///     layer_fn(|_: &YieldBudget, ()| CloneFactory::from(Echo)),
+///     when cooperative => YieldEvery::layer(),
+/// );
+/// let svc = stack.make().unwrap();
+/// ```
+#[macro_export]
+macro_rules! stack {
+    ($config:expr => $($layers:tt)*) => {
+        $crate::__stack_push!($crate::stack::FactoryStack::new($config); $($layers)*)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __stack_push {
+    ($stack:expr;) => {
+        $stack
+    };
+    ($stack:expr; when $cond:expr => $layer:expr $(, $($rest:tt)*)?) => {
+        $crate::__stack_push!(
+            $stack.push(if $cond {
+                ::std::option::Option::Some($layer)
+            } else {
+                ::std::option::Option::None
+            });
+            $($($rest)*)?
+        )
+    };
+    ($stack:expr; $layer:expr $(, $($rest:tt)*)?) => {
+        $crate::__stack_push!($stack.push($layer); $($($rest)*)?)
+    };
+}
+
+#[cfg(all(test, not(feature = "boxed-futures")))]
+mod tests {
+    use std::{convert::Infallible, sync::Arc};
+
+    use super::FactoryStack;
+    use crate::{
+        fallback::{AlwaysFallback, Fallback},
+        layer::{layer_fn, WrapInner},
+        test_util::block_on,
+        utils::CloneFactory,
+        yielding::{YieldBudget, YieldEvery},
+        MakeService, Service,
+    };
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<u32> for Echo {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<u32, Infallible> {
+            Ok(req)
+        }
+    }
+
+    #[test]
+    fn stack_macro() {
+        let cooperative = false;
+        let svc = stack!(YieldBudget(16) =>
+            layer_fn(|_: &YieldBudget, ()| CloneFactory::from(Echo)),
+            when cooperative => YieldEvery::layer(),
+            // An `if` expression is an ordinary layer.
+            if cooperative { None } else { Some(YieldEvery::layer()) },
+        )
+        .make()
+        .unwrap();
+        assert_eq!(block_on(svc.call(7)), Ok(7));
+    }
+
+    #[test]
+    fn leaf_factories() {
+        let svc = FactoryStack::new(()).push_clone_leaf(Echo).make().unwrap();
+        assert_eq!(block_on(svc.call(3)), Ok(3));
+
+        let factory = FactoryStack::new(()).push_arc_leaf(Echo).into_inner();
+        let first = factory.make().unwrap();
+        let second = factory.make_via_ref(Some(&first)).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(block_on(second.call(4)), Ok(4));
+    }
+
+    /// Makes [`Echo`], with a config which may be invalid.
+    #[derive(Clone)]
+    struct Checked(bool);
+
+    impl MakeService for Checked {
+        type Service = Echo;
+        type Error = &'static str;
+
+        fn make_via_ref(&self, _old: Option<&Echo>) -> Result<Echo, &'static str> {
+            Ok(Echo)
+        }
+
+        fn validate(&self) -> Result<(), &'static str> {
+            self.0.then_some(()).ok_or("invalid config")
+        }
+    }
+
+    #[test]
+    fn validate_all_reaches_every_factory() {
+        let stack = |leaf, fallback| {
+            FactoryStack::new(())
+                .replace(Checked(leaf))
+                .push(Fallback::layer(Checked(fallback), AlwaysFallback))
+                .push_map_target(|req: u32| req + 1)
+        };
+        assert_eq!(stack(true, true).validate_all(), Ok(()));
+        assert_eq!(stack(false, true).validate_all(), Err("invalid config"));
+        assert_eq!(stack(true, false).validate_all(), Err("invalid config"));
+        // Making does not validate.
+        assert!(stack(false, false).make().is_ok());
+    }
+
+    #[test]
+    fn replace_the_leaf_under_layers() {
+        let stack = FactoryStack::new(())
+            .replace(Checked(false))
+            .push_map_target(|req: u32| req + 1)
+            .push_map_target(|req: u32| req * 10);
+        assert!(stack.validate_all().is_err());
+
+        let stack = stack.map_wrapped(|outer| outer.map_inner(|_| Checked(true)));
+        assert_eq!(stack.validate_all(), Ok(()));
+        let svc = stack.make().unwrap();
+        assert_eq!(block_on(svc.call(1)), Ok(11));
+
+        let stack = stack.take_layer();
+        assert_eq!(block_on(stack.make().unwrap().call(1)), Ok(2));
+        assert!(stack.take_layer().into_inner().0);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Unified {
+        Rejected(&'static str),
+    }
+
+    impl From<Infallible> for Unified {
+        fn from(e: Infallible) -> Self {
+            match e {}
+        }
+    }
+
+    impl From<&'static str> for Unified {
+        fn from(e: &'static str) -> Self {
+            Unified::Rejected(e)
+        }
+    }
+
+    #[derive(Clone)]
+    struct Reject;
+
+    impl Service<u32> for Reject {
+        type Response = u32;
+        type Error = &'static str;
+
+        async fn call(&self, _req: u32) -> Result<u32, &'static str> {
+            Err("rejected")
+        }
+    }
+
+    #[test]
+    fn map_err_into_before_boxing() {
+        let echo = FactoryStack::new(())
+            .push_clone_leaf(Echo)
+            .push_map_err_into::<Unified>()
+            .into_boxed_service()
+            .make()
+            .unwrap();
+        let reject = FactoryStack::new(())
+            .push_clone_leaf(Reject)
+            .push_map_err_into::<Unified>()
+            .into_boxed_service()
+            .make()
+            .unwrap();
+
+        let svcs = [echo, reject];
+        assert_eq!(block_on(svcs[0].call(1)), Ok(1));
+        assert_eq!(
+            block_on(svcs[1].call(1)),
+            Err(Unified::Rejected("rejected"))
+        );
+    }
 }
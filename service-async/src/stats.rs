@@ -0,0 +1,465 @@
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use param::{ParamMaybeRef, ParamSet};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    time::{Clock, SystemClock},
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// A timed section of a request, recorded by a [`SpanGuard`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub name: &'static str,
+    /// Number of spans this one is nested in.
+    pub depth: usize,
+    /// Start of the span, relative to the start of the request.
+    pub offset: Duration,
+    pub elapsed: Duration,
+}
+
+/// Statistics of one request, returned in [`WithStats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Spans ordered by start, so parents come before their children.
+    pub spans: Vec<Span>,
+    pub annotations: Vec<(&'static str, String)>,
+    pub total: Duration,
+}
+
+impl Display for Stats {
+    /// Print the spans as an indented breakdown, followed by the annotations.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "total {:?}", self.total)?;
+        for span in &self.spans {
+            let indent = 2 * (span.depth + 1);
+            writeln!(
+                f,
+                "{:indent$}{} +{:?} {:?}",
+                "", span.name, span.offset, span.elapsed
+            )?;
+        }
+        for (key, value) in &self.annotations {
+            writeln!(f, "{key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the statistics of one request.
+///
+/// [`CollectStats`] puts a recorder in the request context; layers below it
+/// take it with `ParamMaybeRef<StatsRecorder>` and record spans and
+/// annotations. Clones record into the same request.
+#[derive(Clone)]
+pub struct StatsRecorder {
+    inner: Arc<Mutex<Recording>>,
+    clock: Arc<dyn Clock + Send + Sync>,
+}
+
+impl std::fmt::Debug for StatsRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatsRecorder")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct Recording {
+    start: Instant,
+    depth: usize,
+    spans: Vec<Span>,
+    annotations: Vec<(&'static str, String)>,
+}
+
+impl Default for StatsRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsRecorder {
+    /// A recorder reading the time from [`SystemClock`].
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+
+    /// A recorder reading the time from `clock`.
+    pub fn with_clock(clock: impl Clock + Send + Sync + 'static) -> Self {
+        StatsRecorder {
+            inner: Arc::new(Mutex::new(Recording {
+                start: clock.now(),
+                depth: 0,
+                spans: Vec::new(),
+                annotations: Vec::new(),
+            })),
+            clock: Arc::new(clock),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Recording> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start a span, recorded when the guard is dropped.
+    ///
+    /// Spans started while it is alive are nested in it. Nesting assumes spans
+    /// of one request end in reverse order, which holds unless the request
+    /// fans out concurrently.
+    pub fn span(&self, name: &'static str) -> SpanGuard {
+        let depth = {
+            let mut rec = self.lock();
+            rec.depth += 1;
+            rec.depth - 1
+        };
+        SpanGuard {
+            recorder: self.clone(),
+            name,
+            depth,
+            start: self.clock.now(),
+        }
+    }
+
+    /// Attach a key-value annotation to the request.
+    pub fn annotate(&self, key: &'static str, value: impl Into<String>) {
+        self.lock().annotations.push((key, value.into()));
+    }
+
+    /// Take the statistics recorded so far.
+    pub fn finish(&self) -> Stats {
+        let mut rec = self.lock();
+        let mut spans = std::mem::take(&mut rec.spans);
+        spans.sort_by_key(|s| (s.offset, s.depth));
+        Stats {
+            spans,
+            annotations: std::mem::take(&mut rec.annotations),
+            total: self.clock.now().saturating_duration_since(rec.start),
+        }
+    }
+}
+
+/// Records a [`Span`] when dropped. Created by [`StatsRecorder::span`].
+#[derive(Debug)]
+pub struct SpanGuard {
+    recorder: StatsRecorder,
+    name: &'static str,
+    depth: usize,
+    start: Instant,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        let elapsed = self
+            .recorder
+            .clock
+            .now()
+            .saturating_duration_since(self.start);
+        let mut rec = self.recorder.lock();
+        rec.depth = rec.depth.saturating_sub(1);
+        let offset = self.start.saturating_duration_since(rec.start);
+        rec.spans.push(Span {
+            name: self.name,
+            depth: self.depth,
+            offset,
+            elapsed,
+        });
+    }
+}
+
+/// A response with the statistics of its request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithStats<T> {
+    pub response: T,
+    pub stats: Stats,
+}
+
+impl<T> WithStats<T> {
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.response
+    }
+
+    #[inline]
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> WithStats<U> {
+        WithStats {
+            response: f(self.response),
+            stats: self.stats,
+        }
+    }
+}
+
+/// A middleware which collects per-request statistics and returns them with
+/// the response in [`WithStats`].
+///
+/// It handles `(R, CX)` requests and sets a fresh [`StatsRecorder`] in `CX`.
+/// Layers below record into it, e.g. with [`Timed`]. The statistics of failed
+/// requests are dropped. The recorders read the time from the clock `CL`,
+/// taken from the stack config through `Param<CL>`.
+pub struct CollectStats<T, CL> {
+    clock: CL,
+    inner: T,
+}
+
+impl<T, CL> CollectStats<T, CL> {
+    pub fn layer<C: Param<CL>>() -> impl FactoryLayer<C, T, Factory = Self> {
+        layer_fn(|c: &C, inner| CollectStats {
+            clock: c.param(),
+            inner,
+        })
+    }
+}
+
+impl<T, CL, R, CX> Service<(R, CX)> for CollectStats<T, CL>
+where
+    CX: ParamSet<StatsRecorder>,
+    T: Service<(R, CX::Transformed)>,
+    CL: Clock + Clone + Send + Sync + 'static,
+{
+    type Response = WithStats<T::Response>;
+    type Error = T::Error;
+
+    async fn call(&self, (req, cx): (R, CX)) -> Result<Self::Response, Self::Error> {
+        let recorder = StatsRecorder::with_clock(self.clock.clone());
+        let response = self
+            .inner
+            .call((req, cx.param_set(recorder.clone())))
+            .await?;
+        Ok(WithStats {
+            response,
+            stats: recorder.finish(),
+        })
+    }
+}
+
+impl<F: MakeService, CL: Clone> MakeService for CollectStats<F, CL> {
+    type Service = CollectStats<F::Service, CL>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(CollectStats {
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, CL: Clone> AsyncMakeService for CollectStats<F, CL> {
+    type Service = CollectStats<F::Service, CL>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(CollectStats {
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(CollectStats<_, CL> { clock });
+
+impl<T, CL> Layered for CollectStats<T, CL> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(CollectStats<T, CL>);
+
+/// A middleware which records the inner call as a [`Span`] named `name`.
+///
+/// Push it above a layer to time that layer and everything below it. Without
+/// a [`StatsRecorder`] in the request context it does nothing.
+pub struct Timed<T> {
+    name: &'static str,
+    inner: T,
+}
+
+impl<T> Timed<T> {
+    pub fn layer<C>(name: &'static str) -> impl FactoryLayer<C, T, Factory = Timed<T>> {
+        layer_fn(move |_: &C, inner| Timed { name, inner })
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T, R, CX> Service<(R, CX)> for Timed<T>
+where
+    CX: ParamMaybeRef<StatsRecorder>,
+    T: Service<(R, CX)>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, (req, cx): (R, CX)) -> Result<Self::Response, Self::Error> {
+        let _span = cx.param_maybe_ref().map(|r| r.span(self.name));
+        self.inner.call((req, cx)).await
+    }
+}
+
+impl<F: MakeService> MakeService for Timed<F> {
+    type Service = Timed<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Timed {
+            name: self.name,
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("name", self.name)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for Timed<F> {
+    type Service = Timed<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Timed {
+            name: self.name,
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("name", self.name)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(Timed<_> { name });
+
+impl<T> Layered for Timed<T> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Timed<T>);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use param::context_map;
+
+    use super::{CollectStats, Span, StatsRecorder, Timed};
+    use crate::{
+        layer::FactoryLayer, test_util::block_on, time::MockTimer, ParamMaybeRef, Service,
+    };
+
+    context_map! {
+        struct Context {
+            stats: StatsRecorder,
+        }
+    }
+
+    /// Takes 2ms of mock time, then times a 3ms section of its own.
+    struct Work(MockTimer);
+
+    impl<CX: ParamMaybeRef<StatsRecorder>> Service<(u32, CX)> for Work {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, (req, cx): (u32, CX)) -> Result<u32, Infallible> {
+            self.0.advance(Duration::from_millis(2));
+            let recorder = cx.param_maybe_ref().unwrap();
+            let _span = recorder.span("db");
+            recorder.annotate("rows", req.to_string());
+            self.0.advance(Duration::from_millis(3));
+            Ok(req)
+        }
+    }
+
+    #[test]
+    fn collects_nested_spans() {
+        let timer = MockTimer::new();
+        let svc = Timed::layer("handler").layer(&(), Work(timer.clone()));
+        let svc = CollectStats::layer().layer(&timer, svc);
+
+        let resp = block_on(svc.call((7, Context::new()))).unwrap();
+        assert_eq!(resp.response, 7);
+        let span = |name, depth, offset, elapsed| Span {
+            name,
+            depth,
+            offset: Duration::from_millis(offset),
+            elapsed: Duration::from_millis(elapsed),
+        };
+        assert_eq!(
+            resp.stats.spans,
+            [span("handler", 0, 0, 5), span("db", 1, 2, 3)]
+        );
+        assert_eq!(
+            resp.stats.to_string(),
+            "total 5ms\n  handler +0ns 5ms\n    db +2ms 3ms\nrows=7\n"
+        );
+    }
+
+    #[test]
+    fn timed_without_recorder() {
+        struct Plain;
+
+        impl<CX> Service<(u32, CX)> for Plain {
+            type Response = u32;
+            type Error = Infallible;
+
+            async fn call(&self, (req, _): (u32, CX)) -> Result<u32, Infallible> {
+                Ok(req)
+            }
+        }
+
+        let svc = Timed::layer("handler").layer(&(), Plain);
+        assert_eq!(block_on(svc.call((1, Context::new()))), Ok(1));
+    }
+}
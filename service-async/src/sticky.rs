@@ -0,0 +1,443 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    marker::PhantomData,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    balance::{BalanceError, ConsistentHash, WeightedBalance},
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    time::Clock,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// A service over backends identified by a key.
+///
+/// The key type does not depend on the request type, so [`StickyFactory`] can
+/// name the service it makes.
+pub trait Keyed {
+    type Key;
+}
+
+impl<K, S> Keyed for WeightedBalance<K, S> {
+    type Key = K;
+}
+
+impl<K, S, Q> Keyed for ConsistentHash<K, S, Q> {
+    type Key = K;
+}
+
+/// A service over keyed backends, whose backends [`Sticky`] can pin sessions
+/// to.
+///
+/// It is implemented for the balancers of the [`balance`](crate::balance)
+/// module.
+pub trait StickyBackends<R>: Keyed {
+    type Backend: Service<R>;
+
+    /// Get a backend by key, if it still exists.
+    fn backend(&self, key: &Self::Key) -> Option<&Self::Backend>;
+
+    /// Pick a backend for a request, as the balancer would.
+    fn pick(&self, req: &R) -> Option<(&Self::Key, &Self::Backend)>;
+}
+
+impl<K, S, R> StickyBackends<R> for WeightedBalance<K, S>
+where
+    K: PartialEq,
+    S: Service<R>,
+{
+    type Backend = S;
+
+    #[inline]
+    fn backend(&self, key: &K) -> Option<&S> {
+        WeightedBalance::backend(self, key)
+    }
+
+    #[inline]
+    fn pick(&self, _req: &R) -> Option<(&K, &S)> {
+        WeightedBalance::pick(self)
+    }
+}
+
+impl<K, S, Q, R> StickyBackends<R> for ConsistentHash<K, S, Q>
+where
+    K: Hash + PartialEq,
+    Q: Hash,
+    S: Service<R>,
+    R: Param<Q>,
+{
+    type Backend = S;
+
+    fn backend(&self, key: &K) -> Option<&S> {
+        self.backends()
+            .iter()
+            .find_map(|(k, s)| (k == key).then_some(s))
+    }
+
+    #[inline]
+    fn pick(&self, req: &R) -> Option<(&K, &S)> {
+        self.route(&req.param()).map(|(k, s)| (k, s))
+    }
+}
+
+/// Configuration of [`Sticky`], extracted from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StickyConfig {
+    /// Max number of sessions remembered. The least recently used one is
+    /// forgotten to make room.
+    pub capacity: usize,
+    /// Time after its last request a session is forgotten.
+    pub ttl: Duration,
+}
+
+struct Entry<K> {
+    backend: K,
+    last_used: Instant,
+}
+
+struct Table<Q, K> {
+    config: StickyConfig,
+    sessions: HashMap<Q, Entry<K>>,
+}
+
+impl<Q: Hash + Eq + Clone, K: Clone> Table<Q, K> {
+    fn get(&mut self, session: &Q, now: Instant) -> Option<K> {
+        let ttl = self.config.ttl;
+        match self.sessions.get_mut(session) {
+            Some(entry) if now.saturating_duration_since(entry.last_used) < ttl => {
+                entry.last_used = now;
+                Some(entry.backend.clone())
+            }
+            Some(_) => {
+                self.sessions.remove(session);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, session: Q, backend: K, now: Instant) {
+        if !self.sessions.contains_key(&session) && self.sessions.len() >= self.config.capacity {
+            let ttl = self.config.ttl;
+            self.sessions
+                .retain(|_, e| now.saturating_duration_since(e.last_used) < ttl);
+            if self.sessions.len() >= self.config.capacity {
+                let lru = self
+                    .sessions
+                    .iter()
+                    .min_by_key(|(_, e)| e.last_used)
+                    .map(|(q, _)| q.clone());
+                if let Some(lru) = lru {
+                    self.sessions.remove(&lru);
+                }
+            }
+        }
+        if self.config.capacity > 0 {
+            self.sessions.insert(
+                session,
+                Entry {
+                    backend,
+                    last_used: now,
+                },
+            );
+        }
+    }
+}
+
+/// A middleware which routes the requests of a session to the backend which
+/// served its first request, e.g. for servers keeping per-session state.
+///
+/// The session key `Q` is taken from the request through `Param<Option<Q>>`;
+/// requests without one are balanced as usual. The session table is bounded
+/// by [`StickyConfig::capacity`] and forgets idle sessions after
+/// [`StickyConfig::ttl`]. When the backend of a session is gone, e.g. removed
+/// on reload, the session is balanced again and pinned to the new backend.
+///
+/// The table is carried over to the service made on reload, so sessions stay
+/// where they are. The clock `CL` is taken from the stack config through
+/// `Param<CL>`.
+pub struct Sticky<T, Q, K, CL> {
+    table: Arc<Mutex<Table<Q, K>>>,
+    clock: CL,
+    inner: T,
+}
+
+impl<T, Q, K, CL> Sticky<T, Q, K, CL> {
+    fn lock(&self) -> MutexGuard<'_, Table<Q, K>> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Number of sessions remembered, including expired ones not yet removed.
+    pub fn sessions(&self) -> usize {
+        self.lock().sessions.len()
+    }
+}
+
+impl<T, Q, CL, R> Service<R> for Sticky<T, Q, T::Key, CL>
+where
+    T: StickyBackends<R>,
+    T::Key: Clone,
+    R: Param<Option<Q>>,
+    Q: Hash + Eq + Clone,
+    CL: Clock,
+{
+    type Response = <T::Backend as Service<R>>::Response;
+    type Error = BalanceError<<T::Backend as Service<R>>::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let backend = match req.param() {
+            Some(session) => {
+                let now = self.clock.now();
+                let pinned = self.lock().get(&session, now);
+                match pinned.and_then(|key| self.inner.backend(&key)) {
+                    Some(backend) => backend,
+                    None => {
+                        let (key, backend) =
+                            self.inner.pick(&req).ok_or(BalanceError::NoBackend)?;
+                        self.lock().insert(session, key.clone(), now);
+                        backend
+                    }
+                }
+            }
+            None => self.inner.pick(&req).ok_or(BalanceError::NoBackend)?.1,
+        };
+        backend.call(req).await.map_err(BalanceError::Inner)
+    }
+}
+
+/// Factory of [`Sticky`].
+pub struct StickyFactory<F, Q, CL> {
+    config: StickyConfig,
+    clock: CL,
+    inner: F,
+    _marker: PhantomData<fn(Q)>,
+}
+
+impl<F, Q, CL> StickyFactory<F, Q, CL> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self>
+    where
+        C: Param<StickyConfig> + Param<CL>,
+    {
+        layer_fn(|c: &C, inner| StickyFactory {
+            config: Param::<StickyConfig>::param(c),
+            clock: Param::<CL>::param(c),
+            inner,
+            _marker: PhantomData,
+        })
+    }
+
+    fn table<T, K>(&self, old: Option<&Sticky<T, Q, K, CL>>) -> Arc<Mutex<Table<Q, K>>> {
+        match old {
+            Some(old) => {
+                old.lock().config = self.config;
+                old.table.clone()
+            }
+            None => Arc::new(Mutex::new(Table {
+                config: self.config,
+                sessions: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl<F, Q, CL, K> MakeService for StickyFactory<F, Q, CL>
+where
+    F: MakeService,
+    F::Service: Keyed<Key = K>,
+    CL: Clone,
+{
+    type Service = Sticky<F::Service, Q, K, CL>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Sticky {
+            table: self.table(old),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_resource("sessions", self.config.capacity as u64)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F, Q, CL, K> AsyncMakeService for StickyFactory<F, Q, CL>
+where
+    F: AsyncMakeService,
+    F::Service: Keyed<Key = K>,
+    CL: Clone,
+{
+    type Service = Sticky<F::Service, Q, K, CL>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Sticky {
+            table: self.table(old),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_resource("sessions", self.config.capacity as u64)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(StickyFactory<_, Q, CL> { config, clock, _marker });
+
+impl<T, Q, K, CL> Layered for Sticky<T, Q, K, CL> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Sticky<T, Q, K, CL>);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use super::{Sticky, StickyConfig, StickyFactory};
+    use crate::{
+        balance::{Weight, WeightedBalanceFactory},
+        layer::FactoryLayer,
+        test_util::block_on,
+        time::MockTimer,
+        MakeService, Param, Service,
+    };
+
+    struct Config(StickyConfig, MockTimer);
+
+    impl Param<StickyConfig> for Config {
+        fn param(&self) -> StickyConfig {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    /// A request of an optional session.
+    struct Req(Option<u32>);
+
+    impl Param<Option<u32>> for Req {
+        fn param(&self) -> Option<u32> {
+            self.0
+        }
+    }
+
+    /// A backend which answers with its name.
+    struct Named(&'static str);
+
+    impl Service<Req> for Named {
+        type Response = &'static str;
+        type Error = Infallible;
+
+        async fn call(&self, _req: Req) -> Result<&'static str, Infallible> {
+            Ok(self.0)
+        }
+    }
+
+    struct NamedFactory(&'static str);
+
+    impl MakeService for NamedFactory {
+        type Service = Named;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, _old: Option<&Named>) -> Result<Named, Infallible> {
+            Ok(Named(self.0))
+        }
+    }
+
+    fn factory(
+        timer: &MockTimer,
+        capacity: usize,
+        names: &[&'static str],
+    ) -> StickyFactory<WeightedBalanceFactory<usize, NamedFactory>, u32, MockTimer> {
+        let config = StickyConfig {
+            capacity,
+            ttl: Duration::from_secs(10),
+        };
+        let backends = names
+            .iter()
+            .enumerate()
+            .map(|(key, &name)| (key, NamedFactory(name), Weight(1)))
+            .collect();
+        StickyFactory::layer().layer(
+            &Config(config, timer.clone()),
+            WeightedBalanceFactory::new(backends),
+        )
+    }
+
+    #[test]
+    fn pins_sessions_until_they_expire() {
+        let timer = MockTimer::new();
+        let svc = factory(&timer, 8, &["a", "b"]).make().unwrap();
+        let call = |session| block_on(svc.call(Req(session))).unwrap();
+
+        // Requests without a session are balanced round robin.
+        assert_eq!(call(Some(7)), "a");
+        assert_eq!((call(None), call(None)), ("b", "a"));
+        assert_eq!(call(Some(7)), "a");
+
+        // Each request keeps the session alive for the ttl.
+        timer.advance(Duration::from_secs(9));
+        assert_eq!(call(Some(7)), "a");
+        timer.advance(Duration::from_secs(9));
+        assert_eq!(call(Some(7)), "a");
+        timer.advance(Duration::from_secs(10));
+        assert_eq!(call(Some(7)), "b");
+        assert_eq!(call(Some(7)), "b");
+    }
+
+    #[test]
+    fn bounded_table_kept_on_reload() {
+        let timer = MockTimer::new();
+        let factory_of = |names: &[&'static str]| factory(&timer, 2, names);
+        let svc = factory_of(&["a", "b"]).make().unwrap();
+        let call =
+            |svc: &Sticky<_, _, _, _>, session| block_on(svc.call(Req(Some(session)))).unwrap();
+
+        assert_eq!(call(&svc, 1), "a");
+        timer.advance(Duration::from_secs(1));
+        assert_eq!(call(&svc, 2), "b");
+        timer.advance(Duration::from_secs(1));
+        // The least recently used session makes room.
+        assert_eq!(call(&svc, 3), "a");
+        assert_eq!(svc.sessions(), 2);
+        assert_eq!(call(&svc, 1), "b");
+
+        let svc = factory_of(&["a", "b"]).make_via_ref(Some(&svc)).unwrap();
+        assert_eq!(svc.sessions(), 2);
+        assert_eq!((call(&svc, 1), call(&svc, 3)), ("b", "a"));
+        // The session of a removed backend is pinned again.
+        let svc = factory_of(&["a"]).make_via_ref(Some(&svc)).unwrap();
+        assert_eq!(call(&svc, 1), "a");
+    }
+}
@@ -0,0 +1,570 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    time::{Clock, Timer},
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// An asynchronous stream of items, such as the chunks of a response body.
+pub trait BodyStream {
+    type Item;
+
+    /// Poll the next item; `None` means the stream has ended.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+
+    /// Wait for the next item.
+    fn next(&mut self) -> impl Future<Output = Option<Self::Item>>
+    where
+        Self: Unpin,
+    {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+    }
+}
+
+impl<S: BodyStream + Unpin + ?Sized> BodyStream for &mut S {
+    type Item = S::Item;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut **self).poll_next(cx)
+    }
+}
+
+impl<S: BodyStream + Unpin + ?Sized> BodyStream for Box<S> {
+    type Item = S::Item;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut **self).poll_next(cx)
+    }
+}
+
+impl<S: BodyStream + ?Sized> BodyStream for Pin<Box<S>> {
+    type Item = S::Item;
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().as_mut().poll_next(cx)
+    }
+}
+
+/// A service whose response is a [`BodyStream`].
+///
+/// It is implemented for every such service. The call completes when the
+/// response starts, and the items follow through the stream; middleware in this
+/// module accounts for the whole stream instead of just the call.
+pub trait StreamingService<R>: Service<R> {
+    /// The item type of the response stream.
+    type Item;
+}
+
+impl<S, R> StreamingService<R> for S
+where
+    S: Service<R>,
+    S::Response: BodyStream,
+{
+    type Item = <S::Response as BodyStream>::Item;
+}
+
+/// A stream which yields a prefetched first item before the rest of a stream.
+pub struct Prefetched<St: BodyStream> {
+    first: Option<St::Item>,
+    rest: Pin<Box<St>>,
+}
+
+// The first item is never pinned and the rest is boxed.
+impl<St: BodyStream> Unpin for Prefetched<St> {}
+
+impl<St: BodyStream> BodyStream for Prefetched<St> {
+    type Item = St::Item;
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.first.take() {
+            Some(item) => Poll::Ready(Some(item)),
+            None => this.rest.as_mut().poll_next(cx),
+        }
+    }
+}
+
+/// Max time from the start of a call until the first item of its response stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirstItemTimeoutConfig(pub Duration);
+
+/// Error returned by [`FirstItemTimeout`].
+#[derive(Debug)]
+pub enum FirstItemTimeoutError<E> {
+    /// The first item did not arrive in time.
+    Elapsed,
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for FirstItemTimeoutError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FirstItemTimeoutError::Elapsed => f.write_str("first item of the response timed out"),
+            FirstItemTimeoutError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for FirstItemTimeoutError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FirstItemTimeoutError::Elapsed => None,
+            FirstItemTimeoutError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A middleware which times out a streaming call when the first item of the
+/// response does not arrive in time.
+///
+/// A unary timeout would only bound the time until the stream is returned,
+/// which is often immediate. Here the call waits for the first item, so the
+/// timeout covers time to first byte; the rest of the stream is not limited.
+/// The time source `TM` is taken from the stack config through `Param<TM>`.
+pub struct FirstItemTimeout<T, TM> {
+    timeout: Duration,
+    timer: TM,
+    inner: T,
+}
+
+impl<T, TM> FirstItemTimeout<T, TM> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = FirstItemTimeoutFactory<T, TM>>
+    where
+        C: Param<FirstItemTimeoutConfig> + Param<TM>,
+    {
+        layer_fn(|c: &C, inner| FirstItemTimeoutFactory {
+            timeout: Param::<FirstItemTimeoutConfig>::param(c).0,
+            timer: Param::<TM>::param(c),
+            inner,
+        })
+    }
+}
+
+impl<T, TM, R> Service<R> for FirstItemTimeout<T, TM>
+where
+    T: Service<R>,
+    T::Response: BodyStream,
+    TM: Timer,
+{
+    type Response = Prefetched<T::Response>;
+    type Error = FirstItemTimeoutError<T::Error>;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let first = async {
+            let mut rest = Box::pin(self.inner.call(req).await?);
+            let first = poll_fn(|cx| rest.as_mut().poll_next(cx)).await;
+            Ok(Prefetched { first, rest })
+        };
+        let mut first = pin!(first);
+        let mut sleep = pin!(self.timer.sleep(self.timeout));
+        poll_fn(|cx| {
+            if let Poll::Ready(res) = first.as_mut().poll(cx) {
+                return Poll::Ready(res.map_err(FirstItemTimeoutError::Inner));
+            }
+            sleep
+                .as_mut()
+                .poll(cx)
+                .map(|_| Err(FirstItemTimeoutError::Elapsed))
+        })
+        .await
+    }
+}
+
+/// Factory of [`FirstItemTimeout`].
+pub struct FirstItemTimeoutFactory<F, TM> {
+    timeout: Duration,
+    timer: TM,
+    inner: F,
+}
+
+impl<F: MakeService, TM: Clone> MakeService for FirstItemTimeoutFactory<F, TM> {
+    type Service = FirstItemTimeout<F::Service, TM>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(FirstItemTimeout {
+            timeout: self.timeout,
+            timer: self.timer.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, TM: Clone> AsyncMakeService for FirstItemTimeoutFactory<F, TM> {
+    type Service = FirstItemTimeout<F::Service, TM>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(FirstItemTimeout {
+            timeout: self.timeout,
+            timer: self.timer.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(FirstItemTimeoutFactory<_, TM> { timeout, timer });
+
+impl<T, TM> Layered for FirstItemTimeout<T, TM> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(FirstItemTimeout<T, TM>);
+
+/// Statistics of a streaming call, reported by [`StreamMetrics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStats {
+    /// Number of items yielded.
+    pub items: u64,
+    /// Time from the start of the call until the stream ended or was dropped.
+    pub duration: Duration,
+    /// Whether the stream reached its end, rather than being dropped early.
+    pub completed: bool,
+}
+
+/// Receives the [`StreamStats`] of every response stream of [`StreamMetrics`].
+pub trait StreamObserver {
+    fn on_stream_end(&self, stats: &StreamStats);
+}
+
+impl<F: Fn(&StreamStats)> StreamObserver for F {
+    #[inline]
+    fn on_stream_end(&self, stats: &StreamStats) {
+        (self)(stats)
+    }
+}
+
+/// A middleware which reports the duration and item count of response streams.
+///
+/// The stats are reported once, when the stream ends or is dropped, so the
+/// duration covers the whole response rather than just the call. Failed calls
+/// produce no stream and are not reported. The clock `CL` is taken from the
+/// stack config through `Param<CL>`.
+pub struct StreamMetrics<T, O, CL> {
+    observer: O,
+    clock: CL,
+    inner: T,
+}
+
+impl<T, O, CL> StreamMetrics<T, O, CL> {
+    pub fn layer<C>(observer: O) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<CL>,
+        O: Clone,
+    {
+        layer_fn(move |c: &C, inner| StreamMetrics {
+            observer: observer.clone(),
+            clock: c.param(),
+            inner,
+        })
+    }
+}
+
+impl<T, O, CL, R> Service<R> for StreamMetrics<T, O, CL>
+where
+    T: Service<R>,
+    T::Response: BodyStream,
+    O: StreamObserver + Clone,
+    CL: Clock + Clone,
+{
+    type Response = Instrumented<T::Response, O, CL>;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let start = self.clock.now();
+        let stream = self.inner.call(req).await?;
+        Ok(Instrumented {
+            stream: Box::pin(stream),
+            observer: self.observer.clone(),
+            clock: self.clock.clone(),
+            start,
+            items: 0,
+            done: false,
+        })
+    }
+}
+
+impl<F: MakeService, O: Clone, CL: Clone> MakeService for StreamMetrics<F, O, CL> {
+    type Service = StreamMetrics<F::Service, O, CL>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(StreamMetrics {
+            observer: self.observer.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, O: Clone, CL: Clone> AsyncMakeService for StreamMetrics<F, O, CL> {
+    type Service = StreamMetrics<F::Service, O, CL>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(StreamMetrics {
+            observer: self.observer.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(StreamMetrics<_, O, CL> { observer, clock });
+
+impl<T, O, CL> Layered for StreamMetrics<T, O, CL> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(StreamMetrics<T, O, CL>);
+
+/// The response stream of [`StreamMetrics`].
+pub struct Instrumented<St, O: StreamObserver, CL: Clock> {
+    stream: Pin<Box<St>>,
+    observer: O,
+    clock: CL,
+    start: Instant,
+    items: u64,
+    done: bool,
+}
+
+impl<St, O: StreamObserver, CL: Clock> Instrumented<St, O, CL> {
+    fn finish(&mut self, completed: bool) {
+        if !self.done {
+            self.done = true;
+            self.observer.on_stream_end(&StreamStats {
+                items: self.items,
+                duration: self.clock.now().saturating_duration_since(self.start),
+                completed,
+            });
+        }
+    }
+}
+
+// The stream is boxed and the other fields are never pinned.
+impl<St, O: StreamObserver, CL: Clock> Unpin for Instrumented<St, O, CL> {}
+
+impl<St: BodyStream, O: StreamObserver, CL: Clock> BodyStream for Instrumented<St, O, CL> {
+    type Item = St::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = this.stream.as_mut().poll_next(cx);
+        match &item {
+            Poll::Ready(Some(_)) => this.items += 1,
+            Poll::Ready(None) => this.finish(true),
+            Poll::Pending => {}
+        }
+        item
+    }
+}
+
+impl<St, O: StreamObserver, CL: Clock> Drop for Instrumented<St, O, CL> {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::VecDeque,
+        convert::Infallible,
+        pin::{pin, Pin},
+        rc::Rc,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use super::{
+        BodyStream, FirstItemTimeout, FirstItemTimeoutConfig, FirstItemTimeoutError, StreamMetrics,
+        StreamStats,
+    };
+    use crate::{
+        layer::FactoryLayer,
+        test_util::{block_on, poll_once},
+        time::MockTimer,
+        utils::CloneFactory,
+        MakeService, Param, Service,
+    };
+
+    /// A stream of scripted items; `None` stalls the stream for good, and the
+    /// stream ends after the script. Each item takes 10ms of mock time.
+    struct Items {
+        timer: MockTimer,
+        script: VecDeque<Option<u32>>,
+    }
+
+    impl BodyStream for Items {
+        type Item = u32;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<u32>> {
+            let this = self.get_mut();
+            match this.script.front() {
+                Some(None) => Poll::Pending,
+                Some(Some(_)) => {
+                    this.timer.advance(Duration::from_millis(10));
+                    Poll::Ready(this.script.pop_front().flatten())
+                }
+                None => Poll::Ready(None),
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct Streamer(MockTimer);
+
+    impl Service<Vec<Option<u32>>> for Streamer {
+        type Response = Items;
+        type Error = Infallible;
+
+        async fn call(&self, script: Vec<Option<u32>>) -> Result<Items, Infallible> {
+            Ok(Items {
+                timer: self.0.clone(),
+                script: script.into(),
+            })
+        }
+    }
+
+    fn collect(mut stream: impl BodyStream<Item = u32> + Unpin) -> Vec<u32> {
+        let mut items = Vec::new();
+        while let Some(item) = block_on(stream.next()) {
+            items.push(item);
+        }
+        items
+    }
+
+    struct Config(FirstItemTimeoutConfig, MockTimer);
+
+    impl Param<FirstItemTimeoutConfig> for Config {
+        fn param(&self) -> FirstItemTimeoutConfig {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    #[test]
+    fn first_item_timeout() {
+        let timer = MockTimer::new();
+        let config = Config(
+            FirstItemTimeoutConfig(Duration::from_millis(100)),
+            timer.clone(),
+        );
+        let svc = FirstItemTimeout::<_, MockTimer>::layer()
+            .layer(&config, CloneFactory::new(Streamer(timer.clone())))
+            .make()
+            .unwrap();
+
+        let stream = block_on(svc.call(vec![Some(1), Some(2)])).unwrap();
+        assert_eq!(collect(stream), [1, 2]);
+
+        let mut stalled = pin!(svc.call(vec![None]));
+        assert!(poll_once(stalled.as_mut()).is_pending());
+        timer.advance(Duration::from_millis(100));
+        assert!(matches!(
+            poll_once(stalled),
+            Poll::Ready(Err(FirstItemTimeoutError::Elapsed))
+        ));
+    }
+
+    #[test]
+    fn stream_metrics() {
+        let timer = MockTimer::new();
+        let stats = Rc::new(RefCell::new(Vec::new()));
+        let observer = {
+            let stats = stats.clone();
+            move |s: &StreamStats| stats.borrow_mut().push(*s)
+        };
+        let svc = StreamMetrics::layer(observer)
+            .layer(&timer, CloneFactory::new(Streamer(timer.clone())))
+            .make()
+            .unwrap();
+
+        let stream = block_on(svc.call(vec![Some(1), Some(2)])).unwrap();
+        assert_eq!(collect(stream), [1, 2]);
+        let mut stream = block_on(svc.call(vec![Some(3), None])).unwrap();
+        assert_eq!(block_on(stream.next()), Some(3));
+        assert_eq!(stats.borrow().len(), 1);
+        drop(stream);
+
+        let ended = StreamStats {
+            items: 2,
+            duration: Duration::from_millis(20),
+            completed: true,
+        };
+        let dropped = StreamStats {
+            items: 1,
+            duration: Duration::from_millis(10),
+            completed: false,
+        };
+        assert_eq!(*stats.borrow(), [ended, dropped]);
+    }
+}
@@ -0,0 +1,109 @@
+//! Helpers for the unit tests. They do not need an async runtime, so the tests
+//! run the same on every platform and under Miri.
+// Most tests are compiled out with `boxed-futures`.
+#![cfg_attr(feature = "boxed-futures", allow(dead_code))]
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::{pin, Pin},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
+};
+
+/// Rounds of polling without completion after which a test is considered hung.
+const MAX_ROUNDS: usize = 100_000;
+
+/// Run `fut` to completion, polling it in a loop so it does not rely on the
+/// wakers of the futures under test.
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    for _ in 0..MAX_ROUNDS {
+        if let Poll::Ready(out) = poll_once(fut.as_mut()) {
+            return out;
+        }
+    }
+    panic!("future did not complete after {MAX_ROUNDS} polls");
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// A single-threaded executor which polls every task on each round, so it
+/// does not rely on the wakers of the futures under test.
+#[derive(Default, Clone)]
+pub(crate) struct LocalExecutor {
+    tasks: Rc<RefCell<Vec<Task>>>,
+}
+
+impl LocalExecutor {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// A spawner for the `Spawn` trait, which runs the tasks on this executor.
+    pub(crate) fn spawner(&self) -> impl Fn(Task) + Clone + 'static {
+        let tasks = self.tasks.clone();
+        move |task| tasks.borrow_mut().push(task)
+    }
+
+    /// Run `fut`, and the spawned tasks meanwhile, until `fut` completes.
+    pub(crate) fn block_on<F: Future>(&self, fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        for _ in 0..MAX_ROUNDS {
+            if let Poll::Ready(out) = poll_once(fut.as_mut()) {
+                return out;
+            }
+            self.run_tasks();
+        }
+        panic!("future did not complete after {MAX_ROUNDS} rounds");
+    }
+
+    /// Poll each spawned task once.
+    pub(crate) fn run_tasks(&self) {
+        let mut tasks = std::mem::take(&mut *self.tasks.borrow_mut());
+        tasks.retain_mut(|task| poll_once(task.as_mut()).is_pending());
+        // Tasks spawned while polling were pushed to the emptied list.
+        tasks.append(&mut self.tasks.borrow_mut());
+        *self.tasks.borrow_mut() = tasks;
+    }
+
+    /// Number of spawned tasks which have not completed.
+    pub(crate) fn pending_tasks(&self) -> usize {
+        self.tasks.borrow().len()
+    }
+}
+
+/// Poll `fut` once with a waker which does nothing.
+pub(crate) fn poll_once<F: Future + ?Sized>(fut: Pin<&mut F>) -> Poll<F::Output> {
+    fut.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+/// A waker which counts how many times it was woken.
+#[derive(Default)]
+pub(crate) struct WakeCounter(AtomicUsize);
+
+impl WakeCounter {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Poll `fut` once with this waker.
+    pub(crate) fn poll<F: Future + ?Sized>(self: &Arc<Self>, fut: Pin<&mut F>) -> Poll<F::Output> {
+        let waker = Waker::from(self.clone());
+        fut.poll(&mut Context::from_waker(&waker))
+    }
+}
+
+impl Wake for WakeCounter {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+/// A source of sleep futures, implemented for the timer of a runtime.
+///
+/// Time-based middleware takes a `Timer` instead of depending on a runtime.
+pub trait Timer {
+    type Sleep: Future<Output = ()>;
+
+    /// Create a future which completes after `duration`.
+    fn sleep(&self, duration: Duration) -> Self::Sleep;
+}
+
+impl<T: Timer + ?Sized> Timer for &T {
+    type Sleep = T::Sleep;
+
+    #[inline]
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        (**self).sleep(duration)
+    }
+}
+
+impl<T: Timer + ?Sized> Timer for Arc<T> {
+    type Sleep = T::Sleep;
+
+    #[inline]
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        (**self).sleep(duration)
+    }
+}
+
+/// A source of the current time, implemented by [`SystemClock`] and
+/// [`MockTimer`].
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+impl<T: Clock + ?Sized> Clock for &T {
+    #[inline]
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+impl<T: Clock + ?Sized> Clock for Arc<T> {
+    #[inline]
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// A [`Clock`] reading [`Instant::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [`Timer`] made of a function returning sleep futures. Created by
+/// [`timer_fn`].
+#[derive(Debug, Clone, Copy)]
+pub struct TimerFn<F>(F);
+
+/// Create a [`Timer`] from a function like `tokio::time::sleep`.
+#[inline]
+pub const fn timer_fn<F>(f: F) -> TimerFn<F> {
+    TimerFn(f)
+}
+
+impl<F, S> Timer for TimerFn<F>
+where
+    F: Fn(Duration) -> S,
+    S: Future<Output = ()>,
+{
+    type Sleep = S;
+
+    #[inline]
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        (self.0)(duration)
+    }
+}
+
+trait DynTimer {
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()>>>;
+    fn now(&self) -> Instant;
+}
+
+struct Erased<T, CL> {
+    timer: T,
+    clock: CL,
+}
+
+impl<T, CL> DynTimer for Erased<T, CL>
+where
+    T: Timer,
+    T::Sleep: 'static,
+    CL: Clock,
+{
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(self.timer.sleep(duration))
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+}
+
+/// A type-erased [`Timer`] and [`Clock`].
+///
+/// Put it in the stack config and take it with `Param<TimerHandle>`, so the
+/// same stack can run on any runtime, or on a [`MockTimer`] in tests.
+#[derive(Clone)]
+pub struct TimerHandle {
+    inner: Arc<dyn DynTimer + Send + Sync>,
+}
+
+impl std::fmt::Debug for TimerHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TimerHandle").finish_non_exhaustive()
+    }
+}
+
+impl TimerHandle {
+    /// Erase `timer`, reading the time from [`SystemClock`].
+    pub fn new<T>(timer: T) -> Self
+    where
+        T: Timer + Send + Sync + 'static,
+        T::Sleep: 'static,
+    {
+        Self::with_clock(timer, SystemClock)
+    }
+
+    /// Erase `timer`, reading the time from `clock`.
+    pub fn with_clock<T, CL>(timer: T, clock: CL) -> Self
+    where
+        T: Timer + Send + Sync + 'static,
+        T::Sleep: 'static,
+        CL: Clock + Send + Sync + 'static,
+    {
+        TimerHandle {
+            inner: Arc::new(Erased { timer, clock }),
+        }
+    }
+}
+
+impl Timer for TimerHandle {
+    type Sleep = Pin<Box<dyn Future<Output = ()>>>;
+
+    #[inline]
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        self.inner.sleep(duration)
+    }
+}
+
+impl Clock for TimerHandle {
+    #[inline]
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+}
+
+/// A [`Timer`] and [`Clock`] whose time only moves when advanced, for tests.
+///
+/// Clones share the time. Sleeps complete once the time is advanced past
+/// their deadline.
+#[derive(Debug, Clone)]
+pub struct MockTimer {
+    base: Instant,
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    elapsed: Duration,
+    sleepers: Vec<(Duration, Waker)>,
+}
+
+impl Default for MockTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockTimer {
+    pub fn new() -> Self {
+        MockTimer {
+            base: Instant::now(),
+            state: Arc::new(Mutex::new(MockState::default())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Move the time forward, waking the sleeps which are due.
+    pub fn advance(&self, duration: Duration) {
+        let wakers = {
+            let mut state = self.lock();
+            state.elapsed += duration;
+            let now = state.elapsed;
+            let (due, pending) = std::mem::take(&mut state.sleepers)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+            state.sleepers = pending;
+            due
+        };
+        for (_, waker) in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Time advanced since the timer was created.
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    /// Erase it into a [`TimerHandle`].
+    pub fn handle(&self) -> TimerHandle {
+        TimerHandle::with_clock(self.clone(), self.clone())
+    }
+}
+
+impl Timer for MockTimer {
+    type Sleep = MockSleep;
+
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        let deadline = self.lock().elapsed + duration;
+        MockSleep {
+            timer: self.clone(),
+            deadline,
+        }
+    }
+}
+
+impl Clock for MockTimer {
+    #[inline]
+    fn now(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+}
+
+/// Future returned by [`MockTimer::sleep`].
+#[derive(Debug)]
+pub struct MockSleep {
+    timer: MockTimer,
+    deadline: Duration,
+}
+
+impl Future for MockSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.timer.lock();
+        if state.elapsed >= self.deadline {
+            return Poll::Ready(());
+        }
+        let waker = cx.waker();
+        let deadline = self.deadline;
+        if !state
+            .sleepers
+            .iter()
+            .any(|(d, w)| *d == deadline && w.will_wake(waker))
+        {
+            state.sleepers.push((deadline, waker.clone()));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::ready, pin::pin, task::Poll, time::Duration};
+
+    use super::{timer_fn, Clock, MockTimer, Timer, TimerHandle};
+    use crate::test_util::{poll_once, WakeCounter};
+
+    #[test]
+    fn mock_sleep_wakes_when_due() {
+        let timer = MockTimer::new();
+        let start = timer.now();
+        let waker = WakeCounter::new();
+        let mut sleep = pin!(timer.sleep(Duration::from_secs(2)));
+        assert!(waker.poll(sleep.as_mut()).is_pending());
+        assert!(waker.poll(sleep.as_mut()).is_pending());
+
+        let clone = timer.clone();
+        clone.advance(Duration::from_secs(1));
+        assert_eq!(waker.count(), 0);
+        clone.advance(Duration::from_secs(1));
+        // Registered once despite being polled twice.
+        assert_eq!(waker.count(), 1);
+        assert_eq!(poll_once(sleep.as_mut()), Poll::Ready(()));
+        assert_eq!(timer.elapsed(), Duration::from_secs(2));
+        assert_eq!(timer.now() - start, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn erased_timers() {
+        let timer = MockTimer::new();
+        let handle = timer.handle();
+        let start = handle.now();
+        let mut sleep = pin!(handle.sleep(Duration::from_millis(5)));
+        assert!(poll_once(sleep.as_mut()).is_pending());
+        timer.advance(Duration::from_millis(5));
+        assert_eq!(poll_once(sleep.as_mut()), Poll::Ready(()));
+        assert_eq!(handle.now() - start, Duration::from_millis(5));
+
+        let handle = TimerHandle::new(timer_fn(|_| ready(())));
+        assert_eq!(
+            poll_once(pin!(handle.sleep(Duration::MAX))),
+            Poll::Ready(())
+        );
+    }
+}
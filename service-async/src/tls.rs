@@ -0,0 +1,311 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, ParamSet, Service, ServiceMetadata,
+};
+
+/// Information about an accepted TLS session, passed to the inner service of
+/// [`TlsAccept`] through the request context.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The SNI server name sent by the client.
+    pub server_name: Option<String>,
+    /// The negotiated ALPN protocol.
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+/// Performs the server side of a TLS handshake over a connection.
+///
+/// `IO` is the connection type, usually bounded by the `AsyncRead + AsyncWrite`
+/// traits of the TLS library and runtime an implementation is written for.
+/// `Stream` is the decrypted stream handed to the inner service.
+pub trait TlsAcceptor<IO> {
+    type Stream;
+    type Error;
+
+    fn accept(
+        &self,
+        io: IO,
+    ) -> impl Future<Output = Result<(Self::Stream, TlsInfo), Self::Error>>;
+}
+
+impl<A: TlsAcceptor<IO> + ?Sized, IO> TlsAcceptor<IO> for Arc<A> {
+    type Stream = A::Stream;
+    type Error = A::Error;
+
+    #[inline]
+    fn accept(
+        &self,
+        io: IO,
+    ) -> impl Future<Output = Result<(Self::Stream, TlsInfo), Self::Error>> {
+        (**self).accept(io)
+    }
+}
+
+/// Error returned by [`TlsAccept`].
+#[derive(Debug)]
+pub enum TlsAcceptError<A, E> {
+    /// The TLS handshake failed.
+    Handshake(A),
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<A: Display, E: Display> Display for TlsAcceptError<A, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsAcceptError::Handshake(e) => write!(f, "tls handshake failed: {e}"),
+            TlsAcceptError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<A: Error + 'static, E: Error + 'static> Error for TlsAcceptError<A, E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsAcceptError::Handshake(e) => Some(e),
+            TlsAcceptError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A middleware which accepts TLS on `(IO, CX)` connection requests.
+///
+/// The inner service is called with the decrypted stream and the context with
+/// a [`TlsInfo`] set through `ParamSet<TlsInfo>`.
+///
+/// The acceptor is taken from the stack config through `Param<A>`, so a reload
+/// with new certificates builds a service with a new acceptor. Sessions accepted
+/// by the old service keep using it until they finish; [`TlsAccept::sessions`]
+/// tells how many are left.
+pub struct TlsAccept<T, A> {
+    acceptor: Arc<A>,
+    sessions: Arc<AtomicUsize>,
+    inner: T,
+}
+
+impl<T, A> TlsAccept<T, A> {
+    /// Number of sessions accepted by this service which are still being served.
+    #[inline]
+    pub fn sessions(&self) -> usize {
+        self.sessions.load(Ordering::Relaxed)
+    }
+
+    /// The acceptor of this service.
+    #[inline]
+    pub fn acceptor(&self) -> &A {
+        &self.acceptor
+    }
+
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = TlsAcceptFactory<T, A>>
+    where
+        C: Param<A>,
+    {
+        layer_fn(|c: &C, inner| TlsAcceptFactory {
+            acceptor: Arc::new(c.param()),
+            inner,
+        })
+    }
+}
+
+struct SessionGuard<'a>(&'a AtomicUsize);
+
+impl Drop for SessionGuard<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<T, A, IO, CX> Service<(IO, CX)> for TlsAccept<T, A>
+where
+    A: TlsAcceptor<IO>,
+    CX: ParamSet<TlsInfo>,
+    T: Service<(A::Stream, CX::Transformed)>,
+{
+    type Response = T::Response;
+    type Error = TlsAcceptError<A::Error, T::Error>;
+
+    async fn call(&self, (io, cx): (IO, CX)) -> Result<Self::Response, Self::Error> {
+        let (stream, info) = self
+            .acceptor
+            .accept(io)
+            .await
+            .map_err(TlsAcceptError::Handshake)?;
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        let _guard = SessionGuard(&self.sessions);
+        self.inner
+            .call((stream, cx.param_set(info)))
+            .await
+            .map_err(TlsAcceptError::Inner)
+    }
+}
+
+/// Factory of [`TlsAccept`].
+pub struct TlsAcceptFactory<F, A> {
+    acceptor: Arc<A>,
+    inner: F,
+}
+
+impl<F: MakeService, A> MakeService for TlsAcceptFactory<F, A> {
+    type Service = TlsAccept<F::Service, A>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(TlsAccept {
+            acceptor: self.acceptor.clone(),
+            sessions: Arc::new(AtomicUsize::new(0)),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, A> AsyncMakeService for TlsAcceptFactory<F, A> {
+    type Service = TlsAccept<F::Service, A>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(TlsAccept {
+            acceptor: self.acceptor.clone(),
+            sessions: Arc::new(AtomicUsize::new(0)),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(TlsAcceptFactory<_, A> { acceptor });
+
+impl<T, A> Layered for TlsAccept<T, A> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(TlsAccept<T, A>);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin};
+
+    use super::{TlsAccept, TlsAcceptError, TlsAcceptor, TlsInfo};
+    use crate::{
+        layer::FactoryLayer,
+        test_util::{block_on, poll_once},
+        utils::CloneFactory,
+        yielding::yield_now,
+        MakeService, ParamSet, Service,
+    };
+
+    /// Accepts connections named by a non-empty server name.
+    #[derive(Clone)]
+    struct Acceptor(&'static [u8]);
+
+    impl TlsAcceptor<&'static str> for Acceptor {
+        type Stream = String;
+        type Error = &'static str;
+
+        async fn accept(&self, io: &'static str) -> Result<(String, TlsInfo), &'static str> {
+            if io.is_empty() {
+                return Err("no server name");
+            }
+            let info = TlsInfo {
+                server_name: Some(io.to_owned()),
+                alpn_protocol: Some(self.0.to_vec()),
+            };
+            Ok((io.to_uppercase(), info))
+        }
+    }
+
+    struct Cx;
+
+    impl ParamSet<TlsInfo> for Cx {
+        type Transformed = TlsInfo;
+
+        fn param_set(self, item: TlsInfo) -> TlsInfo {
+            item
+        }
+    }
+
+    #[derive(Clone)]
+    struct Inner;
+
+    impl Service<(String, TlsInfo)> for Inner {
+        type Response = (String, Vec<u8>);
+        type Error = Infallible;
+
+        async fn call(&self, req: (String, TlsInfo)) -> Result<Self::Response, Infallible> {
+            let (stream, info) = req;
+            yield_now().await;
+            Ok((stream, info.alpn_protocol.unwrap()))
+        }
+    }
+
+    #[test]
+    fn accepts_and_counts_sessions() {
+        let svc = TlsAccept::layer()
+            .layer(&Acceptor(b"h2"), CloneFactory::new(Inner))
+            .make()
+            .unwrap();
+        let mut call = pin!(svc.call(("example.com", Cx)));
+        assert!(poll_once(call.as_mut()).is_pending());
+        assert_eq!(svc.sessions(), 1);
+        let (stream, alpn) = block_on(call).unwrap();
+        assert_eq!(
+            (stream.as_str(), alpn.as_slice()),
+            ("EXAMPLE.COM", &b"h2"[..])
+        );
+        assert_eq!(svc.sessions(), 0);
+
+        assert!(matches!(
+            block_on(svc.call(("", Cx))),
+            Err(TlsAcceptError::Handshake("no server name"))
+        ));
+        assert_eq!(svc.sessions(), 0);
+    }
+
+    #[test]
+    fn reload_takes_the_new_acceptor() {
+        let old = TlsAccept::layer()
+            .layer(&Acceptor(b"h2"), CloneFactory::new(Inner))
+            .make()
+            .unwrap();
+        let new = TlsAccept::layer()
+            .layer(&Acceptor(b"http/1.1"), CloneFactory::new(Inner))
+            .make_via_ref(Some(&old))
+            .unwrap();
+        assert_eq!(old.acceptor().0, b"h2");
+        assert_eq!(new.acceptor().0, b"http/1.1");
+    }
+}
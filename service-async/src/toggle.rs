@@ -0,0 +1,236 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// A shared on/off switch of [`Toggle`], extracted from the stack config.
+///
+/// Clones share the same state, so a handle kept outside the stack flips the
+/// services made with it, and across reloads, without rebuilding them.
+#[derive(Debug, Clone)]
+pub struct ToggleSwitch(Arc<AtomicBool>);
+
+impl ToggleSwitch {
+    pub fn new(enabled: bool) -> Self {
+        ToggleSwitch(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn enable(&self) {
+        self.set(true);
+    }
+
+    #[inline]
+    pub fn disable(&self) {
+        self.set(false);
+    }
+}
+
+impl Default for ToggleSwitch {
+    /// An enabled switch.
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+/// A middleware which calls the inner service while its [`ToggleSwitch`] is
+/// enabled, and the passthrough async closure instead while it is disabled.
+///
+/// Unlike an `Option` layer, which picks a branch when the stack is built,
+/// the switch is checked on every call, e.g. for feature flags and kill
+/// switches which take effect without a reload. The passthrough returns the
+/// same response and error types as the inner service:
+///
+/// ```
+/// use std::convert::Infallible;
+///
+/// use service_async::{
+///     stack::FactoryStack,
+///     toggle::{Toggle, ToggleSwitch},
+///     MakeService, Service,
+/// };
+///
+/// #[derive(Clone)]
+/// struct Upstream;
+///
+/// impl Service<u32> for Upstream {
+///     type Response = u32;
+///     type Error = Infallible;
+///
+///     async fn call(&self, req: u32) -> Result<u32, Infallible> {
+///         Ok(req * 2)
+///     }
+/// }
+///
+/// # async fn run() {
+/// let switch = ToggleSwitch::new(true);
+/// let svc = FactoryStack::new(switch.clone())
+///     .push_clone_leaf(Upstream)
+///     .push(Toggle::layer(async |req: u32| Ok(req)))
+///     .make()
+///     .unwrap();
+/// assert_eq!(svc.call(2).await.unwrap(), 4);
+/// switch.disable();
+/// assert_eq!(svc.call(2).await.unwrap(), 2);
+/// # }
+/// ```
+pub struct Toggle<T, P> {
+    switch: ToggleSwitch,
+    passthrough: Arc<P>,
+    inner: T,
+}
+
+impl<T, P> Toggle<T, P> {
+    pub fn layer<C>(passthrough: P) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<ToggleSwitch>,
+    {
+        let passthrough = Arc::new(passthrough);
+        layer_fn(move |c: &C, inner| Toggle {
+            switch: c.param(),
+            passthrough: passthrough.clone(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn switch(&self) -> &ToggleSwitch {
+        &self.switch
+    }
+}
+
+impl<T, P, R> Service<R> for Toggle<T, P>
+where
+    T: Service<R>,
+    P: AsyncFn(R) -> Result<T::Response, T::Error>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        if self.switch.is_enabled() {
+            self.inner.call(req).await
+        } else {
+            (self.passthrough)(req).await
+        }
+    }
+}
+
+impl<F: MakeService, P> MakeService for Toggle<F, P> {
+    type Service = Toggle<F::Service, P>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Toggle {
+            switch: self.switch.clone(),
+            passthrough: self.passthrough.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("enabled", self.switch.is_enabled())
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, P> AsyncMakeService for Toggle<F, P> {
+    type Service = Toggle<F::Service, P>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Toggle {
+            switch: self.switch.clone(),
+            passthrough: self.passthrough.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("enabled", self.switch.is_enabled())
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(Toggle<_, P> { switch, passthrough });
+
+impl<T, P> Layered for Toggle<T, P> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Toggle<T, P>);
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{Toggle, ToggleSwitch};
+    use crate::{
+        layer::FactoryLayer, test_util::block_on, utils::CloneFactory, MakeService, Service,
+    };
+
+    #[derive(Clone)]
+    struct Double;
+
+    impl Service<u32> for Double {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<u32, Infallible> {
+            Ok(req * 2)
+        }
+    }
+
+    #[test]
+    fn switch_is_checked_per_call_and_kept_on_reload() {
+        let switch = ToggleSwitch::default();
+        let factory =
+            Toggle::layer(async |req: u32| Ok(req + 1)).layer(&switch, CloneFactory::new(Double));
+        let svc = factory.make().unwrap();
+        assert_eq!(block_on(svc.call(5)), Ok(10));
+
+        switch.disable();
+        assert!(!svc.switch().is_enabled());
+        assert_eq!(block_on(svc.call(5)), Ok(6));
+
+        let reloaded = factory.make_via_ref(Some(&svc)).unwrap();
+        assert_eq!(block_on(reloaded.call(5)), Ok(6));
+        switch.enable();
+        assert_eq!(block_on(reloaded.call(5)), Ok(10));
+        assert_eq!(block_on(svc.call(5)), Ok(10));
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    convert::Infallible,
+    future::{poll_fn, ready, Future, Ready},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use crate::{
+    layer::{impl_wrap_inner, FactoryLayer, Layered},
+    reload::ServiceSlot,
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Service, ServiceMetadata,
+};
+
+type LocalBoxFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+/// Adapts a service of this crate into a `tower::Service`.
+///
+/// It is always ready, and clones share the service. The futures it returns
+/// are not `Send`, so it runs on executors for local tasks, e.g. a hyper
+/// server with a local executor.
+#[derive(Debug)]
+pub struct TowerService<S>(Arc<S>);
+
+impl<S> TowerService<S> {
+    #[inline]
+    pub fn new(svc: S) -> Self {
+        TowerService(Arc::new(svc))
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.0
+    }
+}
+
+impl<S> From<Arc<S>> for TowerService<S> {
+    #[inline]
+    fn from(svc: Arc<S>) -> Self {
+        TowerService(svc)
+    }
+}
+
+impl<S> Clone for TowerService<S> {
+    #[inline]
+    fn clone(&self) -> Self {
+        TowerService(self.0.clone())
+    }
+}
+
+impl<S, R> tower_service::Service<R> for TowerService<S>
+where
+    S: Service<R> + 'static,
+    R: 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = LocalBoxFuture<Result<S::Response, S::Error>>;
+
+    #[inline]
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let svc = self.0.clone();
+        Box::pin(async move { svc.call(req).await })
+    }
+}
+
+/// A `tower::MakeService` which makes a fresh service from the factory for
+/// each connection, so connections share no state.
+///
+/// The target, e.g. the connection info of a server framework, is ignored.
+#[derive(Debug)]
+pub struct TowerMakeService<F>(Arc<F>);
+
+impl<F> TowerMakeService<F> {
+    #[inline]
+    pub fn new(factory: F) -> Self {
+        TowerMakeService(Arc::new(factory))
+    }
+}
+
+impl<F> Clone for TowerMakeService<F> {
+    #[inline]
+    fn clone(&self) -> Self {
+        TowerMakeService(self.0.clone())
+    }
+}
+
+impl<F, T> tower_service::Service<T> for TowerMakeService<F>
+where
+    F: AsyncMakeService + 'static,
+{
+    type Response = TowerService<F::Service>;
+    type Error = F::Error;
+    type Future = LocalBoxFuture<Result<Self::Response, Self::Error>>;
+
+    #[inline]
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _target: T) -> Self::Future {
+        let factory = self.0.clone();
+        Box::pin(async move { factory.make().await.map(TowerService::new) })
+    }
+}
+
+/// A `tower::MakeService` which hands every connection the live service of a
+/// [`ServiceSlot`], like `tower::make::Shared`.
+///
+/// Connections share the state of the service. When the slot is reloaded,
+/// new connections get the new service, with the state migrated by
+/// `make_via_ref`, while open connections keep the one they got.
+pub struct TowerShared<S>(ServiceSlot<S>);
+
+impl<S> TowerShared<S> {
+    #[inline]
+    pub fn new(svc: S) -> Self {
+        TowerShared(ServiceSlot::new(svc))
+    }
+
+    /// Serve the live service of `slot`, following its reloads.
+    #[inline]
+    pub fn from_slot(slot: ServiceSlot<S>) -> Self {
+        TowerShared(slot)
+    }
+
+    #[inline]
+    pub fn slot(&self) -> &ServiceSlot<S> {
+        &self.0
+    }
+}
+
+impl<S> Clone for TowerShared<S> {
+    #[inline]
+    fn clone(&self) -> Self {
+        TowerShared(self.0.clone())
+    }
+}
+
+impl<S, T> tower_service::Service<T> for TowerShared<S> {
+    type Response = TowerService<S>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    #[inline]
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&mut self, _target: T) -> Self::Future {
+        ready(Ok(TowerService::from(self.0.get())))
+    }
+}
+
+/// Adapts a `tower::Service` into a service of this crate.
+///
+/// Each call clones the tower service, waits for it to be ready and calls the
+/// clone, like `tower::ServiceExt::oneshot`. Tower services are cheap to clone
+/// by convention, sharing their state behind an `Arc`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FromTower<T>(pub T);
+
+impl<T, R> Service<R> for FromTower<T>
+where
+    T: tower_service::Service<R> + Clone,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let mut svc = self.0.clone();
+        poll_fn(|cx| svc.poll_ready(cx)).await?;
+        svc.call(req).await
+    }
+}
+
+/// A [`FactoryLayer`] which applies a `tower::Layer` around the inner service,
+/// so tower middleware, e.g. from tower-http, can be pushed into a
+/// `FactoryStack`.
+///
+/// The inner service is adapted with [`TowerService`], wrapped by the tower
+/// layer, and the result adapted back with [`FromTower`]. The tower layer is
+/// applied again on every make, so state it keeps does not survive a reload;
+/// the inner service is still made against the old one.
+#[derive(Debug, Clone, Copy)]
+pub struct WrapTowerLayer<L> {
+    layer: L,
+}
+
+impl<L> WrapTowerLayer<L> {
+    #[inline]
+    pub const fn new(layer: L) -> Self {
+        WrapTowerLayer { layer }
+    }
+}
+
+impl<C, F, L: Clone> FactoryLayer<C, F> for WrapTowerLayer<L> {
+    type Factory = TowerLayerFactory<F, L>;
+
+    #[inline]
+    fn layer(&self, _config: &C, inner: F) -> Self::Factory {
+        TowerLayerFactory {
+            layer: self.layer.clone(),
+            inner,
+        }
+    }
+}
+
+/// The factory made by [`WrapTowerLayer`].
+#[derive(Debug, Clone)]
+pub struct TowerLayerFactory<F, L> {
+    layer: L,
+    inner: F,
+}
+
+/// A service wrapped by a `tower::Layer`, made by [`TowerLayerFactory`].
+pub struct TowerLayerService<S, T> {
+    inner: Arc<S>,
+    svc: FromTower<T>,
+}
+
+impl<S, T> TowerLayerService<S, T> {
+    fn new<L: tower_layer::Layer<TowerService<S>, Service = T>>(layer: &L, inner: S) -> Self {
+        let inner = Arc::new(inner);
+        TowerLayerService {
+            svc: FromTower(layer.layer(TowerService::from(inner.clone()))),
+            inner,
+        }
+    }
+}
+
+impl<S, T, R> Service<R> for TowerLayerService<S, T>
+where
+    T: tower_service::Service<R> + Clone,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    #[inline]
+    fn call(&self, req: R) -> impl Future<Output = Result<Self::Response, Self::Error>> {
+        self.svc.call(req)
+    }
+}
+
+impl<S, T> Layered for TowerLayerService<S, T> {
+    type Inner = S;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(TowerLayerService<S, T>);
+
+impl<F, L> MakeService for TowerLayerFactory<F, L>
+where
+    F: MakeService,
+    L: tower_layer::Layer<TowerService<F::Service>>,
+{
+    type Service = TowerLayerService<F::Service, L::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let inner = self.inner.make_via_ref(old.map(|o| &*o.inner))?;
+        Ok(TowerLayerService::new(&self.layer, inner))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F, L> AsyncMakeService for TowerLayerFactory<F, L>
+where
+    F: AsyncMakeService,
+    L: tower_layer::Layer<TowerService<F::Service>>,
+{
+    type Service = TowerLayerService<F::Service, L::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let inner = self.inner.make_via_ref(old.map(|o| &*o.inner)).await?;
+        Ok(TowerLayerService::new(&self.layer, inner))
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(TowerLayerFactory<_, L> { layer });
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, convert::Infallible};
+
+    use tower::util::MapRequestLayer;
+    use tower_service::Service as _;
+
+    use super::{FromTower, TowerMakeService, TowerShared, WrapTowerLayer};
+    use crate::{
+        layer::{FactoryLayer, Layered},
+        make_service::AsyncMakeServiceWrapper,
+        test_util::block_on,
+        MakeService, Service,
+    };
+
+    /// Counts its calls, and answers with the count and the request.
+    #[derive(Default)]
+    struct Counter {
+        generation: usize,
+        calls: Cell<usize>,
+    }
+
+    impl Service<u32> for Counter {
+        type Response = (usize, usize, u32);
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<Self::Response, Infallible> {
+            self.calls.set(self.calls.get() + 1);
+            Ok((self.generation, self.calls.get(), req))
+        }
+    }
+
+    struct Factory;
+
+    impl MakeService for Factory {
+        type Service = Counter;
+        type Error = Infallible;
+
+        fn make_via_ref(&self, old: Option<&Counter>) -> Result<Counter, Infallible> {
+            Ok(Counter {
+                generation: old.map_or(0, |o| o.generation + 1),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn make_per_connection() {
+        let mut make = TowerMakeService::new(AsyncMakeServiceWrapper(Factory));
+        let first = block_on(make.call(())).unwrap();
+        let second = block_on(make.call(())).unwrap();
+        // Tower services adapted back share the state of their clones.
+        let first = FromTower(first);
+        assert_eq!(block_on(first.call(7)), Ok((0, 1, 7)));
+        assert_eq!(block_on(first.clone().call(8)), Ok((0, 2, 8)));
+        assert_eq!(block_on(FromTower(second).call(9)), Ok((0, 1, 9)));
+    }
+
+    #[test]
+    fn shared_follows_the_slot() {
+        let mut shared = TowerShared::new(Factory.make().unwrap());
+        let mut open = block_on(shared.call(())).unwrap();
+        assert_eq!(block_on(open.call(1)), Ok((0, 1, 1)));
+
+        let slot = shared.slot().clone();
+        slot.swap(Factory.make_via_ref(Some(&slot.get())).unwrap());
+        let mut new = block_on(shared.call(())).unwrap();
+        assert_eq!(block_on(new.call(2)), Ok((1, 1, 2)));
+        assert_eq!(block_on(open.call(3)), Ok((0, 2, 3)));
+    }
+
+    #[test]
+    fn tower_layers_in_a_stack() {
+        let layer = WrapTowerLayer::new(MapRequestLayer::new(|req: u32| req * 10));
+        let factory = layer.layer(&(), Factory);
+        let svc = factory.make().unwrap();
+        assert_eq!(block_on(svc.call(1)), Ok((0, 1, 10)));
+        assert_eq!(svc.inner().calls.get(), 1);
+
+        // The inner service is made against the old one.
+        let svc = factory.make_via_ref(Some(&svc)).unwrap();
+        assert_eq!(block_on(svc.call(2)), Ok((1, 1, 20)));
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::{ready, Future},
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Decodes the requests of a service wrapped by [`Transform`], e.g. to
+/// decompress, decrypt or unframe them.
+///
+/// For byte-oriented codecs, implement [`ByteCodec`] and use [`Bytes`].
+pub trait Decode<Req> {
+    /// The decoded request passed to the inner service.
+    type Output;
+    type Error;
+
+    fn decode(&self, req: Req) -> impl Future<Output = Result<Self::Output, Self::Error>>;
+}
+
+/// Encodes the responses of a service wrapped by [`Transform`], e.g. to
+/// compress, encrypt or frame them.
+pub trait Encode<Resp> {
+    /// The encoded response returned to the caller.
+    type Output;
+    type Error;
+
+    fn encode(&self, resp: Resp) -> impl Future<Output = Result<Self::Output, Self::Error>>;
+}
+
+/// An encoding made of two sync closures. Created by [`encoding_fn`].
+#[derive(Debug, Clone, Copy)]
+pub struct EncodingFn<D, E> {
+    decode: D,
+    encode: E,
+}
+
+/// Create an encoding from a sync `decode` and `encode` closure.
+#[inline]
+pub const fn encoding_fn<D, E>(decode: D, encode: E) -> EncodingFn<D, E> {
+    EncodingFn { decode, encode }
+}
+
+impl<Req, D, E, O, Err> Decode<Req> for EncodingFn<D, E>
+where
+    D: Fn(Req) -> Result<O, Err>,
+{
+    type Output = O;
+    type Error = Err;
+
+    #[inline]
+    fn decode(&self, req: Req) -> impl Future<Output = Result<O, Err>> {
+        ready((self.decode)(req))
+    }
+}
+
+impl<Resp, D, E, O, Err> Encode<Resp> for EncodingFn<D, E>
+where
+    E: Fn(Resp) -> Result<O, Err>,
+{
+    type Output = O;
+    type Error = Err;
+
+    #[inline]
+    fn encode(&self, resp: Resp) -> impl Future<Output = Result<O, Err>> {
+        ready((self.encode)(resp))
+    }
+}
+
+/// A sync codec over byte buffers, like a compression or cipher.
+pub trait ByteCodec {
+    type Error;
+
+    /// Decode `input` into `output`.
+    fn decode_bytes(&self, input: &[u8], output: &mut Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Encode `input` into `output`.
+    fn encode_bytes(&self, input: &[u8], output: &mut Vec<u8>) -> Result<(), Self::Error>;
+}
+
+/// Decodes byte requests and encodes byte responses with a [`ByteCodec`].
+///
+/// Requests and responses can be any `AsRef<[u8]>`, such as `Vec<u8>` or
+/// `Bytes`; they are decoded and encoded into `Vec<u8>`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bytes<BC>(pub BC);
+
+impl<BC: ByteCodec, Req: AsRef<[u8]>> Decode<Req> for Bytes<BC> {
+    type Output = Vec<u8>;
+    type Error = BC::Error;
+
+    #[inline]
+    fn decode(&self, req: Req) -> impl Future<Output = Result<Vec<u8>, BC::Error>> {
+        let mut output = Vec::new();
+        ready(self.0.decode_bytes(req.as_ref(), &mut output).map(|_| output))
+    }
+}
+
+impl<BC: ByteCodec, Resp: AsRef<[u8]>> Encode<Resp> for Bytes<BC> {
+    type Output = Vec<u8>;
+    type Error = BC::Error;
+
+    #[inline]
+    fn encode(&self, resp: Resp) -> impl Future<Output = Result<Vec<u8>, BC::Error>> {
+        let mut output = Vec::new();
+        ready(self.0.encode_bytes(resp.as_ref(), &mut output).map(|_| output))
+    }
+}
+
+/// Error returned by [`Transform`].
+#[derive(Debug)]
+pub enum TransformError<D, E, I> {
+    /// Decoding the request failed.
+    Decode(D),
+    /// Encoding the response failed.
+    Encode(E),
+    /// The inner service failed.
+    Inner(I),
+}
+
+impl<D: Display, E: Display, I: Display> Display for TransformError<D, E, I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransformError::Decode(e) => write!(f, "decode request error: {e}"),
+            TransformError::Encode(e) => write!(f, "encode response error: {e}"),
+            TransformError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<D, E, I> Error for TransformError<D, E, I>
+where
+    D: Error + 'static,
+    E: Error + 'static,
+    I: Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransformError::Decode(e) => Some(e),
+            TransformError::Encode(e) => Some(e),
+            TransformError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A middleware which decodes each request with [`Decode`] and encodes each
+/// response with [`Encode`].
+///
+/// It expresses compression, encryption or framing as a layer, without tying
+/// it to a protocol. The encoding is taken from the stack config through
+/// `Param<Enc>`, so a reload can change it, e.g. to a new compression level.
+pub struct Transform<T, Enc> {
+    encoding: Enc,
+    inner: T,
+}
+
+impl<T, Enc> Transform<T, Enc> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = Transform<T, Enc>>
+    where
+        C: Param<Enc>,
+    {
+        layer_fn(|c: &C, inner| Transform {
+            encoding: c.param(),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn encoding(&self) -> &Enc {
+        &self.encoding
+    }
+}
+
+impl<T, Enc, R> Service<R> for Transform<T, Enc>
+where
+    Enc: Decode<R> + Encode<T::Response>,
+    T: Service<<Enc as Decode<R>>::Output>,
+{
+    type Response = <Enc as Encode<T::Response>>::Output;
+    type Error = TransformError<
+        <Enc as Decode<R>>::Error,
+        <Enc as Encode<T::Response>>::Error,
+        T::Error,
+    >;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let req = self
+            .encoding
+            .decode(req)
+            .await
+            .map_err(TransformError::Decode)?;
+        let resp = self.inner.call(req).await.map_err(TransformError::Inner)?;
+        self.encoding
+            .encode(resp)
+            .await
+            .map_err(TransformError::Encode)
+    }
+}
+
+impl<F: MakeService, Enc: Clone> MakeService for Transform<F, Enc> {
+    type Service = Transform<F::Service, Enc>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Transform {
+            encoding: self.encoding.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, Enc: Clone> AsyncMakeService for Transform<F, Enc> {
+    type Service = Transform<F::Service, Enc>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Transform {
+            encoding: self.encoding.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>().with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(Transform<_, Enc> { encoding });
+
+impl<T, Enc> Layered for Transform<T, Enc> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(Transform<T, Enc>);
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{encoding_fn, ByteCodec, Bytes, Transform, TransformError};
+    use crate::{layer::FactoryLayer, test_util::block_on, Service};
+
+    /// Xors every byte with the key; empty buffers are rejected.
+    #[derive(Clone)]
+    struct Xor(u8);
+
+    impl ByteCodec for Xor {
+        type Error = &'static str;
+
+        fn decode_bytes(&self, input: &[u8], output: &mut Vec<u8>) -> Result<(), Self::Error> {
+            if input.is_empty() {
+                return Err("empty");
+            }
+            output.extend(input.iter().map(|b| b ^ self.0));
+            Ok(())
+        }
+
+        fn encode_bytes(&self, input: &[u8], output: &mut Vec<u8>) -> Result<(), Self::Error> {
+            self.decode_bytes(input, output)
+        }
+    }
+
+    /// Upper-cases the request, or answers nothing to `"-"`.
+    struct Upper;
+
+    impl Service<Vec<u8>> for Upper {
+        type Response = Vec<u8>;
+        type Error = Infallible;
+
+        async fn call(&self, req: Vec<u8>) -> Result<Vec<u8>, Infallible> {
+            if req == b"-" {
+                return Ok(Vec::new());
+            }
+            Ok(req.to_ascii_uppercase())
+        }
+    }
+
+    fn xor(data: &[u8]) -> Vec<u8> {
+        data.iter().map(|b| b ^ 0x20).collect()
+    }
+
+    #[test]
+    fn byte_codec() {
+        let svc = Transform::layer().layer(&Bytes(Xor(0x20)), Upper);
+        let resp = block_on(svc.call(xor(b"hello"))).unwrap();
+        assert_eq!(xor(&resp), b"HELLO");
+
+        let decode = block_on(svc.call(Vec::new()));
+        assert!(matches!(decode, Err(TransformError::Decode("empty"))));
+        let encode = block_on(svc.call(xor(b"-")));
+        assert!(matches!(encode, Err(TransformError::Encode("empty"))));
+    }
+
+    #[test]
+    fn encoding_from_closures() {
+        let encoding = encoding_fn(
+            |req: &str| req.strip_prefix('/').map(Vec::from).ok_or("relative path"),
+            |resp: Vec<u8>| String::from_utf8(resp).map(|s| s.len()),
+        );
+        let svc = Transform::layer().layer(&encoding, Upper);
+        assert_eq!(block_on(svc.call("/abc")).unwrap(), 3);
+        assert!(matches!(
+            block_on(svc.call("abc")),
+            Err(TransformError::Decode("relative path"))
+        ));
+    }
+}
@@ -1,7 +1,10 @@
-use std::convert::Infallible;
+use std::{convert::Infallible, fmt, marker::PhantomData, sync::Arc};
 
+#[cfg(not(feature = "boxed-futures"))]
+use super::AsyncMakeService;
 use super::MakeService;
 
+/// A factory which makes services by cloning a service instance.
 #[derive(Debug, Clone)]
 pub struct CloneFactory<T> {
     svc: T,
@@ -21,6 +24,24 @@ where
     }
 }
 
+#[cfg(not(feature = "boxed-futures"))]
+impl<T> AsyncMakeService for CloneFactory<T>
+where
+    T: Clone,
+{
+    type Service = T;
+
+    type Error = Infallible;
+
+    #[inline]
+    async fn make_via_ref(
+        &self,
+        _old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(self.svc.clone())
+    }
+}
+
 impl<T> From<T> for CloneFactory<T> {
     #[inline]
     fn from(svc: T) -> Self {
@@ -39,3 +60,177 @@ impl<T> CloneFactory<T> {
         self.svc
     }
 }
+
+/// A factory which makes services with `Default::default()`.
+pub struct ConstFactory<T> {
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> ConstFactory<T> {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for ConstFactory<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for ConstFactory<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ConstFactory<T> {}
+
+impl<T> fmt::Debug for ConstFactory<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConstFactory").finish()
+    }
+}
+
+impl<T> MakeService for ConstFactory<T>
+where
+    T: Default,
+{
+    type Service = T;
+
+    type Error = Infallible;
+
+    #[inline]
+    fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(T::default())
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<T> AsyncMakeService for ConstFactory<T>
+where
+    T: Default,
+{
+    type Service = T;
+
+    type Error = Infallible;
+
+    #[inline]
+    async fn make_via_ref(
+        &self,
+        _old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(T::default())
+    }
+}
+
+/// A factory which shares one service instance across all makes.
+///
+/// Every make returns a clone of the same `Arc<T>`, so state in the service
+/// is shared by all the services made, including across reloads.
+#[derive(Debug)]
+pub struct ArcFactory<T> {
+    svc: Arc<T>,
+}
+
+impl<T> Clone for ArcFactory<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            svc: self.svc.clone(),
+        }
+    }
+}
+
+impl<T> From<T> for ArcFactory<T> {
+    #[inline]
+    fn from(svc: T) -> Self {
+        Self::new(svc)
+    }
+}
+
+impl<T> From<Arc<T>> for ArcFactory<T> {
+    #[inline]
+    fn from(svc: Arc<T>) -> Self {
+        Self { svc }
+    }
+}
+
+impl<T> ArcFactory<T> {
+    #[inline]
+    pub fn new(svc: T) -> Self {
+        Self { svc: Arc::new(svc) }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> Arc<T> {
+        self.svc
+    }
+}
+
+impl<T> MakeService for ArcFactory<T> {
+    type Service = Arc<T>;
+
+    type Error = Infallible;
+
+    #[inline]
+    fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(self.svc.clone())
+    }
+}
+
+#[cfg(not(feature = "boxed-futures"))]
+impl<T> AsyncMakeService for ArcFactory<T> {
+    type Service = Arc<T>;
+
+    type Error = Infallible;
+
+    #[inline]
+    async fn make_via_ref(
+        &self,
+        _old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(self.svc.clone())
+    }
+}
+
+#[cfg(all(test, not(feature = "boxed-futures")))]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ArcFactory, CloneFactory, ConstFactory};
+    use crate::{test_util::block_on, AsyncMakeService, MakeService};
+
+    #[test]
+    fn clone_factory() {
+        let factory = CloneFactory::new(vec![1, 2]);
+        assert_eq!(MakeService::make(&factory).unwrap(), [1, 2]);
+        let svc = block_on(AsyncMakeService::make(&factory)).unwrap();
+        assert_eq!(svc, [1, 2]);
+    }
+
+    #[test]
+    fn const_factory() {
+        let factory = ConstFactory::<Vec<u8>>::new();
+        assert!(MakeService::make(&factory).unwrap().is_empty());
+        assert!(block_on(AsyncMakeService::make(&factory))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn arc_factory_shares_the_service() {
+        let factory = ArcFactory::new(7);
+        let sync = MakeService::make(&factory).unwrap();
+        let reloaded = MakeService::make_via_ref(&factory, Some(&sync)).unwrap();
+        let async_made = block_on(AsyncMakeService::make(&factory)).unwrap();
+        assert!(Arc::ptr_eq(&sync, &reloaded));
+        assert!(Arc::ptr_eq(&sync, &async_made));
+        assert_eq!(Arc::strong_count(&factory.into_inner()), 4);
+    }
+}
@@ -0,0 +1,315 @@
+use std::{error::Error, fmt::Display, sync::Arc};
+
+use crate::{AsyncMakeService, MakeService, ServiceMetadata};
+
+/// A rule that the layer named `outer` must wrap the layer named `inner`
+/// when a stack has both. Layers are named as in their [`ServiceMetadata`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderConstraint {
+    pub outer: &'static str,
+    pub inner: &'static str,
+    /// Why the order matters, shown in the error.
+    pub reason: &'static str,
+}
+
+/// A stack which breaks an [`OrderConstraint`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MisorderedStack {
+    pub constraint: OrderConstraint,
+}
+
+impl Display for MisorderedStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` must be outside `{}`: {}",
+            self.constraint.outer, self.constraint.inner, self.constraint.reason
+        )
+    }
+}
+
+impl Error for MisorderedStack {}
+
+/// Checks the order of the layers of a stack against [`OrderConstraint`]s,
+/// so a misordered stack fails with a config error instead of misbehaving.
+///
+/// The layers are read from the [`ServiceMetadata`] of the factory. A
+/// constraint is broken when its `inner` layer wraps its `outer` one at any
+/// depth; stacks without one of the two layers pass.
+///
+/// [`StackValidator::default`] holds the constraints of the middleware of this
+/// crate; more are added with [`constraint`](StackValidator::constraint).
+#[derive(Debug, Clone)]
+pub struct StackValidator {
+    constraints: Vec<OrderConstraint>,
+}
+
+impl StackValidator {
+    /// A validator without constraints.
+    pub const fn new() -> Self {
+        StackValidator {
+            constraints: Vec::new(),
+        }
+    }
+
+    /// Require the layer `outer` to wrap the layer `inner`.
+    pub fn constraint(
+        mut self,
+        outer: &'static str,
+        inner: &'static str,
+        reason: &'static str,
+    ) -> Self {
+        self.constraints.push(OrderConstraint {
+            outer,
+            inner,
+            reason,
+        });
+        self
+    }
+
+    #[inline]
+    pub fn constraints(&self) -> &[OrderConstraint] {
+        &self.constraints
+    }
+
+    /// Check a stack described by `meta`.
+    pub fn validate(&self, meta: &ServiceMetadata) -> Result<(), MisorderedStack> {
+        self.constraints.iter().try_for_each(|c| {
+            let wraps_outer = |m: &ServiceMetadata| {
+                m.inner.iter().any(|i| contains(i, c.outer, &|_| true))
+            };
+            if contains(meta, c.inner, &wraps_outer) {
+                return Err(MisorderedStack { constraint: *c });
+            }
+            Ok(())
+        })
+    }
+
+    /// Check the stack of a factory.
+    #[inline]
+    pub fn validate_factory<F: MakeService>(&self, factory: &F) -> Result<(), MisorderedStack> {
+        self.validate(&factory.service_metadata())
+    }
+
+    /// Wrap `factory`, so its order is checked on `validate` and `make`.
+    #[inline]
+    pub fn wrap<F>(self, factory: F) -> Validated<F> {
+        Validated {
+            validator: Arc::new(self),
+            inner: factory,
+        }
+    }
+}
+
+impl Default for StackValidator {
+    fn default() -> Self {
+        StackValidator::new()
+            .constraint(
+                "StaticFallback",
+                "Budgeted",
+                "calls over budget are only degraded by an outer fallback",
+            )
+            .constraint(
+                "Budgeted",
+                "FaultInject",
+                "injected delays only count against an outer budget",
+            )
+            .constraint(
+                "Draining",
+                "Bulkhead",
+                "requests refused while draining must not take permits",
+            )
+    }
+}
+
+/// Whether the tree of `meta` has a node named `name` which satisfies `f`.
+fn contains(meta: &ServiceMetadata, name: &str, f: &impl Fn(&ServiceMetadata) -> bool) -> bool {
+    (meta.name == name && f(meta)) || meta.inner.iter().any(|inner| contains(inner, name, f))
+}
+
+/// Error of a factory wrapped by [`StackValidator::wrap`].
+#[derive(Debug)]
+pub enum ValidatedError<E> {
+    /// The layers are in the wrong order.
+    Misordered(MisorderedStack),
+    /// The inner factory failed.
+    Inner(E),
+}
+
+impl<E: Display> Display for ValidatedError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidatedError::Misordered(e) => e.fmt(f),
+            ValidatedError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ValidatedError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ValidatedError::Misordered(e) => Some(e),
+            ValidatedError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A factory which checks the order of the stack it wraps before making a
+/// service. Created by [`StackValidator::wrap`].
+///
+/// It makes the same service as the inner factory.
+pub struct Validated<F> {
+    validator: Arc<StackValidator>,
+    inner: F,
+}
+
+impl<F> Validated<F> {
+    #[inline]
+    pub fn validator(&self) -> &StackValidator {
+        &self.validator
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F> Clone for Validated<F>
+where
+    F: Clone,
+{
+    fn clone(&self) -> Self {
+        Validated {
+            validator: self.validator.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<F: MakeService> MakeService for Validated<F> {
+    type Service = F::Service;
+    type Error = ValidatedError<F::Error>;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.validator
+            .validate(&self.inner.service_metadata())
+            .map_err(ValidatedError::Misordered)?;
+        self.inner.make_via_ref(old).map_err(ValidatedError::Inner)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.validator
+            .validate(&self.inner.service_metadata())
+            .map_err(ValidatedError::Misordered)?;
+        self.inner.validate().map_err(ValidatedError::Inner)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for Validated<F> {
+    type Service = F::Service;
+    type Error = ValidatedError<F::Error>;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.validator
+            .validate(&self.inner.service_metadata())
+            .map_err(ValidatedError::Misordered)?;
+        self.inner
+            .make_via_ref(old)
+            .await
+            .map_err(ValidatedError::Inner)
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.validator
+            .validate(&self.inner.service_metadata())
+            .map_err(ValidatedError::Misordered)?;
+        self.inner.validate().map_err(ValidatedError::Inner)
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        self.inner.service_metadata()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{StackValidator, ValidatedError};
+    use crate::{MakeService, ServiceMetadata};
+
+    /// Builds a chain of layers, the outermost first.
+    fn chain(names: &[&'static str]) -> ServiceMetadata {
+        names
+            .iter()
+            .rev()
+            .fold(None, |inner, name| {
+                let meta = ServiceMetadata::new(name);
+                Some(match inner {
+                    Some(inner) => meta.with_inner(inner),
+                    None => meta,
+                })
+            })
+            .unwrap()
+    }
+
+    /// A factory of a stack described by its metadata.
+    struct Described(ServiceMetadata);
+
+    impl MakeService for Described {
+        type Service = ();
+        type Error = Infallible;
+
+        fn make_via_ref(&self, _old: Option<&()>) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn service_metadata(&self) -> ServiceMetadata {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn checks_the_order_at_any_depth() {
+        let validator = StackValidator::default();
+        let ok = chain(&["StaticFallback", "Timeout", "Budgeted", "Leaf"]);
+        assert_eq!(validator.validate(&ok), Ok(()));
+        assert_eq!(validator.validate(&chain(&["Budgeted", "Leaf"])), Ok(()));
+
+        let bad = chain(&["Budgeted", "Timeout", "StaticFallback", "Leaf"]);
+        let err = validator.validate(&bad).unwrap_err();
+        assert_eq!(err.constraint, validator.constraints()[0]);
+        assert!(err
+            .to_string()
+            .starts_with("`StaticFallback` must be outside `Budgeted`: "));
+
+        // Side by side branches are not ordered.
+        let branches = ServiceMetadata::new("Router")
+            .with_inner(chain(&["Budgeted", "Leaf"]))
+            .with_inner(chain(&["StaticFallback", "Leaf"]));
+        assert_eq!(validator.validate(&branches), Ok(()));
+    }
+
+    #[test]
+    fn wrapped_factories_fail_to_make() {
+        let validator = StackValidator::new().constraint("Auth", "Cache", "cache per user");
+        let factory = validator.clone().wrap(Described(chain(&["Auth", "Cache"])));
+        assert!(factory.make().is_ok());
+        assert!(factory.validate().is_ok());
+
+        let factory = validator.wrap(Described(chain(&["Cache", "Auth"])));
+        assert!(matches!(factory.make(), Err(ValidatedError::Misordered(_))));
+        assert!(matches!(
+            factory.validate(),
+            Err(ValidatedError::Misordered(_))
+        ));
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::Display,
+    sync::Arc,
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// A request tagged with the version of its schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned<R> {
+    pub version: u32,
+    pub request: R,
+}
+
+impl<R> Versioned<R> {
+    #[inline]
+    pub const fn new(version: u32, request: R) -> Self {
+        Versioned { version, request }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.request
+    }
+}
+
+/// The request version the service of a [`MapRequestVersion`] serves, taken
+/// from the stack config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestVersion(pub u32);
+
+type Converter<R, E> = Arc<dyn Fn(R) -> Result<R, E>>;
+
+/// Converters between request versions, registered per pair of versions.
+///
+/// A request is converted through the fewest registered steps, so converters
+/// between neighbouring versions are enough to reach any version.
+pub struct VersionConverters<R, E> {
+    edges: Vec<(u32, u32, Converter<R, E>)>,
+}
+
+impl<R, E> VersionConverters<R, E> {
+    pub fn new() -> Self {
+        VersionConverters { edges: Vec::new() }
+    }
+
+    /// Register a converter of requests of version `from` into version `to`,
+    /// replacing the one of the same pair.
+    pub fn register(
+        mut self,
+        from: u32,
+        to: u32,
+        f: impl Fn(R) -> Result<R, E> + 'static,
+    ) -> Self {
+        self.edges.retain(|(a, b, _)| (*a, *b) != (from, to));
+        self.edges.push((from, to, Arc::new(f)));
+        self
+    }
+
+    /// Number of registered converters.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.edges.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.edges.is_empty()
+    }
+
+    /// The shortest chain of converters from each version to `target`.
+    fn routes(&self, target: u32) -> HashMap<u32, Vec<Converter<R, E>>> {
+        let mut routes = HashMap::new();
+        routes.insert(target, Vec::new());
+        let mut queue = VecDeque::from([target]);
+        while let Some(to) = queue.pop_front() {
+            for (from, _, f) in self.edges.iter().filter(|(_, b, _)| *b == to) {
+                if routes.contains_key(from) {
+                    continue;
+                }
+                let mut chain = vec![f.clone()];
+                chain.extend(routes[&to].iter().cloned());
+                routes.insert(*from, chain);
+                queue.push_back(*from);
+            }
+        }
+        routes
+    }
+}
+
+impl<R, E> Default for VersionConverters<R, E> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R, E> Clone for VersionConverters<R, E> {
+    fn clone(&self) -> Self {
+        VersionConverters {
+            edges: self.edges.clone(),
+        }
+    }
+}
+
+/// Error returned by [`MapRequestVersion`].
+#[derive(Debug)]
+pub enum VersionError<E, CE> {
+    /// No chain of converters leads from the version of the request to the
+    /// served one.
+    Unsupported { version: u32, served: u32 },
+    /// A converter failed.
+    Convert(CE),
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: Display, CE: Display> Display for VersionError<E, CE> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionError::Unsupported { version, served } => write!(
+                f,
+                "request version {version} can not be converted to version {served}"
+            ),
+            VersionError::Convert(e) => write!(f, "request conversion failed: {e}"),
+            VersionError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static, CE: Error + 'static> Error for VersionError<E, CE> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VersionError::Unsupported { .. } => None,
+            VersionError::Convert(e) => Some(e),
+            VersionError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// A middleware which takes [`Versioned`] requests and converts them to the
+/// version its inner service serves, so a stack serves old and new request
+/// formats during a rolling upgrade of a protocol.
+///
+/// The served version is taken from the stack config through
+/// `Param<RequestVersion>`, so a reload moves the stack to a new version. The
+/// inner service gets the bare request.
+pub struct MapRequestVersion<T, R, E> {
+    served: u32,
+    routes: Arc<HashMap<u32, Vec<Converter<R, E>>>>,
+    inner: T,
+}
+
+impl<T, R, E> MapRequestVersion<T, R, E> {
+    pub fn layer<C>(converters: VersionConverters<R, E>) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<RequestVersion>,
+    {
+        layer_fn(move |c: &C, inner| {
+            let served = c.param().0;
+            MapRequestVersion {
+                served,
+                routes: Arc::new(converters.routes(served)),
+                inner,
+            }
+        })
+    }
+
+    /// The version of the requests passed to the inner service.
+    #[inline]
+    pub fn served(&self) -> u32 {
+        self.served
+    }
+
+    /// Whether requests of `version` can be served.
+    #[inline]
+    pub fn supports(&self, version: u32) -> bool {
+        self.routes.contains_key(&version)
+    }
+
+    fn convert<IE>(&self, req: Versioned<R>) -> Result<R, VersionError<IE, E>> {
+        let chain = self
+            .routes
+            .get(&req.version)
+            .ok_or(VersionError::Unsupported {
+                version: req.version,
+                served: self.served,
+            })?;
+        chain
+            .iter()
+            .try_fold(req.request, |r, f| f(r))
+            .map_err(VersionError::Convert)
+    }
+}
+
+impl<T, R, E> Service<Versioned<R>> for MapRequestVersion<T, R, E>
+where
+    T: Service<R>,
+{
+    type Response = T::Response;
+    type Error = VersionError<T::Error, E>;
+
+    async fn call(&self, req: Versioned<R>) -> Result<Self::Response, Self::Error> {
+        let req = self.convert(req)?;
+        self.inner.call(req).await.map_err(VersionError::Inner)
+    }
+}
+
+impl<F: MakeService, R, E> MakeService for MapRequestVersion<F, R, E> {
+    type Service = MapRequestVersion<F::Service, R, E>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(MapRequestVersion {
+            served: self.served,
+            routes: self.routes.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        version_metadata::<Self::Service>(self.served, self.routes.len())
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, R, E> AsyncMakeService for MapRequestVersion<F, R, E> {
+    type Service = MapRequestVersion<F::Service, R, E>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(MapRequestVersion {
+            served: self.served,
+            routes: self.routes.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        version_metadata::<Self::Service>(self.served, self.routes.len())
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+fn version_metadata<S>(served: u32, versions: usize) -> ServiceMetadata {
+    ServiceMetadata::of::<S>()
+        .with_config("version", served)
+        .with_resource("versions", versions as u64)
+}
+
+impl_wrap_inner!(MapRequestVersion<_, R, E> { served, routes });
+
+impl<T, R, E> Layered for MapRequestVersion<T, R, E> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(MapRequestVersion<T, R, E>);
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{MapRequestVersion, RequestVersion, VersionConverters, VersionError, Versioned};
+    use crate::{layer::FactoryLayer, test_util::block_on, Service};
+
+    struct Echo;
+
+    impl Service<String> for Echo {
+        type Response = String;
+        type Error = Infallible;
+
+        async fn call(&self, req: String) -> Result<String, Infallible> {
+            Ok(req)
+        }
+    }
+
+    #[test]
+    fn converts_through_the_fewest_steps() {
+        let converters = VersionConverters::new()
+            .register(1, 2, |r: String| Ok(r + ">2"))
+            .register(2, 3, |r: String| {
+                if r.contains("bad") {
+                    return Err("bad request");
+                }
+                Ok(r + ">3")
+            })
+            .register(1, 3, |r: String| Ok(r + ">3!"))
+            .register(1, 3, |r: String| Ok(r + ">>3"));
+        assert_eq!(converters.len(), 3);
+        let svc = MapRequestVersion::layer(converters).layer(&RequestVersion(3), Echo);
+        assert_eq!(svc.served(), 3);
+        assert!(svc.supports(2) && !svc.supports(4));
+
+        let call = |version, req: &str| block_on(svc.call(Versioned::new(version, req.into())));
+        assert_eq!(call(3, "a").unwrap(), "a");
+        assert_eq!(call(2, "a").unwrap(), "a>3");
+        assert_eq!(call(1, "a").unwrap(), "a>>3");
+        assert!(matches!(
+            call(2, "bad"),
+            Err(VersionError::Convert("bad request"))
+        ));
+        assert!(matches!(
+            call(4, "a"),
+            Err(VersionError::Unsupported {
+                version: 4,
+                served: 3
+            })
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{error::Error, fmt::Display, future::Future, rc::Rc, sync::Arc};
+
+use crate::{either::Either, layer::Layered};
+
+/// A service which prepares itself before it takes requests, e.g. by opening
+/// connections to its upstreams or priming a cache.
+///
+/// A service made by [`FactoryStack::make_warmed_async`](crate::stack::FactoryStack::make_warmed_async)
+/// is warmed up before it is returned, so it is only swapped in once ready.
+///
+/// Layered services warm up their inner service: the chain is walked through
+/// [`Layered::inner`] down to the leaf, which implements `Warmup` itself.
+/// Leaves with nothing to prepare implement it with an empty body.
+pub trait Warmup {
+    type Error;
+
+    fn warmup(&self) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+impl<T> Warmup for T
+where
+    T: Layered,
+    T::Inner: Warmup,
+{
+    type Error = <T::Inner as Warmup>::Error;
+
+    #[inline]
+    fn warmup(&self) -> impl Future<Output = Result<(), Self::Error>> {
+        self.inner().warmup()
+    }
+}
+
+impl<A: Warmup, B: Warmup> Warmup for Either<A, B> {
+    type Error = Either<A::Error, B::Error>;
+
+    async fn warmup(&self) -> Result<(), Self::Error> {
+        match self {
+            Either::Left(a) => a.warmup().await.map_err(Either::Left),
+            Either::Right(b) => b.warmup().await.map_err(Either::Right),
+        }
+    }
+}
+
+impl<T: Warmup + ?Sized> Warmup for Arc<T> {
+    type Error = T::Error;
+
+    #[inline]
+    fn warmup(&self) -> impl Future<Output = Result<(), Self::Error>> {
+        (**self).warmup()
+    }
+}
+
+impl<T: Warmup + ?Sized> Warmup for Rc<T> {
+    type Error = T::Error;
+
+    #[inline]
+    fn warmup(&self) -> impl Future<Output = Result<(), Self::Error>> {
+        (**self).warmup()
+    }
+}
+
+/// Error returned by [`FactoryStack::make_warmed_async`](crate::stack::FactoryStack::make_warmed_async).
+#[derive(Debug)]
+pub enum WarmupError<ME, WE> {
+    /// The service could not be made.
+    Make(ME),
+    /// The service was made but failed to warm up.
+    Warmup(WE),
+}
+
+impl<ME: Display, WE: Display> Display for WarmupError<ME, WE> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarmupError::Make(e) => e.fmt(f),
+            WarmupError::Warmup(e) => write!(f, "warmup failed: {e}"),
+        }
+    }
+}
+
+impl<ME: Error + 'static, WE: Error + 'static> Error for WarmupError<ME, WE> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WarmupError::Make(e) => Some(e),
+            WarmupError::Warmup(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::{Warmup, WarmupError};
+    use crate::{either::Either, stack::FactoryStack, test_util::block_on, Service};
+
+    /// Counts its warmups, which fail if it is `cold`.
+    #[derive(Clone, Default)]
+    struct Pool {
+        warmed: Rc<Cell<usize>>,
+        cold: bool,
+    }
+
+    impl Service<u32> for Pool {
+        type Response = u32;
+        type Error = &'static str;
+
+        async fn call(&self, req: u32) -> Result<u32, &'static str> {
+            Ok(req)
+        }
+    }
+
+    impl Warmup for Pool {
+        type Error = &'static str;
+
+        async fn warmup(&self) -> Result<(), &'static str> {
+            self.warmed.set(self.warmed.get() + 1);
+            if self.cold {
+                return Err("cold");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stacks_warm_the_leaf_before_returning() {
+        let pool = Pool::default();
+        let stack = FactoryStack::new(())
+            .push_clone_leaf(pool.clone())
+            .push_map_target(|req: u32| req + 1);
+        let svc = block_on(stack.make_warmed_async()).unwrap();
+        assert_eq!(pool.warmed.get(), 1);
+        block_on(stack.make_warmed_via_ref_async(Some(&svc))).unwrap();
+        assert_eq!(pool.warmed.get(), 2);
+        assert_eq!(block_on(svc.call(1)), Ok(2));
+
+        let cold = Pool {
+            cold: true,
+            ..pool.clone()
+        };
+        let stack = FactoryStack::new(()).push_clone_leaf(cold);
+        let Err(err) = block_on(stack.make_warmed_async()) else {
+            panic!("a cold pool was returned");
+        };
+        assert!(matches!(err, WarmupError::Warmup("cold")));
+        assert_eq!(err.to_string(), "warmup failed: cold");
+        assert_eq!(pool.warmed.get(), 3);
+    }
+
+    #[test]
+    fn wrappers_warm_the_inner_service() {
+        let pool = Pool::default();
+        let either: Either<Rc<Pool>, Pool> = Either::Left(Rc::new(pool.clone()));
+        assert!(block_on(either.warmup()).is_ok());
+        let cold = Pool {
+            cold: true,
+            ..pool.clone()
+        };
+        let either: Either<Rc<Pool>, Pool> = Either::Right(cold);
+        assert!(matches!(
+            block_on(either.warmup()),
+            Err(Either::Right("cold"))
+        ));
+        assert_eq!(pool.warmed.get(), 2);
+    }
+}
@@ -0,0 +1,224 @@
+use std::{marker::PhantomData, time::Duration};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    time::Clock,
+    AsyncMakeService, MakeService, Param, ParamRef, Service, ServiceMetadata,
+};
+
+/// Calls of [`SlowRequestWatchdog`] slower than this are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowRequestThreshold(pub Duration);
+
+/// Receives the slow calls of [`SlowRequestWatchdog`], with the request
+/// metadata `M` and the latency of the call.
+pub trait SlowRequestObserver<M> {
+    fn on_slow_request(&self, meta: &M, latency: Duration);
+}
+
+impl<M, F: Fn(&M, Duration)> SlowRequestObserver<M> for F {
+    #[inline]
+    fn on_slow_request(&self, meta: &M, latency: Duration) {
+        (self)(meta, latency)
+    }
+}
+
+/// A middleware which reports calls slower than a threshold, e.g. to log them
+/// or emit a tracing event.
+///
+/// The metadata `M` of the request is read through `ParamRef<M>` and cloned
+/// before the call, since the request is moved into the inner service. Calls
+/// are reported when they complete, whether they succeed or fail; a call which
+/// never completes is not reported.
+///
+/// The threshold and the clock `CL` are taken from the stack config through
+/// `Param<SlowRequestThreshold>` and `Param<CL>`. It is its own factory.
+pub struct SlowRequestWatchdog<T, O, M, CL> {
+    threshold: Duration,
+    observer: O,
+    clock: CL,
+    inner: T,
+    _marker: PhantomData<fn(&M)>,
+}
+
+impl<T, O, M, CL> SlowRequestWatchdog<T, O, M, CL> {
+    pub fn layer<C>(observer: O) -> impl FactoryLayer<C, T, Factory = Self>
+    where
+        C: Param<SlowRequestThreshold> + Param<CL>,
+        O: Clone,
+    {
+        layer_fn(move |c: &C, inner| SlowRequestWatchdog {
+            threshold: Param::<SlowRequestThreshold>::param(c).0,
+            observer: observer.clone(),
+            clock: Param::<CL>::param(c),
+            inner,
+            _marker: PhantomData,
+        })
+    }
+
+    #[inline]
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+}
+
+impl<T, O, M, CL, R> Service<R> for SlowRequestWatchdog<T, O, M, CL>
+where
+    T: Service<R>,
+    O: SlowRequestObserver<M>,
+    R: ParamRef<M>,
+    M: Clone,
+    CL: Clock,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        let meta = req.param_ref().clone();
+        let start = self.clock.now();
+        let res = self.inner.call(req).await;
+        let latency = self.clock.now().saturating_duration_since(start);
+        if latency > self.threshold {
+            self.observer.on_slow_request(&meta, latency);
+        }
+        res
+    }
+}
+
+impl<F: MakeService, O: Clone, M, CL: Clone> MakeService for SlowRequestWatchdog<F, O, M, CL> {
+    type Service = SlowRequestWatchdog<F::Service, O, M, CL>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(SlowRequestWatchdog {
+            threshold: self.threshold,
+            observer: self.observer.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("threshold", format_args!("{:?}", self.threshold))
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService, O: Clone, M, CL: Clone> AsyncMakeService
+    for SlowRequestWatchdog<F, O, M, CL>
+{
+    type Service = SlowRequestWatchdog<F::Service, O, M, CL>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(SlowRequestWatchdog {
+            threshold: self.threshold,
+            observer: self.observer.clone(),
+            clock: self.clock.clone(),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            _marker: PhantomData,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("threshold", format_args!("{:?}", self.threshold))
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(SlowRequestWatchdog<_, O, M, CL> { threshold, observer, clock, _marker });
+
+impl<T, O, M, CL> Layered for SlowRequestWatchdog<T, O, M, CL> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(SlowRequestWatchdog<T, O, M, CL>);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, convert::Infallible, rc::Rc, time::Duration};
+
+    use super::{SlowRequestThreshold, SlowRequestWatchdog};
+    use crate::{
+        layer::FactoryLayer, test_util::block_on, time::MockTimer, Param, ParamRef, Service,
+    };
+
+    struct Config(SlowRequestThreshold, MockTimer);
+
+    impl Param<SlowRequestThreshold> for Config {
+        fn param(&self) -> SlowRequestThreshold {
+            self.0
+        }
+    }
+
+    impl Param<MockTimer> for Config {
+        fn param(&self) -> MockTimer {
+            self.1.clone()
+        }
+    }
+
+    struct Request {
+        path: &'static str,
+        millis: u64,
+    }
+
+    impl ParamRef<&'static str> for Request {
+        fn param_ref(&self) -> &&'static str {
+            &self.path
+        }
+    }
+
+    /// Takes the mock time asked by the request.
+    struct Work(MockTimer);
+
+    impl Service<Request> for Work {
+        type Response = ();
+        type Error = Infallible;
+
+        async fn call(&self, req: Request) -> Result<(), Infallible> {
+            self.0.advance(Duration::from_millis(req.millis));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reports_slow_calls() {
+        let timer = MockTimer::new();
+        let slow = Rc::new(RefCell::new(Vec::new()));
+        let observer = {
+            let slow = slow.clone();
+            move |path: &&'static str, latency: Duration| slow.borrow_mut().push((*path, latency))
+        };
+        let config = Config(
+            SlowRequestThreshold(Duration::from_millis(10)),
+            timer.clone(),
+        );
+        let svc =
+            SlowRequestWatchdog::<_, _, _, MockTimer>::layer(observer).layer(&config, Work(timer));
+
+        for (path, millis) in [("/fast", 5), ("/edge", 10), ("/slow", 30)] {
+            block_on(svc.call(Request { path, millis })).unwrap();
+        }
+        assert_eq!(*slow.borrow(), [("/slow", Duration::from_millis(30))]);
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    future::{poll_fn, Future},
+    sync::atomic::{AtomicU32, Ordering},
+    task::Poll,
+};
+
+use crate::{
+    layer::{impl_wrap_inner, layer_fn, FactoryLayer, Layered},
+    shutdown::impl_shutdown_inner,
+    AsyncMakeService, MakeService, Param, Service, ServiceMetadata,
+};
+
+/// Number of calls [`YieldEvery`] lets through between yields. `0` never
+/// yields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct YieldBudget(pub u32);
+
+/// Yield to the runtime once, letting other tasks of the thread run.
+///
+/// It wakes the task right away, so it works on any runtime.
+pub fn yield_now() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    poll_fn(move |cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
+
+/// A middleware which yields to the runtime before the inner call once every
+/// budget of calls is used up.
+///
+/// A burst of requests to a service which rarely waits can keep the other
+/// tasks of a thread-per-core runtime from running; the yield points keep it
+/// responsive. The budget is taken from the stack config through
+/// `Param<YieldBudget>`. It is its own factory.
+pub struct YieldEvery<T> {
+    budget: u32,
+    calls: AtomicU32,
+    inner: T,
+}
+
+impl<T> YieldEvery<T> {
+    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = YieldEvery<T>>
+    where
+        C: Param<YieldBudget>,
+    {
+        layer_fn(|c: &C, inner| YieldEvery {
+            budget: c.param().0,
+            calls: AtomicU32::new(0),
+            inner,
+        })
+    }
+
+    #[inline]
+    pub fn budget(&self) -> u32 {
+        self.budget
+    }
+}
+
+impl<T, R> Service<R> for YieldEvery<T>
+where
+    T: Service<R>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn call(&self, req: R) -> Result<Self::Response, Self::Error> {
+        if self.budget != 0 {
+            let calls = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+            if calls.is_multiple_of(self.budget) {
+                yield_now().await;
+            }
+        }
+        self.inner.call(req).await
+    }
+}
+
+impl<F: MakeService> MakeService for YieldEvery<F> {
+    type Service = YieldEvery<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(YieldEvery {
+            budget: self.budget,
+            calls: AtomicU32::new(0),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("budget", self.budget)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for YieldEvery<F> {
+    type Service = YieldEvery<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(YieldEvery {
+            budget: self.budget,
+            calls: AtomicU32::new(0),
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        self.inner.validate()
+    }
+
+    fn service_metadata(&self) -> ServiceMetadata {
+        ServiceMetadata::of::<Self::Service>()
+            .with_config("budget", self.budget)
+            .with_inner(self.inner.service_metadata())
+    }
+}
+
+impl_wrap_inner!(YieldEvery<_> { budget, calls });
+
+impl<T> Layered for YieldEvery<T> {
+    type Inner = T;
+
+    #[inline]
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+}
+
+impl_shutdown_inner!(YieldEvery<T>);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, pin::pin};
+
+    use super::{yield_now, YieldBudget, YieldEvery};
+    use crate::{layer::FactoryLayer, test_util::WakeCounter, Service};
+
+    struct Echo;
+
+    impl Service<u32> for Echo {
+        type Response = u32;
+        type Error = Infallible;
+
+        async fn call(&self, req: u32) -> Result<u32, Infallible> {
+            Ok(req)
+        }
+    }
+
+    #[test]
+    fn yield_now_wakes_itself() {
+        let waker = WakeCounter::new();
+        let mut fut = pin!(yield_now());
+        assert!(waker.poll(fut.as_mut()).is_pending());
+        assert_eq!(waker.count(), 1);
+        assert!(waker.poll(fut.as_mut()).is_ready());
+    }
+
+    #[test]
+    fn yields_once_per_budget() {
+        let pending_calls = |budget| {
+            let svc = YieldEvery::layer().layer(&YieldBudget(budget), Echo);
+            let waker = WakeCounter::new();
+            (1..=6)
+                .filter(|&req| {
+                    let mut call = pin!(svc.call(req));
+                    let pending = waker.poll(call.as_mut()).is_pending();
+                    if pending {
+                        assert!(waker.poll(call.as_mut()).is_ready());
+                    }
+                    pending
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pending_calls(3), [3, 6]);
+        assert_eq!(pending_calls(1), [1, 2, 3, 4, 5, 6]);
+        assert!(pending_calls(0).is_empty());
+    }
+}